@@ -0,0 +1,68 @@
+//! A ceiling on requests the node has taken on but not yet answered.
+//!
+//! The queue depth limit only counts jobs the scheduler holds. Streamed
+//! prompts are read on tasks of their own before they get there, and under a
+//! flood those tasks would pile up without bound. So every request holds a
+//! [`Permit`] from the moment it is accepted, through reading, queueing and
+//! running, until its answer is handed back. A request that finds none left is
+//! refused with `Busy` straight away, before anything is spawned for it.
+
+use std::sync::Arc;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+#[derive(Clone)]
+pub struct Admission {
+    limit: usize,
+    permits: Arc<Semaphore>,
+}
+
+impl Admission {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            permits: Arc::new(Semaphore::new(limit)),
+        }
+    }
+
+    /// A permit for one request, or `None` if the node has `limit` pending.
+    pub fn try_admit(&self) -> Option<Permit> {
+        self.permits
+            .clone()
+            .try_acquire_owned()
+            .ok()
+            .map(Permit::new)
+    }
+
+    /// A permit for each of `n` jobs, e.g. the two generations of a
+    /// comparison, or none at all.
+    pub fn try_admit_many(&self, n: u32) -> Option<Vec<Permit>> {
+        let mut permit = self.permits.clone().try_acquire_many_owned(n).ok()?;
+        let mut permits = Vec::with_capacity(n as usize);
+        for _ in 1..n {
+            permits.push(Permit::new(permit.split(1)?));
+        }
+        permits.push(Permit::new(permit));
+        Some(permits)
+    }
+
+    /// Requests accepted and not yet answered.
+    pub fn pending(&self) -> usize {
+        self.limit - self.permits.available_permits()
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+}
+
+/// One admitted request's place; given back when dropped.
+pub struct Permit {
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Permit {
+    fn new(permit: OwnedSemaphorePermit) -> Self {
+        Self { _permit: permit }
+    }
+}
@@ -0,0 +1,57 @@
+//! The software each connected peer announced through identify, e.g.
+//! `mesh-ai-node/0.1.0`, so request logs say what sent the request.
+//!
+//! A client may send its first request before identify has completed on the
+//! connection. Such a request is logged without an agent and its id is kept,
+//! and once identify arrives the agent is logged with the ids of the requests
+//! that beat it, so they can still be linked.
+
+use std::collections::HashMap;
+
+use libp2p::PeerId;
+
+use crate::node;
+
+/// Early request ids kept per peer; a peer that never completes identify
+/// can't grow the list past this.
+const MAX_EARLY_REQUESTS: usize = 16;
+
+#[derive(Debug, Default)]
+pub struct PeerAgents {
+    agents: HashMap<PeerId, String>,
+    /// Requests received from a peer before its identify, by request id.
+    early: HashMap<PeerId, Vec<u64>>,
+}
+
+impl PeerAgents {
+    /// The agent `peer` announced, for logging request `request_id`. If
+    /// identify hasn't arrived yet, the request is remembered until it does.
+    pub fn for_request(&mut self, peer: PeerId, request_id: u64) -> Option<&str> {
+        if !self.agents.contains_key(&peer) {
+            let early = self.early.entry(peer).or_default();
+            if early.len() < MAX_EARLY_REQUESTS {
+                early.push(request_id);
+            }
+        }
+        self.agents.get(&peer).map(String::as_str)
+    }
+
+    /// Records the agent from `peer`'s identify, returning it with the ids of
+    /// requests that arrived before it, if there were any.
+    pub fn identified(&mut self, peer: PeerId, agent_version: &str) -> Option<(&str, Vec<u64>)> {
+        let agent = node::announced_agent(agent_version).unwrap_or_else(|| "unknown".to_string());
+        let early = self.early.remove(&peer);
+        let agent = self.agents.entry(peer).insert_entry(agent).into_mut();
+        early.map(|early| (agent.as_str(), early))
+    }
+
+    pub fn agent(&self, peer: &PeerId) -> Option<&str> {
+        self.agents.get(peer).map(String::as_str)
+    }
+
+    /// Forgets `peer` once its last connection closes.
+    pub fn remove(&mut self, peer: &PeerId) {
+        self.agents.remove(peer);
+        self.early.remove(peer);
+    }
+}
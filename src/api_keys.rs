@@ -0,0 +1,350 @@
+//! Named API keys, so that requests from several teams sharing the same few
+//! hosts can be told apart for quotas and usage.
+//!
+//! The keys file holds one key per line: the team it belongs to, the key's
+//! SHA-256 in hex, and optionally the team's quota as `REQUESTS:TOKENS`, when
+//! the key stops working as `expires=UNIX_SECONDS`, and the highest priority
+//! its requests may ask for as `priority=N`, e.g.
+//! `search 9f86d0...0f00a08 1000:200000 expires=1767225600 priority=5`.
+//! Without one a key's requests, like those without a key, run at priority
+//! 0, however high they ask for. Only hashes are
+//! kept, e.g. made with `printf %s "$KEY" | sha256sum`. Blank lines and `#`
+//! comments are ignored. A team may have several keys; a quota given on more
+//! than one of them must be the same. Deleting a key's line and reloading
+//! revokes it.
+//!
+//! To rotate a key, add the new one next to the old, possibly with an expiry
+//! on the old, and move clients over. Requests are logged with the id of the
+//! key they used, the first eight hex digits of its hash, and the status
+//! output counts each key's uses, so the old key can be seen to be unused
+//! before it is removed.
+//!
+//! A key only decides whose allowance a request counts against. It never
+//! lifts a ban or the denylist, and peers without one are still served unless
+//! the node requires a key.
+
+use std::{
+    collections::HashMap,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use sha2::{Digest, Sha256};
+use tokio::sync::oneshot;
+
+use crate::{identity::decode_hex, quota::Limits};
+
+/// Longest team name, in characters.
+pub const MAX_TEAM_CHARS: usize = 32;
+
+/// A key from the file, as far as it may be logged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiKey {
+    pub team: String,
+    /// The first eight hex digits of the key's hash, which identify it in
+    /// logs without giving it away.
+    pub id: String,
+    /// Seconds since the Unix epoch after which the key is refused.
+    pub expires_at: Option<u64>,
+    /// The highest priority the key's requests may run at.
+    pub max_priority: u8,
+}
+
+impl ApiKey {
+    /// The priority a request asking for `asked` runs at, given the key it
+    /// was accepted with, if any.
+    pub fn allowed_priority(key: Option<&Self>, asked: u8) -> u8 {
+        asked.min(key.map_or(0, |key| key.max_priority))
+    }
+}
+
+/// Asks the swarm loop to re-read the keys file, answered with how many keys
+/// it holds or why it couldn't be read.
+pub type ReloadRequest = oneshot::Sender<Result<usize, String>>;
+
+/// Why a request's key wasn't accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyRefusal {
+    /// Not in the file: mistyped, revoked, or not yet added on this node.
+    Unknown,
+    Expired,
+    /// The node requires a key and the request carried none.
+    Missing,
+}
+
+impl KeyRefusal {
+    /// Whether the request carried a key that isn't good, which counts
+    /// towards a ban as guessing keys would. A missing key doesn't.
+    pub fn is_bad_key(self) -> bool {
+        matches!(self, Self::Unknown | Self::Expired)
+    }
+}
+
+impl fmt::Display for KeyRefusal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Unknown => "Unknown or revoked API key",
+            Self::Expired => "Expired API key",
+            Self::Missing => "This node requires an API key",
+        })
+    }
+}
+
+pub struct ApiKeys {
+    path: PathBuf,
+    /// Each key, by its hash.
+    keys: HashMap<[u8; 32], ApiKey>,
+    /// Quotas given in the file, by team.
+    limits: HashMap<String, Limits>,
+    /// Requests accepted with each key since the node started, by its hash.
+    /// Kept across reloads.
+    uses: HashMap<[u8; 32], u64>,
+}
+
+impl ApiKeys {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let (keys, limits) = read(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            keys,
+            limits,
+            uses: HashMap::new(),
+        })
+    }
+
+    /// Re-reads the file, returning how many keys it holds. On error the
+    /// current keys are kept.
+    pub fn reload(&mut self) -> io::Result<usize> {
+        let (keys, limits) = read(&self.path)?;
+        self.uses.retain(|hash, _| keys.contains_key(hash));
+        self.keys = keys;
+        self.limits = limits;
+        Ok(self.keys.len())
+    }
+
+    /// The entry for `key` if it may be used now. The use isn't counted
+    /// until [`Self::record_use`], once the request is served.
+    pub fn check(&self, key: &str) -> Result<&ApiKey, KeyRefusal> {
+        let entry = self.keys.get(&digest(key)).ok_or(KeyRefusal::Unknown)?;
+        if entry.expires_at.is_some_and(|at| unix_now() >= at) {
+            return Err(KeyRefusal::Expired);
+        }
+        Ok(entry)
+    }
+
+    /// Counts a request accepted with `key`. Unknown keys aren't counted.
+    pub fn record_use(&mut self, key: &str) {
+        let hash = digest(key);
+        if self.keys.contains_key(&hash) {
+            *self.uses.entry(hash).or_default() += 1;
+        }
+    }
+
+    /// Every key with its uses since the node started, by team and id.
+    pub fn usage(&self) -> Vec<(&ApiKey, u64)> {
+        let mut usage: Vec<(&ApiKey, u64)> = self
+            .keys
+            .iter()
+            .map(|(hash, key)| (key, self.uses.get(hash).copied().unwrap_or_default()))
+            .collect();
+        usage.sort_by(|a, b| (&a.0.team, &a.0.id).cmp(&(&b.0.team, &b.0.id)));
+        usage
+    }
+
+    /// The teams with keys, each once.
+    pub fn teams(&self) -> impl Iterator<Item = String> + '_ {
+        let teams: std::collections::BTreeSet<&String> =
+            self.keys.values().map(|key| &key.team).collect();
+        teams.into_iter().cloned()
+    }
+
+    /// The quotas the file gives, by team.
+    pub fn limits(&self) -> impl Iterator<Item = (String, Limits)> + '_ {
+        self.limits
+            .iter()
+            .map(|(team, limits)| (team.clone(), *limits))
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+type Keys = (HashMap<[u8; 32], ApiKey>, HashMap<String, Limits>);
+
+fn read(path: &Path) -> io::Result<Keys> {
+    let invalid =
+        |line: &str, e: String| io::Error::new(io::ErrorKind::InvalidData, format!("{line}: {e}"));
+    let mut keys = HashMap::new();
+    let mut limits: HashMap<String, Limits> = HashMap::new();
+    for line in fs::read_to_string(path)?.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let (Some(team), Some(hash)) = (fields.next(), fields.next()) else {
+            return Err(invalid(
+                line,
+                "expected TEAM SHA256 [REQUESTS:TOKENS] [expires=UNIX_SECONDS] [priority=N]".into(),
+            ));
+        };
+        let (mut quota, mut expires_at, mut priority) = (None, None, None);
+        for field in fields {
+            let (slot, value) = if let Some(at) = field.strip_prefix("expires=") {
+                (&mut expires_at, at)
+            } else if let Some(n) = field.strip_prefix("priority=") {
+                (&mut priority, n)
+            } else {
+                (&mut quota, field)
+            };
+            if slot.replace(value).is_some() {
+                return Err(invalid(line, format!("`{field}` given twice")));
+            }
+        }
+        let expires_at = expires_at
+            .map(|at| at.parse::<u64>())
+            .transpose()
+            .map_err(|e| invalid(line, format!("invalid expiry: {e}")))?;
+        let max_priority = priority
+            .map(|n| n.parse::<u8>())
+            .transpose()
+            .map_err(|e| invalid(line, format!("invalid priority, expected 0 to 255: {e}")))?
+            .unwrap_or(0);
+        let team_ok = (1..=MAX_TEAM_CHARS).contains(&team.len())
+            && team
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        if !team_ok {
+            return Err(invalid(
+                line,
+                format!("expected a team name of 1 to {MAX_TEAM_CHARS} letters, digits or `-_.`"),
+            ));
+        }
+        let hash: [u8; 32] = decode_hex(&hash.to_ascii_lowercase())
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| invalid(line, "expected a SHA-256 of 64 hex characters".into()))?;
+        let key = ApiKey {
+            team: team.to_string(),
+            id: hash[..4].iter().map(|b| format!("{b:02x}")).collect(),
+            expires_at,
+            max_priority,
+        };
+        if keys.insert(hash, key).is_some() {
+            return Err(invalid(line, "key listed more than once".into()));
+        }
+        if let Some(quota) = quota {
+            let quota: Limits = quota.parse().map_err(|e| invalid(line, e))?;
+            if limits
+                .insert(team.to_string(), quota)
+                .is_some_and(|q| q != quota)
+            {
+                return Err(invalid(
+                    line,
+                    format!("team `{team}` given different quotas"),
+                ));
+            }
+        }
+    }
+    Ok((keys, limits))
+}
+
+fn digest(key: &str) -> [u8; 32] {
+    Sha256::digest(key.as_bytes()).into()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    /// A keys file holding `contents`, in a directory removed when dropped.
+    fn keys_file(name: &str, contents: &str) -> (TempDir, PathBuf) {
+        let dir = TempDir::new(&format!("keys-{name}"));
+        let file = dir.join("keys");
+        fs::write(&file, contents).unwrap();
+        (dir, file)
+    }
+
+    fn hash(key: &str) -> String {
+        Sha256::digest(key.as_bytes())
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+
+    #[test]
+    fn keys_carry_their_priority_cap() {
+        let (_dir, file) = keys_file(
+            "priority",
+            &format!(
+                "search {} priority=5\nbatch {} 10:\n",
+                hash("search-key"),
+                hash("batch-key")
+            ),
+        );
+        let keys = ApiKeys::load(&file).unwrap();
+        let search = keys.check("search-key").unwrap().clone();
+        assert_eq!(search.max_priority, 5);
+        let batch = keys.check("batch-key").unwrap().clone();
+        assert_eq!(batch.max_priority, 0);
+        assert_eq!(keys.teams().collect::<Vec<_>>(), ["batch", "search"]);
+
+        assert_eq!(ApiKey::allowed_priority(Some(&search), 9), 5);
+        assert_eq!(ApiKey::allowed_priority(Some(&search), 2), 2);
+        assert_eq!(ApiKey::allowed_priority(Some(&batch), 9), 0);
+        assert_eq!(ApiKey::allowed_priority(None, 255), 0);
+    }
+
+    #[test]
+    fn counts_only_recorded_uses() {
+        let (_dir, file) = keys_file(
+            "uses",
+            &format!(
+                "search {}
+old {} expires=1
+",
+                hash("search-key"),
+                hash("old-key")
+            ),
+        );
+        let mut keys = ApiKeys::load(&file).unwrap();
+        assert!(keys.check("search-key").is_ok());
+        assert!(keys.check("search-key").is_ok());
+        keys.record_use("search-key");
+        keys.record_use("guessed-key");
+        let usage: Vec<_> = keys
+            .usage()
+            .into_iter()
+            .map(|(key, uses)| (key.team.clone(), uses))
+            .collect();
+        assert_eq!(usage, [("old".to_string(), 0), ("search".to_string(), 1)]);
+
+        assert_eq!(keys.check("guessed-key"), Err(KeyRefusal::Unknown));
+        assert_eq!(keys.check("old-key"), Err(KeyRefusal::Expired));
+        assert!(KeyRefusal::Unknown.is_bad_key());
+        assert!(KeyRefusal::Expired.is_bad_key());
+        assert!(!KeyRefusal::Missing.is_bad_key());
+    }
+
+    #[test]
+    fn refuses_bad_priorities() {
+        for bad in ["priority=256", "priority=high", "priority=1 priority=2"] {
+            let (_dir, file) = keys_file("bad-priority", &format!("team {} {bad}\n", hash("k")));
+            let e = ApiKeys::load(&file).err().expect(bad);
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{bad}: {e}");
+        }
+    }
+}
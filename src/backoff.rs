@@ -0,0 +1,118 @@
+//! Exponential backoff with jitter, for redialing infrastructure peers.
+//!
+//! When a relay restarts, every node behind it loses its connection at the
+//! same moment. Without jitter they would all redial on the same schedule and
+//! hit the relay in waves; the random part spreads them out.
+
+use std::{
+    hash::{BuildHasher, Hasher, RandomState},
+    time::Duration,
+};
+
+#[derive(Debug, Clone)]
+pub struct BackoffConfig {
+    /// Delay before the first retry; doubles for each one after.
+    pub initial: Duration,
+    /// Cap on the exponential part.
+    pub max: Duration,
+    /// Largest random addition, as a fraction of the exponential part: with
+    /// 0.5, a 10s delay becomes anything from 10s to 15s.
+    pub jitter: f64,
+}
+
+impl Default for BackoffConfig {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+            jitter: 1.0,
+        }
+    }
+}
+
+pub struct Backoff {
+    config: BackoffConfig,
+    failures: u32,
+}
+
+impl Backoff {
+    pub fn new(config: BackoffConfig) -> Self {
+        Self {
+            config,
+            failures: 0,
+        }
+    }
+
+    /// The delay before the next attempt. Each call counts as a failure, so
+    /// the one after is longer.
+    pub fn next_delay(&mut self) -> Duration {
+        let base = self
+            .config
+            .initial
+            .saturating_mul(2u32.saturating_pow(self.failures))
+            .min(self.config.max);
+        self.failures = self.failures.saturating_add(1);
+        let jitter = base.as_secs_f64() * self.config.jitter.max(0.0) * random_fraction();
+        base.saturating_add(Duration::try_from_secs_f64(jitter).unwrap_or(Duration::MAX))
+    }
+
+    /// Starts over from the initial delay, e.g. once a connection succeeds.
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+}
+
+/// A random number, without pulling in a random number generator.
+pub(crate) fn random_u64() -> u64 {
+    // `RandomState` is seeded randomly per instance.
+    RandomState::new().build_hasher().finish()
+}
+
+/// A random number in `[0, 1)`.
+pub(crate) fn random_fraction() -> f64 {
+    let bits = random_u64() >> 11;
+    bits as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn doubles_up_to_the_cap_and_starts_over_on_reset() {
+        let mut backoff = Backoff::new(BackoffConfig {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(5),
+            jitter: 0.0,
+        });
+        let delays: Vec<_> = (0..5).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 5, 5]);
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn jitter_adds_at_most_its_fraction() {
+        let mut backoff = Backoff::new(BackoffConfig {
+            initial: Duration::from_secs(10),
+            max: Duration::from_secs(10),
+            jitter: 0.5,
+        });
+        for _ in 0..100 {
+            let delay = backoff.next_delay();
+            assert!(delay >= Duration::from_secs(10) && delay < Duration::from_secs(15));
+        }
+    }
+
+    #[test]
+    fn huge_delays_saturate() {
+        let mut backoff = Backoff::new(BackoffConfig {
+            initial: Duration::MAX,
+            max: Duration::MAX,
+            jitter: 1.0,
+        });
+        for _ in 0..40 {
+            assert_eq!(backoff.next_delay(), Duration::MAX);
+        }
+    }
+}
@@ -0,0 +1,335 @@
+//! Escalating temporary bans for peers that keep sending bad requests.
+//!
+//! Failures are counted over a sliding window. Crossing the threshold bans the
+//! peer; every repeat offence doubles the ban, up to a cap. Bans lift on their
+//! own once they expire. A peer's record, strikes included, is forgotten after
+//! [`RECORD_TTL`] without failures, and the least recently failing peers make
+//! way once [`BanConfig::max_records`] are kept. A peer serving a ban never
+//! makes way: when every record is a ban in force, failures of peers without
+//! a record aren't counted until one lifts.
+
+use std::{
+    collections::{HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+use libp2p::PeerId;
+use serde::Serialize;
+
+use crate::bounded::{BoundedConfig, BoundedMap};
+
+/// How long a record is kept after the peer's last failure, unless the ban it
+/// earned lasts longer.
+pub const RECORD_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Clone)]
+pub struct BanConfig {
+    /// Failures within `window` that trigger a ban.
+    pub threshold: usize,
+    pub window: Duration,
+    /// Length of the first ban; later bans double from here.
+    pub base_ban: Duration,
+    pub max_ban: Duration,
+    /// Most peers with a record at once.
+    pub max_records: usize,
+}
+
+#[derive(Default)]
+struct PeerRecord {
+    failures: VecDeque<Instant>,
+    /// Number of bans served so far, used to escalate the next one.
+    strikes: u32,
+    banned_until: Option<Instant>,
+}
+
+/// Where a peer stands with the ban list.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Standing {
+    /// Failures within the window, towards the next ban.
+    pub recent_failures: usize,
+    /// Bans served so far.
+    pub strikes: u32,
+    /// Seconds left on the current ban, if banned.
+    pub banned_for_secs: Option<u64>,
+}
+
+pub struct BanList {
+    config: BanConfig,
+    peers: BoundedMap<PeerId, PeerRecord>,
+    /// Peers whose record is kept for good, not just while banned.
+    kept: HashSet<PeerId>,
+    /// Whether the list is full of bans in force, so it is logged once.
+    full: bool,
+}
+
+impl BanList {
+    pub fn new(config: BanConfig) -> Self {
+        let peers = BoundedMap::new(BoundedConfig {
+            capacity: config.max_records,
+            // Failing is what counts as a use, so a ban can't be pruned
+            // before it lifts.
+            ttl: Some(RECORD_TTL.max(config.max_ban)),
+        });
+        Self {
+            config,
+            peers,
+            kept: HashSet::new(),
+            full: false,
+        }
+    }
+
+    /// Keeps `peer`'s record however long ago it failed, e.g. for the relay.
+    pub fn exempt(&mut self, peer: PeerId) {
+        self.kept.insert(peer);
+        self.peers.exempt(peer);
+    }
+
+    /// Records a failed request from `peer`. Returns the ban length if this
+    /// failure got the peer banned. Not counted for a peer without a record
+    /// while every record is a ban in force.
+    pub fn record_failure(&mut self, peer: PeerId, now: Instant) -> Option<Duration> {
+        if self.peers.get(&peer).is_none() && !self.peers.has_room() {
+            if !self.full {
+                tracing::warn!(
+                    "Ban list full with {} bans in force; not counting failures of other peers until one lifts",
+                    self.peers.len()
+                );
+                self.full = true;
+            }
+            return None;
+        }
+        self.full = false;
+        let config = &self.config;
+        let record = self
+            .peers
+            .get_or_insert_with(peer, now, PeerRecord::default);
+        if record.banned_until.is_some_and(|until| until > now) {
+            return None;
+        }
+        while record
+            .failures
+            .front()
+            .is_some_and(|t| now.duration_since(*t) > config.window)
+        {
+            record.failures.pop_front();
+        }
+        record.failures.push_back(now);
+        if record.failures.len() < config.threshold {
+            return None;
+        }
+
+        let ban = config
+            .base_ban
+            .saturating_mul(2u32.saturating_pow(record.strikes))
+            .min(config.max_ban);
+        record.strikes += 1;
+        record.failures.clear();
+        record.banned_until = Some(now + ban);
+        // Lifted in `prune` once it expires.
+        self.peers.exempt(peer);
+        Some(ban)
+    }
+
+    /// Time left on `peer`'s ban, if it is currently banned.
+    pub fn remaining(&self, peer: &PeerId, now: Instant) -> Option<Duration> {
+        self.peers
+            .get(peer)?
+            .banned_until
+            .and_then(|until| until.checked_duration_since(now))
+            .filter(|left| !left.is_zero())
+    }
+
+    /// Lifts `peer`'s ban and forgets its history. Returns `false` if it wasn't banned.
+    pub fn unban(&mut self, peer: &PeerId) -> bool {
+        if !self.kept.contains(peer) {
+            self.peers.unexempt(peer);
+        }
+        self.peers
+            .remove(peer)
+            .is_some_and(|r| r.banned_until.is_some())
+    }
+
+    pub fn standing(&self, peer: &PeerId, now: Instant) -> Standing {
+        let Some(record) = self.peers.get(peer) else {
+            return Standing::default();
+        };
+        Standing {
+            recent_failures: record
+                .failures
+                .iter()
+                .filter(|t| now.duration_since(**t) <= self.config.window)
+                .count(),
+            strikes: record.strikes,
+            banned_for_secs: self.remaining(peer, now).map(|left| left.as_secs()),
+        }
+    }
+
+    /// Currently banned peers and the time left on each ban.
+    pub fn banned(&self, now: Instant) -> impl Iterator<Item = (&PeerId, Duration)> {
+        self.peers
+            .keys()
+            .filter_map(move |p| Some((p, self.remaining(p, now)?)))
+    }
+
+    /// Lets records whose ban has lifted make way again, and forgets those
+    /// past their TTL, returning how many were dropped, for age or for room,
+    /// since the last call.
+    pub fn prune(&mut self, now: Instant) -> u64 {
+        let lifted: Vec<PeerId> = self
+            .peers
+            .iter()
+            .filter(|(peer, record)| {
+                !self.kept.contains(*peer) && record.banned_until.is_some_and(|until| until <= now)
+            })
+            .map(|(peer, _)| *peer)
+            .collect();
+        for peer in &lifted {
+            self.peers.unexempt(peer);
+        }
+        self.peers.prune(now);
+        self.peers.take_evicted()
+    }
+
+    /// Peers with a record.
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> BanConfig {
+        BanConfig {
+            threshold: 3,
+            window: Duration::from_secs(60),
+            base_ban: Duration::from_secs(10),
+            max_ban: Duration::from_secs(35),
+            max_records: 100,
+        }
+    }
+
+    /// Fails `peer` until it is banned, returning the ban.
+    fn ban(bans: &mut BanList, peer: PeerId, now: Instant) -> Duration {
+        (0..config().threshold)
+            .find_map(|_| bans.record_failure(peer, now))
+            .expect("banned at the threshold")
+    }
+
+    #[test]
+    fn bans_at_the_threshold_within_the_window() {
+        let mut bans = BanList::new(config());
+        let peer = PeerId::random();
+        let now = Instant::now();
+
+        assert_eq!(bans.record_failure(peer, now), None);
+        // The first failure has left the window by the third.
+        assert_eq!(
+            bans.record_failure(peer, now + Duration::from_secs(30)),
+            None
+        );
+        assert_eq!(
+            bans.record_failure(peer, now + Duration::from_secs(61)),
+            None
+        );
+        assert_eq!(bans.remaining(&peer, now + Duration::from_secs(61)), None);
+
+        let later = now + Duration::from_secs(62);
+        assert_eq!(
+            bans.record_failure(peer, later),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(bans.remaining(&peer, later), Some(Duration::from_secs(10)));
+        assert_eq!(bans.remaining(&peer, later + Duration::from_secs(10)), None);
+    }
+
+    #[test]
+    fn repeat_offences_double_the_ban_up_to_the_cap() {
+        let mut bans = BanList::new(config());
+        let peer = PeerId::random();
+        let mut now = Instant::now();
+        let mut lengths = Vec::new();
+        for _ in 0..4 {
+            let length = ban(&mut bans, peer, now);
+            lengths.push(length.as_secs());
+            now += length;
+        }
+        assert_eq!(lengths, [10, 20, 35, 35]);
+        assert_eq!(bans.standing(&peer, now).strikes, 4);
+    }
+
+    #[test]
+    fn failures_while_banned_do_not_count() {
+        let mut bans = BanList::new(config());
+        let peer = PeerId::random();
+        let now = Instant::now();
+        ban(&mut bans, peer, now);
+        for _ in 0..10 {
+            assert_eq!(bans.record_failure(peer, now), None);
+        }
+        let standing = bans.standing(&peer, now);
+        assert_eq!(standing.recent_failures, 0);
+        assert_eq!(standing.strikes, 1);
+        assert_eq!(standing.banned_for_secs, Some(10));
+    }
+
+    #[test]
+    fn unbanning_forgets_the_history() {
+        let mut bans = BanList::new(config());
+        let peer = PeerId::random();
+        let now = Instant::now();
+        assert!(!bans.unban(&peer));
+
+        ban(&mut bans, peer, now);
+        assert_eq!(bans.banned(now).count(), 1);
+        assert!(bans.unban(&peer));
+        assert_eq!(bans.banned(now).count(), 0);
+        assert_eq!(bans.standing(&peer, now), Standing::default());
+        // A fresh start: the next ban is the first again.
+        assert_eq!(ban(&mut bans, peer, now), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn bans_in_force_are_never_evicted() {
+        let mut bans = BanList::new(BanConfig {
+            max_records: 4,
+            ..config()
+        });
+        let now = Instant::now();
+        let banned: Vec<PeerId> = (0..4).map(|_| PeerId::random()).collect();
+        for peer in &banned {
+            ban(&mut bans, *peer, now);
+        }
+        // Newcomers aren't counted rather than pushing a ban out.
+        let newcomer = PeerId::random();
+        for _ in 0..10 {
+            assert_eq!(bans.record_failure(newcomer, now), None);
+        }
+        assert_eq!(bans.standing(&newcomer, now), Standing::default());
+        assert_eq!(bans.banned(now).count(), 4);
+
+        // Once the bans lift, their records make way as usual.
+        let lifted = now + Duration::from_secs(10);
+        assert_eq!(bans.prune(lifted), 0);
+        assert_eq!(bans.record_failure(newcomer, lifted), None);
+        assert_eq!(bans.standing(&newcomer, lifted).recent_failures, 1);
+        assert_eq!(bans.len(), 4);
+        assert_eq!(bans.prune(lifted), 1);
+    }
+
+    #[test]
+    fn records_are_forgotten_after_the_ttl() {
+        let mut bans = BanList::new(config());
+        let peer = PeerId::random();
+        let now = Instant::now();
+        ban(&mut bans, peer, now);
+        assert_eq!(bans.prune(now + RECORD_TTL - Duration::from_secs(1)), 0);
+        assert_eq!(bans.prune(now + RECORD_TTL), 1);
+        assert!(bans.is_empty());
+    }
+}
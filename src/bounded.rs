@@ -0,0 +1,354 @@
+//! A map that holds a bounded number of entries and forgets those left unused,
+//! for state kept about peers that come and go.
+//!
+//! Without a bound, every peer that ever sent a bad request, used a quota or
+//! announced itself would leave an entry behind for the life of the node. A
+//! [`BoundedMap`] makes room for a new entry by evicting the least recently
+//! used one, and [`BoundedMap::prune`], called periodically, drops entries
+//! unused for longer than the TTL. Keys marked [`BoundedMap::exempt`], such as
+//! the relay and other pinned infrastructure peers, are never evicted or
+//! pruned; they count towards the capacity all the same. Evictions are
+//! counted, so the owner can report them.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    hash::Hash,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BoundedConfig {
+    /// Most entries kept, counting exempt ones.
+    pub capacity: usize,
+    /// How long an entry may go unused before [`BoundedMap::prune`] drops
+    /// it. `None` keeps entries until they are evicted for room.
+    pub ttl: Option<Duration>,
+}
+
+#[derive(Debug)]
+struct Slot<V> {
+    value: V,
+    /// Position in `order`.
+    use_seq: u64,
+    used_at: Instant,
+}
+
+#[derive(Debug)]
+pub struct BoundedMap<K, V> {
+    config: BoundedConfig,
+    entries: HashMap<K, Slot<V>>,
+    /// Keys by when they were last used, least recent first.
+    order: BTreeMap<u64, K>,
+    next_seq: u64,
+    exempt: HashSet<K>,
+    /// Entries dropped for room or age since [`BoundedMap::take_evicted`].
+    evicted: u64,
+}
+
+impl<K: Clone + Eq + Hash, V> BoundedMap<K, V> {
+    pub fn new(config: BoundedConfig) -> Self {
+        Self {
+            config,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            next_seq: 0,
+            exempt: HashSet::new(),
+            evicted: 0,
+        }
+    }
+
+    /// Keeps `key`'s entry, present or future, until it is removed.
+    pub fn exempt(&mut self, key: K) {
+        self.exempt.insert(key);
+    }
+
+    /// Lets `key`'s entry be evicted and pruned again.
+    pub fn unexempt(&mut self, key: &K) {
+        self.exempt.remove(key);
+    }
+
+    /// The value for `key`, without counting as a use.
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.entries.get(key).map(|slot| &slot.value)
+    }
+
+    /// The value for `key`, counting as a use at `now`.
+    pub fn get_mut(&mut self, key: &K, now: Instant) -> Option<&mut V> {
+        let seq = self.next_seq;
+        let slot = self.entries.get_mut(key)?;
+        self.order.remove(&slot.use_seq);
+        self.order.insert(seq, key.clone());
+        self.next_seq += 1;
+        slot.use_seq = seq;
+        slot.used_at = now;
+        Some(&mut slot.value)
+    }
+
+    /// The value for `key`, inserted with `value` if missing, counting as a
+    /// use at `now`. Inserting may evict the least recently used entry.
+    pub fn get_or_insert_with(
+        &mut self,
+        key: K,
+        now: Instant,
+        value: impl FnOnce() -> V,
+    ) -> &mut V {
+        if !self.entries.contains_key(&key) {
+            self.insert(key.clone(), value(), now);
+        }
+        self.get_mut(&key, now)
+            .expect("the entry was just inserted and is never evicted on use")
+    }
+
+    /// Sets `key`'s value, counting as a use at `now`, and returns the old
+    /// one. Inserting may evict the least recently used entry.
+    pub fn insert(&mut self, key: K, value: V, now: Instant) -> Option<V> {
+        let old = self.remove(&key);
+        if old.is_none() {
+            while self.entries.len() >= self.config.capacity && self.evict_one() {}
+        }
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.order.insert(seq, key.clone());
+        self.entries.insert(
+            key,
+            Slot {
+                value,
+                use_seq: seq,
+                used_at: now,
+            },
+        );
+        old
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let slot = self.entries.remove(key)?;
+        self.order.remove(&slot.use_seq);
+        Some(slot.value)
+    }
+
+    /// Keeps only the entries `keep` returns `true` for. Removals this way
+    /// aren't counted as evictions.
+    pub fn retain(&mut self, mut keep: impl FnMut(&K, &V) -> bool) {
+        let order = &mut self.order;
+        self.entries.retain(|key, slot| {
+            let kept = keep(key, &slot.value);
+            if !kept {
+                order.remove(&slot.use_seq);
+            }
+            kept
+        });
+    }
+
+    /// Drops entries unused for longer than the TTL, returning how many.
+    pub fn prune(&mut self, now: Instant) -> usize {
+        let Some(ttl) = self.config.ttl else {
+            return 0;
+        };
+        let stale: Vec<K> = self
+            .entries
+            .iter()
+            .filter(|(key, slot)| {
+                !self.exempt.contains(*key) && now.saturating_duration_since(slot.used_at) >= ttl
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &stale {
+            self.remove(key);
+        }
+        self.evicted += stale.len() as u64;
+        stale.len()
+    }
+
+    /// Entries used within the TTL, and exempt ones, whether or not the map
+    /// has been pruned since the others went stale.
+    pub fn fresh(&self, now: Instant) -> impl Iterator<Item = (&K, &V)> {
+        self.entries
+            .iter()
+            .filter(move |(key, slot)| {
+                self.exempt.contains(*key)
+                    || self
+                        .config
+                        .ttl
+                        .is_none_or(|ttl| now.saturating_duration_since(slot.used_at) < ttl)
+            })
+            .map(|(key, slot)| (key, &slot.value))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries.iter().map(|(key, slot)| (key, &slot.value))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.entries.keys()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Whether a new entry can be inserted without going over the capacity,
+    /// evicting an entry that isn't exempt if need be.
+    pub fn has_room(&self) -> bool {
+        self.entries.len() < self.config.capacity
+            || self.order.values().any(|key| !self.exempt.contains(key))
+    }
+
+    /// Entries evicted for room or pruned for age since the last call.
+    pub fn take_evicted(&mut self) -> u64 {
+        std::mem::take(&mut self.evicted)
+    }
+
+    /// Evicts the least recently used entry that isn't exempt. `false` if
+    /// every entry is exempt.
+    fn evict_one(&mut self) -> bool {
+        let Some(key) = self
+            .order
+            .values()
+            .find(|key| !self.exempt.contains(*key))
+            .cloned()
+        else {
+            return false;
+        };
+        self.remove(&key);
+        self.evicted += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    fn map(capacity: usize) -> BoundedMap<&'static str, u32> {
+        BoundedMap::new(BoundedConfig {
+            capacity,
+            ttl: Some(TTL),
+        })
+    }
+
+    fn keys(map: &BoundedMap<&'static str, u32>) -> Vec<&'static str> {
+        let mut keys: Vec<_> = map.keys().copied().collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used() {
+        let now = Instant::now();
+        let mut map = map(2);
+        map.insert("a", 1, now);
+        map.insert("b", 2, now);
+        map.insert("c", 3, now);
+        assert_eq!(keys(&map), ["b", "c"]);
+        // Reading without counting it as a use doesn't save "b".
+        assert_eq!(map.get(&"b"), Some(&2));
+        map.insert("d", 4, now);
+        assert_eq!(keys(&map), ["c", "d"]);
+    }
+
+    #[test]
+    fn a_use_makes_an_entry_most_recent() {
+        let now = Instant::now();
+        let mut map = map(2);
+        map.insert("a", 1, now);
+        map.insert("b", 2, now);
+        *map.get_mut(&"a", now).unwrap() += 10;
+        map.insert("c", 3, now);
+        assert_eq!(keys(&map), ["a", "c"]);
+        assert_eq!(map.get(&"a"), Some(&11));
+        *map.get_or_insert_with("c", now, || 0) += 1;
+        map.insert("d", 4, now);
+        assert_eq!(keys(&map), ["c", "d"]);
+        // Replacing a value doesn't make room for it.
+        assert_eq!(map.insert("c", 5, now), Some(4));
+        assert_eq!(keys(&map), ["c", "d"]);
+    }
+
+    #[test]
+    fn exempt_entries_are_never_evicted_or_pruned() {
+        let now = Instant::now();
+        let mut map = map(2);
+        map.exempt("relay");
+        map.insert("relay", 0, now);
+        map.insert("a", 1, now);
+        map.insert("b", 2, now);
+        assert_eq!(keys(&map), ["b", "relay"]);
+        assert_eq!(map.prune(now + TTL), 1);
+        assert_eq!(keys(&map), ["relay"]);
+        assert_eq!(map.fresh(now + TTL).count(), 1);
+        // Once unexempt it ages like any other.
+        map.unexempt(&"relay");
+        assert_eq!(map.prune(now + TTL), 1);
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn goes_over_capacity_when_every_entry_is_exempt() {
+        let now = Instant::now();
+        let mut map = map(1);
+        map.exempt("relay");
+        map.exempt("bootnode");
+        map.insert("relay", 0, now);
+        assert!(!map.has_room());
+        map.insert("bootnode", 1, now);
+        assert_eq!(keys(&map), ["bootnode", "relay"]);
+        assert_eq!(map.take_evicted(), 0);
+        // The first entry that isn't exempt goes as soon as the next comes.
+        map.insert("a", 2, now);
+        assert!(map.has_room());
+        map.insert("b", 3, now);
+        assert_eq!(keys(&map), ["b", "bootnode", "relay"]);
+    }
+
+    #[test]
+    fn entries_go_stale_once_the_ttl_has_passed() {
+        let now = Instant::now();
+        let mut map = map(4);
+        map.insert("old", 1, now);
+        map.insert("new", 2, now + Duration::from_secs(1));
+        let just_before = now + TTL - Duration::from_millis(1);
+        assert_eq!(map.fresh(just_before).count(), 2);
+        assert_eq!(map.prune(just_before), 0);
+        let fresh: Vec<_> = map.fresh(now + TTL).map(|(key, _)| *key).collect();
+        assert_eq!(fresh, ["new"]);
+        // Stale but not yet pruned entries are still held.
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.prune(now + TTL), 1);
+        assert_eq!(keys(&map), ["new"]);
+    }
+
+    #[test]
+    fn without_a_ttl_nothing_is_pruned() {
+        let now = Instant::now();
+        let mut map = BoundedMap::new(BoundedConfig {
+            capacity: 1,
+            ttl: None,
+        });
+        map.insert("a", 1, now);
+        let later = now + Duration::from_secs(86_400);
+        assert_eq!(map.prune(later), 0);
+        assert_eq!(map.fresh(later).count(), 1);
+    }
+
+    #[test]
+    fn counts_evictions_and_prunes_but_not_retain() {
+        let now = Instant::now();
+        let mut map = map(2);
+        map.insert("a", 1, now);
+        map.insert("b", 2, now);
+        map.insert("c", 3, now);
+        map.insert("d", 4, now + TTL);
+        assert_eq!(map.take_evicted(), 2);
+        assert_eq!(map.take_evicted(), 0);
+        assert_eq!(map.prune(now + TTL), 1);
+        map.retain(|_, _| false);
+        assert!(map.is_empty());
+        assert_eq!(map.take_evicted(), 1);
+    }
+}
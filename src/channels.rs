@@ -0,0 +1,82 @@
+//! Counts of request-response exchanges still waiting on an answer.
+//!
+//! Every inbound request ends in exactly one `ResponseSent` or
+//! `InboundFailure` event, and every outbound one in a response or an
+//! `OutboundFailure`, so following the events is enough to know what is
+//! open without touching the code that holds the channels. A growing inbound
+//! count means inference is falling behind the requests coming in.
+
+use std::collections::{BTreeMap, HashSet};
+
+use libp2p::request_response::{Event, InboundRequestId, Message, OutboundRequestId};
+
+#[derive(Debug, Default)]
+struct Open {
+    inbound: HashSet<InboundRequestId>,
+    outbound: HashSet<OutboundRequestId>,
+}
+
+/// Open exchanges per protocol, keyed by a short label such as `prompt`.
+#[derive(Debug, Default)]
+pub struct ChannelCounts {
+    protocols: BTreeMap<&'static str, Open>,
+}
+
+impl ChannelCounts {
+    /// Updates the counts for `protocol` from one of its events. Returns
+    /// them, as [`counts`](Self::counts) would.
+    pub fn observe<Req, Resp, ChannelResp>(
+        &mut self,
+        protocol: &'static str,
+        event: &Event<Req, Resp, ChannelResp>,
+    ) -> (&'static str, usize, usize) {
+        let open = self.protocols.entry(protocol).or_default();
+        match event {
+            Event::Message {
+                message: Message::Request { request_id, .. },
+                ..
+            } => {
+                open.inbound.insert(*request_id);
+            }
+            Event::Message {
+                message: Message::Response { request_id, .. },
+                ..
+            }
+            | Event::OutboundFailure { request_id, .. } => {
+                open.outbound.remove(request_id);
+            }
+            Event::InboundFailure { request_id, .. } | Event::ResponseSent { request_id, .. } => {
+                open.inbound.remove(request_id);
+            }
+        }
+        (protocol, open.inbound.len(), open.outbound.len())
+    }
+
+    /// Records a request this node sent on `protocol`.
+    pub fn sent(&mut self, protocol: &'static str, request_id: OutboundRequestId) {
+        self.protocols
+            .entry(protocol)
+            .or_default()
+            .outbound
+            .insert(request_id);
+    }
+
+    /// Inbound requests not yet answered and outbound ones not yet
+    /// answered, per protocol.
+    pub fn counts(&self) -> impl Iterator<Item = (&'static str, usize, usize)> + '_ {
+        self.protocols
+            .iter()
+            .map(|(protocol, open)| (*protocol, open.inbound.len(), open.outbound.len()))
+    }
+
+    pub fn inbound(&self) -> usize {
+        self.protocols.values().map(|open| open.inbound.len()).sum()
+    }
+
+    pub fn outbound(&self) -> usize {
+        self.protocols
+            .values()
+            .map(|open| open.outbound.len())
+            .sum()
+    }
+}
@@ -0,0 +1,167 @@
+//! Conversation history for interactive clients.
+//!
+//! Nodes only take single prompts, so a client that wants a conversation
+//! keeps the turns itself and sends them along with each new message, folded
+//! into the prompt by [`ChatHistory::prompt`].
+
+use std::{collections::VecDeque, fmt};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Role {
+    User,
+    Assistant,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::User => write!(f, "User"),
+            Role::Assistant => write!(f, "Assistant"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: Role,
+    pub content: String,
+}
+
+/// The most recent messages of a conversation, oldest first.
+pub struct ChatHistory {
+    messages: VecDeque<ChatMessage>,
+    max_messages: usize,
+}
+
+impl ChatHistory {
+    /// Keeps at most `max_messages` messages, counting both sides.
+    pub fn new(max_messages: usize) -> Self {
+        Self {
+            messages: VecDeque::new(),
+            max_messages,
+        }
+    }
+
+    pub fn push(&mut self, role: Role, content: String) {
+        self.messages.push_back(ChatMessage { role, content });
+        self.trim();
+    }
+
+    /// Forgets the conversation so far.
+    pub fn reset(&mut self) {
+        self.messages.clear();
+    }
+
+    pub fn messages(&self) -> impl Iterator<Item = &ChatMessage> {
+        self.messages.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// The prompt to send for `input`: the history as a transcript, then
+    /// `input` as the user's next turn. Doesn't record `input`; push it along
+    /// with the answer once the turn succeeds, so a failed turn leaves no
+    /// trace.
+    pub fn prompt(&self, input: &str) -> String {
+        if self.messages.is_empty() {
+            return input.to_string();
+        }
+        let mut prompt = String::new();
+        for message in &self.messages {
+            prompt.push_str(&format!("{}: {}\n\n", message.role, message.content));
+        }
+        prompt.push_str(&format!("{}: {input}\n\n{}:", Role::User, Role::Assistant));
+        prompt
+    }
+
+    /// Drops the oldest messages beyond the limit, then any assistant message
+    /// left at the front without the question it answered.
+    fn trim(&mut self) {
+        while self.messages.len() > self.max_messages {
+            self.messages.pop_front();
+        }
+        while self
+            .messages
+            .front()
+            .is_some_and(|m| m.role == Role::Assistant)
+        {
+            self.messages.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roles(history: &ChatHistory) -> Vec<Role> {
+        history.messages().map(|m| m.role).collect()
+    }
+
+    fn turn(history: &mut ChatHistory, question: &str, answer: &str) {
+        history.push(Role::User, question.to_string());
+        history.push(Role::Assistant, answer.to_string());
+    }
+
+    #[test]
+    fn the_first_prompt_is_sent_as_is() {
+        assert_eq!(ChatHistory::new(10).prompt("Hi"), "Hi");
+    }
+
+    #[test]
+    fn later_prompts_carry_the_transcript() {
+        let mut history = ChatHistory::new(10);
+        turn(&mut history, "Hi", "Hello!");
+        assert_eq!(
+            history.prompt("How are you?"),
+            "User: Hi\n\nAssistant: Hello!\n\nUser: How are you?\n\nAssistant:"
+        );
+        // Building the prompt doesn't record the turn.
+        assert_eq!(history.len(), 2);
+    }
+
+    #[test]
+    fn keeps_the_most_recent_messages() {
+        let mut history = ChatHistory::new(4);
+        turn(&mut history, "1", "one");
+        turn(&mut history, "2", "two");
+        turn(&mut history, "3", "three");
+        let contents: Vec<_> = history.messages().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, ["2", "two", "3", "three"]);
+    }
+
+    #[test]
+    fn never_starts_with_an_answer_to_a_forgotten_question() {
+        // An odd limit would leave an answer first.
+        let mut history = ChatHistory::new(3);
+        turn(&mut history, "1", "one");
+        turn(&mut history, "2", "two");
+        assert_eq!(roles(&history), [Role::User, Role::Assistant]);
+        assert!(history.prompt("3").starts_with("User: 2"));
+    }
+
+    #[test]
+    fn a_zero_limit_keeps_nothing() {
+        let mut history = ChatHistory::new(0);
+        turn(&mut history, "1", "one");
+        assert!(history.is_empty());
+        assert_eq!(history.prompt("2"), "2");
+    }
+
+    #[test]
+    fn reset_forgets_the_conversation() {
+        let mut history = ChatHistory::new(10);
+        turn(&mut history, "1", "one");
+        history.reset();
+        assert!(history.is_empty());
+        assert_eq!(history.prompt("2"), "2");
+    }
+}
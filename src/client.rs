@@ -0,0 +1,1413 @@
+//! Client side of the prompt protocol.
+//!
+//! [`Client`] owns a swarm on a background task and lets any number of callers
+//! have prompts in flight at once. Each outbound request is correlated with its
+//! caller by [`OutboundRequestId`], so responses always land on the right
+//! future no matter how they interleave.
+//!
+//! Talking to a worker goes through three phases, each with its own deadline
+//! and error: connecting, confirming the worker speaks our protocol, and
+//! waiting for the response. A failure says which phase it happened in, and
+//! one after the request went out carries its [`OutboundRequestId`], the
+//! peer, the [`FailureKind`] and how long it took, to match with libp2p's
+//! logs.
+
+use std::{
+    collections::HashMap,
+    fmt, fs, io,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures::StreamExt;
+use libp2p::{
+    Multiaddr, PeerId, StreamProtocol, Swarm, identify,
+    multiaddr::Protocol,
+    request_response::{self, OutboundFailure, OutboundRequestId},
+    swarm::{ConnectionId, SwarmEvent, dial_opts::DialOpts},
+};
+use tokio::{
+    sync::{Semaphore, mpsc, oneshot},
+    time::timeout,
+};
+
+use crate::{
+    CompareRequest, CompareResponse, MAX_IMAGE_BYTES, PromptRequest, PromptResponse, RerankRequest,
+    RerankResponse, ResponseStatus,
+    client_info::ClientInfo,
+    direct::{self, DirectUpgrades},
+    estimate::{EstimateRequest, EstimateResponse},
+    feedback::{Feedback, FeedbackAck},
+    labels::Labels,
+    node::{
+        self, Behaviour, BehaviourEvent, PROTOCOL_NAME, RERANK_PROTOCOL_NAME, STREAM_PROTOCOL_NAME,
+        VersionMismatch,
+    },
+    pex::{PexRequest, PexResponse},
+    profile::Profile,
+    stream::{StreamFrame, read_frame, write_frame},
+};
+
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// Requests allowed in flight at once; further callers wait for a slot.
+    pub max_in_flight: usize,
+    /// Deadline for establishing a connection to the worker.
+    pub connect_timeout: Duration,
+    /// Deadline for the worker to confirm it speaks the prompt protocol.
+    pub protocol_timeout: Duration,
+    /// Deadline for the response once the request has been sent.
+    pub response_timeout: Duration,
+    /// Sent with every prompt that doesn't name its own application.
+    pub client_info: Option<ClientInfo>,
+    /// Sent with every prompt that doesn't carry its own key.
+    pub api_key: Option<String>,
+    /// Tried once when a node refuses `api_key`, e.g. while keys are being
+    /// rotated and some nodes only know the old or the new one.
+    pub fallback_api_key: Option<String>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            max_in_flight: 8,
+            connect_timeout: Duration::from_secs(10),
+            protocol_timeout: Duration::from_secs(10),
+            response_timeout: Duration::from_secs(300),
+            client_info: None,
+            api_key: None,
+            fallback_api_key: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    /// No connection to the peer could be established.
+    Dial(String),
+    /// The connection wasn't up before the connect deadline.
+    ConnectTimeout(Duration),
+    /// The peer didn't confirm its protocols before the deadline.
+    ProtocolTimeout(Duration),
+    /// The peer is connected but doesn't speak the protocol the request needs.
+    ProtocolUnsupported,
+    /// The peer speaks the mesh protocol, but no version of it this client
+    /// does; one side needs upgrading.
+    ProtocolMismatch {
+        peer: PeerId,
+        mismatch: VersionMismatch,
+    },
+    /// The request was sent but the response deadline passed.
+    ResponseTimeout(Duration),
+    /// The request was sent but no response came back.
+    Outbound(Box<OutboundError>),
+    /// There is no worker left to send the request to.
+    NoPeers,
+    /// The client's event loop has shut down.
+    Closed,
+}
+
+impl ClientError {
+    /// Process exit code for command-line clients, distinct per phase so
+    /// scripts can tell an unreachable worker from a slow model.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ClientError::Dial(_) | ClientError::ConnectTimeout(_) | ClientError::NoPeers => 10,
+            ClientError::ProtocolTimeout(_)
+            | ClientError::ProtocolUnsupported
+            | ClientError::ProtocolMismatch { .. } => 11,
+            ClientError::Outbound(e) if e.kind == FailureKind::UnsupportedProtocols => 11,
+            ClientError::ResponseTimeout(_) | ClientError::Outbound(_) => 12,
+            ClientError::Closed => 1,
+        }
+    }
+
+    /// Whether the failure means the peer itself is unusable, as opposed to
+    /// the request merely being slow or the client shutting down.
+    pub fn is_peer_failure(&self) -> bool {
+        matches!(
+            self,
+            ClientError::Dial(_)
+                | ClientError::ConnectTimeout(_)
+                | ClientError::ProtocolTimeout(_)
+                | ClientError::ProtocolUnsupported
+                | ClientError::ProtocolMismatch { .. }
+                | ClientError::Outbound(_)
+        )
+    }
+
+    /// The peer the request went to, when the error knows it.
+    pub fn peer(&self) -> Option<PeerId> {
+        match self {
+            ClientError::Outbound(e) => Some(e.peer),
+            ClientError::ProtocolMismatch { peer, .. } => Some(*peer),
+            _ => None,
+        }
+    }
+
+    /// The id libp2p gave the failed request, to match with its logs.
+    pub fn request_id(&self) -> Option<OutboundRequestId> {
+        match self {
+            ClientError::Outbound(e) => e.request_id,
+            _ => None,
+        }
+    }
+
+    /// How a sent request failed.
+    pub fn failure_kind(&self) -> Option<FailureKind> {
+        match self {
+            ClientError::Outbound(e) => Some(e.kind),
+            _ => None,
+        }
+    }
+
+    /// How long after being sent the request failed.
+    pub fn elapsed(&self) -> Option<Duration> {
+        match self {
+            ClientError::Outbound(e) => Some(e.elapsed),
+            ClientError::ResponseTimeout(t) => Some(*t),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Dial(e) => write!(f, "dial failed: {e}"),
+            ClientError::ConnectTimeout(t) => write!(f, "connect timed out after {t:?}"),
+            ClientError::ProtocolTimeout(t) => {
+                write!(f, "protocol confirmation timed out after {t:?}")
+            }
+            ClientError::ProtocolUnsupported => {
+                write!(f, "peer does not support the requested protocol")
+            }
+            ClientError::ProtocolMismatch { peer, mismatch } => write!(f, "peer {peer} {mismatch}"),
+            ClientError::ResponseTimeout(t) => write!(f, "no response after {t:?}"),
+            ClientError::Outbound(e) => write!(f, "request failed: {e}"),
+            ClientError::NoPeers => write!(f, "no workers available"),
+            ClientError::Closed => write!(f, "client event loop has shut down"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+/// How a sent request failed, after libp2p's `OutboundFailure`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    /// The peer couldn't be dialed to send the request.
+    DialFailure,
+    /// libp2p's own request timeout passed without a response.
+    Timeout,
+    /// The connection closed before the response came; the peer may have
+    /// run the request.
+    ConnectionClosed,
+    /// The peer speaks none of the request's protocols.
+    UnsupportedProtocols,
+    /// Reading or writing the stream failed, or what came back was malformed.
+    Io,
+}
+
+impl fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FailureKind::DialFailure => "dial failure",
+            FailureKind::Timeout => "timeout",
+            FailureKind::ConnectionClosed => "connection closed",
+            FailureKind::UnsupportedProtocols => "unsupported protocols",
+            FailureKind::Io => "I/O error",
+        })
+    }
+}
+
+/// A request that went out to a peer but got no response.
+#[derive(Debug, Clone)]
+pub struct OutboundError {
+    peer: PeerId,
+    request_id: Option<OutboundRequestId>,
+    kind: FailureKind,
+    elapsed: Duration,
+    detail: Option<String>,
+}
+
+impl OutboundError {
+    /// A failure on a stream opened at `opened_at`, which unlike a
+    /// request-response exchange has no request id.
+    pub(crate) fn stream(
+        peer: PeerId,
+        kind: FailureKind,
+        opened_at: Instant,
+        detail: String,
+    ) -> Self {
+        Self {
+            peer,
+            request_id: None,
+            kind,
+            elapsed: opened_at.elapsed(),
+            detail: Some(detail),
+        }
+    }
+
+    pub fn peer(&self) -> PeerId {
+        self.peer
+    }
+
+    /// `None` for streamed prompts.
+    pub fn request_id(&self) -> Option<OutboundRequestId> {
+        self.request_id
+    }
+
+    pub fn kind(&self) -> FailureKind {
+        self.kind
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// What went wrong beyond the kind, e.g. the I/O error.
+    pub fn detail(&self) -> Option<&str> {
+        self.detail.as_deref()
+    }
+}
+
+/// E.g. `connection closed on request 7 to 12D3KooW… after 1.2s`, or
+/// `I/O error on a stream to 12D3KooW… after 5ms: unexpected end of file`.
+impl fmt::Display for OutboundError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.request_id {
+            Some(id) => write!(f, "{} on request {id} to {}", self.kind, self.peer)?,
+            None => write!(f, "{} on a stream to {}", self.kind, self.peer)?,
+        }
+        write!(f, " after {:?}", self.elapsed)?;
+        match &self.detail {
+            Some(detail) => write!(f, ": {detail}"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for OutboundError {}
+
+/// The peer `addr` leads to. For a relayed address that is the one after
+/// `/p2p-circuit`, not the relay before it, so one that names no peer there
+/// is refused rather than taken to mean the relay.
+pub fn target_peer(addr: &Multiaddr) -> Result<PeerId, String> {
+    let mut target = None;
+    let mut relay = None;
+    for protocol in addr.iter() {
+        match protocol {
+            Protocol::P2p(id) => target = Some(id),
+            Protocol::P2pCircuit => relay = target.take(),
+            _ => {}
+        }
+    }
+    match (target, relay) {
+        (Some(target), _) => Ok(target),
+        (None, Some(relay)) => Err(format!(
+            "{addr} goes through relay {relay} but names no peer after /p2p-circuit"
+        )),
+        (None, None) => Err(format!("no peer id in {addr}")),
+    }
+}
+
+/// The relay a `/p2p-circuit` address goes through, if it names one.
+fn relay_peer(addr: &Multiaddr) -> Option<PeerId> {
+    let mut last = None;
+    for protocol in addr.iter() {
+        match protocol {
+            Protocol::P2p(id) => last = Some(id),
+            Protocol::P2pCircuit => return last,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Reads image files for [`PromptRequest::images`]. Files adding up to
+/// more than [`MAX_IMAGE_BYTES`] are refused before they are read.
+pub fn load_images(paths: &[impl AsRef<Path>]) -> io::Result<Vec<Vec<u8>>> {
+    let mut total = 0;
+    for path in paths {
+        total += fs::metadata(path)?.len();
+    }
+    if total > MAX_IMAGE_BYTES as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("the images add up to {total} bytes, over the limit of {MAX_IMAGE_BYTES}"),
+        ));
+    }
+    paths.iter().map(fs::read).collect()
+}
+
+enum Command {
+    Dial {
+        peer: PeerId,
+        addrs: Vec<Multiaddr>,
+        reply: oneshot::Sender<Result<(), ClientError>>,
+    },
+    Send {
+        peer: PeerId,
+        request: Box<PromptRequest>,
+        reply: oneshot::Sender<Result<PromptResponse, ClientError>>,
+    },
+    Rerank {
+        peer: PeerId,
+        request: RerankRequest,
+        reply: oneshot::Sender<Result<RerankResponse, ClientError>>,
+    },
+    Compare {
+        peer: PeerId,
+        request: CompareRequest,
+        reply: oneshot::Sender<Result<CompareResponse, ClientError>>,
+    },
+    Estimate {
+        peer: PeerId,
+        request: EstimateRequest,
+        reply: oneshot::Sender<Result<EstimateResponse, ClientError>>,
+    },
+    Pex {
+        peer: PeerId,
+        request: PexRequest,
+        reply: oneshot::Sender<Result<PexResponse, ClientError>>,
+    },
+    Feedback {
+        peer: PeerId,
+        feedback: Feedback,
+        reply: oneshot::Sender<Result<FeedbackAck, ClientError>>,
+    },
+    Confirm {
+        peer: PeerId,
+        protocol: &'static str,
+        reply: oneshot::Sender<Result<(), ClientError>>,
+    },
+    Profile {
+        peer: PeerId,
+        reply: oneshot::Sender<Option<Profile>>,
+    },
+    Labels {
+        peer: PeerId,
+        reply: oneshot::Sender<Option<Labels>>,
+    },
+}
+
+#[derive(Clone)]
+pub struct Client {
+    local_peer_id: PeerId,
+    commands: mpsc::Sender<Command>,
+    streams: libp2p_stream::Control,
+    in_flight: Arc<Semaphore>,
+    config: ClientConfig,
+}
+
+impl Client {
+    /// Spawns the event loop for `swarm`.
+    pub fn new(swarm: Swarm<Behaviour>, config: ClientConfig) -> Self {
+        let (commands, rx) = mpsc::channel(32);
+        let local_peer_id = *swarm.local_peer_id();
+        let streams = swarm.behaviour().stream.new_control();
+        let in_flight = Arc::new(Semaphore::new(config.max_in_flight.max(1)));
+        tokio::spawn(EventLoop::new(swarm, rx, in_flight.clone()).run());
+        Self {
+            local_peer_id,
+            commands,
+            streams,
+            in_flight,
+            config,
+        }
+    }
+
+    pub fn local_peer_id(&self) -> PeerId {
+        self.local_peer_id
+    }
+
+    /// Runs the connect and protocol-confirmation phases against `peer`.
+    pub async fn connect(&self, peer: PeerId, addrs: Vec<Multiaddr>) -> Result<(), ClientError> {
+        self.dial(peer, addrs).await?;
+        self.confirm_protocol(peer).await
+    }
+
+    /// Connects to `peer`, trying all `addrs` in parallel. Resolves once a
+    /// connection is up, or immediately if one already is.
+    pub async fn dial(&self, peer: PeerId, addrs: Vec<Multiaddr>) -> Result<(), ClientError> {
+        let deadline = self.config.connect_timeout;
+        let (reply, rx) = oneshot::channel();
+        self.commands
+            .send(Command::Dial { peer, addrs, reply })
+            .await
+            .map_err(|_| ClientError::Closed)?;
+        timeout(deadline, rx)
+            .await
+            .map_err(|_| ClientError::ConnectTimeout(deadline))?
+            .map_err(|_| ClientError::Closed)?
+    }
+
+    /// Waits for `peer` to identify itself and checks it speaks the prompt
+    /// protocol.
+    pub async fn confirm_protocol(&self, peer: PeerId) -> Result<(), ClientError> {
+        self.confirm(peer, PROTOCOL_NAME).await
+    }
+
+    /// Like [`Client::confirm_protocol`], for workers that serve reranking.
+    pub async fn confirm_rerank(&self, peer: PeerId) -> Result<(), ClientError> {
+        self.confirm(peer, RERANK_PROTOCOL_NAME).await
+    }
+
+    /// The nickname and contact `peer` announced, once it has identified
+    /// itself, e.g. after [`Client::confirm_protocol`].
+    pub async fn profile(&self, peer: PeerId) -> Option<Profile> {
+        let (reply, rx) = oneshot::channel();
+        self.commands
+            .send(Command::Profile { peer, reply })
+            .await
+            .ok()?;
+        rx.await.ok().flatten()
+    }
+
+    /// The labels `peer` announced, once it has identified itself.
+    pub async fn labels(&self, peer: PeerId) -> Option<Labels> {
+        let (reply, rx) = oneshot::channel();
+        self.commands
+            .send(Command::Labels { peer, reply })
+            .await
+            .ok()?;
+        rx.await.ok().flatten()
+    }
+
+    async fn confirm(&self, peer: PeerId, protocol: &'static str) -> Result<(), ClientError> {
+        let deadline = self.config.protocol_timeout;
+        let (reply, rx) = oneshot::channel();
+        self.commands
+            .send(Command::Confirm {
+                peer,
+                protocol,
+                reply,
+            })
+            .await
+            .map_err(|_| ClientError::Closed)?;
+        timeout(deadline, rx)
+            .await
+            .map_err(|_| ClientError::ProtocolTimeout(deadline))?
+            .map_err(|_| ClientError::Closed)?
+    }
+
+    pub async fn send_prompt(
+        &self,
+        peer: PeerId,
+        request: PromptRequest,
+    ) -> Result<PromptResponse, ClientError> {
+        let request = self.with_defaults(request);
+        let fallback = self.with_fallback_key(&request);
+        let response = self
+            .request(|reply| Command::Send {
+                peer,
+                request: Box::new(request),
+                reply,
+            })
+            .await?;
+        match fallback {
+            Some(request) if response.status == ResponseStatus::Unauthorized => {
+                tracing::debug!(%peer, "API key refused, retrying with the fallback key");
+                self.request(|reply| Command::Send {
+                    peer,
+                    request: Box::new(request),
+                    reply,
+                })
+                .await
+            }
+            _ => Ok(response),
+        }
+    }
+
+    pub async fn rerank(
+        &self,
+        peer: PeerId,
+        request: RerankRequest,
+    ) -> Result<RerankResponse, ClientError> {
+        self.request(|reply| Command::Rerank {
+            peer,
+            request,
+            reply,
+        })
+        .await
+    }
+
+    /// Has `peer` run one prompt on two models.
+    pub async fn compare(
+        &self,
+        peer: PeerId,
+        request: CompareRequest,
+    ) -> Result<CompareResponse, ClientError> {
+        self.request(|reply| Command::Compare {
+            peer,
+            request,
+            reply,
+        })
+        .await
+    }
+
+    /// Asks `peer` how long it would take to answer a prompt, without
+    /// sending it.
+    pub async fn estimate(
+        &self,
+        peer: PeerId,
+        request: EstimateRequest,
+    ) -> Result<EstimateResponse, ClientError> {
+        self.request(|reply| Command::Estimate {
+            peer,
+            request,
+            reply,
+        })
+        .await
+    }
+
+    /// Asks `peer` for the other workers it knows.
+    pub async fn exchange_peers(
+        &self,
+        peer: PeerId,
+        request: PexRequest,
+    ) -> Result<PexResponse, ClientError> {
+        self.request(|reply| Command::Pex {
+            peer,
+            request,
+            reply,
+        })
+        .await
+    }
+
+    /// Rates the answer `peer` gave to the request with the idempotency key
+    /// `feedback.request_id`.
+    pub async fn send_feedback(
+        &self,
+        peer: PeerId,
+        feedback: Feedback,
+    ) -> Result<FeedbackAck, ClientError> {
+        self.request(|reply| Command::Feedback {
+            peer,
+            feedback,
+            reply,
+        })
+        .await
+    }
+
+    /// Sends `request` over the streaming protocol and calls `on_chunk` with
+    /// each piece of the answer as it arrives. Resolves with the whole answer
+    /// in `response`, as [`Client::send_prompt`] would.
+    ///
+    /// `on_chunk` runs between reads, so a slow callback slows the node down
+    /// instead of piling chunks up in memory. The response deadline applies
+    /// to the gap between chunks rather than to the whole answer. Dropping
+    /// the future closes the stream, which stops the generation.
+    ///
+    /// A peer that doesn't serve the streaming protocol is sent the prompt
+    /// as [`Client::send_prompt`] would, and a successful answer is passed
+    /// to `on_chunk` in one piece.
+    pub async fn prompt_stream(
+        &self,
+        peer: PeerId,
+        request: PromptRequest,
+        mut on_chunk: impl FnMut(&str),
+    ) -> Result<PromptResponse, ClientError> {
+        let request = self.with_defaults(request);
+        let fallback = self.with_fallback_key(&request);
+        let response = match self.stream_once(peer, request.clone(), &mut on_chunk).await {
+            // The stream wasn't opened, so nothing was run yet.
+            Err(ClientError::ProtocolUnsupported) => {
+                tracing::debug!(%peer, "Peer doesn't stream, sending the prompt whole");
+                let response = self.send_prompt(peer, request).await?;
+                if response.status == ResponseStatus::Ok && !response.response.is_empty() {
+                    on_chunk(&response.response);
+                }
+                return Ok(response);
+            }
+            response => response?,
+        };
+        match fallback {
+            // A refused key is answered before any chunk is sent.
+            Some(request) if response.status == ResponseStatus::Unauthorized => {
+                tracing::debug!(%peer, "API key refused, retrying with the fallback key");
+                self.stream_once(peer, request, &mut on_chunk).await
+            }
+            _ => Ok(response),
+        }
+    }
+
+    async fn stream_once(
+        &self,
+        peer: PeerId,
+        request: PromptRequest,
+        on_chunk: &mut impl FnMut(&str),
+    ) -> Result<PromptResponse, ClientError> {
+        let _permit = self
+            .in_flight
+            .acquire()
+            .await
+            .map_err(|_| ClientError::Closed)?;
+        let opened_at = Instant::now();
+        let failed = |kind, detail| {
+            ClientError::Outbound(Box::new(OutboundError::stream(
+                peer, kind, opened_at, detail,
+            )))
+        };
+        let outbound = |e: std::io::Error| failed(FailureKind::Io, e.to_string());
+        let opened = self
+            .streams
+            .clone()
+            .open_stream(peer, StreamProtocol::new(STREAM_PROTOCOL_NAME))
+            .await;
+        let mut stream = match opened {
+            Ok(stream) => stream,
+            // Identify says whether a version mismatch is why.
+            Err(libp2p_stream::OpenStreamError::UnsupportedProtocol(_)) => {
+                return Err(self
+                    .confirm(peer, STREAM_PROTOCOL_NAME)
+                    .await
+                    .err()
+                    .unwrap_or(ClientError::ProtocolUnsupported));
+            }
+            Err(e) => return Err(failed(FailureKind::Io, e.to_string())),
+        };
+        write_frame(&mut stream, &request).await.map_err(outbound)?;
+
+        let deadline = self.config.response_timeout;
+        let mut text = String::new();
+        let mut next_seq = 0;
+        loop {
+            let frame = timeout(deadline, read_frame(&mut stream))
+                .await
+                .map_err(|_| ClientError::ResponseTimeout(deadline))?
+                .map_err(outbound)?;
+            match frame {
+                Some(StreamFrame::Chunk { seq, text: piece }) => {
+                    if seq != next_seq {
+                        return Err(failed(
+                            FailureKind::Io,
+                            format!("got chunk {seq} when expecting {next_seq}"),
+                        ));
+                    }
+                    next_seq += 1;
+                    on_chunk(&piece);
+                    text.push_str(&piece);
+                }
+                Some(StreamFrame::End(mut response)) => {
+                    // A fallback carries its text in the last frame.
+                    if response.status == ResponseStatus::Ok && !response.is_fallback {
+                        response.response = text;
+                    }
+                    return Ok(response);
+                }
+                None => {
+                    return Err(failed(
+                        FailureKind::ConnectionClosed,
+                        "stream closed before the answer was complete".to_string(),
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Sends the command built by `command` once an in-flight slot is free and
+    /// waits for its response.
+    async fn request<T>(
+        &self,
+        command: impl FnOnce(oneshot::Sender<Result<T, ClientError>>) -> Command,
+    ) -> Result<T, ClientError> {
+        let _permit = self
+            .in_flight
+            .acquire()
+            .await
+            .map_err(|_| ClientError::Closed)?;
+        let (reply, rx) = oneshot::channel();
+        self.commands
+            .send(command(reply))
+            .await
+            .map_err(|_| ClientError::Closed)?;
+        let deadline = self.config.response_timeout;
+        timeout(deadline, rx)
+            .await
+            .map_err(|_| ClientError::ResponseTimeout(deadline))?
+            .map_err(|_| ClientError::Closed)?
+    }
+
+    /// `request`, with the configured application and API key unless it
+    /// carries its own.
+    fn with_defaults(&self, mut request: PromptRequest) -> PromptRequest {
+        request.client_info = request
+            .client_info
+            .as_ref()
+            .or(self.config.client_info.as_ref())
+            .and_then(ClientInfo::sanitized);
+        if request.api_key.is_none() {
+            request.api_key = self.config.api_key.clone();
+        }
+        request
+    }
+
+    /// `request` with the fallback API key in place of the configured one,
+    /// for a second try if the node refuses the first. `None` if there is no
+    /// fallback or the request carries a key of its own.
+    fn with_fallback_key(&self, request: &PromptRequest) -> Option<PromptRequest> {
+        let fallback = self.config.fallback_api_key.clone()?;
+        (request.api_key == self.config.api_key).then(|| PromptRequest {
+            api_key: Some(fallback),
+            ..request.clone()
+        })
+    }
+}
+
+type ConfirmReply = oneshot::Sender<Result<(), ClientError>>;
+
+/// A sent request: when it went out, and who waits for its response.
+type Pending<T> = (Instant, oneshot::Sender<Result<T, ClientError>>);
+
+/// A dial to a worker, some of whose addresses may go through relays.
+struct PendingDial {
+    peer: PeerId,
+    /// Relays of the dial's circuit addresses. The swarm connects to each
+    /// on a connection of its own before the circuit is opened.
+    relays: Vec<PeerId>,
+    /// Why connecting to a relay failed, which the circuit's own error
+    /// doesn't say.
+    relay_errors: Vec<String>,
+    reply: oneshot::Sender<Result<(), ClientError>>,
+}
+
+struct EventLoop {
+    swarm: Swarm<Behaviour>,
+    commands: mpsc::Receiver<Command>,
+    pending_dials: HashMap<ConnectionId, PendingDial>,
+    pending_requests: HashMap<OutboundRequestId, Pending<PromptResponse>>,
+    pending_reranks: HashMap<OutboundRequestId, Pending<RerankResponse>>,
+    pending_compares: HashMap<OutboundRequestId, Pending<CompareResponse>>,
+    pending_estimates: HashMap<OutboundRequestId, Pending<EstimateResponse>>,
+    pending_pex: HashMap<OutboundRequestId, Pending<PexResponse>>,
+    pending_feedback: HashMap<OutboundRequestId, Pending<FeedbackAck>>,
+    pending_confirmations: HashMap<PeerId, Vec<(&'static str, ConfirmReply)>>,
+    /// The protocols each identified peer supports.
+    identified: HashMap<PeerId, Vec<StreamProtocol>>,
+    /// What each identified peer announced about itself.
+    profiles: HashMap<PeerId, Profile>,
+    labels: HashMap<PeerId, Labels>,
+    direct: DirectUpgrades,
+    /// The callers' slots; any taken means something may be in flight on
+    /// any connection.
+    in_flight: Arc<Semaphore>,
+    max_in_flight: usize,
+}
+
+impl EventLoop {
+    fn new(
+        swarm: Swarm<Behaviour>,
+        commands: mpsc::Receiver<Command>,
+        in_flight: Arc<Semaphore>,
+    ) -> Self {
+        Self {
+            max_in_flight: in_flight.available_permits(),
+            in_flight,
+            direct: DirectUpgrades::default(),
+            swarm,
+            commands,
+            pending_dials: HashMap::new(),
+            pending_requests: HashMap::new(),
+            pending_reranks: HashMap::new(),
+            pending_compares: HashMap::new(),
+            pending_estimates: HashMap::new(),
+            pending_pex: HashMap::new(),
+            pending_feedback: HashMap::new(),
+            pending_confirmations: HashMap::new(),
+            identified: HashMap::new(),
+            profiles: HashMap::new(),
+            labels: HashMap::new(),
+        }
+    }
+
+    async fn run(mut self) {
+        let mut direct_tick = tokio::time::interval(direct::CHECK_INTERVAL);
+        loop {
+            tokio::select! {
+                event = self.swarm.select_next_some() => self.handle_event(event),
+                _ = direct_tick.tick() => self.close_relayed(),
+                command = self.commands.recv() => match command {
+                    Some(command) => self.handle_command(command),
+                    // Every `Client` handle is gone.
+                    None => return,
+                },
+            }
+        }
+    }
+
+    fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::Dial { peer, addrs, reply } => {
+                if self.swarm.is_connected(&peer) {
+                    let _ = reply.send(Ok(()));
+                    return;
+                }
+                let relays = addrs.iter().filter_map(relay_peer).collect();
+                let opts = DialOpts::peer_id(peer).addresses(addrs).build();
+                let connection_id = opts.connection_id();
+                match self.swarm.dial(opts) {
+                    Ok(()) => {
+                        let dial = PendingDial {
+                            peer,
+                            relays,
+                            relay_errors: Vec::new(),
+                            reply,
+                        };
+                        self.pending_dials.insert(connection_id, dial);
+                    }
+                    Err(e) => {
+                        let _ = reply.send(Err(ClientError::Dial(e.to_string())));
+                    }
+                }
+            }
+            Command::Send {
+                peer,
+                request,
+                reply,
+            } => {
+                let id = self
+                    .swarm
+                    .behaviour_mut()
+                    .request_response
+                    .send_request(&peer, *request);
+                self.pending_requests.insert(id, (Instant::now(), reply));
+            }
+            Command::Rerank {
+                peer,
+                request,
+                reply,
+            } => {
+                let id = self
+                    .swarm
+                    .behaviour_mut()
+                    .rerank
+                    .send_request(&peer, request);
+                self.pending_reranks.insert(id, (Instant::now(), reply));
+            }
+            Command::Compare {
+                peer,
+                request,
+                reply,
+            } => {
+                let id = self
+                    .swarm
+                    .behaviour_mut()
+                    .compare
+                    .send_request(&peer, request);
+                self.pending_compares.insert(id, (Instant::now(), reply));
+            }
+            Command::Estimate {
+                peer,
+                request,
+                reply,
+            } => {
+                let id = self
+                    .swarm
+                    .behaviour_mut()
+                    .estimate
+                    .send_request(&peer, request);
+                self.pending_estimates.insert(id, (Instant::now(), reply));
+            }
+            Command::Pex {
+                peer,
+                request,
+                reply,
+            } => {
+                let id = self.swarm.behaviour_mut().pex.send_request(&peer, request);
+                self.pending_pex.insert(id, (Instant::now(), reply));
+            }
+            Command::Feedback {
+                peer,
+                feedback,
+                reply,
+            } => {
+                let id = self
+                    .swarm
+                    .behaviour_mut()
+                    .feedback
+                    .send_request(&peer, feedback);
+                self.pending_feedback.insert(id, (Instant::now(), reply));
+            }
+            Command::Confirm {
+                peer,
+                protocol,
+                reply,
+            } => match self.identified.get(&peer) {
+                Some(protocols) => {
+                    let _ = reply.send(supports(peer, protocols, protocol));
+                }
+                None => self
+                    .pending_confirmations
+                    .entry(peer)
+                    .or_default()
+                    .push((protocol, reply)),
+            },
+            Command::Profile { peer, reply } => {
+                let _ = reply.send(self.profiles.get(&peer).cloned());
+            }
+            Command::Labels { peer, reply } => {
+                let _ = reply.send(self.labels.get(&peer).cloned());
+            }
+        }
+    }
+
+    /// Closes relayed connections that a direct one has replaced, once
+    /// nothing is in flight.
+    fn close_relayed(&mut self) {
+        let idle = self.in_flight.available_permits() == self.max_in_flight;
+        for (connection, peer) in self.direct.due(Instant::now(), |_| !idle) {
+            if self.swarm.close_connection(connection) {
+                tracing::debug!(%peer, "Closing relayed connection {connection} for the direct one");
+            }
+        }
+    }
+
+    /// Why a request to `peer` found no protocol in common.
+    fn unsupported(&self, peer: PeerId) -> ClientError {
+        self.identified
+            .get(&peer)
+            .map_or(ClientError::ProtocolUnsupported, |protocols| {
+                unsupported(peer, protocols)
+            })
+    }
+
+    /// The error for a request sent to `peer` at `sent_at` that libp2p gave
+    /// up on. Every failure is mapped here, so a new one in libp2p has to be
+    /// placed before it compiles.
+    fn outbound_failure(
+        &self,
+        peer: PeerId,
+        request_id: OutboundRequestId,
+        error: OutboundFailure,
+        sent_at: Instant,
+    ) -> ClientError {
+        let (kind, detail) = match error {
+            OutboundFailure::DialFailure => (FailureKind::DialFailure, None),
+            OutboundFailure::Timeout => (FailureKind::Timeout, None),
+            OutboundFailure::ConnectionClosed => (FailureKind::ConnectionClosed, None),
+            OutboundFailure::UnsupportedProtocols => match self.unsupported(peer) {
+                // Identify says this is a version mismatch, which is clearer.
+                mismatch @ ClientError::ProtocolMismatch { .. } => return mismatch,
+                _ => (FailureKind::UnsupportedProtocols, None),
+            },
+            OutboundFailure::Io(e) => (FailureKind::Io, Some(e.to_string())),
+        };
+        ClientError::Outbound(Box::new(OutboundError {
+            peer,
+            request_id: Some(request_id),
+            kind,
+            elapsed: sent_at.elapsed(),
+            detail,
+        }))
+    }
+
+    fn handle_event(&mut self, event: SwarmEvent<BehaviourEvent>) {
+        match event {
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                connection_id,
+                endpoint,
+                ..
+            } => {
+                self.direct
+                    .established(connection_id, peer_id, &endpoint, Instant::now());
+                // Any successful connection to the peer satisfies every
+                // outstanding dial to it, whichever address won. One to a
+                // relay only gets a circuit dial halfway.
+                let done: Vec<ConnectionId> = self
+                    .pending_dials
+                    .iter()
+                    .filter(|(_, dial)| dial.peer == peer_id)
+                    .map(|(id, _)| *id)
+                    .collect();
+                for id in done {
+                    if let Some(dial) = self.pending_dials.remove(&id) {
+                        let _ = dial.reply.send(Ok(()));
+                    }
+                }
+                for dial in self.pending_dials.values() {
+                    if dial.relays.contains(&peer_id) {
+                        tracing::debug!(relay = %peer_id, "Connected to the relay; opening a circuit to {}", dial.peer);
+                    }
+                }
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                connection_id,
+                num_established,
+                ..
+            } => {
+                self.direct.closed(connection_id);
+                if num_established > 0 {
+                    return;
+                }
+                self.identified.remove(&peer_id);
+                self.profiles.remove(&peer_id);
+                self.labels.remove(&peer_id);
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Received {
+                peer_id,
+                info,
+                ..
+            })) => {
+                for (protocol, reply) in self
+                    .pending_confirmations
+                    .remove(&peer_id)
+                    .unwrap_or_default()
+                {
+                    let _ = reply.send(supports(peer_id, &info.protocols, protocol));
+                }
+                if let Some(mismatch) =
+                    VersionMismatch::check(&node::protocol_versions(&info.protocols))
+                {
+                    tracing::warn!("Peer {peer_id} {mismatch}");
+                }
+                self.profiles
+                    .insert(peer_id, node::announced_profile(&info.agent_version));
+                self.labels
+                    .insert(peer_id, node::announced_labels(&info.agent_version));
+                if let Some(dial) = self.direct.dial(peer_id, &info.listen_addrs) {
+                    tracing::debug!(%peer_id, "Reached through a relay; dialing directly");
+                    let _ = self.swarm.dial(dial);
+                }
+                self.identified.insert(peer_id, info.protocols);
+            }
+            SwarmEvent::OutgoingConnectionError {
+                connection_id,
+                peer_id,
+                error,
+            } => {
+                if let Some(dial) = self.pending_dials.remove(&connection_id) {
+                    let mut message = error.to_string();
+                    for relay_error in dial.relay_errors {
+                        message.push_str("; ");
+                        message.push_str(&relay_error);
+                    }
+                    let _ = dial.reply.send(Err(ClientError::Dial(message)));
+                    return;
+                }
+                // The relay leg of a circuit dial. The circuit fails with it,
+                // but only says its request was canceled.
+                let Some(relay) = peer_id else {
+                    return;
+                };
+                for dial in self.pending_dials.values_mut() {
+                    if dial.relays.contains(&relay) {
+                        dial.relay_errors
+                            .push(format!("couldn't reach relay {relay}: {error}"));
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::RequestResponse(
+                request_response::Event::Message {
+                    message:
+                        request_response::Message::Response {
+                            request_id,
+                            response,
+                        },
+                    ..
+                },
+            )) => {
+                if let Some((_, reply)) = self.pending_requests.remove(&request_id) {
+                    let _ = reply.send(Ok(response));
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::RequestResponse(
+                request_response::Event::OutboundFailure {
+                    request_id,
+                    peer,
+                    error,
+                    ..
+                },
+            )) => {
+                if let Some((sent_at, reply)) = self.pending_requests.remove(&request_id) {
+                    let error = self.outbound_failure(peer, request_id, error, sent_at);
+                    let _ = reply.send(Err(error));
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Rerank(request_response::Event::Message {
+                message:
+                    request_response::Message::Response {
+                        request_id,
+                        response,
+                    },
+                ..
+            })) => {
+                if let Some((_, reply)) = self.pending_reranks.remove(&request_id) {
+                    let _ = reply.send(Ok(response));
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Rerank(
+                request_response::Event::OutboundFailure {
+                    request_id,
+                    peer,
+                    error,
+                    ..
+                },
+            )) => {
+                if let Some((sent_at, reply)) = self.pending_reranks.remove(&request_id) {
+                    let error = self.outbound_failure(peer, request_id, error, sent_at);
+                    let _ = reply.send(Err(error));
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Compare(request_response::Event::Message {
+                message:
+                    request_response::Message::Response {
+                        request_id,
+                        response,
+                    },
+                ..
+            })) => {
+                if let Some((_, reply)) = self.pending_compares.remove(&request_id) {
+                    let _ = reply.send(Ok(response));
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Compare(
+                request_response::Event::OutboundFailure {
+                    request_id,
+                    peer,
+                    error,
+                    ..
+                },
+            )) => {
+                if let Some((sent_at, reply)) = self.pending_compares.remove(&request_id) {
+                    let error = self.outbound_failure(peer, request_id, error, sent_at);
+                    let _ = reply.send(Err(error));
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Estimate(request_response::Event::Message {
+                message:
+                    request_response::Message::Response {
+                        request_id,
+                        response,
+                    },
+                ..
+            })) => {
+                if let Some((_, reply)) = self.pending_estimates.remove(&request_id) {
+                    let _ = reply.send(Ok(response));
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Estimate(
+                request_response::Event::OutboundFailure {
+                    request_id,
+                    peer,
+                    error,
+                    ..
+                },
+            )) => {
+                if let Some((sent_at, reply)) = self.pending_estimates.remove(&request_id) {
+                    let error = self.outbound_failure(peer, request_id, error, sent_at);
+                    let _ = reply.send(Err(error));
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Pex(request_response::Event::Message {
+                message:
+                    request_response::Message::Response {
+                        request_id,
+                        response,
+                    },
+                ..
+            })) => {
+                if let Some((_, reply)) = self.pending_pex.remove(&request_id) {
+                    let _ = reply.send(Ok(response));
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Pex(
+                request_response::Event::OutboundFailure {
+                    request_id,
+                    peer,
+                    error,
+                    ..
+                },
+            )) => {
+                if let Some((sent_at, reply)) = self.pending_pex.remove(&request_id) {
+                    let error = self.outbound_failure(peer, request_id, error, sent_at);
+                    let _ = reply.send(Err(error));
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Feedback(request_response::Event::Message {
+                message:
+                    request_response::Message::Response {
+                        request_id,
+                        response,
+                    },
+                ..
+            })) => {
+                if let Some((_, reply)) = self.pending_feedback.remove(&request_id) {
+                    let _ = reply.send(Ok(response));
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Feedback(
+                request_response::Event::OutboundFailure {
+                    request_id,
+                    peer,
+                    error,
+                    ..
+                },
+            )) => {
+                if let Some((sent_at, reply)) = self.pending_feedback.remove(&request_id) {
+                    let error = self.outbound_failure(peer, request_id, error, sent_at);
+                    let _ = reply.send(Err(error));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn supports(peer: PeerId, protocols: &[StreamProtocol], protocol: &str) -> Result<(), ClientError> {
+    if protocols.iter().any(|p| p.as_ref() == protocol) {
+        Ok(())
+    } else {
+        Err(unsupported(peer, protocols))
+    }
+}
+
+/// Why `peer`, which listed `protocols`, can't take a request: a version
+/// mismatch if that explains it, otherwise a plainly missing protocol.
+fn unsupported(peer: PeerId, protocols: &[StreamProtocol]) -> ClientError {
+    match VersionMismatch::check(&node::protocol_versions(protocols)) {
+        Some(mismatch) => ClientError::ProtocolMismatch { peer, mismatch },
+        None => ClientError::ProtocolUnsupported,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::{
+        identity::Keypair,
+        request_response::{Config, ProtocolSupport, cbor},
+    };
+
+    use super::*;
+
+    /// The same PeerId every run, so the messages can be compared whole.
+    fn peer() -> PeerId {
+        Keypair::ed25519_from_bytes([7; 32])
+            .unwrap()
+            .public()
+            .to_peer_id()
+    }
+
+    /// A request id as libp2p hands them out; the first is 1.
+    fn request_id() -> OutboundRequestId {
+        let mut behaviour = cbor::Behaviour::<String, String>::new(
+            [(StreamProtocol::new("/test/1"), ProtocolSupport::Full)],
+            Config::default(),
+        );
+        behaviour.send_request(&peer(), String::new())
+    }
+
+    fn failure(
+        request_id: Option<OutboundRequestId>,
+        kind: FailureKind,
+        detail: Option<&str>,
+    ) -> String {
+        ClientError::Outbound(Box::new(OutboundError {
+            peer: peer(),
+            request_id,
+            kind,
+            elapsed: Duration::from_millis(1200),
+            detail: detail.map(str::to_string),
+        }))
+        .to_string()
+    }
+
+    #[test]
+    fn outbound_errors_name_the_request_peer_and_time() {
+        let id = Some(request_id());
+        let cases = [
+            (
+                failure(id, FailureKind::ConnectionClosed, None),
+                "request failed: connection closed on request 1 to \
+                 12D3KooWRawPbxPtP1eZaJpumGnyWX2DcUyd3RQnydr3eAto4Az7 after 1.2s",
+            ),
+            (
+                failure(id, FailureKind::DialFailure, None),
+                "request failed: dial failure on request 1 to \
+                 12D3KooWRawPbxPtP1eZaJpumGnyWX2DcUyd3RQnydr3eAto4Az7 after 1.2s",
+            ),
+            (
+                failure(id, FailureKind::Timeout, None),
+                "request failed: timeout on request 1 to \
+                 12D3KooWRawPbxPtP1eZaJpumGnyWX2DcUyd3RQnydr3eAto4Az7 after 1.2s",
+            ),
+            (
+                failure(id, FailureKind::UnsupportedProtocols, None),
+                "request failed: unsupported protocols on request 1 to \
+                 12D3KooWRawPbxPtP1eZaJpumGnyWX2DcUyd3RQnydr3eAto4Az7 after 1.2s",
+            ),
+            (
+                failure(id, FailureKind::Io, Some("unexpected end of file")),
+                "request failed: I/O error on request 1 to \
+                 12D3KooWRawPbxPtP1eZaJpumGnyWX2DcUyd3RQnydr3eAto4Az7 after 1.2s: \
+                 unexpected end of file",
+            ),
+            (
+                failure(None, FailureKind::Io, Some("stream reset")),
+                "request failed: I/O error on a stream to \
+                 12D3KooWRawPbxPtP1eZaJpumGnyWX2DcUyd3RQnydr3eAto4Az7 after 1.2s: stream reset",
+            ),
+        ];
+        for (message, expected) in cases {
+            assert_eq!(message, expected);
+        }
+    }
+
+    #[test]
+    fn outbound_errors_keep_their_exit_code() {
+        let error = |kind| {
+            ClientError::Outbound(Box::new(OutboundError {
+                peer: peer(),
+                request_id: None,
+                kind,
+                elapsed: Duration::ZERO,
+                detail: None,
+            }))
+        };
+        assert_eq!(error(FailureKind::UnsupportedProtocols).exit_code(), 11);
+        assert_eq!(error(FailureKind::ConnectionClosed).exit_code(), 12);
+        assert_eq!(error(FailureKind::Io).exit_code(), 12);
+    }
+
+    #[test]
+    fn finds_the_target_and_the_relay_of_an_address() {
+        let relay = peer();
+        let target = Keypair::ed25519_from_bytes([8; 32])
+            .unwrap()
+            .public()
+            .to_peer_id();
+        let cases = [
+            (
+                format!("/ip4/203.0.113.7/tcp/4001/p2p/{relay}/p2p-circuit/p2p/{target}"),
+                Ok(target),
+                Some(relay),
+            ),
+            (
+                format!("/ip4/203.0.113.7/tcp/4001/p2p/{relay}/p2p-circuit"),
+                Err(format!(
+                    "/ip4/203.0.113.7/tcp/4001/p2p/{relay}/p2p-circuit goes through relay {relay} but names no peer after /p2p-circuit"
+                )),
+                Some(relay),
+            ),
+            (
+                format!("/ip4/192.0.2.1/tcp/4001/p2p/{target}"),
+                Ok(target),
+                None,
+            ),
+            (
+                "/ip4/192.0.2.1/tcp/4001".to_string(),
+                Err("no peer id in /ip4/192.0.2.1/tcp/4001".to_string()),
+                None,
+            ),
+        ];
+        for (addr, expected_target, expected_relay) in cases {
+            let parsed: Multiaddr = addr.parse().unwrap();
+            assert_eq!(target_peer(&parsed), expected_target, "{addr}");
+            assert_eq!(relay_peer(&parsed), expected_relay, "{addr}");
+        }
+    }
+}
@@ -0,0 +1,169 @@
+//! Which application sent a request, e.g. `batch-evaluator 0.3`, for working
+//! out where a worker's traffic comes from.
+//!
+//! A client may attach a [`ClientInfo`] to its prompts. The worker logs it
+//! with the request and tallies it in a [`ClientMix`] for its status output.
+//! It is whatever the client claims, so it is only ever observed: nothing is
+//! allowed or refused because of it. Like a profile it is sanitized and cut
+//! to length, both when sent and again when received.
+
+use std::{collections::HashMap, fmt};
+
+use serde::{Deserialize, Serialize};
+
+use crate::profile;
+
+/// Longest application name kept, in characters.
+pub const MAX_APP_NAME_CHARS: usize = 64;
+/// Longest application version kept, in characters.
+pub const MAX_APP_VERSION_CHARS: usize = 32;
+/// Distinct applications a [`ClientMix`] counts; any further are counted
+/// together, so a peer making names up can't grow it without bound.
+const MAX_TRACKED_CLIENTS: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ClientInfo {
+    pub app_name: String,
+    #[serde(default)]
+    pub app_version: String,
+}
+
+impl ClientInfo {
+    /// Client info from untrusted values, sanitized. `None` if the name is
+    /// left empty.
+    pub fn new(app_name: &str, app_version: &str) -> Option<Self> {
+        Some(Self {
+            app_name: profile::sanitize(app_name, MAX_APP_NAME_CHARS)?,
+            app_version: profile::sanitize(app_version, MAX_APP_VERSION_CHARS).unwrap_or_default(),
+        })
+    }
+
+    /// What the command-line clients in `examples/` send.
+    pub fn cli() -> Self {
+        Self {
+            app_name: "mesh-ai-node-cli".to_string(),
+            app_version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+
+    /// This info as received from a peer, sanitized again.
+    pub fn sanitized(&self) -> Option<Self> {
+        Self::new(&self.app_name, &self.app_version)
+    }
+}
+
+impl fmt::Display for ClientInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.app_version.is_empty() {
+            f.write_str(&self.app_name)
+        } else {
+            write!(f, "{} {}", self.app_name, self.app_version)
+        }
+    }
+}
+
+/// How many requests each application sent.
+#[derive(Debug, Default)]
+pub struct ClientMix {
+    counts: HashMap<ClientInfo, u64>,
+    /// Requests without client info.
+    unidentified: u64,
+    /// Requests from applications beyond [`MAX_TRACKED_CLIENTS`].
+    others: u64,
+}
+
+impl ClientMix {
+    /// Counts a request from `client`, sanitized first, so only cleaned-up
+    /// names of bounded length ever show in the status output.
+    pub fn record(&mut self, client: Option<&ClientInfo>) {
+        let Some(client) = client.and_then(ClientInfo::sanitized) else {
+            self.unidentified += 1;
+            return;
+        };
+        if let Some(count) = self.counts.get_mut(&client) {
+            *count += 1;
+        } else if self.counts.len() < MAX_TRACKED_CLIENTS {
+            self.counts.insert(client, 1);
+        } else {
+            self.others += 1;
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.values().sum::<u64>() + self.unidentified + self.others
+    }
+
+    /// Each application's share of the requests, largest first, e.g.
+    /// `("batch-evaluator 0.3", 0.8)`.
+    pub fn shares(&self) -> Vec<(String, f64)> {
+        let total = self.total();
+        if total == 0 {
+            return Vec::new();
+        }
+        let mut shares: Vec<(String, u64)> = self
+            .counts
+            .iter()
+            .map(|(client, count)| (client.to_string(), *count))
+            .chain((self.unidentified > 0).then(|| ("unidentified".to_string(), self.unidentified)))
+            .chain((self.others > 0).then(|| ("others".to_string(), self.others)))
+            .collect();
+        shares.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        shares
+            .into_iter()
+            .map(|(client, count)| (client, count as f64 / total as f64))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_and_versions_are_cleaned_and_cut() {
+        let name = format!(" \u{202E}evil\u{7}{}", "n".repeat(MAX_APP_NAME_CHARS * 2));
+        let version = "v".repeat(MAX_APP_VERSION_CHARS + 10);
+        let info = ClientInfo::new(&name, &version).unwrap();
+        assert!(info.app_name.starts_with("evil"), "{info:?}");
+        assert_eq!(info.app_name.chars().count(), MAX_APP_NAME_CHARS);
+        assert_eq!(info.app_version.chars().count(), MAX_APP_VERSION_CHARS);
+        assert!(!info.to_string().chars().any(char::is_control));
+
+        assert_eq!(ClientInfo::new(" \n\u{2066} ", "1.0"), None);
+        assert_eq!(ClientInfo::new("app", "\t").unwrap().to_string(), "app");
+    }
+
+    #[test]
+    fn received_info_is_sanitized_again() {
+        let raw = ClientInfo {
+            app_name: format!("app\n{}", "x".repeat(1000)),
+            app_version: "1.0\r\n".to_string(),
+        };
+        let clean = raw.sanitized().unwrap();
+        assert_eq!(clean.app_name.chars().count(), MAX_APP_NAME_CHARS);
+        assert_eq!(clean.app_version, "1.0");
+    }
+
+    #[test]
+    fn mix_tracks_a_bounded_number_of_clean_names() {
+        let mut mix = ClientMix::default();
+        for i in 0..MAX_TRACKED_CLIENTS + 10 {
+            mix.record(ClientInfo::new(&format!("app-{i}"), "").as_ref());
+        }
+        mix.record(None);
+        mix.record(Some(&ClientInfo {
+            app_name: "\u{0}".to_string(),
+            app_version: String::new(),
+        }));
+        mix.record(Some(&ClientInfo {
+            app_name: "app-0\u{202E}".to_string(),
+            app_version: String::new(),
+        }));
+        assert_eq!(mix.total(), MAX_TRACKED_CLIENTS as u64 + 13);
+        let shares = mix.shares();
+        assert_eq!(shares.len(), MAX_TRACKED_CLIENTS + 2);
+        assert_eq!(shares[0].0, "others");
+        assert_eq!(shares[1], ("app-0".to_string(), 2.0 / mix.total() as f64));
+        assert!(shares.iter().any(|(name, _)| name == "unidentified"));
+    }
+}
@@ -0,0 +1,125 @@
+//! Bookkeeping for comparisons while their generations run.
+//!
+//! A comparison is scheduled as two independent jobs, one per model, so each
+//! is subject to the same concurrency limits as any prompt. Their outcomes are
+//! collected here until both are in.
+
+use std::collections::HashMap;
+
+use crate::{CompareOutcome, ResponseStatus};
+
+struct Pending<C> {
+    channel: C,
+    outcomes: [Option<CompareOutcome>; 2],
+}
+
+pub struct PendingCompares<C> {
+    next_id: u64,
+    pending: HashMap<u64, Pending<C>>,
+}
+
+impl<C> Default for PendingCompares<C> {
+    fn default() -> Self {
+        Self {
+            next_id: 0,
+            pending: HashMap::new(),
+        }
+    }
+}
+
+impl<C> PendingCompares<C> {
+    /// Starts tracking a comparison to be answered on `channel`. Returns the
+    /// id its two jobs report back with.
+    pub fn start(&mut self, channel: C) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.pending.insert(
+            id,
+            Pending {
+                channel,
+                outcomes: [None, None],
+            },
+        );
+        id
+    }
+
+    /// Records the outcome for model `slot` (0 or 1) of comparison `id`. Once
+    /// both are in, returns the channel and the outcomes in model order.
+    pub fn finish(
+        &mut self,
+        id: u64,
+        slot: usize,
+        outcome: CompareOutcome,
+    ) -> Option<(C, Vec<CompareOutcome>)> {
+        let pending = self.pending.get_mut(&id)?;
+        *pending.outcomes.get_mut(slot)? = Some(outcome);
+        if pending.outcomes.iter().any(Option::is_none) {
+            return None;
+        }
+        let Pending { channel, outcomes } = self.pending.remove(&id)?;
+        Some((channel, outcomes.into_iter().flatten().collect()))
+    }
+}
+
+/// The status a comparison is logged with: the first of its generations
+/// that didn't succeed, or `Ok` if both did.
+pub fn status(outcomes: &[CompareOutcome]) -> ResponseStatus {
+    outcomes
+        .iter()
+        .map(|outcome| outcome.status)
+        .find(|status| *status != ResponseStatus::Ok)
+        .unwrap_or(ResponseStatus::Ok)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outcome(model: &str, status: ResponseStatus) -> CompareOutcome {
+        CompareOutcome {
+            model: model.to_string(),
+            status,
+            response: String::new(),
+            prompt_tokens: 0,
+            completion_tokens: 0,
+            latency_ms: 0,
+        }
+    }
+
+    #[test]
+    fn answers_once_both_generations_are_in() {
+        let mut pending = PendingCompares::default();
+        let id = pending.start("channel");
+        assert!(
+            pending
+                .finish(id, 1, outcome("b", ResponseStatus::Ok))
+                .is_none()
+        );
+        let (channel, outcomes) = pending
+            .finish(id, 0, outcome("a", ResponseStatus::Ok))
+            .unwrap();
+        assert_eq!(channel, "channel");
+        let models: Vec<_> = outcomes.iter().map(|o| o.model.as_str()).collect();
+        assert_eq!(models, ["a", "b"]);
+        // Finished comparisons and bad slots are ignored.
+        assert!(
+            pending
+                .finish(id, 0, outcome("a", ResponseStatus::Ok))
+                .is_none()
+        );
+        let other = pending.start("other");
+        assert!(
+            pending
+                .finish(other, 2, outcome("c", ResponseStatus::Ok))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn status_is_the_first_failure() {
+        let ok = outcome("a", ResponseStatus::Ok);
+        assert_eq!(status(&[ok.clone(), ok.clone()]), ResponseStatus::Ok);
+        let failed = outcome("b", ResponseStatus::DeadlineExceeded);
+        assert_eq!(status(&[ok, failed]), ResponseStatus::DeadlineExceeded);
+    }
+}
@@ -0,0 +1,364 @@
+//! Detecting prompts that don't fit the model's context window.
+//!
+//! Ollama doesn't refuse a prompt that is too long. It quietly keeps only
+//! the end of it, and the model answers a question it never fully saw. The
+//! node looks up each model's window through `/api/show` once, then compares
+//! the prompt tokens Ollama reports against it. A prompt that filled the
+//! window was cut. A lookup that failed, e.g. while Ollama was still
+//! starting, is tried again after [`LOOKUP_RETRY`].
+//!
+//! Unless the request sets [`PromptRequest::allow_truncate`], such a prompt
+//! is answered with [`ResponseStatus::ContextOverflow`]. With the flag, the
+//! node cuts the prompt to fit as its [`TruncateStrategy`] says and marks the
+//! response [`PromptResponse::truncated`].
+//!
+//! A request may size the window itself with [`PromptRequest::num_ctx`].
+//! Ollama only finds out mid-generation that a window is larger than the
+//! model was trained for, so [`ValidateOptions`] checks it against the
+//! looked-up length, and the operator's `--max-num-ctx` if set, first and
+//! answers [`ResponseStatus::InvalidOption`]. Without either, a window
+//! can't be checked and is refused, since a large one is a cheap way for a
+//! peer to make the backend allocate memory it doesn't have.
+//!
+//! [`PromptRequest::allow_truncate`]: crate::PromptRequest::allow_truncate
+//! [`PromptRequest::num_ctx`]: crate::PromptRequest::num_ctx
+//! [`ResponseStatus::ContextOverflow`]: crate::ResponseStatus::ContextOverflow
+//! [`ResponseStatus::InvalidOption`]: crate::ResponseStatus::InvalidOption
+//! [`PromptResponse::truncated`]: crate::PromptResponse::truncated
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+
+use crate::{
+    PromptRequest, PromptResponse,
+    middleware::{Middleware, Next, Outcome, Request},
+    ollama::{self, BackendError, GenerateOptions, Generation, ModelInfo},
+};
+
+/// Ollama's window for models that don't set `num_ctx`, unless the server
+/// overrides it with `OLLAMA_CONTEXT_LENGTH`.
+pub const DEFAULT_NUM_CTX: u64 = 4096;
+
+/// How long a failed lookup stands before the next prompt tries again.
+pub const LOOKUP_RETRY: Duration = Duration::from_secs(60);
+
+/// Characters per token assumed when cutting a prompt. Deliberately low, so
+/// the cut prompt fits with room to spare.
+const CHARS_PER_TOKEN: u64 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum TruncateStrategy {
+    /// Drop the start of the prompt and keep the end, as Ollama itself does.
+    Front,
+    /// Drop the end of the prompt and keep the start.
+    Back,
+    /// Never truncate; overflowing prompts are always refused.
+    Off,
+}
+
+impl fmt::Display for TruncateStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TruncateStrategy::Front => write!(f, "front"),
+            TruncateStrategy::Back => write!(f, "back"),
+            TruncateStrategy::Off => write!(f, "off"),
+        }
+    }
+}
+
+pub struct ContextPolicy {
+    pub strategy: TruncateStrategy,
+    /// Window assumed for models whose `/api/show` doesn't set `num_ctx`.
+    pub default_num_ctx: u64,
+    /// Largest window a request may ask for, whatever the model allows.
+    pub max_num_ctx: Option<u64>,
+    models: Mutex<HashMap<String, Lookup>>,
+    retry_after: Duration,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Lookup {
+    Found(ModelInfo),
+    /// Kept so the failure is logged once per retry rather than on every
+    /// prompt.
+    Failed(Instant),
+}
+
+impl ContextPolicy {
+    pub fn new(strategy: TruncateStrategy, default_num_ctx: u64) -> Self {
+        Self {
+            strategy,
+            default_num_ctx,
+            max_num_ctx: None,
+            models: Mutex::new(HashMap::new()),
+            retry_after: LOOKUP_RETRY,
+        }
+    }
+
+    /// Tries a failed lookup again after `retry_after` rather than
+    /// [`LOOKUP_RETRY`].
+    pub fn with_lookup_retry(mut self, retry_after: Duration) -> Self {
+        self.retry_after = retry_after;
+        self
+    }
+
+    /// Refuses requests asking for a window over `max` tokens.
+    pub fn with_max_num_ctx(mut self, max: Option<u64>) -> Self {
+        self.max_num_ctx = max;
+        self
+    }
+
+    /// What `/api/show` says about `model`, e.g. its context window, or
+    /// `None` if Ollama couldn't say. Looked up once per model, or again
+    /// once a failure is older than the retry interval.
+    pub async fn info(&self, model: &str) -> Option<ModelInfo> {
+        match self.models.lock().ok()?.get(model) {
+            Some(Lookup::Found(info)) => return Some(*info),
+            Some(Lookup::Failed(at)) if at.elapsed() < self.retry_after => return None,
+            _ => {}
+        }
+        let (info, lookup) = match ollama::show(model).await {
+            Ok(info) => (Some(info), Lookup::Found(info)),
+            Err(e) => {
+                tracing::warn!(
+                    "Failed to look up {model}, not checking its context window or template for {:?}: {e}",
+                    self.retry_after
+                );
+                (None, Lookup::Failed(Instant::now()))
+            }
+        };
+        self.models.lock().ok()?.insert(model.to_string(), lookup);
+        info
+    }
+
+    /// Forgets what was looked up, e.g. after models were pulled again.
+    pub fn forget(&self) {
+        if let Ok(mut models) = self.models.lock() {
+            models.clear();
+        }
+    }
+
+    /// The context window of `model`, in tokens, or `None` if Ollama
+    /// couldn't say.
+    pub async fn limit(&self, model: &str) -> Option<u64> {
+        // `num_ctx` is what Ollama actually allocates; the trained length
+        // only caps it.
+        let info = self.info(model).await?;
+        let limit = info.num_ctx.unwrap_or(self.default_num_ctx);
+        Some(info.context_length.map_or(limit, |max| limit.min(max)))
+    }
+
+    /// The window a request asking for `num_ctx` runs `model` with.
+    async fn window(&self, model: &str, num_ctx: Option<u64>) -> Option<u64> {
+        match num_ctx {
+            Some(num_ctx) => Some(num_ctx),
+            None => self.limit(model).await,
+        }
+    }
+
+    /// Checks `request`'s options against what `model` supports and the
+    /// operator allows. A `num_ctx` that can't be checked, because Ollama
+    /// couldn't say how long `model` was trained for and there's no
+    /// operator cap, is refused.
+    pub async fn validate(&self, model: &str, request: &PromptRequest) -> Result<(), String> {
+        let Some(num_ctx) = request.num_ctx else {
+            return Ok(());
+        };
+        if num_ctx == 0 {
+            return Err("num_ctx must be at least 1".to_string());
+        }
+        if let Some(max) = self.max_num_ctx
+            && num_ctx > max
+        {
+            return Err(format!(
+                "num_ctx {num_ctx} is over this node's limit of {max}"
+            ));
+        }
+        let trained = self.info(model).await.and_then(|info| info.context_length);
+        match trained {
+            Some(max) if num_ctx > max => Err(format!(
+                "num_ctx {num_ctx} is over the {max} tokens {model} was trained for"
+            )),
+            None if self.max_num_ctx.is_none() => Err(format!(
+                "num_ctx can't be checked against {model}, whose trained length is unknown; leave it unset"
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether requests may ask for truncation.
+    pub fn can_truncate(&self) -> bool {
+        self.strategy != TruncateStrategy::Off
+    }
+
+    /// Runs `request` on `model` like [`ollama::generate`], checking that
+    /// the prompt fit. `raw` says the prompt is already in the model's
+    /// template. An overflow is returned as an [`Overflow`] error
+    /// unless the request allows truncation. Also returns whether the prompt
+    /// was truncated.
+    ///
+    /// Ollama has already kept the end of an overflowing prompt, which is
+    /// [`TruncateStrategy::Front`], so that answer is used as is. For
+    /// [`TruncateStrategy::Back`] the prompt is cut here and run again.
+    pub async fn generate(
+        &self,
+        model: &str,
+        request: &PromptRequest,
+        raw: bool,
+    ) -> (Result<Generation, BackendError>, bool) {
+        let options = GenerateOptions {
+            raw,
+            ..GenerateOptions::of(request)
+        };
+        let result = ollama::generate(model, request.prompt.clone(), options).await;
+        let (result, truncated) = self
+            .check(model, request.allow_truncate, request.num_ctx, result)
+            .await;
+        if !truncated || self.strategy != TruncateStrategy::Back {
+            return (result, truncated);
+        }
+        // `check` only reports truncation when the limit is known.
+        let limit = self
+            .window(model, request.num_ctx)
+            .await
+            .unwrap_or(self.default_num_ctx);
+        let prompt_tokens = result.as_ref().map_or(0, |g| g.prompt_tokens);
+        let Some(prompt) = truncate(&request.prompt, limit, self.strategy) else {
+            // Already short in characters but not in tokens; there's no
+            // sensible cut to make.
+            return (
+                Err(Box::new(self.overflow(model, prompt_tokens, limit))),
+                false,
+            );
+        };
+        let result = ollama::generate(model, prompt, options).await;
+        match result {
+            Ok(generation) if overflowed(generation.prompt_tokens, limit) => (
+                Err(Box::new(self.overflow(
+                    model,
+                    generation.prompt_tokens,
+                    limit,
+                ))),
+                false,
+            ),
+            result => (result, true),
+        }
+    }
+
+    /// Checks a finished generation, run with a window of `num_ctx` if the
+    /// request set one, for overflow. Returns the result, or an [`Overflow`]
+    /// in its place, and whether the prompt was truncated. Streamed answers,
+    /// which can't be run again, only get this check.
+    pub async fn check(
+        &self,
+        model: &str,
+        allow_truncate: bool,
+        num_ctx: Option<u64>,
+        result: Result<Generation, BackendError>,
+    ) -> (Result<Generation, BackendError>, bool) {
+        let Ok(generation) = &result else {
+            return (result, false);
+        };
+        let prompt_tokens = generation.prompt_tokens;
+        let Some(limit) = self.window(model, num_ctx).await else {
+            return (result, false);
+        };
+        if !overflowed(prompt_tokens, limit) {
+            return (result, false);
+        }
+        if allow_truncate && self.can_truncate() {
+            return (result, true);
+        }
+        (
+            Err(Box::new(self.overflow(model, prompt_tokens, limit))),
+            false,
+        )
+    }
+
+    fn overflow(&self, model: &str, prompt_tokens: u64, limit: u64) -> Overflow {
+        Overflow {
+            model: model.to_string(),
+            prompt_tokens,
+            limit,
+            strategy: self.strategy,
+        }
+    }
+}
+
+/// A prompt didn't fit the model's context window.
+#[derive(Debug)]
+pub struct Overflow {
+    pub model: String,
+    /// What Ollama evaluated, which is as much as fit; the prompt was at
+    /// least this long.
+    pub prompt_tokens: u64,
+    pub limit: u64,
+    /// The node's strategy, to say whether asking for truncation would help.
+    pub strategy: TruncateStrategy,
+}
+
+impl fmt::Display for Overflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Prompt is at least {} tokens but {} accepts {}; ",
+            self.prompt_tokens, self.model, self.limit
+        )?;
+        match self.strategy {
+            TruncateStrategy::Off => write!(f, "this node doesn't truncate prompts"),
+            strategy => write!(
+                f,
+                "set allow_truncate to have the node cut it to fit ({strategy} truncation)"
+            ),
+        }
+    }
+}
+
+impl Error for Overflow {}
+
+/// Answers prompts whose options the model can't take before they reach
+/// the backend.
+pub struct ValidateOptions(pub Arc<ContextPolicy>);
+
+#[async_trait]
+impl Middleware for ValidateOptions {
+    async fn handle(&self, request: Request, next: Next<'_>) -> Outcome {
+        match self.0.validate(&request.model, &request.prompt).await {
+            Ok(()) => next.run(request).await,
+            Err(reason) => {
+                tracing::info!(model = %request.model, "Refusing options: {reason}");
+                Outcome::answered(PromptResponse::invalid_option(reason))
+            }
+        }
+    }
+}
+
+/// Whether a prompt that Ollama reports as `prompt_tokens` long filled a
+/// window of `limit`, i.e. was cut to fit. Ollama trims a little below the
+/// window to leave room for output, hence the slack.
+pub fn overflowed(prompt_tokens: u64, limit: u64) -> bool {
+    prompt_tokens >= limit - limit / 64
+}
+
+/// Cuts `prompt` down to what should fit in a `limit`-token window while
+/// leaving a quarter of it for the answer. Returns `None` if the strategy is
+/// [`TruncateStrategy::Off`] or the prompt is already that short.
+pub fn truncate(prompt: &str, limit: u64, strategy: TruncateStrategy) -> Option<String> {
+    let budget = (limit - limit / 4).saturating_mul(CHARS_PER_TOKEN) as usize;
+    let chars = prompt.chars().count();
+    if chars <= budget {
+        return None;
+    }
+    match strategy {
+        TruncateStrategy::Front => Some(prompt.chars().skip(chars - budget).collect()),
+        TruncateStrategy::Back => Some(prompt.chars().take(budget).collect()),
+        TruncateStrategy::Off => None,
+    }
+}
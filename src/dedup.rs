@@ -0,0 +1,587 @@
+//! Server-side deduplication of retried prompts by idempotency key.
+//!
+//! A retry whose key matches a prompt still running waits for that run's
+//! result instead of starting another. One matching a finished prompt gets the
+//! cached response. Finished entries expire after a TTL and the table is
+//! capped, so a peer can't grow it without bound.
+//!
+//! A request can shorten its entry's TTL with
+//! [`PromptRequest::cache_ttl_ms`], and choose how the cache is used with
+//! [`PromptRequest::cache`]. A [`CacheMode::Bypass`] run starts afresh even
+//! while the key is running or cached; its result replaces the entry unless
+//! the entry came from a later run, or the run failed and the entry didn't.
+//! Operators clear entries, e.g. after a model update, with
+//! [`Dedup::flush`].
+//!
+//! Only final results are cached. A run that failed in a way a retry may get
+//! past, e.g. [`ResponseStatus::Busy`], [`ResponseStatus::Error`] or
+//! [`ResponseStatus::BackendOutOfMemory`], or that was answered with the
+//! node's fallback text, hands its response to the retries waiting on it and
+//! leaves the key free, so the next retry runs it for real.
+//!
+//! A run whose result never comes back, however it was lost, is given up on
+//! once it has been running longer than a request may wait, and
+//! [`Dedup::prune`] hands back the retries that were waiting on it. Should
+//! its result arrive after all, it is cached like any other.
+//!
+//! [`PromptRequest::cache_ttl_ms`]: crate::PromptRequest::cache_ttl_ms
+//! [`PromptRequest::cache`]: crate::PromptRequest::cache
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+use crate::{PromptResponse, ResponseStatus};
+
+/// Finished entries kept at most; past this new results aren't cached.
+const MAX_ENTRIES: usize = 10_000;
+
+/// How a request uses the responses cached under its idempotency key.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+pub enum CacheMode {
+    /// Answer from the cache when it can, and run the prompt otherwise.
+    #[default]
+    Prefer,
+    /// Run the prompt afresh, and cache the result.
+    Bypass,
+    /// Answer from the cache only; a miss is answered with
+    /// [`ResponseStatus::NotCached`] instead of running the prompt.
+    Only,
+}
+
+/// A request to drop cached results, answered with how many were dropped.
+#[derive(Debug)]
+pub struct FlushRequest {
+    /// Only the results of this model; `None` for all of them.
+    pub model: Option<String>,
+    pub reply: oneshot::Sender<usize>,
+}
+
+/// A run of a prompt, as recorded with its result.
+#[derive(Debug, Clone)]
+pub struct Run {
+    pub model: String,
+    /// When the request was admitted.
+    pub started: Instant,
+    /// How long the result is kept.
+    pub ttl: Duration,
+}
+
+enum Entry<C> {
+    /// Running since the given time; holds the channels of retries waiting
+    /// on the result.
+    InFlight(Vec<C>, Instant),
+    Done(Box<PromptResponse>, Run, Instant),
+}
+
+impl<C> Entry<C> {
+    fn is_fresh(&self, now: Instant, max_run: Duration) -> bool {
+        match self {
+            Entry::InFlight(_, since) => now.saturating_duration_since(*since) < max_run,
+            Entry::Done(_, run, at) => now.saturating_duration_since(*at) < run.ttl,
+        }
+    }
+}
+
+/// What to do with an incoming request carrying an idempotency key.
+pub enum Lookup<C> {
+    /// First time this key is seen; run the request.
+    New(C),
+    /// The key is already running; the channel has been parked.
+    Waiting,
+    /// Already answered; send this response again.
+    Done(C, Box<PromptResponse>),
+    /// Nothing is cached and the request asked for [`CacheMode::Only`].
+    NotCached(C),
+}
+
+pub struct Dedup<C> {
+    /// The longest a result is kept; requests may ask for less.
+    ttl: Duration,
+    /// How long a run may go without a result before it is given up on.
+    max_run: Duration,
+    entries: HashMap<(PeerId, String), Entry<C>>,
+}
+
+impl<C> Dedup<C> {
+    /// `max_run` should be the longest a request is waited on, past which
+    /// nobody is left to answer.
+    pub fn new(ttl: Duration, max_run: Duration) -> Self {
+        Self {
+            ttl,
+            max_run,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// How long to keep a result whose request asked for `requested`.
+    pub fn ttl_for(&self, requested: Option<Duration>) -> Duration {
+        requested.map_or(self.ttl, |ttl| ttl.min(self.ttl))
+    }
+
+    /// Looks up `key` from `peer`. Keys are scoped per peer, so one peer can't
+    /// read another's responses by guessing keys.
+    pub fn lookup(
+        &mut self,
+        peer: PeerId,
+        key: &str,
+        mode: CacheMode,
+        channel: C,
+        now: Instant,
+    ) -> Lookup<C> {
+        if mode == CacheMode::Bypass {
+            return Lookup::New(channel);
+        }
+        match self.entries.get_mut(&(peer, key.to_string())) {
+            Some(Entry::InFlight(waiters, since))
+                if now.saturating_duration_since(*since) < self.max_run =>
+            {
+                waiters.push(channel);
+                Lookup::Waiting
+            }
+            Some(Entry::Done(response, run, at))
+                if now.saturating_duration_since(*at) < run.ttl =>
+            {
+                Lookup::Done(channel, response.clone())
+            }
+            _ if mode == CacheMode::Only => Lookup::NotCached(channel),
+            _ => Lookup::New(channel),
+        }
+    }
+
+    /// Marks `key` as running. Call once the request has actually been
+    /// admitted, so rejected requests can be retried for real. A key already
+    /// running or cached, as it is for a [`CacheMode::Bypass`] run, is left
+    /// as it is until the run finishes. Retries still waiting on a run given
+    /// up on wait on this one instead.
+    pub fn start(&mut self, peer: PeerId, key: String, now: Instant) {
+        let key = (peer, key);
+        let waiters = match self.entries.get(&key) {
+            Some(entry) if entry.is_fresh(now, self.max_run) => return,
+            _ => match self.entries.remove(&key) {
+                Some(Entry::InFlight(waiters, _)) => waiters,
+                _ => Vec::new(),
+            },
+        };
+        self.entries.insert(key, Entry::InFlight(waiters, now));
+    }
+
+    /// Whether any retry is waiting on `key`'s result.
+    pub fn has_waiters(&self, peer: PeerId, key: &str) -> bool {
+        matches!(
+            self.entries.get(&(peer, key.to_string())),
+            Some(Entry::InFlight(waiters, _)) if !waiters.is_empty()
+        )
+    }
+
+    /// Forgets `key` without caching a result, e.g. when it was never run,
+    /// so a later retry runs it for real. Returns the channels of any retries
+    /// that were waiting on it.
+    pub fn abandon(&mut self, peer: PeerId, key: String) -> Vec<C> {
+        let key = (peer, key);
+        match self.entries.remove(&key) {
+            Some(Entry::InFlight(waiters, _)) => waiters,
+            Some(done) => {
+                self.entries.insert(key, done);
+                Vec::new()
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Records the result of `run` for `key` and returns the channels of any
+    /// retries that were waiting on it. A cached result from a run that
+    /// started later is kept, and so is a successful one when this run
+    /// failed. A result that isn't final is not cached.
+    pub fn finish(
+        &mut self,
+        peer: PeerId,
+        key: String,
+        run: Run,
+        response: &PromptResponse,
+        now: Instant,
+    ) -> Vec<C> {
+        self.entries.retain(|_, entry| {
+            matches!(entry, Entry::InFlight(..)) || entry.is_fresh(now, self.max_run)
+        });
+        let key = (peer, key);
+        let waiters = match self.entries.remove(&key) {
+            Some(Entry::InFlight(waiters, _)) => waiters,
+            Some(Entry::Done(cached, cached_run, at))
+                if cached_run.started > run.started
+                    || (cached.status == ResponseStatus::Ok
+                        && response.status != ResponseStatus::Ok)
+                    || !is_final(response) =>
+            {
+                self.entries
+                    .insert(key, Entry::Done(cached, cached_run, at));
+                return Vec::new();
+            }
+            _ => Vec::new(),
+        };
+        if is_final(response) && self.entries.len() < MAX_ENTRIES {
+            self.entries
+                .insert(key, Entry::Done(Box::new(response.clone()), run, now));
+        }
+        waiters
+    }
+
+    /// Drops expired results and gives up on runs that have gone too long
+    /// without one. Returns the channels of the retries that were waiting on
+    /// those runs, which are left for the caller to answer.
+    pub fn prune(&mut self, now: Instant) -> Vec<C> {
+        let stale: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| !entry.is_fresh(now, self.max_run))
+            .map(|(key, _)| key.clone())
+            .collect();
+        stale
+            .into_iter()
+            .flat_map(|key| match self.entries.remove(&key) {
+                Some(Entry::InFlight(waiters, _)) => waiters,
+                _ => Vec::new(),
+            })
+            .collect()
+    }
+
+    /// Keys running or cached.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Drops the cached results for `model`, or all of them, and returns how
+    /// many were dropped. Running keys are left to finish.
+    pub fn flush(&mut self, model: Option<&str>) -> usize {
+        let before = self.entries.len();
+        self.entries.retain(|_, entry| match entry {
+            Entry::InFlight(..) => true,
+            Entry::Done(_, run, _) => model.is_some_and(|model| run.model != model),
+        });
+        before - self.entries.len()
+    }
+}
+
+/// Whether `response` is the answer to its prompt, rather than a failure a
+/// retry may get past or the node's fallback text.
+fn is_final(response: &PromptResponse) -> bool {
+    !response.is_fallback
+        && !matches!(
+            response.status,
+            ResponseStatus::Busy
+                | ResponseStatus::Error
+                | ResponseStatus::Overloaded
+                | ResponseStatus::BackendOutOfMemory
+                | ResponseStatus::DeadlineExceeded
+                | ResponseStatus::Internal
+                | ResponseStatus::Maintenance
+                | ResponseStatus::Standby
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(60);
+    const MAX_RUN: Duration = Duration::from_secs(30);
+
+    fn run(started: Instant) -> Run {
+        Run {
+            model: "llama3".to_string(),
+            started,
+            ttl: TTL,
+        }
+    }
+
+    fn ok(text: &str) -> PromptResponse {
+        PromptResponse::ok(text.to_string())
+    }
+
+    fn is_new(lookup: Lookup<u32>) -> bool {
+        matches!(lookup, Lookup::New(_))
+    }
+
+    fn cached(lookup: Lookup<u32>) -> Option<String> {
+        match lookup {
+            Lookup::Done(_, response) => Some(response.response),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn retries_wait_on_the_run_then_get_its_result() {
+        let mut dedup = Dedup::new(TTL, MAX_RUN);
+        let (peer, now) = (PeerId::random(), Instant::now());
+        assert!(is_new(dedup.lookup(peer, "k", CacheMode::Prefer, 1, now)));
+        dedup.start(peer, "k".to_string(), now);
+        assert!(matches!(
+            dedup.lookup(peer, "k", CacheMode::Prefer, 2, now),
+            Lookup::Waiting
+        ));
+        assert!(dedup.has_waiters(peer, "k"));
+
+        let waiters = dedup.finish(peer, "k".to_string(), run(now), &ok("hi"), now);
+        assert_eq!(waiters, [2]);
+        assert_eq!(
+            cached(dedup.lookup(peer, "k", CacheMode::Prefer, 3, now)).as_deref(),
+            Some("hi")
+        );
+        // Keys are per peer.
+        assert!(is_new(dedup.lookup(
+            PeerId::random(),
+            "k",
+            CacheMode::Prefer,
+            4,
+            now
+        )));
+    }
+
+    #[test]
+    fn abandoning_a_run_caches_nothing() {
+        let mut dedup = Dedup::new(TTL, MAX_RUN);
+        let (peer, now) = (PeerId::random(), Instant::now());
+        dedup.start(peer, "k".to_string(), now);
+        dedup.lookup(peer, "k", CacheMode::Prefer, 2, now);
+        assert_eq!(dedup.abandon(peer, "k".to_string()), [2]);
+        assert!(dedup.is_empty());
+    }
+
+    #[test]
+    fn runs_that_never_finish_are_given_up_on() {
+        let mut dedup = Dedup::new(TTL, MAX_RUN);
+        let (peer, now) = (PeerId::random(), Instant::now());
+        dedup.start(peer, "k".to_string(), now);
+        dedup.lookup(peer, "k", CacheMode::Prefer, 2, now);
+
+        assert!(dedup.prune(now + MAX_RUN / 2).is_empty());
+        assert_eq!(dedup.prune(now + MAX_RUN), [2]);
+        assert!(dedup.is_empty());
+        assert!(is_new(dedup.lookup(
+            peer,
+            "k",
+            CacheMode::Prefer,
+            3,
+            now + MAX_RUN
+        )));
+    }
+
+    #[test]
+    fn a_run_given_up_on_hands_its_waiters_to_the_next_one() {
+        let mut dedup = Dedup::new(TTL, MAX_RUN);
+        let (peer, now) = (PeerId::random(), Instant::now());
+        dedup.start(peer, "k".to_string(), now);
+        dedup.lookup(peer, "k", CacheMode::Prefer, 2, now);
+
+        // Not pruned yet, but too old to wait on.
+        let later = now + MAX_RUN;
+        assert!(is_new(dedup.lookup(peer, "k", CacheMode::Prefer, 3, later)));
+        dedup.start(peer, "k".to_string(), later);
+        let waiters = dedup.finish(peer, "k".to_string(), run(later), &ok("hi"), later);
+        assert_eq!(waiters, [2]);
+    }
+
+    #[test]
+    fn a_result_arriving_after_its_run_was_given_up_on_is_cached() {
+        let mut dedup = Dedup::new(TTL, MAX_RUN);
+        let (peer, now) = (PeerId::random(), Instant::now());
+        dedup.start(peer, "k".to_string(), now);
+        dedup.lookup(peer, "k", CacheMode::Prefer, 2, now);
+        assert_eq!(dedup.prune(now + MAX_RUN), [2]);
+
+        let late = now + MAX_RUN + Duration::from_secs(1);
+        assert!(
+            dedup
+                .finish(peer, "k".to_string(), run(now), &ok("late"), late)
+                .is_empty()
+        );
+        assert_eq!(
+            cached(dedup.lookup(peer, "k", CacheMode::Prefer, 3, late)).as_deref(),
+            Some("late")
+        );
+    }
+
+    #[test]
+    fn a_late_result_answers_the_retry_run_but_does_not_outlive_it() {
+        let mut dedup = Dedup::new(TTL, MAX_RUN);
+        let (peer, first) = (PeerId::random(), Instant::now());
+        dedup.start(peer, "k".to_string(), first);
+        dedup.prune(first + MAX_RUN);
+
+        let second = first + MAX_RUN;
+        dedup.start(peer, "k".to_string(), second);
+        dedup.lookup(peer, "k", CacheMode::Prefer, 2, second);
+        let waiters = dedup.finish(peer, "k".to_string(), run(first), &ok("first"), second);
+        assert_eq!(waiters, [2]);
+
+        // The retry's own run started later, so its result wins.
+        dedup.finish(peer, "k".to_string(), run(second), &ok("second"), second);
+        assert_eq!(
+            cached(dedup.lookup(peer, "k", CacheMode::Prefer, 3, second)).as_deref(),
+            Some("second")
+        );
+    }
+
+    #[test]
+    fn results_expire_after_their_ttl() {
+        let mut dedup = Dedup::new(TTL, MAX_RUN);
+        let (peer, now) = (PeerId::random(), Instant::now());
+        dedup.start(peer, "k".to_string(), now);
+        dedup.finish(peer, "k".to_string(), run(now), &ok("hi"), now);
+        assert!(dedup.prune(now + TTL).is_empty());
+        assert!(dedup.is_empty());
+        assert!(matches!(
+            dedup.lookup(peer, "k", CacheMode::Only, 1, now + TTL),
+            Lookup::NotCached(1)
+        ));
+    }
+
+    #[test]
+    fn transient_failures_release_the_waiters_without_being_cached() {
+        let transient = [
+            PromptResponse::busy(),
+            PromptResponse::backend_out_of_memory("oom".to_string()),
+            PromptResponse::deadline_exceeded(Duration::from_secs(1)),
+            PromptResponse::internal("bug".to_string()),
+        ];
+        for response in transient {
+            let mut dedup = Dedup::new(TTL, MAX_RUN);
+            let (peer, now) = (PeerId::random(), Instant::now());
+            dedup.start(peer, "k".to_string(), now);
+            assert!(matches!(
+                dedup.lookup(peer, "k", CacheMode::Prefer, 1, now),
+                Lookup::Waiting
+            ));
+            let waiters = dedup.finish(peer, "k".to_string(), run(now), &response, now);
+            assert_eq!(waiters, [1], "{:?}", response.status);
+            assert!(
+                is_new(dedup.lookup(peer, "k", CacheMode::Prefer, 2, now)),
+                "{:?} was cached",
+                response.status
+            );
+        }
+    }
+
+    #[test]
+    fn an_unreachable_backend_is_not_cached() {
+        let mut dedup = Dedup::new(TTL, MAX_RUN);
+        let (peer, now) = (PeerId::random(), Instant::now());
+        dedup.start(peer, "k".to_string(), now);
+        let error = PromptResponse::error("Ollama unreachable".to_string());
+        dedup.finish(peer, "k".to_string(), run(now), &error, now);
+
+        // The retry runs again rather than getting the error back.
+        assert!(is_new(dedup.lookup(peer, "k", CacheMode::Prefer, 1, now)));
+        dedup.start(peer, "k".to_string(), now);
+        dedup.finish(peer, "k".to_string(), run(now), &ok("hi"), now);
+        assert_eq!(
+            cached(dedup.lookup(peer, "k", CacheMode::Prefer, 2, now)).as_deref(),
+            Some("hi")
+        );
+    }
+
+    #[test]
+    fn fallbacks_are_not_cached() {
+        let mut dedup = Dedup::new(TTL, MAX_RUN);
+        let (peer, now) = (PeerId::random(), Instant::now());
+        let fallback = PromptResponse {
+            is_fallback: true,
+            ..ok("Try again later")
+        };
+        dedup.start(peer, "k".to_string(), now);
+        dedup.finish(peer, "k".to_string(), run(now), &fallback, now);
+        assert!(is_new(dedup.lookup(peer, "k", CacheMode::Prefer, 1, now)));
+    }
+
+    #[test]
+    fn a_transient_failure_keeps_an_earlier_result() {
+        let mut dedup = Dedup::new(TTL, MAX_RUN);
+        let (peer, now) = (PeerId::random(), Instant::now());
+        dedup.start(peer, "k".to_string(), now);
+        dedup.finish(
+            peer,
+            "k".to_string(),
+            run(now),
+            &PromptResponse::invalid_json("bad".to_string()),
+            now,
+        );
+        let later = now + Duration::from_secs(1);
+        dedup.finish(
+            peer,
+            "k".to_string(),
+            run(later),
+            &PromptResponse::busy(),
+            later,
+        );
+        assert!(matches!(
+            dedup.lookup(peer, "k", CacheMode::Prefer, 1, later),
+            Lookup::Done(1, response) if response.status == ResponseStatus::InvalidJson
+        ));
+    }
+
+    #[test]
+    fn a_bypass_run_finishing_late_keeps_the_fresher_entry() {
+        let mut dedup = Dedup::new(TTL, MAX_RUN);
+        let (peer, now) = (PeerId::random(), Instant::now());
+        dedup.start(peer, "k".to_string(), now);
+        dedup.finish(peer, "k".to_string(), run(now), &ok("first"), now);
+
+        // Bypass runs ignore the cached result, and don't touch it on start.
+        let (slow, fast) = (now + Duration::from_secs(1), now + Duration::from_secs(2));
+        assert!(is_new(dedup.lookup(peer, "k", CacheMode::Bypass, 1, slow)));
+        dedup.start(peer, "k".to_string(), slow);
+        assert!(is_new(dedup.lookup(peer, "k", CacheMode::Bypass, 2, fast)));
+        dedup.start(peer, "k".to_string(), fast);
+        let prefer =
+            |dedup: &mut Dedup<u32>, at| cached(dedup.lookup(peer, "k", CacheMode::Prefer, 3, at));
+        assert_eq!(prefer(&mut dedup, fast).as_deref(), Some("first"));
+
+        // The run that started last finishes first and replaces it...
+        let done = now + Duration::from_secs(3);
+        dedup.finish(peer, "k".to_string(), run(fast), &ok("fast"), done);
+        assert_eq!(prefer(&mut dedup, done).as_deref(), Some("fast"));
+        // ...and the older one finishing after it changes nothing.
+        let late = now + Duration::from_secs(4);
+        dedup.finish(peer, "k".to_string(), run(slow), &ok("slow"), late);
+        assert_eq!(prefer(&mut dedup, late).as_deref(), Some("fast"));
+    }
+
+    #[test]
+    fn flushes_one_models_results_or_all() {
+        let mut dedup = Dedup::new(TTL, MAX_RUN);
+        let (peer, now) = (PeerId::random(), Instant::now());
+        let on = |model: &str| Run {
+            model: model.to_string(),
+            ..run(now)
+        };
+        for (key, model) in [("a", "llama3"), ("b", "llama3"), ("c", "mistral")] {
+            dedup.start(peer, key.to_string(), now);
+            dedup.finish(peer, key.to_string(), on(model), &ok(key), now);
+        }
+        dedup.start(peer, "running".to_string(), now);
+
+        assert_eq!(dedup.flush(Some("llama3")), 2);
+        assert!(is_new(dedup.lookup(peer, "a", CacheMode::Prefer, 1, now)));
+        assert_eq!(
+            cached(dedup.lookup(peer, "c", CacheMode::Prefer, 1, now)).as_deref(),
+            Some("c")
+        );
+        assert_eq!(dedup.flush(Some("gemma")), 0);
+        // Runs in progress are left to finish.
+        assert_eq!(dedup.flush(None), 1);
+        assert_eq!(dedup.len(), 1);
+        assert!(matches!(
+            dedup.lookup(peer, "running", CacheMode::Prefer, 1, now),
+            Lookup::Waiting
+        ));
+    }
+}
@@ -0,0 +1,222 @@
+//! Operator-maintained list of peers that are refused service outright.
+//!
+//! The file holds one PeerId per line; blank lines and `#` comments are
+//! ignored. Denials are logged at most once per peer per [`LOG_INTERVAL`] so a
+//! denied peer hammering the node can't flood the logs.
+//!
+//! [`Denial::check`] decides whether a peer is refused before its request is
+//! looked at: the denylist wins over a ban, and both over an allowlist, so
+//! being allowed never lets a denylisted or banned peer in.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use libp2p::PeerId;
+
+use crate::{PromptResponse, bans::BanList};
+
+pub const LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct Denylist {
+    path: PathBuf,
+    peers: HashSet<PeerId>,
+    last_logged: HashMap<PeerId, Instant>,
+}
+
+impl Denylist {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            peers: read(path)?,
+            last_logged: HashMap::new(),
+        })
+    }
+
+    /// Re-reads the file, returning the peers that were added and removed.
+    /// On error the current list is kept.
+    pub fn reload(&mut self) -> io::Result<(Vec<PeerId>, Vec<PeerId>)> {
+        let peers = read(&self.path)?;
+        let added = peers.difference(&self.peers).copied().collect();
+        let removed = self.peers.difference(&peers).copied().collect();
+        self.last_logged.retain(|p, _| peers.contains(p));
+        self.peers = peers;
+        Ok((added, removed))
+    }
+
+    pub fn contains(&self, peer: &PeerId) -> bool {
+        self.peers.contains(peer)
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = &PeerId> {
+        self.peers.iter()
+    }
+
+    /// Whether a denial of `peer` should be logged now.
+    pub fn should_log(&mut self, peer: PeerId, now: Instant) -> bool {
+        match self.last_logged.get(&peer) {
+            Some(t) if now.duration_since(*t) < LOG_INTERVAL => false,
+            _ => {
+                self.last_logged.insert(peer, now);
+                true
+            }
+        }
+    }
+}
+
+/// Why a peer is refused outright, whatever it asked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Denial {
+    Denylisted,
+    /// Serving a ban, with the time left on it.
+    Banned(Duration),
+    /// Not on an allowlist that is in force.
+    NotAllowed,
+}
+
+impl Denial {
+    /// Checks `peer` against the denylist, then its ban, then `allowlist`
+    /// unless that is empty.
+    pub fn check(
+        peer: &PeerId,
+        denylist: Option<&Denylist>,
+        bans: &BanList,
+        allowlist: &[PeerId],
+        now: Instant,
+    ) -> Option<Self> {
+        if denylist.is_some_and(|d| d.contains(peer)) {
+            Some(Denial::Denylisted)
+        } else if let Some(left) = bans.remaining(peer, now) {
+            Some(Denial::Banned(left))
+        } else if !allowlist.is_empty() && !allowlist.contains(peer) {
+            Some(Denial::NotAllowed)
+        } else {
+            None
+        }
+    }
+
+    /// The answer to a prompt from a peer refused this way.
+    pub fn response(self) -> PromptResponse {
+        match self {
+            Denial::Denylisted => PromptResponse::error("Peer is denylisted".to_string()),
+            Denial::Banned(left) => PromptResponse::banned(left),
+            Denial::NotAllowed => {
+                PromptResponse::unauthorized("Peer is not on the allowlist".to_string())
+            }
+        }
+    }
+}
+
+fn read(path: &Path) -> io::Result<HashSet<PeerId>> {
+    fs::read_to_string(path)?
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            line.parse()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{line}: {e}")))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{bans::BanConfig, testing::TempDir};
+
+    fn denylist(peers: &[PeerId]) -> Denylist {
+        Denylist {
+            path: PathBuf::new(),
+            peers: peers.iter().copied().collect(),
+            last_logged: HashMap::new(),
+        }
+    }
+
+    fn banned(peers: &[PeerId], now: Instant) -> BanList {
+        let mut bans = BanList::new(BanConfig {
+            threshold: 1,
+            window: Duration::from_secs(60),
+            base_ban: Duration::from_secs(30),
+            max_ban: Duration::from_secs(30),
+            max_records: 100,
+        });
+        for peer in peers {
+            bans.record_failure(*peer, now);
+        }
+        bans
+    }
+
+    #[test]
+    fn the_denylist_wins_over_bans_and_the_allowlist() {
+        let now = Instant::now();
+        let peer = PeerId::random();
+        let denylist = denylist(&[peer]);
+        let bans = banned(&[peer], now);
+        assert_eq!(
+            Denial::check(&peer, Some(&denylist), &bans, &[peer], now),
+            Some(Denial::Denylisted)
+        );
+    }
+
+    #[test]
+    fn a_ban_wins_over_the_allowlist() {
+        let now = Instant::now();
+        let peer = PeerId::random();
+        let bans = banned(&[peer], now);
+        assert_eq!(
+            Denial::check(&peer, None, &bans, &[peer], now),
+            Some(Denial::Banned(Duration::from_secs(30)))
+        );
+        // Once the ban lifts, the allowlist lets the peer in again.
+        let later = now + Duration::from_secs(30);
+        assert_eq!(Denial::check(&peer, None, &bans, &[peer], later), None);
+    }
+
+    #[test]
+    fn an_allowlist_in_force_refuses_everyone_else() {
+        let now = Instant::now();
+        let (allowed, stranger) = (PeerId::random(), PeerId::random());
+        let bans = banned(&[], now);
+        let denylist = denylist(&[]);
+        assert_eq!(
+            Denial::check(&allowed, Some(&denylist), &bans, &[allowed], now),
+            None
+        );
+        assert_eq!(
+            Denial::check(&stranger, Some(&denylist), &bans, &[allowed], now),
+            Some(Denial::NotAllowed)
+        );
+        // An empty allowlist allows everyone.
+        assert_eq!(Denial::check(&stranger, None, &bans, &[], now), None);
+    }
+
+    #[test]
+    fn denials_are_logged_once_per_interval() {
+        let now = Instant::now();
+        let peer = PeerId::random();
+        let mut denylist = denylist(&[peer]);
+        assert!(denylist.should_log(peer, now));
+        assert!(!denylist.should_log(peer, now + LOG_INTERVAL / 2));
+        assert!(denylist.should_log(peer, now + LOG_INTERVAL));
+    }
+
+    #[test]
+    fn reads_peer_ids_and_comments() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let dir = TempDir::new("denylist");
+        let path = dir.join("denylist");
+        fs::write(&path, format!("# abusers\n{a}\n\n{b}  # seen 2026-10-01\n")).unwrap();
+        let mut denylist = Denylist::load(&path).unwrap();
+        assert!(denylist.contains(&a) && denylist.contains(&b));
+
+        fs::write(&path, format!("{a}\n")).unwrap();
+        assert_eq!(denylist.reload().unwrap(), (vec![], vec![b]));
+
+        fs::write(&path, "not a peer id\n").unwrap();
+        assert!(denylist.reload().is_err());
+        assert!(denylist.contains(&a), "a bad file keeps the current list");
+    }
+}
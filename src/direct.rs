@@ -0,0 +1,213 @@
+//! Moving peers off relayed connections once a direct one works.
+//!
+//! A peer reached through a relay still announces its own listen addresses
+//! through identify. While a peer is connected only through relays, the
+//! swarm loop dials those addresses once, circuit addresses aside. When a
+//! direct connection to the peer comes up, whether from that dial, from
+//! DCUtR hole punching or from the peer dialing us, its relayed connections
+//! are retired, so long-lived sessions stop costing the relay bandwidth.
+//!
+//! libp2p spreads requests over all of a peer's connections, so a relayed
+//! connection is only closed [`RELAY_GRACE`] after the direct one is up, and
+//! not while the peer has a request or stream in flight, which might be on
+//! it.
+
+use std::{
+    collections::{HashMap, HashSet, hash_map::Entry},
+    time::{Duration, Instant},
+};
+
+use libp2p::{
+    Multiaddr, PeerId,
+    core::ConnectedPoint,
+    multiaddr::Protocol,
+    swarm::{
+        ConnectionId,
+        dial_opts::{DialOpts, PeerCondition},
+    },
+};
+
+/// How long a relayed connection is kept once a direct one to the same peer
+/// is up.
+pub const RELAY_GRACE: Duration = Duration::from_secs(10);
+
+/// How often retired relayed connections are checked for closing.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Default)]
+pub struct DirectUpgrades {
+    /// Open connections, and whether each goes through a relay.
+    connections: HashMap<ConnectionId, (PeerId, bool)>,
+    /// Peers dialed directly since they last connected, so a dial that
+    /// can't work isn't repeated on every identify.
+    dialed: HashSet<PeerId>,
+    /// Relayed connections with a direct one alongside, and when they may
+    /// be closed.
+    retiring: HashMap<ConnectionId, Instant>,
+}
+
+impl DirectUpgrades {
+    /// Records a new connection. Returns how many relayed connections to
+    /// the peer it retires, if it is direct.
+    pub fn established(
+        &mut self,
+        connection: ConnectionId,
+        peer: PeerId,
+        endpoint: &ConnectedPoint,
+        now: Instant,
+    ) -> usize {
+        let relayed = endpoint.is_relayed();
+        self.connections.insert(connection, (peer, relayed));
+        let has_direct = self
+            .connections
+            .values()
+            .any(|(p, relayed)| *p == peer && !relayed);
+        if !has_direct {
+            return 0;
+        }
+        let relayed: Vec<ConnectionId> = self
+            .connections
+            .iter()
+            .filter(|(_, (p, relayed))| *p == peer && *relayed)
+            .map(|(id, _)| *id)
+            .collect();
+        let mut retired = 0;
+        for id in relayed {
+            if let Entry::Vacant(entry) = self.retiring.entry(id) {
+                entry.insert(now + RELAY_GRACE);
+                retired += 1;
+            }
+        }
+        retired
+    }
+
+    /// Forgets a closed connection. Once a peer's last direct connection
+    /// closes, its relayed ones are no longer retired, as they are all it
+    /// has left.
+    pub fn closed(&mut self, connection: ConnectionId) {
+        self.retiring.remove(&connection);
+        let Some((peer, relayed)) = self.connections.remove(&connection) else {
+            return;
+        };
+        if !self.connections.values().any(|(p, _)| *p == peer) {
+            self.dialed.remove(&peer);
+        } else if !relayed
+            && !self
+                .connections
+                .values()
+                .any(|(p, relayed)| *p == peer && !relayed)
+        {
+            let connections = &self.connections;
+            self.retiring
+                .retain(|id, _| connections.get(id).is_none_or(|(p, _)| *p != peer));
+        }
+    }
+
+    /// A dial to `peer`'s announced `listen_addrs` that skips the relay, if
+    /// it is connected only through relays and hasn't been dialed so yet.
+    pub fn dial(&mut self, peer: PeerId, listen_addrs: &[Multiaddr]) -> Option<DialOpts> {
+        let relayed_only = self
+            .connections
+            .values()
+            .filter(|(p, _)| *p == peer)
+            .map(|(_, relayed)| *relayed)
+            .reduce(|a, b| a && b)
+            .unwrap_or(false);
+        if !relayed_only || self.dialed.contains(&peer) {
+            return None;
+        }
+        let addrs: Vec<Multiaddr> = listen_addrs
+            .iter()
+            .filter(|addr| !addr.iter().any(|p| p == Protocol::P2pCircuit))
+            .cloned()
+            .collect();
+        if addrs.is_empty() {
+            return None;
+        }
+        self.dialed.insert(peer);
+        Some(
+            DialOpts::peer_id(peer)
+                .addresses(addrs)
+                .condition(PeerCondition::Always)
+                .build(),
+        )
+    }
+
+    /// Retired relayed connections due to close, unless their peer is
+    /// `busy`; these are no longer tracked as retiring.
+    pub fn due(
+        &mut self,
+        now: Instant,
+        busy: impl Fn(&PeerId) -> bool,
+    ) -> Vec<(ConnectionId, PeerId)> {
+        let due: Vec<(ConnectionId, PeerId)> = self
+            .retiring
+            .iter()
+            .filter(|(_, at)| **at <= now)
+            .filter_map(|(id, _)| {
+                let (peer, _) = self.connections.get(id)?;
+                (!busy(peer)).then_some((*id, *peer))
+            })
+            .collect();
+        for (id, _) in &due {
+            self.retiring.remove(id);
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoint(addr: &str) -> ConnectedPoint {
+        ConnectedPoint::Listener {
+            local_addr: addr.parse().unwrap(),
+            send_back_addr: "/ip4/10.0.0.2/tcp/4001".parse().unwrap(),
+        }
+    }
+
+    fn relayed() -> ConnectedPoint {
+        endpoint("/ip4/10.0.0.1/tcp/4001/p2p-circuit")
+    }
+
+    fn direct() -> ConnectedPoint {
+        endpoint("/ip4/10.0.0.1/tcp/4001")
+    }
+
+    #[test]
+    fn retires_relayed_connections_once_a_direct_one_is_up() {
+        let now = Instant::now();
+        let peer = PeerId::random();
+        let mut upgrades = DirectUpgrades::default();
+        let via_relay = ConnectionId::new_unchecked(1);
+        assert_eq!(upgrades.established(via_relay, peer, &relayed(), now), 0);
+        assert_eq!(
+            upgrades.established(ConnectionId::new_unchecked(2), peer, &direct(), now),
+            1
+        );
+        assert!(upgrades.due(now, |_| false).is_empty());
+        assert!(upgrades.due(now + RELAY_GRACE, |_| true).is_empty());
+        assert_eq!(
+            upgrades.due(now + RELAY_GRACE, |_| false),
+            [(via_relay, peer)]
+        );
+    }
+
+    #[test]
+    fn keeps_relayed_connections_when_the_direct_one_closes() {
+        let now = Instant::now();
+        let peer = PeerId::random();
+        let mut upgrades = DirectUpgrades::default();
+        upgrades.established(ConnectionId::new_unchecked(1), peer, &relayed(), now);
+        let direct_id = ConnectionId::new_unchecked(2);
+        upgrades.established(direct_id, peer, &direct(), now);
+
+        upgrades.closed(direct_id);
+        assert!(upgrades.due(now + RELAY_GRACE, |_| false).is_empty());
+
+        // A new direct connection retires them again.
+        upgrades.established(ConnectionId::new_unchecked(3), peer, &direct(), now);
+        assert_eq!(upgrades.due(now + RELAY_GRACE, |_| false).len(), 1);
+    }
+}
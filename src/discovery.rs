@@ -0,0 +1,160 @@
+//! How often the node re-announces itself and how long what peers announce
+//! is believed.
+//!
+//! Identify is how nodes find out about each other: each connection asks the
+//! peer for its identify info when it opens and again every
+//! [`DiscoveryConfig::announce_interval`], which is also how often the peer
+//! hears from us. A worker's record, its addresses, models, profile and
+//! labels, is dropped when the worker disconnects, or when it hasn't
+//! re-identified within [`DiscoveryConfig::record_ttl`], so a peer whose
+//! identify stalled short of disconnecting isn't handed out through peer
+//! exchange for good.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use libp2p::PeerId;
+
+/// Announcements a record must be able to miss before it expires, counting
+/// the one that is due: a TTL must be at least this many announce intervals.
+pub const TTL_SAFETY_FACTOR: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiscoveryConfig {
+    /// Time between identify exchanges on each connection.
+    pub announce_interval: Duration,
+    /// How long a worker's record is kept without a fresh identify.
+    pub record_ttl: Duration,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            announce_interval: Duration::from_secs(60),
+            record_ttl: Duration::from_secs(180),
+        }
+    }
+}
+
+impl DiscoveryConfig {
+    /// Announcing every `announce_interval`, with records kept for
+    /// `record_ttl`, or [`TTL_SAFETY_FACTOR`] intervals if not given.
+    pub fn new(announce_interval: Duration, record_ttl: Option<Duration>) -> Self {
+        Self {
+            announce_interval,
+            record_ttl: record_ttl
+                .unwrap_or_else(|| announce_interval.saturating_mul(TTL_SAFETY_FACTOR)),
+        }
+    }
+
+    /// Checks that the interval is positive, that records outlive
+    /// [`TTL_SAFETY_FACTOR`] announce intervals, and that neither is too
+    /// long to schedule.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.announce_interval.is_zero() {
+            return Err("the announce interval must be positive".to_string());
+        }
+        let now = Instant::now();
+        for (what, duration) in [
+            ("announce interval", self.announce_interval),
+            ("record TTL", self.record_ttl),
+        ] {
+            if now.checked_add(duration).is_none() {
+                return Err(format!("a {what} of {}s is too long", duration.as_secs()));
+            }
+        }
+        let min_ttl = self
+            .announce_interval
+            .checked_mul(TTL_SAFETY_FACTOR)
+            .ok_or_else(|| {
+                format!(
+                    "an announce interval of {}s is too long",
+                    self.announce_interval.as_secs()
+                )
+            })?;
+        if self.record_ttl < min_ttl {
+            return Err(format!(
+                "a record TTL of {}s is under {TTL_SAFETY_FACTOR} announce intervals of {}s; \
+                 use at least {}s so a late announcement doesn't expire records",
+                self.record_ttl.as_secs(),
+                self.announce_interval.as_secs(),
+                min_ttl.as_secs(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// When each connected peer last identified.
+#[derive(Debug, Default)]
+pub struct Announcements {
+    last: HashMap<PeerId, Instant>,
+}
+
+impl Announcements {
+    pub fn record(&mut self, peer: PeerId, now: Instant) {
+        self.last.insert(peer, now);
+    }
+
+    pub fn remove(&mut self, peer: &PeerId) {
+        self.last.remove(peer);
+    }
+
+    /// How long until the next identify is due from any peer, or `None`
+    /// without peers. Zero when one is overdue.
+    pub fn next_due(&self, interval: Duration, now: Instant) -> Option<Duration> {
+        self.last
+            .values()
+            .map(|at| interval.saturating_sub(now.saturating_duration_since(*at)))
+            .min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_default_ttl_follows_the_interval() {
+        let config = DiscoveryConfig::new(Duration::from_secs(300), None);
+        assert_eq!(config.record_ttl, Duration::from_secs(900));
+        assert_eq!(config.validate(), Ok(()));
+
+        let config = DiscoveryConfig::new(Duration::from_secs(60), Some(Duration::from_secs(120)));
+        assert!(config.validate().is_err());
+        assert_eq!(
+            DiscoveryConfig::new(Duration::from_secs(60), None),
+            DiscoveryConfig::default()
+        );
+    }
+
+    #[test]
+    fn refuses_intervals_too_long_to_schedule() {
+        let config = DiscoveryConfig::new(Duration::from_secs(u64::MAX), None);
+        assert_eq!(config.record_ttl, Duration::MAX);
+        assert!(config.validate().unwrap_err().contains("too long"));
+
+        let config = DiscoveryConfig::new(Duration::from_secs(u64::MAX / 2), Some(Duration::MAX));
+        assert!(config.validate().unwrap_err().contains("too long"));
+    }
+
+    #[test]
+    fn next_due_saturates() {
+        let now = Instant::now();
+        let peer = PeerId::random();
+        let mut announcements = Announcements::default();
+        assert_eq!(announcements.next_due(Duration::from_secs(60), now), None);
+
+        announcements.record(peer, now);
+        assert_eq!(
+            announcements.next_due(Duration::MAX, now + Duration::from_secs(1)),
+            Some(Duration::MAX - Duration::from_secs(1))
+        );
+        assert_eq!(
+            announcements.next_due(Duration::from_secs(60), now + Duration::from_secs(90)),
+            Some(Duration::ZERO)
+        );
+    }
+}
@@ -0,0 +1,366 @@
+//! Estimating what a prompt will cost before sending it.
+//!
+//! A gateway choosing between workers wants to know how long each would take.
+//! A worker answers an [`EstimateRequest`] from the figures it already keeps
+//! for the status report, the recent tokens per second of each model, and from
+//! its queue, without running anything. An estimate is only as good as those
+//! figures, which its [`Confidence`] reflects.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{PromptResponse, ResponseStatus, perf::PerfSummary};
+
+/// Characters per token assumed when only the prompt text is given; typical
+/// for English.
+const CHARS_PER_TOKEN: u64 = 4;
+
+/// Recent requests to a model needed for [`Confidence::Medium`] and
+/// [`Confidence::High`].
+const MEDIUM_CONFIDENCE_REQUESTS: u64 = 5;
+const HIGH_CONFIDENCE_REQUESTS: u64 = 30;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EstimateRequest {
+    /// Defaults to the model a prompt without one would get.
+    #[serde(default)]
+    pub model: Option<String>,
+    /// Length of the prompt. Takes precedence over `prompt`.
+    #[serde(default)]
+    pub prompt_tokens: Option<u64>,
+    /// The prompt itself, when its length in tokens isn't known.
+    #[serde(default)]
+    pub prompt: Option<String>,
+    /// Length of the answer. Defaults to the model's recent average.
+    #[serde(default)]
+    pub max_tokens: Option<u64>,
+}
+
+impl EstimateRequest {
+    /// The prompt length in tokens, as given or guessed from the text.
+    pub fn prompt_tokens(&self) -> u64 {
+        self.prompt_tokens
+            .unwrap_or_else(|| self.prompt.as_deref().map_or(0, tokens_in))
+    }
+}
+
+/// A guess at the length of `text` in tokens.
+pub fn tokens_in(text: &str) -> u64 {
+    (text.chars().count() as u64).div_ceil(CHARS_PER_TOKEN)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Confidence {
+    /// The node has no recent figures for the model, so it can't say.
+    None,
+    /// Few recent requests, or the model isn't loaded and would have to be.
+    Low,
+    Medium,
+    High,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EstimateResponse {
+    pub model: String,
+    #[serde(default)]
+    pub status: ResponseStatus,
+    /// Why there's no estimate, when `status` isn't `Ok`.
+    #[serde(default)]
+    pub error: Option<String>,
+    /// The prompt length the estimate assumes.
+    pub prompt_tokens: u64,
+    /// The answer length the estimate assumes.
+    pub completion_tokens: u64,
+    /// Time queued before the backend is called.
+    pub queue_wait_ms: Option<u64>,
+    /// Time the backend takes once called, not counting loading the model.
+    pub generation_ms: Option<u64>,
+    pub confidence: Confidence,
+}
+
+impl EstimateResponse {
+    /// No estimate, for the reason a prompt would have been refused.
+    pub fn refused(model: String, refusal: PromptResponse) -> Self {
+        Self {
+            model,
+            status: refusal.status,
+            error: Some(refusal.response),
+            prompt_tokens: 0,
+            completion_tokens: 0,
+            queue_wait_ms: None,
+            generation_ms: None,
+            confidence: Confidence::None,
+        }
+    }
+}
+
+/// The node's queue when the estimate is made.
+#[derive(Debug, Clone, Copy)]
+pub struct QueueState {
+    pub queued: usize,
+    pub running: usize,
+    pub max_concurrent: usize,
+    /// Whether the model is the one most recently run, and so likely loaded.
+    pub loaded: bool,
+    /// How long a request has recently taken on average, across models.
+    pub avg_latency: Option<Duration>,
+}
+
+/// Estimates `request` on `model` from the model's recent figures and the
+/// queue.
+///
+/// The queue is assumed to drain at `max_concurrent` requests per average
+/// latency; per-model limits and model swaps along the way are ignored.
+pub fn estimate(
+    model: String,
+    request: &EstimateRequest,
+    perf: Option<&PerfSummary>,
+    queue: QueueState,
+) -> EstimateResponse {
+    let prompt_tokens = request.prompt_tokens();
+    let completion_tokens = request
+        .max_tokens
+        .unwrap_or_else(|| perf.map_or(0, |p| p.avg_completion_tokens.round() as u64));
+    let generation = perf.filter(|p| p.generation_tokens_per_sec > 0.0).map(|p| {
+        // Prompts served from Ollama's cache report no prompt rate.
+        let prompt_secs = if p.prompt_tokens_per_sec > 0.0 {
+            prompt_tokens as f64 / p.prompt_tokens_per_sec
+        } else {
+            0.0
+        };
+        prompt_secs + completion_tokens as f64 / p.generation_tokens_per_sec
+    });
+    let queue_wait = if queue.queued == 0 && queue.running < queue.max_concurrent {
+        Some(Duration::ZERO)
+    } else {
+        // Jobs that have to finish before this one gets a slot.
+        let ahead = (queue.queued + queue.running + 1).saturating_sub(queue.max_concurrent);
+        queue
+            .avg_latency
+            .map(|latency| latency.mul_f64(ahead as f64 / queue.max_concurrent.max(1) as f64))
+    };
+    let requests = perf.map_or(0, |p| p.requests);
+    let confidence = if generation.is_none() {
+        Confidence::None
+    } else if requests < MEDIUM_CONFIDENCE_REQUESTS || !queue.loaded || queue_wait.is_none() {
+        Confidence::Low
+    } else if requests < HIGH_CONFIDENCE_REQUESTS {
+        Confidence::Medium
+    } else {
+        Confidence::High
+    };
+    EstimateResponse {
+        model,
+        status: ResponseStatus::Ok,
+        error: None,
+        prompt_tokens,
+        completion_tokens,
+        queue_wait_ms: queue_wait.map(|wait| wait.as_millis() as u64),
+        generation_ms: generation.map(|secs| (secs * 1000.0) as u64),
+        confidence,
+    }
+}
+
+/// The average latency of recent requests across `summaries`, weighted by
+/// how many each model served.
+pub fn average_latency<'a>(
+    summaries: impl IntoIterator<Item = &'a PerfSummary>,
+) -> Option<Duration> {
+    let (requests, total) =
+        summaries
+            .into_iter()
+            .fold((0, Duration::ZERO), |(requests, total), summary| {
+                (
+                    requests + summary.requests,
+                    total + summary.avg_latency.mul_f64(summary.requests as f64),
+                )
+            });
+    (requests > 0).then(|| total.div_f64(requests as f64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(prompt_tokens: u64, max_tokens: u64) -> EstimateRequest {
+        EstimateRequest {
+            model: None,
+            prompt_tokens: Some(prompt_tokens),
+            prompt: None,
+            max_tokens: Some(max_tokens),
+        }
+    }
+
+    /// A model that read prompts at 100 tokens a second and wrote at 10.
+    fn perf(requests: u64) -> PerfSummary {
+        PerfSummary {
+            requests,
+            generation_tokens_per_sec: 10.0,
+            prompt_tokens_per_sec: 100.0,
+            avg_latency: Duration::from_secs(2),
+            avg_completion_tokens: 20.0,
+            ..PerfSummary::default()
+        }
+    }
+
+    fn queue(queued: usize, running: usize, max_concurrent: usize) -> QueueState {
+        QueueState {
+            queued,
+            running,
+            max_concurrent,
+            loaded: true,
+            avg_latency: Some(Duration::from_secs(4)),
+        }
+    }
+
+    #[test]
+    fn an_idle_queue_means_no_wait() {
+        let estimate = estimate(
+            "m".to_string(),
+            &request(100, 10),
+            Some(&perf(50)),
+            queue(0, 1, 2),
+        );
+        assert_eq!(estimate.status, ResponseStatus::Ok);
+        assert_eq!(estimate.queue_wait_ms, Some(0));
+        // One second reading the prompt, one writing the answer.
+        assert_eq!(estimate.generation_ms, Some(2000));
+        assert_eq!(estimate.confidence, Confidence::High);
+    }
+
+    #[test]
+    fn waits_for_the_jobs_ahead_to_free_a_slot() {
+        let wait = |queued, running, max_concurrent| {
+            estimate(
+                "m".to_string(),
+                &request(0, 0),
+                Some(&perf(50)),
+                queue(queued, running, max_concurrent),
+            )
+            .queue_wait_ms
+        };
+        // Full but nothing queued: one job has to finish, a quarter of the
+        // four-second latency with four slots.
+        assert_eq!(wait(0, 4, 4), Some(1000));
+        // Three queued behind two running, one slot: five jobs ahead.
+        assert_eq!(wait(3, 2, 1), Some(20_000));
+        // Without a latency to go by the wait is unknown.
+        let unknown = estimate(
+            "m".to_string(),
+            &request(0, 0),
+            Some(&perf(50)),
+            QueueState {
+                avg_latency: None,
+                ..queue(1, 1, 1)
+            },
+        );
+        assert_eq!(unknown.queue_wait_ms, None);
+        assert_eq!(unknown.confidence, Confidence::Low);
+    }
+
+    #[test]
+    fn no_figures_means_no_confidence() {
+        let unknown = estimate("m".to_string(), &request(10, 10), None, queue(0, 0, 1));
+        assert_eq!(unknown.generation_ms, None);
+        assert_eq!(unknown.confidence, Confidence::None);
+        let no_rate = PerfSummary {
+            generation_tokens_per_sec: 0.0,
+            ..perf(50)
+        };
+        let unknown = estimate(
+            "m".to_string(),
+            &request(10, 10),
+            Some(&no_rate),
+            queue(0, 0, 1),
+        );
+        assert_eq!(unknown.generation_ms, None);
+        assert_eq!(unknown.confidence, Confidence::None);
+    }
+
+    #[test]
+    fn confidence_grows_with_recent_requests() {
+        let confidence = |requests, loaded| {
+            estimate(
+                "m".to_string(),
+                &request(10, 10),
+                Some(&perf(requests)),
+                QueueState {
+                    loaded,
+                    ..queue(0, 0, 1)
+                },
+            )
+            .confidence
+        };
+        assert_eq!(
+            confidence(MEDIUM_CONFIDENCE_REQUESTS - 1, true),
+            Confidence::Low
+        );
+        assert_eq!(
+            confidence(MEDIUM_CONFIDENCE_REQUESTS, true),
+            Confidence::Medium
+        );
+        assert_eq!(
+            confidence(HIGH_CONFIDENCE_REQUESTS - 1, true),
+            Confidence::Medium
+        );
+        assert_eq!(confidence(HIGH_CONFIDENCE_REQUESTS, true), Confidence::High);
+        // A model that would have to be loaded first can't be judged well.
+        assert_eq!(confidence(HIGH_CONFIDENCE_REQUESTS, false), Confidence::Low);
+    }
+
+    #[test]
+    fn a_missing_answer_length_uses_the_recent_average() {
+        let estimate = estimate(
+            "m".to_string(),
+            &EstimateRequest {
+                max_tokens: None,
+                ..request(0, 0)
+            },
+            Some(&perf(50)),
+            queue(0, 0, 1),
+        );
+        assert_eq!(estimate.completion_tokens, 20);
+        assert_eq!(estimate.generation_ms, Some(2000));
+    }
+
+    #[test]
+    fn given_prompt_tokens_win_over_the_text() {
+        let both = EstimateRequest {
+            prompt: Some("x".repeat(400)),
+            ..request(7, 0)
+        };
+        assert_eq!(both.prompt_tokens(), 7);
+        let text = EstimateRequest {
+            prompt_tokens: None,
+            ..both
+        };
+        assert_eq!(text.prompt_tokens(), 100);
+        assert_eq!(tokens_in("abcde"), 2);
+        assert_eq!(tokens_in(""), 0);
+        let neither = EstimateRequest {
+            prompt: None,
+            ..text
+        };
+        assert_eq!(neither.prompt_tokens(), 0);
+    }
+
+    #[test]
+    fn average_latency_is_weighted_by_requests() {
+        let summaries = [
+            PerfSummary {
+                requests: 3,
+                avg_latency: Duration::from_secs(1),
+                ..PerfSummary::default()
+            },
+            PerfSummary {
+                requests: 1,
+                avg_latency: Duration::from_secs(5),
+                ..PerfSummary::default()
+            },
+        ];
+        assert_eq!(average_latency(&summaries), Some(Duration::from_secs(2)));
+        assert_eq!(average_latency(&[PerfSummary::default()]), None);
+        assert_eq!(average_latency(std::iter::empty()), None);
+    }
+}
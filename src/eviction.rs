@@ -0,0 +1,210 @@
+//! Making room for new connections once the node is at its limit.
+//!
+//! With `NodeConfig::max_incoming_connections` set, connections beyond the
+//! limit are refused. Left at that, clients that connected once and went
+//! quiet would keep newcomers out for as long as they stay connected. So
+//! whenever the limit refuses a connection, and periodically while a refused
+//! dialer may still be retrying, the swarm loop closes the least valuable
+//! inbound connection:
+//! one idle for at least [`MIN_IDLE`] whose peer has nothing in flight,
+//! the longest idle first. Pinned peers, such as the relay and schedulers,
+//! are never picked, nor are connections the node dialed itself.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use libp2p::{
+    PeerId,
+    request_response::{Event, Message},
+    swarm::ConnectionId,
+};
+
+/// How long a connection must have been idle before it may be closed to
+/// make room, so one that just answered isn't cut off mid-conversation.
+pub const MIN_IDLE: Duration = Duration::from_secs(10);
+
+/// How long after a connection is refused room is still made for it, as the
+/// dialer may retry. Nobody is closed for room past this.
+pub const DIALER_PATIENCE: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct Tracked {
+    peer: PeerId,
+    inbound: bool,
+}
+
+/// A connection chosen to be closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Eviction {
+    pub connection: ConnectionId,
+    pub peer: PeerId,
+    pub idle: Duration,
+}
+
+/// Which connections are open and when each peer last had work in flight.
+#[derive(Debug, Default)]
+pub struct ConnectionActivity {
+    connections: HashMap<ConnectionId, Tracked>,
+    /// When each connected peer last sent a request, got an answer or
+    /// opened the connection.
+    last_active: HashMap<PeerId, Instant>,
+    /// Requests and streams of each peer not yet answered.
+    in_flight: HashMap<PeerId, usize>,
+    /// When a connection was last refused for the limit, until room is
+    /// made for it.
+    refused_at: Option<Instant>,
+}
+
+impl ConnectionActivity {
+    pub fn opened(&mut self, connection: ConnectionId, peer: PeerId, inbound: bool, now: Instant) {
+        self.connections
+            .insert(connection, Tracked { peer, inbound });
+        self.last_active.insert(peer, now);
+    }
+
+    pub fn closed(&mut self, connection: ConnectionId) {
+        let Some(closed) = self.connections.remove(&connection) else {
+            return;
+        };
+        if !self.connections.values().any(|c| c.peer == closed.peer) {
+            self.last_active.remove(&closed.peer);
+            self.in_flight.remove(&closed.peer);
+        }
+    }
+
+    /// Follows a request-response event: requests put work in flight until
+    /// they are answered or fail.
+    pub fn observe<Req, Resp, ChannelResp>(
+        &mut self,
+        event: &Event<Req, Resp, ChannelResp>,
+        now: Instant,
+    ) {
+        let (peer, delta) = match event {
+            Event::Message {
+                peer,
+                message: Message::Request { .. },
+                ..
+            } => (peer, 1),
+            Event::ResponseSent { peer, .. } | Event::InboundFailure { peer, .. } => (peer, -1),
+            Event::Message { peer, .. } | Event::OutboundFailure { peer, .. } => (peer, 0),
+        };
+        self.track(*peer, delta, now);
+    }
+
+    /// A stream request from `peer` was accepted.
+    pub fn stream_started(&mut self, peer: PeerId, now: Instant) {
+        self.track(peer, 1, now);
+    }
+
+    /// A stream request from `peer` got its answer.
+    pub fn stream_ended(&mut self, peer: PeerId, now: Instant) {
+        self.track(peer, -1, now);
+    }
+
+    /// Whether `peer` has a request or stream in flight.
+    pub fn busy(&self, peer: &PeerId) -> bool {
+        self.in_flight.get(peer).is_some_and(|n| *n > 0)
+    }
+
+    /// A connection was refused for the limit.
+    pub fn refused(&mut self, now: Instant) {
+        self.refused_at = Some(now);
+    }
+
+    /// Whether a dialer refused within [`DIALER_PATIENCE`] is still owed
+    /// room.
+    pub fn room_wanted(&self, now: Instant) -> bool {
+        self.refused_at
+            .is_some_and(|at| now.saturating_duration_since(at) <= DIALER_PATIENCE)
+    }
+
+    /// A connection was closed to make room, which is what a refused dialer
+    /// was owed.
+    pub fn room_made(&mut self, connection: ConnectionId) {
+        self.refused_at = None;
+        self.closed(connection);
+    }
+
+    /// Open connections the node didn't dial.
+    pub fn inbound(&self) -> usize {
+        self.connections.values().filter(|c| c.inbound).count()
+    }
+
+    /// The connection to close to make room, if any may be: inbound, idle
+    /// for at least [`MIN_IDLE`], not `protected` and with nothing in
+    /// flight, the longest idle first.
+    pub fn candidate(&self, now: Instant, protected: impl Fn(&PeerId) -> bool) -> Option<Eviction> {
+        self.connections
+            .iter()
+            .filter(|(_, c)| c.inbound && !protected(&c.peer))
+            .filter(|(_, c)| !self.busy(&c.peer))
+            .filter_map(|(id, c)| {
+                let idle = now.saturating_duration_since(*self.last_active.get(&c.peer)?);
+                (idle >= MIN_IDLE).then_some(Eviction {
+                    connection: *id,
+                    peer: c.peer,
+                    idle,
+                })
+            })
+            .max_by_key(|eviction| eviction.idle)
+    }
+
+    fn track(&mut self, peer: PeerId, delta: isize, now: Instant) {
+        if !self.connections.values().any(|c| c.peer == peer) {
+            return;
+        }
+        self.last_active.insert(peer, now);
+        let in_flight = self.in_flight.entry(peer).or_default();
+        *in_flight = in_flight.saturating_add_signed(delta);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn room_is_only_made_for_a_refused_dialer() {
+        let start = Instant::now();
+        let mut activity = ConnectionActivity::default();
+        let idle = PeerId::random();
+        activity.opened(ConnectionId::new_unchecked(1), idle, true, start);
+        let later = start + MIN_IDLE;
+        assert!(activity.candidate(later, |_| false).is_some());
+        // Idle connections alone don't get anyone closed.
+        assert!(!activity.room_wanted(later));
+
+        activity.refused(later);
+        assert!(activity.room_wanted(later + DIALER_PATIENCE));
+        assert!(!activity.room_wanted(later + DIALER_PATIENCE + Duration::from_secs(1)));
+
+        let eviction = activity.candidate(later, |_| false).unwrap();
+        assert_eq!(eviction.peer, idle);
+        activity.room_made(eviction.connection);
+        assert!(!activity.room_wanted(later));
+        assert_eq!(activity.inbound(), 0);
+    }
+
+    #[test]
+    fn busy_protected_and_outbound_connections_are_kept() {
+        let start = Instant::now();
+        let later = start + MIN_IDLE;
+        let mut activity = ConnectionActivity::default();
+        let (busy, pinned, dialed) = (PeerId::random(), PeerId::random(), PeerId::random());
+        activity.opened(ConnectionId::new_unchecked(1), busy, true, start);
+        activity.opened(ConnectionId::new_unchecked(2), pinned, true, start);
+        activity.opened(ConnectionId::new_unchecked(3), dialed, false, start);
+        activity.stream_started(busy, start);
+        assert_eq!(activity.candidate(later, |peer| *peer == pinned), None);
+
+        // Not idle long enough once it finishes.
+        activity.stream_ended(busy, later);
+        assert_eq!(activity.candidate(later, |peer| *peer == pinned), None);
+        let eviction = activity
+            .candidate(later + MIN_IDLE, |peer| *peer == pinned)
+            .unwrap();
+        assert_eq!(eviction.peer, busy);
+    }
+}
@@ -0,0 +1,118 @@
+use clap::Parser;
+use libp2p::{Multiaddr, identity::Keypair};
+use mesh_ai_node::{
+    PromptRequest, ResponseStatus,
+    chat::{ChatHistory, Role},
+    client::{Client, ClientConfig, ClientError, target_peer},
+    client_info::ClientInfo,
+    node::{self, NodeConfig},
+    retry::idempotency_key,
+};
+use std::{
+    error::Error,
+    io::{self, BufRead, Write},
+    time::Duration,
+};
+use tracing_subscriber::EnvFilter;
+
+#[derive(Parser, Debug)]
+#[command(name = "chat")]
+struct Opt {
+    /// Addresses of the target node.
+    #[arg(required = true)]
+    target_addrs: Vec<Multiaddr>,
+
+    /// Model to chat with. Defaults to the node's.
+    #[arg(long)]
+    model: Option<String>,
+
+    /// Messages of earlier turns sent along with each new one, counting both
+    /// sides. The oldest are dropped first.
+    #[arg(long, default_value_t = 20)]
+    max_history: usize,
+
+    /// Seconds allowed for each answer.
+    #[arg(long, default_value_t = 300)]
+    response_timeout_secs: u64,
+}
+
+#[tokio::main]
+async fn main() {
+    if let Err(e) = run().await {
+        eprintln!("Error: {e}");
+        let code = e
+            .downcast_ref::<ClientError>()
+            .map_or(1, ClientError::exit_code);
+        std::process::exit(code);
+    }
+}
+
+async fn run() -> Result<(), Box<dyn Error>> {
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .try_init();
+
+    let opt = Opt::parse();
+    let client_config = ClientConfig {
+        response_timeout: Duration::from_secs(opt.response_timeout_secs),
+        client_info: Some(ClientInfo::cli()),
+        ..Default::default()
+    };
+    let config = NodeConfig {
+        idle_timeout: Duration::from_secs(u64::MAX),
+        request_timeout: client_config.response_timeout,
+        ..Default::default()
+    };
+    let swarm = node::build_swarm(Keypair::generate_ed25519(), &config)?;
+    let target_peer_id = target_peer(&opt.target_addrs[0])?;
+
+    let client = Client::new(swarm, client_config);
+    client.connect(target_peer_id, opt.target_addrs).await?;
+    let target = client
+        .profile(target_peer_id)
+        .await
+        .unwrap_or_default()
+        .label(target_peer_id);
+    println!("Connected to {target}. /reset clears the conversation, /quit leaves.");
+
+    let mut history = ChatHistory::new(opt.max_history);
+    let stdin = io::stdin();
+    loop {
+        print!("> ");
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let input = line.trim();
+        match input {
+            "" => continue,
+            "/quit" => return Ok(()),
+            "/reset" => {
+                history.reset();
+                println!("Conversation cleared.");
+                continue;
+            }
+            _ => {}
+        }
+
+        let request = PromptRequest {
+            model: opt.model.clone(),
+            idempotency_key: Some(idempotency_key()),
+            ..PromptRequest::new(history.prompt(input))
+        };
+        // A failed turn is reported and left out of the history; the
+        // conversation carries on.
+        match client.send_prompt(target_peer_id, request).await {
+            Ok(response) if response.is_fallback => println!("{}", response.response.trim()),
+            Ok(response) if response.status == ResponseStatus::Ok => {
+                let answer = response.response.trim().to_string();
+                println!("{answer}");
+                history.push(Role::User, input.to_string());
+                history.push(Role::Assistant, answer);
+            }
+            Ok(response) => eprintln!("{:?}: {}", response.status, response.response),
+            Err(e) => eprintln!("Error: {e}"),
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use clap::Parser;
+use libp2p::{Multiaddr, identity::Keypair};
+use mesh_ai_node::{
+    CompareRequest, ResponseStatus,
+    client::{Client, ClientConfig, ClientError, target_peer},
+    node::{self, NodeConfig},
+};
+use std::{error::Error, time::Duration};
+use tracing_subscriber::EnvFilter;
+
+#[derive(Parser, Debug)]
+#[command(name = "compare")]
+struct Opt {
+    /// Addresses of the target node.
+    #[arg(required = true)]
+    target_addrs: Vec<Multiaddr>,
+
+    #[arg(long)]
+    prompt: String,
+
+    /// The two models to compare, e.g. `llama3:8b,phi3:mini`.
+    #[arg(long, value_delimiter = ',', required = true)]
+    models: Vec<String>,
+
+    /// `json`, or a JSON schema, to constrain both outputs.
+    #[arg(long)]
+    format: Option<String>,
+
+    /// Seconds allowed for the response once the request is sent.
+    #[arg(long, default_value_t = 600)]
+    response_timeout_secs: u64,
+}
+
+#[tokio::main]
+async fn main() {
+    if let Err(e) = run().await {
+        eprintln!("Error: {e}");
+        let code = e
+            .downcast_ref::<ClientError>()
+            .map_or(1, ClientError::exit_code);
+        std::process::exit(code);
+    }
+}
+
+async fn run() -> Result<(), Box<dyn Error>> {
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .try_init();
+
+    let opt = Opt::parse();
+    let [first, second]: [String; 2] = opt
+        .models
+        .try_into()
+        .map_err(|_| "--models takes exactly two models")?;
+
+    let client_config = ClientConfig {
+        response_timeout: Duration::from_secs(opt.response_timeout_secs),
+        ..Default::default()
+    };
+    let config = NodeConfig {
+        idle_timeout: Duration::from_secs(u64::MAX),
+        request_timeout: client_config.response_timeout,
+        ..Default::default()
+    };
+    let swarm = node::build_swarm(Keypair::generate_ed25519(), &config)?;
+    let target_peer_id = target_peer(&opt.target_addrs[0])?;
+
+    let client = Client::new(swarm, client_config);
+    client.connect(target_peer_id, opt.target_addrs).await?;
+
+    let response = client
+        .compare(
+            target_peer_id,
+            CompareRequest {
+                prompt: opt.prompt,
+                models: [first, second],
+                format: opt.format,
+            },
+        )
+        .await?;
+    if response.status != ResponseStatus::Ok {
+        let reason = response.error.unwrap_or_default();
+        eprintln!("{target_peer_id} answered {:?}: {reason}", response.status);
+        if response.status == ResponseStatus::ModelNotAllowed {
+            eprintln!("Available models: {}", response.available_models.join(", "));
+        }
+        return Err(reason.into());
+    }
+    // One block per model, headed by a fixed-format line, so the two outputs
+    // can be split apart and fed to diff.
+    for outcome in response.outcomes {
+        println!(
+            "=== {} ({:?}, {} prompt + {} completion tokens, {} ms)",
+            outcome.model,
+            outcome.status,
+            outcome.prompt_tokens,
+            outcome.completion_tokens,
+            outcome.latency_ms
+        );
+        println!("{}", outcome.response);
+    }
+    Ok(())
+}
@@ -1,131 +1,429 @@
-use futures::prelude::*;
-use libp2p::{
-    Multiaddr, PeerId, StreamProtocol, dcutr, identify,
-    multiaddr::Protocol,
-    noise, ping, relay,
-    request_response::{self, ProtocolSupport},
-    swarm::{NetworkBehaviour, SwarmEvent},
-    tcp, upnp, yamux,
+use clap::Parser;
+use libp2p::{Multiaddr, identity::Keypair};
+use mesh_ai_node::{
+    PromptRequest, ResponseStatus,
+    client::{Client, ClientConfig, ClientError, load_images, target_peer},
+    client_info::ClientInfo,
+    dedup::CacheMode,
+    estimate::EstimateRequest,
+    feedback::{Feedback, FeedbackStatus},
+    identity,
+    node::{self, DnsResolver, NodeConfig},
+    retry::{RetryPolicy, idempotency_key},
+    tools::load_tools,
+    trace::TraceContext,
+};
+use std::{
+    error::Error,
+    fs,
+    io::{self, BufRead, Write},
+    num::NonZeroU64,
+    path::PathBuf,
+    time::{Duration, Instant},
 };
-use mesh_ai_node::{PromptRequest, PromptResponse};
-use std::{error::Error, time::Duration};
 use tracing_subscriber::EnvFilter;
 
-#[derive(NetworkBehaviour)]
-struct MyBehaviour {
-    ping: ping::Behaviour,
-    request_response: request_response::cbor::Behaviour<PromptRequest, PromptResponse>,
-    relay: relay::client::Behaviour,
-    identify: identify::Behaviour,
-    dcutr: dcutr::Behaviour,
-    upnp: upnp::tokio::Behaviour,
+#[derive(Parser, Debug)]
+#[command(name = "ping")]
+struct Opt {
+    /// Addresses of the target node. Several addresses of the same peer may be
+    /// given; they are dialed in parallel and the first to connect wins.
+    #[arg(required = true)]
+    target_addrs: Vec<Multiaddr>,
+
+    /// Seconds allowed for connecting to the target.
+    #[arg(long, default_value_t = 10)]
+    connect_timeout_secs: u64,
+
+    /// Seconds allowed for the target to confirm it speaks the protocol.
+    #[arg(long, default_value_t = 10)]
+    protocol_timeout_secs: u64,
+
+    /// Seconds allowed for the response once the prompt is sent.
+    #[arg(long, default_value_t = 300)]
+    response_timeout_secs: u64,
+
+    /// Times to retry the prompt after a transport failure or a Busy or
+    /// Overloaded answer.
+    #[arg(long, default_value_t = 0)]
+    retries: u32,
+
+    /// Seconds allowed for the prompt across all retries.
+    #[arg(long, default_value_t = 600)]
+    retry_budget_secs: u64,
+
+    /// Where to resolve hostnames in `/dns4` and `/dnsaddr` target addresses.
+    #[arg(long, value_enum, default_value_t = DnsResolver::System)]
+    dns_resolver: DnsResolver,
+
+    /// After the answer arrives, ask for a rating and send it to the worker.
+    #[arg(long)]
+    feedback: bool,
+
+    /// Print the answer as it is generated, over the streaming protocol.
+    /// Retries don't apply.
+    #[arg(long)]
+    stream: bool,
+
+    /// Let the node cut the prompt if it doesn't fit the model's context
+    /// window, rather than refuse it.
+    #[arg(long)]
+    allow_truncate: bool,
+
+    /// Milliseconds the node may spend generating the answer before giving
+    /// up, up to its own maximum.
+    #[arg(long)]
+    max_duration_ms: Option<NonZeroU64>,
+
+    /// Ask the node for an estimate first, and compare it with how long the
+    /// answer took.
+    #[arg(long)]
+    estimate: bool,
+
+    /// Application name the node logs the request under. Defaults to this
+    /// tool's own.
+    #[arg(long)]
+    app_name: Option<String>,
+
+    /// Version of `--app-name`.
+    #[arg(long, default_value = "", requires = "app_name")]
+    app_version: String,
+
+    /// API key to send, so the node counts the prompt against the key's
+    /// team.
+    #[arg(long)]
+    api_key: Option<String>,
+
+    /// API key to try if the node refuses --api-key, while keys are being
+    /// rotated.
+    #[arg(long, requires = "api_key")]
+    fallback_api_key: Option<String>,
+
+    /// Where to save the answer if it isn't text, e.g. an image. Without
+    /// it, only its type and size are printed.
+    #[arg(long)]
+    output: Option<PathBuf>,
+
+    /// Image file to send with the prompt, for vision models. May be given
+    /// more than once.
+    #[arg(long = "image")]
+    images: Vec<PathBuf>,
+
+    /// Send the prompt in a new trace and print its trace id, for finding
+    /// the request in the node's logs.
+    #[arg(long)]
+    trace: bool,
+
+    /// Have the node put the prompt in the model's chat template.
+    #[arg(long)]
+    apply_template: bool,
+
+    /// System prompt for the chat template.
+    #[arg(long, requires = "apply_template")]
+    system: Option<String>,
+
+    /// JSON file of tools the model may call, as an array of objects with
+    /// `name`, `description` and a `parameters` schema.
+    #[arg(long)]
+    tools: Option<PathBuf>,
+
+    /// File holding this client's identity, created if missing. The node
+    /// caches answers per peer, so a later ping needs the same identity to
+    /// get them. Defaults to a fresh identity.
+    #[arg(long)]
+    key_file: Option<PathBuf>,
+
+    /// Idempotency key to send, e.g. to get the node's cached answer to an
+    /// earlier ping. Defaults to a fresh one.
+    #[arg(long)]
+    idempotency_key: Option<String>,
+
+    /// How the node uses its cached answer for the idempotency key.
+    #[arg(long, value_enum, default_value_t = CacheMode::Prefer)]
+    cache: CacheMode,
+
+    /// Milliseconds the node keeps the answer for retries, up to its own
+    /// maximum.
+    #[arg(long)]
+    cache_ttl_ms: Option<u64>,
+
+    /// Context window, in tokens, to run the model with.
+    #[arg(long)]
+    num_ctx: Option<u64>,
+
+    /// How soon the node should run the prompt when others are queued,
+    /// higher first, up to what the API key allows.
+    #[arg(long, default_value_t = 0)]
+    priority: u8,
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
+async fn main() {
+    if let Err(e) = run().await {
+        eprintln!("Error: {e}");
+        let code = e
+            .downcast_ref::<ClientError>()
+            .map_or(1, ClientError::exit_code);
+        std::process::exit(code);
+    }
+}
+
+async fn run() -> Result<(), Box<dyn Error>> {
     let _ = tracing_subscriber::fmt()
         .with_env_filter(EnvFilter::from_default_env())
         .try_init();
 
-    let mut swarm = libp2p::SwarmBuilder::with_new_identity()
-        .with_tokio()
-        .with_tcp(
-            tcp::Config::default(),
-            noise::Config::new,
-            yamux::Config::default,
-        )?
-        .with_relay_client(noise::Config::new, yamux::Config::default)?
-        .with_behaviour(|key, relay_behaviour| MyBehaviour {
-            ping: ping::Behaviour::default(),
-            request_response: request_response::cbor::Behaviour::new(
-                [(StreamProtocol::new("/mesh-ai/1.0.0"), ProtocolSupport::Full)],
-                request_response::Config::default(),
-            ),
-            relay: relay_behaviour,
-            identify: identify::Behaviour::new(identify::Config::new(
-                "/mesh-ai/1.0.0".to_string(),
-                key.public(),
-            )),
-            dcutr: dcutr::Behaviour::new(key.public().to_peer_id()),
-            upnp: upnp::tokio::Behaviour::default(),
-        })?
-        .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(Duration::from_secs(u64::MAX)))
-        .build();
-
-    // Dial the peer identified by the multi-address given as the second
-    // command-line argument.
-    let target_addr: Multiaddr = std::env::args()
-        .nth(1)
-        .ok_or("Expected multiaddr as argument")?
-        .parse()?;
-
-    // Extract the target peer ID (the last P2p component in the address)
-    let target_peer_id: PeerId = target_addr
-        .iter()
-        .filter_map(|p| {
-            if let Protocol::P2p(id) = p {
-                Some(id)
-            } else {
-                None
+    let opt = Opt::parse();
+    let client_config = ClientConfig {
+        connect_timeout: Duration::from_secs(opt.connect_timeout_secs),
+        protocol_timeout: Duration::from_secs(opt.protocol_timeout_secs),
+        response_timeout: Duration::from_secs(opt.response_timeout_secs),
+        client_info: match &opt.app_name {
+            Some(name) => {
+                Some(ClientInfo::new(name, &opt.app_version).ok_or("--app-name can't be empty")?)
             }
-        })
-        .last()
-        .ok_or("No peer ID in target address")?;
+            None => Some(ClientInfo::cli()),
+        },
+        api_key: opt.api_key.clone(),
+        fallback_api_key: opt.fallback_api_key.clone(),
+        ..Default::default()
+    };
+
+    let config = NodeConfig {
+        idle_timeout: Duration::from_secs(u64::MAX),
+        request_timeout: client_config.response_timeout,
+        dns_resolver: opt.dns_resolver,
+        ..Default::default()
+    };
+    let keypair = match &opt.key_file {
+        Some(path) => identity::load_or_generate(path, None)?,
+        None => Keypair::generate_ed25519(),
+    };
+    let mut swarm = node::build_swarm(keypair, &config)?;
+    let target_addrs = opt.target_addrs;
+
+    // The peer after `/p2p-circuit` in a relayed address, so only a
+    // connection to it, and not the one to the relay, counts as connected.
+    let target_peer_id = target_peer(&target_addrs[0])?;
+    for addr in &target_addrs[1..] {
+        if target_peer(addr)? != target_peer_id {
+            return Err(
+                format!("{addr} leads to a different peer than {}", target_addrs[0]).into(),
+            );
+        }
+    }
 
     println!("Target peer ID: {target_peer_id}");
 
     // Listen on a direct TCP port for DCUTR hole-punching
-    swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
+    for addr in config.default_listen_addrs() {
+        swarm.listen_on(addr)?;
+    }
+
+    let client = Client::new(swarm, client_config);
+    println!(
+        "Dialing {target_peer_id} at {} address(es)",
+        target_addrs.len()
+    );
+    let phase = Instant::now();
+    client
+        .dial(target_peer_id, target_addrs.clone())
+        .await
+        .inspect_err(|e| eprintln!("Connect phase failed after {:?}: {e}", phase.elapsed()))?;
+    println!("✅ Connected to {target_peer_id} in {:?}", phase.elapsed());
 
-    swarm.dial(target_addr.clone())?;
-    println!("Dialed {target_addr}");
+    let phase = Instant::now();
+    client
+        .confirm_protocol(target_peer_id)
+        .await
+        .inspect_err(|e| eprintln!("Protocol phase failed after {:?}: {e}", phase.elapsed()))?;
+    if let Some(profile) = client.profile(target_peer_id).await
+        && !profile.is_empty()
+    {
+        println!(
+            "Target is {} (operator: {})",
+            profile.label(target_peer_id),
+            profile.operator_contact.as_deref().unwrap_or("not given")
+        );
+    }
+    if let Some(labels) = client.labels(target_peer_id).await
+        && !labels.is_empty()
+    {
+        println!("Target labels: {labels}");
+    }
 
-    let mut prompt_sent = false;
+    let prompt = "whats 1 + 1".to_string();
+    let estimate = if opt.estimate {
+        let estimate = client
+            .estimate(
+                target_peer_id,
+                EstimateRequest {
+                    model: None,
+                    prompt_tokens: None,
+                    prompt: Some(prompt.clone()),
+                    max_tokens: None,
+                },
+            )
+            .await?;
+        if estimate.status != ResponseStatus::Ok {
+            return Err(format!(
+                "{target_peer_id} refused the estimate: {}",
+                estimate.error.unwrap_or_default()
+            )
+            .into());
+        }
+        let ms = |ms: Option<u64>| ms.map_or("unknown".to_string(), |ms| format!("{ms}ms"));
+        println!(
+            "Estimate for {}: {} queued, {} generating ~{} tokens (confidence {:?})",
+            estimate.model,
+            ms(estimate.queue_wait_ms),
+            ms(estimate.generation_ms),
+            estimate.completion_tokens,
+            estimate.confidence
+        );
+        estimate.queue_wait_ms.zip(estimate.generation_ms)
+    } else {
+        None
+    };
+    println!("Sending prompt to {target_peer_id}: {prompt}");
+    let phase = Instant::now();
+    let retry_policy = RetryPolicy {
+        max_retries: opt.retries,
+        budget: Duration::from_secs(opt.retry_budget_secs),
+        ..Default::default()
+    };
+    let request_id = opt.idempotency_key.clone().unwrap_or_else(idempotency_key);
+    let trace = opt.trace.then(TraceContext::new_root);
+    if let Some(trace) = &trace {
+        eprintln!(
+            "Trace {}, span {}",
+            trace.trace_id_hex(),
+            trace.span_id_hex()
+        );
+    }
+    let request = PromptRequest {
+        idempotency_key: Some(request_id.clone()),
+        allow_truncate: opt.allow_truncate,
+        max_duration_ms: opt.max_duration_ms.map(NonZeroU64::get),
+        trace_context: trace.map(|trace| trace.to_string()),
+        images: if opt.images.is_empty() {
+            None
+        } else {
+            Some(load_images(&opt.images)?)
+        },
+        apply_template: opt.apply_template,
+        system: opt.system.clone(),
+        tools: opt.tools.as_deref().map(load_tools).transpose()?,
+        cache: opt.cache,
+        cache_ttl_ms: opt.cache_ttl_ms,
+        num_ctx: opt.num_ctx,
+        priority: opt.priority,
+        ..PromptRequest::new(prompt)
+    };
+    let response = if opt.stream {
+        let response = client
+            .prompt_stream(target_peer_id, request, |chunk| {
+                print!("{chunk}");
+                let _ = io::stdout().flush();
+            })
+            .await;
+        println!();
+        response
+    } else {
+        client
+            .send_prompt_with_retry(target_peer_id, target_addrs, request, &retry_policy)
+            .await
+    }
+    .inspect_err(|e| eprintln!("Response phase failed after {:?}: {e}", phase.elapsed()))?;
 
-    loop {
-        match swarm.select_next_some().await {
-            SwarmEvent::ConnectionEstablished {
-                peer_id, endpoint, ..
-            } => {
+    if response.status != ResponseStatus::Ok {
+        eprintln!(
+            "{target_peer_id} answered {:?}: {}",
+            response.status, response.response
+        );
+        if response.status == ResponseStatus::ModelNotAllowed {
+            eprintln!("Available models: {}", response.available_models.join(", "));
+        }
+        return Err(response.response.into());
+    }
+    println!(
+        "Received response from {target_peer_id}: {}",
+        response.response
+    );
+    if let Some(binary) = &response.binary {
+        match &opt.output {
+            Some(path) => {
+                fs::write(path, &binary.data)?;
                 println!(
-                    "✅ Connection established with {peer_id} via {}",
-                    endpoint.get_remote_address()
+                    "Saved {} bytes of {} to {}",
+                    binary.data.len(),
+                    binary.mime_type,
+                    path.display()
                 );
-                // Only send request when connected to the TARGET peer, not the relay
-                if peer_id == target_peer_id && !prompt_sent {
-                    let prompt = "whats 1 + 1".to_string();
-                    println!("Sending prompt to {peer_id}: {prompt}");
-                    swarm
-                        .behaviour_mut()
-                        .request_response
-                        .send_request(&peer_id, PromptRequest { prompt });
-                    prompt_sent = true;
-                }
-            }
-            SwarmEvent::Behaviour(MyBehaviourEvent::RequestResponse(
-                request_response::Event::Message {
-                    peer,
-                    message: request_response::Message::Response { response, .. },
-                    ..
-                },
-            )) => {
-                println!("Received response from {peer}: {}", response.response);
-                return Ok(());
             }
-            SwarmEvent::Behaviour(MyBehaviourEvent::Ping(event)) => {
-                println!("Ping event: {event:?}");
-            }
-            SwarmEvent::Behaviour(MyBehaviourEvent::Dcutr(event)) => {
-                println!("🔄 DCUTR event: {event:?}");
-            }
-            SwarmEvent::Behaviour(MyBehaviourEvent::Upnp(event)) => {
-                println!("🔌 UPnP event: {event:?}");
-            }
-            SwarmEvent::OutgoingConnectionError { error, .. } => {
-                eprintln!("Connection error: {error}");
-                return Err(error.into());
-            }
-            _ => {}
+            None => println!(
+                "(The answer includes {} bytes of {}; pass --output to save them)",
+                binary.data.len(),
+                binary.mime_type
+            ),
+        }
+    }
+    if response.truncated {
+        println!("(The prompt was truncated to fit the model's context window)");
+    }
+    if response.is_fallback {
+        println!("(The node's backend failed; this is its fallback response)");
+    }
+    for call in &response.tool_calls {
+        println!("Tool call: {}({})", call.name, call.arguments);
+    }
+    if let Some(template) = &response.template {
+        println!("(The prompt was put in the {template} template)");
+    }
+    if let Some(timing) = response.timing {
+        println!(
+            "Queued {}ms, generated in {}ms",
+            timing.queue_wait_ms, timing.inference_ms
+        );
+    }
+    if let Some((queue_wait_ms, generation_ms)) = estimate {
+        println!(
+            "Estimated {}ms, took {}ms",
+            queue_wait_ms + generation_ms,
+            phase.elapsed().as_millis()
+        );
+    }
+
+    if opt.feedback
+        && let Some(feedback) = ask_feedback(request_id)?
+    {
+        let ack = client.send_feedback(target_peer_id, feedback).await?;
+        match ack.status {
+            FeedbackStatus::Accepted => println!("Feedback sent"),
+            status => eprintln!("Feedback rejected: {status:?}"),
         }
     }
+    Ok(())
+}
+
+/// Reads a rating from stdin: `+`, `-` or `0`, optionally followed by a
+/// comment. An empty line skips.
+fn ask_feedback(request_id: String) -> io::Result<Option<Feedback>> {
+    print!("Rate this answer (+, 0 or -, then an optional comment; empty to skip): ");
+    io::stdout().flush()?;
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line)?;
+    let line = line.trim();
+    let rating = match line.chars().next() {
+        Some('+') => 1,
+        Some('0') => 0,
+        Some('-') => -1,
+        _ => return Ok(None),
+    };
+    let comment = line[1..].trim();
+    Ok(Some(Feedback {
+        request_id,
+        rating,
+        comment: (!comment.is_empty()).then(|| comment.to_string()),
+    }))
 }
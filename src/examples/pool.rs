@@ -0,0 +1,180 @@
+use clap::Parser;
+use libp2p::identity::Keypair;
+use mesh_ai_node::{
+    PromptRequest, ResponseStatus,
+    client::{Client, ClientConfig, ClientError},
+    client_info::ClientInfo,
+    labels::{Label, LabelFilter},
+    node::{self, NodeConfig},
+    pool::{DEFAULT_DIAL_CONCURRENCY, PeerPool, Strategy},
+    workers::{HealthConfig, WorkerList},
+};
+use std::{collections::BTreeMap, error::Error, num::NonZeroUsize, path::PathBuf, time::Duration};
+use tokio::signal::unix::{SignalKind, signal};
+use tracing_subscriber::EnvFilter;
+
+/// Sends a prompt to one of a list of workers, chosen by label and session.
+#[derive(Parser, Debug)]
+#[command(name = "pool")]
+struct Opt {
+    /// File listing the workers, one multiaddr ending in `/p2p/<PeerId>` per
+    /// line. Re-read on SIGHUP with `--watch`.
+    #[arg(long)]
+    workers: PathBuf,
+
+    /// After answering, keep checking the workers and print their health as
+    /// it changes, until interrupted.
+    #[arg(long)]
+    watch: bool,
+
+    #[arg(long)]
+    prompt: String,
+
+    /// Requests with the same session go to the same worker while it is up.
+    /// Without one, `--strategy` picks the worker for each request.
+    #[arg(long)]
+    session: Option<String>,
+
+    /// How to pick a worker for requests outside a session.
+    #[arg(long, value_enum, default_value_t = Strategy::default())]
+    strategy: Strategy,
+
+    /// Times to send the prompt, one after another, to see how requests are
+    /// spread.
+    #[arg(long, default_value_t = 1)]
+    repeat: usize,
+
+    /// Only use workers with this label, e.g. `region=eu-west`. Repeatable;
+    /// all must match.
+    #[arg(long = "require-label")]
+    require_labels: Vec<Label>,
+
+    /// Prefer workers with this label, e.g. `tier=gpu`. Repeatable; workers
+    /// matching more are preferred.
+    #[arg(long = "prefer-label")]
+    prefer_labels: Vec<Label>,
+
+    /// Seconds allowed for the response once the prompt is sent.
+    #[arg(long, default_value_t = 300)]
+    response_timeout_secs: u64,
+
+    /// Workers dialed at once when checking the list; the rest wait their
+    /// turn, so a long list is dialed in waves.
+    #[arg(long, default_value_t = NonZeroUsize::new(DEFAULT_DIAL_CONCURRENCY).unwrap())]
+    dial_concurrency: NonZeroUsize,
+}
+
+#[tokio::main]
+async fn main() {
+    if let Err(e) = run().await {
+        eprintln!("Error: {e}");
+        let code = e
+            .downcast_ref::<ClientError>()
+            .map_or(1, ClientError::exit_code);
+        std::process::exit(code);
+    }
+}
+
+async fn run() -> Result<(), Box<dyn Error>> {
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .try_init();
+
+    let opt = Opt::parse();
+    let client_config = ClientConfig {
+        response_timeout: Duration::from_secs(opt.response_timeout_secs),
+        client_info: Some(ClientInfo::cli()),
+        ..Default::default()
+    };
+    let config = NodeConfig {
+        idle_timeout: Duration::from_secs(u64::MAX),
+        request_timeout: client_config.response_timeout,
+        ..Default::default()
+    };
+    let swarm = node::build_swarm(Keypair::generate_ed25519(), &config)?;
+    let client = Client::new(swarm, client_config);
+
+    let health = HealthConfig::default();
+    let mut workers = WorkerList::load(&opt.workers, health.clone())?;
+    let pool = PeerPool::new(client, [])
+        .with_label_filter(LabelFilter {
+            require: opt.require_labels,
+            prefer: opt.prefer_labels,
+        })
+        .with_strategy(opt.strategy)
+        .with_dial_concurrency(opt.dial_concurrency);
+    pool.check_workers(&mut workers).await;
+    for worker in workers.statuses() {
+        let labels = pool
+            .labels(&worker.peer)
+            .map_or("unknown".to_string(), |labels| labels.to_string());
+        println!("{} is {} (labels: {labels})", worker.peer, worker.health);
+    }
+
+    let request = PromptRequest::new(opt.prompt);
+    let mut answered = BTreeMap::new();
+    for _ in 0..opt.repeat {
+        let (peer, response) = match &opt.session {
+            Some(session) => {
+                pool.send_prompt_with_session(session, request.clone())
+                    .await?
+            }
+            None => pool.send_prompt(request.clone()).await?,
+        };
+        if response.status != ResponseStatus::Ok {
+            return Err(format!(
+                "{peer} answered {:?}: {}",
+                response.status, response.response
+            )
+            .into());
+        }
+        println!("{peer} answered: {}", response.response);
+        *answered.entry(peer).or_insert(0) += 1;
+    }
+    if opt.repeat > 1 {
+        for (peer, count) in answered {
+            let latency = pool
+                .loads()
+                .into_iter()
+                .find(|(p, ..)| *p == peer)
+                .and_then(|(.., latency)| latency);
+            println!("{peer}: {count} answers, average latency {latency:?}");
+        }
+    }
+    if opt.watch {
+        watch(&pool, &mut workers, &health).await?;
+    }
+    Ok(())
+}
+
+/// Checks the workers as they come due and reloads the list on SIGHUP, until
+/// interrupted.
+async fn watch(
+    pool: &PeerPool,
+    workers: &mut WorkerList,
+    health: &HealthConfig,
+) -> Result<(), Box<dyn Error>> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut checks = tokio::time::interval(health.interval.min(health.reprobe_interval));
+    loop {
+        tokio::select! {
+            _ = checks.tick() => {
+                for (peer, health) in pool.check_workers(workers).await {
+                    println!("{peer} is {health}");
+                }
+            }
+            _ = hangup.recv() => match pool.reload_workers(workers) {
+                Ok((added, removed)) => {
+                    println!(
+                        "Reloaded the workers: {} added, {} removed",
+                        added.len(),
+                        removed.len()
+                    );
+                    checks.reset_immediately();
+                }
+                Err(e) => eprintln!("Keeping the current workers, reloading failed: {e}"),
+            },
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use clap::Parser;
+use libp2p::{Multiaddr, identity::Keypair};
+use mesh_ai_node::{
+    RerankRequest, ResponseStatus,
+    client::{Client, ClientConfig, ClientError, target_peer},
+    node::{self, NodeConfig},
+};
+use std::{
+    error::Error,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tracing_subscriber::EnvFilter;
+
+#[derive(Parser, Debug)]
+#[command(name = "rerank")]
+struct Opt {
+    /// Addresses of the target node.
+    #[arg(required = true)]
+    target_addrs: Vec<Multiaddr>,
+
+    #[arg(long)]
+    query: String,
+
+    /// Documents to score, one per line, each either a JSON string or an
+    /// object with a `text` field.
+    #[arg(long)]
+    docs_file: PathBuf,
+
+    /// Model to score with; the node's rerank model if omitted.
+    #[arg(long)]
+    model: Option<String>,
+
+    /// Only print the best N documents.
+    #[arg(long)]
+    top_n: Option<usize>,
+
+    /// Seconds allowed for the response once the request is sent.
+    #[arg(long, default_value_t = 300)]
+    response_timeout_secs: u64,
+}
+
+#[tokio::main]
+async fn main() {
+    if let Err(e) = run().await {
+        eprintln!("Error: {e}");
+        let code = e
+            .downcast_ref::<ClientError>()
+            .map_or(1, ClientError::exit_code);
+        std::process::exit(code);
+    }
+}
+
+async fn run() -> Result<(), Box<dyn Error>> {
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .try_init();
+
+    let opt = Opt::parse();
+    let documents = read_documents(&opt.docs_file)?;
+
+    let client_config = ClientConfig {
+        response_timeout: Duration::from_secs(opt.response_timeout_secs),
+        ..Default::default()
+    };
+    let config = NodeConfig {
+        idle_timeout: Duration::from_secs(u64::MAX),
+        request_timeout: client_config.response_timeout,
+        ..Default::default()
+    };
+    let swarm = node::build_swarm(Keypair::generate_ed25519(), &config)?;
+    let target_peer_id = target_peer(&opt.target_addrs[0])?;
+
+    let client = Client::new(swarm, client_config);
+    client.dial(target_peer_id, opt.target_addrs).await?;
+    client.confirm_rerank(target_peer_id).await?;
+
+    let response = client
+        .rerank(
+            target_peer_id,
+            RerankRequest {
+                model: opt.model,
+                query: opt.query,
+                documents: documents.clone(),
+                top_n: opt.top_n,
+            },
+        )
+        .await?;
+    if response.status != ResponseStatus::Ok {
+        let reason = response.error.unwrap_or_default();
+        eprintln!("{target_peer_id} answered {:?}: {reason}", response.status);
+        return Err(reason.into());
+    }
+    println!("Scored with {:?}", response.method);
+    for result in response.results {
+        let document = documents.get(result.index).map_or("?", String::as_str);
+        println!("{:.4}\t{}\t{document}", result.score, result.index);
+    }
+    Ok(())
+}
+
+fn read_documents(path: &Path) -> Result<Vec<String>, Box<dyn Error>> {
+    std::fs::read_to_string(path)?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let value: serde_json::Value = serde_json::from_str(line)?;
+            value
+                .as_str()
+                .or_else(|| value["text"].as_str())
+                .map(str::to_string)
+                .ok_or_else(|| format!("no document text in `{line}`").into())
+        })
+        .collect()
+}
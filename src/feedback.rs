@@ -0,0 +1,249 @@
+//! Ratings clients send back about the answers they got.
+//!
+//! A client rates an answer by the idempotency key its request carried. A
+//! worker accepts a rating only from the peer that made the request, once per
+//! request, and within its feedback window of the answer, so a peer can't
+//! stuff ratings for requests it never made. Accepted ratings are aggregated
+//! per model.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant},
+};
+
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+/// Longest comment accepted, in bytes.
+pub const MAX_COMMENT_BYTES: usize = 1024;
+
+/// Answers remembered at most; past this new answers can't be rated.
+const MAX_ENTRIES: usize = 10_000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Feedback {
+    /// The idempotency key of the request being rated.
+    pub request_id: String,
+    /// -1 for thumbs down, 0 for neutral, 1 for thumbs up.
+    pub rating: i8,
+    #[serde(default)]
+    pub comment: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FeedbackStatus {
+    Accepted,
+    /// No answer to this peer with this request id within the window.
+    UnknownRequest,
+    AlreadyRated,
+    /// The rating is outside -1..=1 or the comment is too long.
+    Invalid,
+    /// The peer is denylisted or banned, so its ratings aren't taken.
+    Denied,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FeedbackAck {
+    pub status: FeedbackStatus,
+}
+
+/// Accepted ratings for one model.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Ratings {
+    pub up: u64,
+    pub neutral: u64,
+    pub down: u64,
+}
+
+impl Ratings {
+    /// The mean rating, from -1 to 1, or `None` before the first one.
+    pub fn mean(&self) -> Option<f64> {
+        let count = self.up + self.neutral + self.down;
+        (count > 0).then(|| (self.up as f64 - self.down as f64) / count as f64)
+    }
+}
+
+struct Answered {
+    model: String,
+    at: Instant,
+    rated: bool,
+}
+
+pub struct FeedbackLog {
+    window: Duration,
+    answered: HashMap<(PeerId, String), Answered>,
+    ratings: BTreeMap<String, Ratings>,
+}
+
+impl FeedbackLog {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            answered: HashMap::new(),
+            ratings: BTreeMap::new(),
+        }
+    }
+
+    /// Remembers that `peer` got an answer from `model` for `request_id`, so
+    /// it can rate it.
+    pub fn record_answer(&mut self, peer: PeerId, request_id: String, model: &str, now: Instant) {
+        let window = self.window;
+        self.answered
+            .retain(|_, answered| now.duration_since(answered.at) < window);
+        if self.answered.len() < MAX_ENTRIES {
+            self.answered.insert(
+                (peer, request_id),
+                Answered {
+                    model: model.to_string(),
+                    at: now,
+                    rated: false,
+                },
+            );
+        }
+    }
+
+    /// Checks `feedback` from `peer` and counts it if it is acceptable.
+    /// Returns the rated model along with the status when accepted.
+    pub fn submit(
+        &mut self,
+        peer: PeerId,
+        feedback: &Feedback,
+        now: Instant,
+    ) -> (FeedbackStatus, Option<&str>) {
+        if !(-1..=1).contains(&feedback.rating)
+            || feedback
+                .comment
+                .as_ref()
+                .is_some_and(|c| c.len() > MAX_COMMENT_BYTES)
+        {
+            return (FeedbackStatus::Invalid, None);
+        }
+        let Some(answered) = self
+            .answered
+            .get_mut(&(peer, feedback.request_id.clone()))
+            .filter(|answered| now.duration_since(answered.at) < self.window)
+        else {
+            return (FeedbackStatus::UnknownRequest, None);
+        };
+        if answered.rated {
+            return (FeedbackStatus::AlreadyRated, None);
+        }
+        answered.rated = true;
+        let ratings = self.ratings.entry(answered.model.clone()).or_default();
+        match feedback.rating {
+            1 => ratings.up += 1,
+            0 => ratings.neutral += 1,
+            _ => ratings.down += 1,
+        }
+        (FeedbackStatus::Accepted, Some(&answered.model))
+    }
+
+    pub fn ratings(&self) -> &BTreeMap<String, Ratings> {
+        &self.ratings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_secs(60);
+
+    fn rating(request_id: &str, rating: i8) -> Feedback {
+        Feedback {
+            request_id: request_id.to_string(),
+            rating,
+            comment: None,
+        }
+    }
+
+    #[test]
+    fn counts_one_rating_per_answer() {
+        let now = Instant::now();
+        let peer = PeerId::random();
+        let mut log = FeedbackLog::new(WINDOW);
+        log.record_answer(peer, "a".to_string(), "llama3", now);
+        log.record_answer(peer, "b".to_string(), "llama3", now);
+        log.record_answer(peer, "c".to_string(), "phi3", now);
+        assert_eq!(
+            log.submit(peer, &rating("a", 1), now),
+            (FeedbackStatus::Accepted, Some("llama3"))
+        );
+        assert_eq!(
+            log.submit(peer, &rating("a", -1), now),
+            (FeedbackStatus::AlreadyRated, None)
+        );
+        log.submit(peer, &rating("b", -1), now);
+        log.submit(peer, &rating("c", 0), now);
+        let llama = log.ratings()["llama3"];
+        assert_eq!(
+            llama,
+            Ratings {
+                up: 1,
+                neutral: 0,
+                down: 1
+            }
+        );
+        assert_eq!(llama.mean(), Some(0.0));
+        assert_eq!(log.ratings()["phi3"].mean(), Some(0.0));
+        assert_eq!(Ratings::default().mean(), None);
+    }
+
+    #[test]
+    fn only_the_asking_peer_may_rate() {
+        let now = Instant::now();
+        let mut log = FeedbackLog::new(WINDOW);
+        log.record_answer(PeerId::random(), "a".to_string(), "llama3", now);
+        let stranger = PeerId::random();
+        assert_eq!(
+            log.submit(stranger, &rating("a", 1), now),
+            (FeedbackStatus::UnknownRequest, None)
+        );
+        assert!(log.ratings().is_empty());
+    }
+
+    #[test]
+    fn rejects_out_of_range_ratings_and_long_comments() {
+        let now = Instant::now();
+        let peer = PeerId::random();
+        let mut log = FeedbackLog::new(WINDOW);
+        log.record_answer(peer, "a".to_string(), "llama3", now);
+        for bad in [
+            rating("a", 2),
+            rating("a", -2),
+            Feedback {
+                comment: Some("x".repeat(MAX_COMMENT_BYTES + 1)),
+                ..rating("a", 1)
+            },
+        ] {
+            assert_eq!(log.submit(peer, &bad, now), (FeedbackStatus::Invalid, None));
+        }
+        // An invalid rating doesn't use up the answer's one rating.
+        let longest = Feedback {
+            comment: Some("x".repeat(MAX_COMMENT_BYTES)),
+            ..rating("a", 1)
+        };
+        assert_eq!(log.submit(peer, &longest, now).0, FeedbackStatus::Accepted);
+    }
+
+    #[test]
+    fn answers_can_only_be_rated_within_the_window() {
+        let start = Instant::now();
+        let peer = PeerId::random();
+        let mut log = FeedbackLog::new(WINDOW);
+        log.record_answer(peer, "a".to_string(), "llama3", start);
+        log.record_answer(peer, "b".to_string(), "llama3", start);
+        let last_moment = start + WINDOW - Duration::from_millis(1);
+        assert_eq!(
+            log.submit(peer, &rating("a", 1), last_moment).0,
+            FeedbackStatus::Accepted
+        );
+        assert_eq!(
+            log.submit(peer, &rating("b", 1), start + WINDOW).0,
+            FeedbackStatus::UnknownRequest
+        );
+        // Expired answers are forgotten when the next one is recorded.
+        log.record_answer(peer, "c".to_string(), "llama3", start + WINDOW);
+        assert_eq!(log.answered.len(), 1);
+    }
+}
@@ -0,0 +1,357 @@
+//! Blocklist-based content filter, run as a [`Middleware`].
+//!
+//! The blocklist file holds one entry per line; blank lines and `#` comments
+//! are ignored. A comment starts a line or follows whitespace, so a `#`
+//! inside a word is kept, and a `re:` line is taken whole, since `#` is
+//! common in patterns. An entry is a word or phrase, matched
+//! case-insensitively and only as a whole word, or, with a `re:` prefix, a
+//! regular expression matched as written. All entries are compiled into one automaton, so checking a text
+//! is a single pass however long the list or the text.
+//!
+//! Responses are always checked and prompts optionally. What happens on a
+//! match is the [`FilterAction`]. Streamed answers are checked as they go
+//! out, a word at a time: each chunk is held back up to its last whitespace,
+//! so a word split across chunks is still caught. A phrase split across
+//! chunks can slip through, and with [`FilterAction::Reject`] the words sent
+//! before the match have already gone out.
+
+use std::{fmt, fs, io, path::Path};
+
+use async_trait::async_trait;
+use libp2p::PeerId;
+use regex::Regex;
+
+use crate::{
+    PromptResponse, ResponseStatus,
+    metrics::Metrics,
+    middleware::{ChunkFilter, Middleware, Next, Outcome, Request},
+};
+
+/// Replaces each match when redacting.
+pub const REDACTED: &str = "[redacted]";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum FilterAction {
+    /// Replace each match with [`REDACTED`].
+    Redact,
+    /// Answer with [`ResponseStatus::ContentBlocked`] instead.
+    Reject,
+    /// Log the match and let the text through unchanged.
+    Log,
+}
+
+impl fmt::Display for FilterAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FilterAction::Redact => write!(f, "redact"),
+            FilterAction::Reject => write!(f, "reject"),
+            FilterAction::Log => write!(f, "log"),
+        }
+    }
+}
+
+/// The entries of a blocklist file, compiled.
+#[derive(Debug, Clone)]
+pub struct Blocklist {
+    pattern: Regex,
+    entries: usize,
+}
+
+impl Blocklist {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let invalid = |line: usize, e: String| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("line {line}: {e}"))
+        };
+        let mut words = Vec::new();
+        let mut patterns = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = strip_comment(line.trim());
+            if line.is_empty() {
+                continue;
+            }
+            match line.strip_prefix("re:") {
+                Some(pattern) => {
+                    // Compiled alone first so a bad entry is reported by line.
+                    Regex::new(pattern).map_err(|e| invalid(i + 1, e.to_string()))?;
+                    patterns.push(format!("(?:{pattern})"));
+                }
+                None => words.push(regex::escape(line)),
+            }
+        }
+        let entries = words.len() + patterns.len();
+        if !words.is_empty() {
+            patterns.push(format!(r"(?i:\b(?:{})\b)", words.join("|")));
+        }
+        let pattern = if patterns.is_empty() {
+            // Matches nothing.
+            r"[^\s\S]".to_string()
+        } else {
+            patterns.join("|")
+        };
+        let pattern = Regex::new(&pattern).map_err(|e| invalid(0, e.to_string()))?;
+        Ok(Self { pattern, entries })
+    }
+
+    /// Number of entries in the file.
+    pub fn len(&self) -> usize {
+        self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
+
+    pub fn count_matches(&self, text: &str) -> usize {
+        self.pattern.find_iter(text).count()
+    }
+
+    /// `text` with every match replaced by [`REDACTED`], and the number of
+    /// matches.
+    pub fn redact(&self, text: &str) -> (String, usize) {
+        let mut redacted = String::with_capacity(text.len());
+        let mut matches = 0;
+        let mut last = 0;
+        for m in self.pattern.find_iter(text) {
+            redacted.push_str(&text[last..m.start()]);
+            redacted.push_str(REDACTED);
+            last = m.end();
+            matches += 1;
+        }
+        redacted.push_str(&text[last..]);
+        (redacted, matches)
+    }
+}
+
+/// `line` without its comment, unless it is a `re:` line.
+fn strip_comment(line: &str) -> &str {
+    if line.starts_with('#') {
+        return "";
+    }
+    if line.starts_with("re:") {
+        return line;
+    }
+    let comment = line
+        .char_indices()
+        .zip(line.chars().skip(1))
+        .find(|((_, c), next)| c.is_whitespace() && *next == '#');
+    match comment {
+        Some(((i, _), _)) => line[..i].trim_end(),
+        None => line,
+    }
+}
+
+/// Checks prompts and responses against a [`Blocklist`].
+#[derive(Clone)]
+pub struct ContentFilter {
+    pub blocklist: Blocklist,
+    pub action: FilterAction,
+    /// Check prompts before they reach the backend, not only responses.
+    pub check_prompts: bool,
+    pub metrics: Metrics,
+}
+
+impl ContentFilter {
+    /// Applies the action to `text`. Returns whether it should be blocked.
+    fn check(&self, target: &str, peer: PeerId, model: &str, text: &mut String) -> bool {
+        let matches = match self.action {
+            FilterAction::Redact => {
+                let (redacted, matches) = self.blocklist.redact(text);
+                if matches > 0 {
+                    *text = redacted;
+                }
+                matches
+            }
+            FilterAction::Reject | FilterAction::Log => self.blocklist.count_matches(text),
+        };
+        if matches == 0 {
+            return false;
+        }
+        self.metrics.record_content_filter_matches(
+            target,
+            &self.action.to_string(),
+            matches as u64,
+        );
+        tracing::info!(
+            "Content filter: {matches} match(es) in {target} for {peer} on {model} ({})",
+            self.action
+        );
+        self.action == FilterAction::Reject
+    }
+}
+
+/// A [`ContentFilter`] on a streamed answer.
+struct StreamedContent {
+    filter: ContentFilter,
+    peer: PeerId,
+    model: String,
+    /// Text after the last whitespace seen, which may be half a word.
+    held: String,
+}
+
+impl StreamedContent {
+    fn release(&mut self, mut text: String) -> Result<String, Box<PromptResponse>> {
+        if self
+            .filter
+            .check("response", self.peer, &self.model, &mut text)
+        {
+            return Err(Box::new(PromptResponse::content_blocked(
+                "The answer matched the node's content filter".to_string(),
+            )));
+        }
+        Ok(text)
+    }
+}
+
+impl ChunkFilter for StreamedContent {
+    fn chunk(&mut self, text: &str) -> Result<String, Box<PromptResponse>> {
+        self.held.push_str(text);
+        let Some((end, c)) = self
+            .held
+            .char_indices()
+            .rev()
+            .find(|(_, c)| c.is_whitespace())
+        else {
+            return Ok(String::new());
+        };
+        let rest = self.held.split_off(end + c.len_utf8());
+        let ready = std::mem::replace(&mut self.held, rest);
+        self.release(ready)
+    }
+
+    fn finish(&mut self) -> Result<String, Box<PromptResponse>> {
+        let rest = std::mem::take(&mut self.held);
+        self.release(rest)
+    }
+}
+
+#[async_trait]
+impl Middleware for ContentFilter {
+    async fn handle(&self, mut request: Request, next: Next<'_>) -> Outcome {
+        let peer = request.peer;
+        let model = request.model.clone();
+        if self.check_prompts && self.check("prompt", peer, &model, &mut request.prompt.prompt) {
+            return Outcome::answered(PromptResponse::content_blocked(
+                "The prompt matched the node's content filter".to_string(),
+            ));
+        }
+        let mut outcome = next.run(request).await;
+        if outcome.response.status == ResponseStatus::Ok
+            && self.check("response", peer, &model, &mut outcome.response.response)
+        {
+            // The generation still ran, so its tokens are still charged.
+            outcome.response = PromptResponse::content_blocked(
+                "The answer matched the node's content filter".to_string(),
+            );
+        }
+        outcome
+    }
+
+    fn chunk_filter(&self, request: &Request) -> Option<Box<dyn ChunkFilter>> {
+        Some(Box::new(StreamedContent {
+            filter: self.clone(),
+            peer: request.peer,
+            model: request.model.clone(),
+            held: String::new(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use prometheus_client::registry::Registry;
+
+    use super::*;
+
+    fn streamed(list: &str, action: FilterAction) -> StreamedContent {
+        StreamedContent {
+            filter: ContentFilter {
+                blocklist: Blocklist::parse(list).unwrap(),
+                action,
+                check_prompts: false,
+                metrics: Metrics::new(&mut Registry::default(), HashSet::new()),
+            },
+            peer: PeerId::random(),
+            model: "llama3".to_string(),
+            held: String::new(),
+        }
+    }
+
+    /// What goes out when `chunks` are streamed through `filter`, or the
+    /// status it stopped the stream with.
+    fn stream(filter: &mut StreamedContent, chunks: &[&str]) -> Result<String, ResponseStatus> {
+        let mut sent = String::new();
+        for chunk in chunks {
+            sent += &filter.chunk(chunk).map_err(|r| r.status)?;
+        }
+        sent += &filter.finish().map_err(|r| r.status)?;
+        Ok(sent)
+    }
+
+    #[test]
+    fn comments_start_lines_or_follow_whitespace() {
+        let list =
+            Blocklist::parse("# a comment\nbad # a trailing comment\nno#tag\n  # indented\n")
+                .unwrap();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list.count_matches("bad"), 1);
+        assert_eq!(list.count_matches("a trailing comment"), 0);
+        assert_eq!(list.count_matches("a no#tag post"), 1);
+    }
+
+    #[test]
+    fn re_lines_are_taken_whole() {
+        let list = Blocklist::parse(r"re:issue #\d+").unwrap();
+        assert_eq!(list.count_matches("see issue #42"), 1);
+        assert_eq!(list.count_matches("see issue 42"), 0);
+    }
+
+    #[test]
+    fn bad_patterns_are_reported_by_line() {
+        let e = Blocklist::parse("fine\nre:(unclosed").unwrap_err();
+        assert!(e.to_string().starts_with("line 2:"), "{e}");
+    }
+
+    #[test]
+    fn words_match_whole_and_case_insensitively() {
+        let list = Blocklist::parse("secret").unwrap();
+        assert_eq!(
+            list.redact("A Secret, secrets."),
+            ("A [redacted], secrets.".to_string(), 1)
+        );
+    }
+
+    #[test]
+    fn streamed_words_split_across_chunks_are_redacted() {
+        let mut filter = streamed("secret", FilterAction::Redact);
+        let sent = stream(&mut filter, &["the sec", "ret is ", "out"]);
+        assert_eq!(sent.unwrap(), "the [redacted] is out");
+    }
+
+    #[test]
+    fn streamed_text_is_held_back_to_a_word_boundary() {
+        let mut filter = streamed("secret", FilterAction::Redact);
+        assert_eq!(filter.chunk("the sec").unwrap(), "the ");
+        assert_eq!(filter.chunk("ret").unwrap(), "");
+        assert_eq!(filter.finish().unwrap(), "[redacted]");
+    }
+
+    #[test]
+    fn a_streamed_match_stops_the_answer_when_rejecting() {
+        let mut filter = streamed("secret", FilterAction::Reject);
+        let sent = stream(&mut filter, &["the sec", "ret is ", "out"]);
+        assert_eq!(sent.unwrap_err(), ResponseStatus::ContentBlocked);
+    }
+
+    #[test]
+    fn streamed_matches_are_let_through_when_logging() {
+        let mut filter = streamed("secret", FilterAction::Log);
+        let sent = stream(&mut filter, &["the sec", "ret is ", "out"]);
+        assert_eq!(sent.unwrap(), "the secret is out");
+    }
+}
@@ -0,0 +1,202 @@
+//! Sheds load when the host itself is under pressure.
+//!
+//! Readings come from a [`ResourceProbe`], normally [`ProcProbe`], sampled on
+//! an interval. While a threshold is crossed the node runs fewer inferences at
+//! once and turns new requests away as overloaded; it recovers on its own once
+//! readings are back under the thresholds.
+
+use std::{fmt, fs, io};
+
+/// One sample of host resources.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Reading {
+    pub available_memory_bytes: u64,
+    /// One-minute load average.
+    pub load_average: f64,
+}
+
+/// A source of [`Reading`]s. Tests can substitute fake readings.
+pub trait ResourceProbe {
+    fn sample(&mut self) -> io::Result<Reading>;
+}
+
+/// Reads `/proc/meminfo` and `/proc/loadavg`.
+#[derive(Debug, Default)]
+pub struct ProcProbe;
+
+impl ResourceProbe for ProcProbe {
+    fn sample(&mut self) -> io::Result<Reading> {
+        let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_string());
+        let available_kb = fs::read_to_string("/proc/meminfo")?
+            .lines()
+            .find_map(|line| line.strip_prefix("MemAvailable:"))
+            .and_then(|rest| rest.split_whitespace().next()?.parse::<u64>().ok())
+            .ok_or_else(|| invalid("no MemAvailable in /proc/meminfo"))?;
+        let load_average = fs::read_to_string("/proc/loadavg")?
+            .split_whitespace()
+            .next()
+            .and_then(|load| load.parse().ok())
+            .ok_or_else(|| invalid("malformed /proc/loadavg"))?;
+        Ok(Reading {
+            available_memory_bytes: available_kb * 1024,
+            load_average,
+        })
+    }
+}
+
+/// Limits past which the host counts as under pressure. `None` disables a check.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GuardConfig {
+    pub min_available_memory_bytes: Option<u64>,
+    pub max_load_average: Option<f64>,
+}
+
+impl GuardConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.min_available_memory_bytes.is_some() || self.max_load_average.is_some()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pressure {
+    Memory,
+    Cpu,
+}
+
+impl fmt::Display for Pressure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pressure::Memory => write!(f, "host memory pressure"),
+            Pressure::Cpu => write!(f, "host CPU pressure"),
+        }
+    }
+}
+
+pub struct ResourceGuard<P> {
+    config: GuardConfig,
+    probe: P,
+    pressure: Option<Pressure>,
+}
+
+impl<P: ResourceProbe> ResourceGuard<P> {
+    pub fn new(config: GuardConfig, probe: P) -> Self {
+        Self {
+            config,
+            probe,
+            pressure: None,
+        }
+    }
+
+    /// Takes a reading and updates the current pressure. Returns the new
+    /// pressure if it changed. A failed reading keeps the previous state.
+    pub fn check(&mut self) -> io::Result<Option<Option<Pressure>>> {
+        let reading = self.probe.sample()?;
+        let pressure = if self
+            .config
+            .min_available_memory_bytes
+            .is_some_and(|min| reading.available_memory_bytes < min)
+        {
+            Some(Pressure::Memory)
+        } else if self
+            .config
+            .max_load_average
+            .is_some_and(|max| reading.load_average > max)
+        {
+            Some(Pressure::Cpu)
+        } else {
+            None
+        };
+        if pressure == self.pressure {
+            return Ok(None);
+        }
+        self.pressure = pressure;
+        Ok(Some(pressure))
+    }
+
+    pub fn pressure(&self) -> Option<Pressure> {
+        self.pressure
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    /// Hands out readings in order; an error where `None` is queued.
+    struct FakeProbe(VecDeque<Option<Reading>>);
+
+    impl ResourceProbe for FakeProbe {
+        fn sample(&mut self) -> io::Result<Reading> {
+            self.0
+                .pop_front()
+                .flatten()
+                .ok_or_else(|| io::Error::other("no reading"))
+        }
+    }
+
+    const GIB: u64 = 1024 * 1024 * 1024;
+
+    fn reading(available_gib: u64, load_average: f64) -> Option<Reading> {
+        Some(Reading {
+            available_memory_bytes: available_gib * GIB,
+            load_average,
+        })
+    }
+
+    fn guard(readings: impl IntoIterator<Item = Option<Reading>>) -> ResourceGuard<FakeProbe> {
+        ResourceGuard::new(
+            GuardConfig {
+                min_available_memory_bytes: Some(2 * GIB),
+                max_load_average: Some(8.0),
+            },
+            FakeProbe(readings.into_iter().collect()),
+        )
+    }
+
+    #[test]
+    fn reports_pressure_only_when_it_changes() {
+        let mut guard = guard([
+            reading(4, 1.0),
+            reading(1, 1.0),
+            reading(1, 1.0),
+            reading(4, 9.0),
+            reading(4, 1.0),
+        ]);
+        assert_eq!(guard.check().unwrap(), None);
+        assert_eq!(guard.check().unwrap(), Some(Some(Pressure::Memory)));
+        assert_eq!(guard.check().unwrap(), None);
+        assert_eq!(guard.check().unwrap(), Some(Some(Pressure::Cpu)));
+        assert_eq!(guard.pressure(), Some(Pressure::Cpu));
+        assert_eq!(guard.check().unwrap(), Some(None));
+        assert_eq!(guard.pressure(), None);
+    }
+
+    #[test]
+    fn memory_pressure_wins_over_cpu() {
+        let mut guard = guard([reading(1, 9.0)]);
+        assert_eq!(guard.check().unwrap(), Some(Some(Pressure::Memory)));
+    }
+
+    #[test]
+    fn a_failed_reading_keeps_the_previous_state() {
+        let mut guard = guard([reading(1, 1.0), None]);
+        guard.check().unwrap();
+        assert!(guard.check().is_err());
+        assert_eq!(guard.pressure(), Some(Pressure::Memory));
+    }
+
+    #[test]
+    fn disabled_checks_never_trigger() {
+        let mut guard = ResourceGuard::new(
+            GuardConfig {
+                min_available_memory_bytes: None,
+                max_load_average: Some(8.0),
+            },
+            FakeProbe([reading(0, 1.0)].into()),
+        );
+        assert!(!GuardConfig::default().is_enabled());
+        assert_eq!(guard.check().unwrap(), None);
+    }
+}
@@ -0,0 +1,201 @@
+//! Persistent node identity.
+//!
+//! Keys are stored in libp2p's protobuf encoding so either key type round-trips
+//! through the same file format. secp256k1 secrets from the wider DePIN stack
+//! can be imported as 32 raw bytes (or their hex encoding).
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::Path,
+};
+
+use clap::ValueEnum;
+use libp2p::identity::{Keypair, secp256k1};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum KeyType {
+    #[default]
+    Ed25519,
+    Secp256k1,
+}
+
+impl KeyType {
+    pub fn generate(self) -> Keypair {
+        match self {
+            KeyType::Ed25519 => Keypair::generate_ed25519(),
+            KeyType::Secp256k1 => Keypair::generate_secp256k1(),
+        }
+    }
+
+    fn of(keypair: &Keypair) -> Option<Self> {
+        match keypair.key_type() {
+            libp2p::identity::KeyType::Ed25519 => Some(KeyType::Ed25519),
+            libp2p::identity::KeyType::Secp256k1 => Some(KeyType::Secp256k1),
+            _ => None,
+        }
+    }
+}
+
+/// Loads the keypair stored at `path`, generating and saving a new one of
+/// `key_type` (ed25519 if `None`) if the file doesn't exist yet. A stored key
+/// of another type than an explicit `key_type` is an error rather than being
+/// used silently.
+pub fn load_or_generate(path: &Path, key_type: Option<KeyType>) -> io::Result<Keypair> {
+    if !path.exists() {
+        let keypair = key_type.unwrap_or_default().generate();
+        save(path, &keypair, false)?;
+        return Ok(keypair);
+    }
+    let keypair = load(path)?;
+    if let Some(wanted) = key_type
+        && KeyType::of(&keypair) != Some(wanted)
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} holds a {:?} key, not {wanted:?}",
+                path.display(),
+                keypair.key_type()
+            ),
+        ));
+    }
+    Ok(keypair)
+}
+
+pub fn load(path: &Path) -> io::Result<Keypair> {
+    let bytes = fs::read(path)?;
+    Keypair::from_protobuf_encoding(&bytes)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Writes `keypair` to `path`, readable by the owner only. An existing file
+/// is an error unless `overwrite` is set, in which case it is replaced
+/// whole, never left half written.
+pub fn save(path: &Path, keypair: &Keypair, overwrite: bool) -> io::Result<()> {
+    let bytes = keypair
+        .to_protobuf_encoding()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    if !overwrite {
+        return write_private(path, &bytes);
+    }
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = Path::new(&tmp);
+    // A leftover from an interrupted save holds nothing worth keeping.
+    let _ = fs::remove_file(tmp);
+    write_private(tmp, &bytes)?;
+    fs::rename(tmp, path)
+}
+
+/// Creates `path`, failing if it exists, with owner-only permissions from
+/// the start so the secret is never readable by others.
+fn write_private(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    file.write_all(bytes)?;
+    file.sync_all()
+}
+
+/// Reads a raw secp256k1 secret from `path`, either as exactly 32 bytes or as
+/// 64 hex characters (surrounding whitespace and a `0x` prefix are ignored).
+pub fn import_secp256k1(path: &Path) -> io::Result<Keypair> {
+    let contents = fs::read(path)?;
+    let mut secret = if contents.len() == 32 {
+        contents
+    } else {
+        let text = String::from_utf8_lossy(&contents);
+        decode_hex(text.trim().trim_start_matches("0x")).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "expected 32 raw bytes or 64 hex characters",
+            )
+        })?
+    };
+    let secret = secp256k1::SecretKey::try_from_bytes(&mut secret)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(secp256k1::Keypair::from(secret).into())
+}
+
+pub(crate) fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() != 64 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn generates_the_requested_type_and_reloads_it() {
+        let dir = TempDir::new("identity-generate");
+        let path = dir.join("key");
+        let keypair = load_or_generate(&path, Some(KeyType::Secp256k1)).unwrap();
+        assert_eq!(KeyType::of(&keypair), Some(KeyType::Secp256k1));
+
+        let again = load_or_generate(&path, None).unwrap();
+        assert_eq!(again.public(), keypair.public());
+    }
+
+    #[test]
+    fn refuses_a_stored_key_of_another_type() {
+        let dir = TempDir::new("identity-mismatch");
+        let path = dir.join("key");
+        load_or_generate(&path, Some(KeyType::Ed25519)).unwrap();
+        let err = load_or_generate(&path, Some(KeyType::Secp256k1)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn saving_does_not_replace_an_existing_key_unless_asked() {
+        let dir = TempDir::new("identity-overwrite");
+        let path = dir.join("key");
+        let first = KeyType::Ed25519.generate();
+        save(&path, &first, false).unwrap();
+
+        let second = KeyType::Ed25519.generate();
+        let err = save(&path, &second, false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(load(&path).unwrap().public(), first.public());
+
+        save(&path, &second, true).unwrap();
+        assert_eq!(load(&path).unwrap().public(), second.public());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn key_files_are_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new("identity-mode");
+        let path = dir.join("key");
+        load_or_generate(&path, None).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        save(&path, &KeyType::Ed25519.generate(), true).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn imports_hex_secp256k1_secrets() {
+        let dir = TempDir::new("identity-import");
+        let path = dir.join("key");
+        fs::write(&path, format!("0x{}\n", "11".repeat(32))).unwrap();
+        let keypair = import_secp256k1(&path).unwrap();
+        assert_eq!(KeyType::of(&keypair), Some(KeyType::Secp256k1));
+
+        fs::write(&path, "not hex").unwrap();
+        assert!(import_secp256k1(&path).is_err());
+    }
+}
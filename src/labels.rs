@@ -0,0 +1,224 @@
+//! Free-form `key=value` labels on workers, e.g. `region=eu-west` or
+//! `tier=gpu`, so clients can pick workers by where and what they are.
+//!
+//! A worker announces its labels through identify. A client can require a
+//! label, which rules out every worker without it, or prefer one, which ranks
+//! workers with it ahead of the rest. Labels come from untrusted peers, so
+//! how many there are and how long they are is bounded, both when announcing
+//! and again when received.
+
+use std::{collections::BTreeMap, fmt};
+
+use serde::{Deserialize, Serialize};
+
+use crate::profile;
+
+/// Most labels a worker announces; any beyond are dropped on receipt.
+pub const MAX_LABELS: usize = 16;
+/// Longest label key, in characters.
+pub const MAX_KEY_CHARS: usize = 32;
+/// Longest label value, in characters.
+pub const MAX_VALUE_CHARS: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Label {
+    pub key: String,
+    pub value: String,
+}
+
+impl Label {
+    /// Keys are ASCII letters, digits and `-_./`; values are printable and
+    /// have no surrounding whitespace.
+    pub fn new(key: &str, value: &str) -> Result<Self, String> {
+        let key_ok = (1..=MAX_KEY_CHARS).contains(&key.len())
+            && key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '/'));
+        if !key_ok {
+            return Err(format!(
+                "invalid label key `{key}`: expected 1 to {MAX_KEY_CHARS} letters, digits or `-_./`"
+            ));
+        }
+        if profile::sanitize(value, MAX_VALUE_CHARS).as_deref() != Some(value) {
+            return Err(format!(
+                "invalid value for label `{key}`: expected 1 to {MAX_VALUE_CHARS} printable characters"
+            ));
+        }
+        Ok(Self {
+            key: key.to_string(),
+            value: value.to_string(),
+        })
+    }
+}
+
+impl std::str::FromStr for Label {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (key, value) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected KEY=VALUE, got `{s}`"))?;
+        Self::new(key, value)
+    }
+}
+
+impl fmt::Display for Label {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.key, self.value)
+    }
+}
+
+/// A worker's labels, at most one value per key.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Labels(BTreeMap<String, String>);
+
+impl Labels {
+    /// This node's own labels. Fails if there are more than [`MAX_LABELS`]
+    /// keys or a key is given twice.
+    pub fn new(labels: impl IntoIterator<Item = Label>) -> Result<Self, String> {
+        let mut map = BTreeMap::new();
+        for Label { key, value } in labels {
+            if map.contains_key(&key) {
+                return Err(format!("label `{key}` given more than once"));
+            }
+            map.insert(key, value);
+        }
+        if map.len() > MAX_LABELS {
+            return Err(format!(
+                "{} labels given, at most {MAX_LABELS} are allowed",
+                map.len()
+            ));
+        }
+        Ok(Self(map))
+    }
+
+    /// Labels a peer announced. Invalid ones are dropped, as are repeated
+    /// keys and any beyond the first [`MAX_LABELS`].
+    pub fn from_untrusted<'a>(pairs: impl IntoIterator<Item = (&'a str, &'a str)>) -> Self {
+        let mut map = BTreeMap::new();
+        for (key, value) in pairs {
+            if map.len() == MAX_LABELS {
+                break;
+            }
+            if let Ok(label) = Label::new(key, value) {
+                map.entry(label.key).or_insert(label.value);
+            }
+        }
+        Self(map)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(String::as_str)
+    }
+
+    pub fn has(&self, label: &Label) -> bool {
+        self.get(&label.key) == Some(label.value.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Display for Labels {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (key, value)) in self.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{key}={value}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Which workers a client will use, by label.
+#[derive(Debug, Clone, Default)]
+pub struct LabelFilter {
+    /// Labels a worker must have to be used at all.
+    pub require: Vec<Label>,
+    /// Labels that rank a worker ahead of those without them, one step per
+    /// label matched.
+    pub prefer: Vec<Label>,
+}
+
+impl LabelFilter {
+    pub fn is_empty(&self) -> bool {
+        self.require.is_empty() && self.prefer.is_empty()
+    }
+
+    /// Whether a worker with `labels` may be used.
+    pub fn admits(&self, labels: &Labels) -> bool {
+        self.require.iter().all(|label| labels.has(label))
+    }
+
+    /// How many of the preferred labels `labels` has.
+    pub fn preference(&self, labels: &Labels) -> usize {
+        self.prefer.iter().filter(|label| labels.has(label)).count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(pairs: &[(&str, &str)]) -> Labels {
+        Labels::from_untrusted(pairs.iter().copied())
+    }
+
+    fn filter(require: &[&str], prefer: &[&str]) -> LabelFilter {
+        LabelFilter {
+            require: require.iter().map(|l| l.parse().unwrap()).collect(),
+            prefer: prefer.iter().map(|l| l.parse().unwrap()).collect(),
+        }
+    }
+
+    #[test]
+    fn requires_every_label_with_its_value() {
+        let filter = filter(&["region=eu", "tier=gpu"], &[]);
+        assert!(filter.admits(&labels(&[("region", "eu"), ("tier", "gpu"), ("x", "y")])));
+        assert!(!filter.admits(&labels(&[("region", "eu")])));
+        assert!(!filter.admits(&labels(&[("region", "us"), ("tier", "gpu")])));
+        assert!(!filter.admits(&Labels::default()));
+        assert!(LabelFilter::default().admits(&Labels::default()));
+    }
+
+    #[test]
+    fn preference_counts_matching_labels() {
+        let filter = filter(&[], &["region=eu", "tier=gpu"]);
+        assert_eq!(filter.preference(&Labels::default()), 0);
+        assert_eq!(
+            filter.preference(&labels(&[("region", "us"), ("tier", "gpu")])),
+            1
+        );
+        assert_eq!(
+            filter.preference(&labels(&[("region", "eu"), ("tier", "gpu")])),
+            2
+        );
+    }
+
+    #[test]
+    fn untrusted_labels_are_bounded() {
+        let long = "v".repeat(MAX_VALUE_CHARS + 1);
+        let received = labels(&[("ok", "1"), ("bad key", "2"), ("long", &long), ("ok", "3")]);
+        assert_eq!(received.to_string(), "ok=1");
+
+        let keys: Vec<_> = (0..MAX_LABELS + 4).map(|i| format!("k{i:02}")).collect();
+        let many = Labels::from_untrusted(keys.iter().map(|k| (k.as_str(), "v")));
+        assert_eq!(many.iter().count(), MAX_LABELS);
+    }
+
+    #[test]
+    fn own_labels_refuse_repeats_and_excess() {
+        let label = |s: &str| s.parse::<Label>().unwrap();
+        assert!(Labels::new([label("a=1"), label("a=2")]).is_err());
+        assert!(Labels::new((0..=MAX_LABELS).map(|i| label(&format!("k{i}=v")))).is_err());
+        assert!("no-equals".parse::<Label>().is_err());
+        assert!(Label::new("k", " padded").is_err());
+    }
+}
@@ -1,11 +1,911 @@
+pub mod admission;
+pub mod agents;
+pub mod api_keys;
+pub mod backoff;
+pub mod bans;
+pub mod bounded;
+pub mod channels;
+pub mod chat;
+pub mod client;
+pub mod client_info;
+pub mod compare;
+pub mod context;
+pub mod dedup;
+pub mod denylist;
+pub mod direct;
+pub mod discovery;
+pub mod estimate;
+pub mod eviction;
+pub mod feedback;
+pub mod filter;
+pub mod guard;
+pub mod http_client;
+pub mod identity;
+pub mod labels;
+pub mod logging;
+pub mod metrics;
+pub mod middleware;
+#[cfg(feature = "test-util")]
+pub mod mock_ollama;
+pub mod models;
+pub mod node;
+pub mod observed;
+pub mod ollama;
+pub mod oom;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+pub mod perf;
+pub mod pex;
+pub mod pin;
+pub mod pool;
+pub mod profile;
+pub mod proto;
+pub mod quota;
+pub mod readiness;
+pub mod rerank;
+pub mod retry;
+pub mod scheduler;
+pub mod server;
+pub mod state;
+pub mod stream;
+pub mod telemetry;
+pub mod templates;
+pub mod tools;
+pub mod trace;
+pub mod usage;
+pub mod workers;
+
+use std::{fmt, time::Duration};
+
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// Most image data a prompt may carry, all images together.
+pub const MAX_IMAGE_BYTES: usize = 4 * 1024 * 1024;
+
+/// Most binary data an answer may carry, so it fits in a response and in a
+/// stream frame. Larger answers are refused with
+/// [`ResponseStatus::ResponseTooLarge`].
+pub const MAX_BINARY_BYTES: usize = MAX_IMAGE_BYTES;
+
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct PromptRequest {
     pub prompt: String,
+    /// Model to run the prompt on. `None` lets the node pick its default.
+    #[serde(default)]
+    pub model: Option<String>,
+    /// Constrains the output format: `json`, or a JSON schema. The response is
+    /// checked to be valid JSON when this is set.
+    #[serde(default)]
+    pub format: Option<String>,
+    /// Identifies the request across retries so the node runs it at most
+    /// once. See [`retry`].
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+    /// Let the node cut a prompt that doesn't fit the model's context window
+    /// instead of refusing it. See [`context`].
+    #[serde(default)]
+    pub allow_truncate: bool,
+    /// Milliseconds the node may spend generating the answer, not counting
+    /// time queued. Past it the generation is stopped and the request is
+    /// answered with [`ResponseStatus::DeadlineExceeded`]. The node caps it
+    /// at its own maximum, which also applies when this is `None` or zero.
+    #[serde(default)]
+    pub max_duration_ms: Option<u64>,
+    /// The application sending the request, for the node's logs and status.
+    /// Never trusted: see [`client_info`].
+    #[serde(default)]
+    pub client_info: Option<client_info::ClientInfo>,
+    /// Counts the request against the key's team rather than the peer. See
+    /// [`api_keys`].
+    #[serde(default)]
+    pub api_key: Option<String>,
+    /// The sender's span as a W3C `traceparent`, for linking its logs with
+    /// the node's. See [`trace`].
+    #[serde(default)]
+    pub trace_context: Option<String>,
+    /// Images for vision models to look at with the prompt, as raw file
+    /// contents, e.g. PNG or JPEG. At most [`MAX_IMAGE_BYTES`] in total.
+    #[serde(default, with = "binary_list")]
+    pub images: Option<Vec<Vec<u8>>>,
+    /// Have the node put the prompt, and `system` if any, in the model's
+    /// chat template. See [`templates`].
+    #[serde(default)]
+    pub apply_template: bool,
+    /// Instructions for the model ahead of the prompt, placed by its chat
+    /// template. Only used with `apply_template` or `tools`: otherwise the
+    /// prompt is taken as complete and this is left out.
+    #[serde(default)]
+    pub system: Option<String>,
+    /// Functions the model may call instead of answering in text. See
+    /// [`tools`].
+    #[serde(default)]
+    pub tools: Option<Vec<tools::ToolDefinition>>,
+    /// Whether the model may call `tools`. `None` leaves it to the model.
+    #[serde(default)]
+    pub tool_choice: Option<tools::ToolChoice>,
+    /// How the node uses the response cached under `idempotency_key`. See
+    /// [`dedup`].
+    #[serde(default)]
+    pub cache: dedup::CacheMode,
+    /// Milliseconds the node keeps this request's response for retries. The
+    /// node caps it at its own TTL, which also applies when this is `None`.
+    #[serde(default)]
+    pub cache_ttl_ms: Option<u64>,
+    /// Context window, in tokens, to run the model with in place of its
+    /// own. At most what the model was trained for; see [`context`].
+    #[serde(default)]
+    pub num_ctx: Option<u64>,
+    /// How soon the node runs the request when others are queued: higher
+    /// goes first. The node caps it at what the request's API key allows,
+    /// which is 0 without one; see [`api_keys`].
+    #[serde(default)]
+    pub priority: u8,
+}
+
+/// Like a derived `Debug`, but with the API key left out, since requests
+/// end up in debug logs.
+impl fmt::Debug for PromptRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self {
+            prompt,
+            model,
+            format,
+            idempotency_key,
+            allow_truncate,
+            max_duration_ms,
+            client_info,
+            api_key,
+            trace_context,
+            images,
+            apply_template,
+            system,
+            tools,
+            tool_choice,
+            cache,
+            cache_ttl_ms,
+            num_ctx,
+            priority,
+        } = self;
+        f.debug_struct("PromptRequest")
+            .field("prompt", prompt)
+            .field("model", model)
+            .field("format", format)
+            .field("idempotency_key", idempotency_key)
+            .field("allow_truncate", allow_truncate)
+            .field("max_duration_ms", max_duration_ms)
+            .field("client_info", client_info)
+            .field("api_key", &api_key.as_ref().map(|_| "<redacted>"))
+            .field("trace_context", trace_context)
+            .field("images", &images.as_ref().map(|images| images.len()))
+            .field("apply_template", apply_template)
+            .field("system", system)
+            .field("tools", tools)
+            .field("tool_choice", tool_choice)
+            .field("cache", cache)
+            .field("cache_ttl_ms", cache_ttl_ms)
+            .field("num_ctx", num_ctx)
+            .field("priority", priority)
+            .finish()
+    }
+}
+
+impl PromptRequest {
+    /// A request for `prompt` with everything else left to the node.
+    pub fn new(prompt: String) -> Self {
+        Self {
+            prompt,
+            model: None,
+            format: None,
+            idempotency_key: None,
+            allow_truncate: false,
+            max_duration_ms: None,
+            client_info: None,
+            api_key: None,
+            trace_context: None,
+            images: None,
+            apply_template: false,
+            system: None,
+            tools: None,
+            tool_choice: None,
+            cache: dedup::CacheMode::default(),
+            cache_ttl_ms: None,
+            num_ctx: None,
+            priority: 0,
+        }
+    }
+
+    /// How long the node gives this request's generation, given its own
+    /// maximum. A budget of zero would fail every request, so it counts as
+    /// none.
+    pub fn budget(&self, max: Duration) -> Duration {
+        self.max_duration_ms
+            .filter(|&ms| ms > 0)
+            .map_or(max, |ms| Duration::from_millis(ms).min(max))
+    }
+
+    /// Checks that the images add up to at most [`MAX_IMAGE_BYTES`].
+    pub fn check_images(&self) -> Result<(), String> {
+        let total: usize = self.images.iter().flatten().map(Vec::len).sum();
+        if total > MAX_IMAGE_BYTES {
+            return Err(format!(
+                "the images add up to {total} bytes, over the limit of {MAX_IMAGE_BYTES}"
+            ));
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct PromptResponse {
     pub response: String,
+    #[serde(default)]
+    pub status: ResponseStatus,
+    /// The requesting peer's remaining quota, when the node enforces one.
+    #[serde(default)]
+    pub quota: Option<QuotaStatus>,
+    /// With [`ResponseStatus::ModelNotAllowed`], the models the node
+    /// advertises, so the client can retry with one of them straight away.
+    #[serde(default)]
+    pub available_models: Vec<String>,
+    /// The prompt didn't fit the model's context window and was cut, as the
+    /// request allowed.
+    #[serde(default)]
+    pub truncated: bool,
+    /// The backend failed and `response` is the node's canned fallback text
+    /// rather than an answer.
+    #[serde(default)]
+    pub is_fallback: bool,
+    /// Output that isn't text, e.g. an image from a multimodal model. Text
+    /// answers leave this `None` and come in `response` as ever.
+    #[serde(default)]
+    pub binary: Option<BinaryPayload>,
+    /// Where the time went on the node, for requests that reached the
+    /// backend. Refusals leave this `None`.
+    #[serde(default)]
+    pub timing: Option<Timing>,
+    /// The chat template the node put the prompt in, when the request asked
+    /// for one: a template name, or `ollama` for the model's own.
+    #[serde(default)]
+    pub template: Option<String>,
+    /// Calls the model made to the request's tools, in place of or next to
+    /// its text.
+    #[serde(default)]
+    pub tool_calls: Vec<tools::ToolCall>,
+}
+
+/// How long a request waited in the node's queue and how long the backend
+/// took with it, to tell a busy node from a slow model.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Timing {
+    /// From the request being accepted to its generation starting.
+    pub queue_wait_ms: u64,
+    /// From the generation starting to the backend's answer.
+    pub inference_ms: u64,
+}
+
+/// Bytes with their MIME type. Sent as raw bytes over CBOR and protobuf,
+/// and as base64 in the JSON frames of the streaming protocol, where the
+/// whole payload has to fit in the last frame.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BinaryPayload {
+    /// E.g. `image/png`.
+    pub mime_type: String,
+    #[serde(with = "binary_data")]
+    pub data: Vec<u8>,
+}
+
+/// Serializes bytes as a byte string, or as base64 for formats that are
+/// meant to be read, like JSON.
+mod binary_data {
+    use std::fmt;
+
+    use base64::{Engine, engine::general_purpose::STANDARD};
+    use serde::{Deserialize, Deserializer, Serializer, de};
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&STANDARD.encode(data))
+        } else {
+            serializer.serialize_bytes(data)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        if deserializer.is_human_readable() {
+            let encoded = String::deserialize(deserializer)?;
+            return STANDARD.decode(encoded).map_err(de::Error::custom);
+        }
+        deserializer.deserialize_byte_buf(BytesVisitor)
+    }
+
+    struct BytesVisitor;
+
+    impl de::Visitor<'_> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("a byte string")
+        }
+
+        fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Vec<u8>, E> {
+            Ok(bytes.to_vec())
+        }
+
+        fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> Result<Vec<u8>, E> {
+            Ok(bytes)
+        }
+    }
+}
+
+/// [`binary_data`] for each of an optional list of byte strings.
+mod binary_list {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize)]
+    struct Borrowed<'a>(#[serde(serialize_with = "super::binary_data::serialize")] &'a [u8]);
+
+    #[derive(Deserialize)]
+    struct Owned(#[serde(deserialize_with = "super::binary_data::deserialize")] Vec<u8>);
+
+    pub fn serialize<S: Serializer>(
+        list: &Option<Vec<Vec<u8>>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        list.as_ref()
+            .map(|list| list.iter().map(|data| Borrowed(data)).collect::<Vec<_>>())
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Vec<Vec<u8>>>, D::Error> {
+        let list = Option::<Vec<Owned>>::deserialize(deserializer)?;
+        Ok(list.map(|list| list.into_iter().map(|Owned(data)| data).collect()))
+    }
+}
+
+/// Scores `documents` by relevance to `query`. Served on
+/// [`node::RERANK_PROTOCOL_NAME`], which only nodes that can rerank advertise.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RerankRequest {
+    /// Model used for scoring. `None` lets the node pick its rerank model.
+    #[serde(default)]
+    pub model: Option<String>,
+    pub query: String,
+    pub documents: Vec<String>,
+    /// Return only the best `top_n` documents.
+    #[serde(default)]
+    pub top_n: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RerankResponse {
+    /// Documents ordered best first.
+    pub results: Vec<RerankScore>,
+    /// How the scores were computed; `None` when the request wasn't served.
+    pub method: Option<RerankMethod>,
+    #[serde(default)]
+    pub status: ResponseStatus,
+    /// Why the request wasn't served, when `status` isn't `Ok`.
+    #[serde(default)]
+    pub error: Option<String>,
+    /// See [`PromptResponse::available_models`].
+    #[serde(default)]
+    pub available_models: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RerankScore {
+    /// Position of the document in the request.
+    pub index: usize,
+    pub score: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RerankMethod {
+    /// Cosine similarity between query and document embeddings.
+    EmbeddingSimilarity,
+}
+
+/// Carries a refusal built for the prompt protocol over to a rerank response.
+impl From<PromptResponse> for RerankResponse {
+    fn from(response: PromptResponse) -> Self {
+        Self {
+            results: Vec::new(),
+            method: None,
+            status: response.status,
+            error: Some(response.response),
+            available_models: response.available_models,
+        }
+    }
+}
+
+/// Runs one prompt on two models, for side-by-side evaluation. Served on
+/// [`node::COMPARE_PROTOCOL_NAME`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompareRequest {
+    pub prompt: String,
+    pub models: [String; 2],
+    /// See [`PromptRequest::format`].
+    #[serde(default)]
+    pub format: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompareResponse {
+    /// One per model, in the order of [`CompareRequest::models`]; empty when
+    /// the request wasn't served.
+    pub outcomes: Vec<CompareOutcome>,
+    #[serde(default)]
+    pub status: ResponseStatus,
+    /// Why the request wasn't served, when `status` isn't `Ok`.
+    #[serde(default)]
+    pub error: Option<String>,
+    /// The requesting peer's remaining quota, after both generations.
+    #[serde(default)]
+    pub quota: Option<QuotaStatus>,
+    /// See [`PromptResponse::available_models`].
+    #[serde(default)]
+    pub available_models: Vec<String>,
+}
+
+/// What one model made of the prompt. Each model succeeds or fails on its
+/// own.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompareOutcome {
+    pub model: String,
+    pub status: ResponseStatus,
+    /// The generated text, or the error when `status` isn't `Ok`.
+    pub response: String,
+    #[serde(default)]
+    pub prompt_tokens: u64,
+    #[serde(default)]
+    pub completion_tokens: u64,
+    /// Time spent generating, excluding the queue.
+    #[serde(default)]
+    pub latency_ms: u64,
+}
+
+/// Carries a refusal built for the prompt protocol over to a compare response.
+impl From<PromptResponse> for CompareResponse {
+    fn from(response: PromptResponse) -> Self {
+        Self {
+            outcomes: Vec::new(),
+            status: response.status,
+            error: Some(response.response),
+            quota: response.quota,
+            available_models: response.available_models,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuotaStatus {
+    /// `None` when requests are unlimited.
+    pub remaining_requests: Option<u64>,
+    /// `None` when completion tokens are unlimited.
+    pub remaining_tokens: Option<u64>,
+    /// Unix time in seconds at which the quota resets.
+    pub resets_at: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ResponseStatus {
+    #[default]
+    Ok,
+    /// The node is at capacity and shed the request; try another node.
+    Busy,
+    /// The request was not served; `response` carries the reason.
+    Error,
+    /// The peer is temporarily banned after repeated bad requests.
+    Banned,
+    /// JSON output was requested but the model didn't produce valid JSON.
+    InvalidJson,
+    /// The peer has used up its quota; `quota` says when it resets.
+    QuotaExceeded,
+    /// The host is short on memory or CPU; `response` carries the reason.
+    Overloaded,
+    /// The requested model isn't served here; `available_models` lists what is.
+    ModelNotAllowed,
+    /// The prompt or the answer matched the node's content filter.
+    ContentBlocked,
+    /// The prompt doesn't fit the model's context window; `response` gives
+    /// the sizes.
+    ContextOverflow,
+    /// The backend ran out of memory; another node, or this one later, may
+    /// manage.
+    BackendOutOfMemory,
+    /// The generation took longer than the request's budget and was stopped.
+    DeadlineExceeded,
+    /// The node hit a bug while serving the request. `response` only says
+    /// so; what went wrong stays in the node's logs.
+    Internal,
+    /// The request's API key was refused, or the node requires one; see
+    /// [`api_keys`].
+    Unauthorized,
+    /// The model finished without producing any output, e.g. nothing but
+    /// whitespace, so there is no answer to give.
+    EmptyResponse,
+    /// The node is down for maintenance, e.g. an upgrade; `response` carries
+    /// the operator's message. Try another node.
+    Maintenance,
+    /// The request asked for a chat template the node couldn't apply;
+    /// `response` says why.
+    TemplateError,
+    /// The request uses a feature the node can't serve, e.g. tools for a
+    /// model without tool support; `response` says which. Another node may.
+    UnsupportedFeature,
+    /// The request asked to be answered from the cache only, and nothing was
+    /// cached under its idempotency key.
+    NotCached,
+    /// The node is a warm standby, not serving until it is promoted; try
+    /// another node.
+    Standby,
+    /// A request option is outside what the model supports, e.g. a
+    /// `num_ctx` over its trained context length; `response` says which.
+    InvalidOption,
+    /// The model's answer was too large to send, e.g. an image over
+    /// [`MAX_BINARY_BYTES`]; `response` gives the sizes.
+    ResponseTooLarge,
+    /// A status added in a newer version of the protocol than this build
+    /// knows. The request wasn't served; `response` may say why.
+    #[serde(other)]
+    Unknown,
+}
+
+impl PromptResponse {
+    /// A response with `status` and `response` and nothing else set, which
+    /// every other constructor builds on.
+    fn new(status: ResponseStatus, response: String) -> Self {
+        Self {
+            response,
+            status,
+            quota: None,
+            available_models: Vec::new(),
+            truncated: false,
+            is_fallback: false,
+            binary: None,
+            timing: None,
+            template: None,
+            tool_calls: Vec::new(),
+        }
+    }
+
+    pub fn ok(response: String) -> Self {
+        Self::new(ResponseStatus::Ok, response)
+    }
+
+    /// This response carrying `binary` next to its text, if any.
+    pub fn with_binary(self, binary: Option<BinaryPayload>) -> Self {
+        Self { binary, ..self }
+    }
+
+    /// This response carrying the model's `tool_calls`.
+    pub fn with_tool_calls(self, tool_calls: Vec<tools::ToolCall>) -> Self {
+        Self { tool_calls, ..self }
+    }
+
+    /// Bytes of answer: the text and any binary payload.
+    pub fn payload_len(&self) -> usize {
+        self.response.len() + self.binary.as_ref().map_or(0, |b| b.data.len())
+    }
+
+    /// The node's `fallback` text in place of an answer the backend failed
+    /// to give.
+    pub fn fallback(fallback: String) -> Self {
+        Self {
+            is_fallback: true,
+            ..Self::ok(fallback)
+        }
+    }
+
+    pub fn busy() -> Self {
+        Self::new(
+            ResponseStatus::Busy,
+            "Node is busy, try again later or route elsewhere".to_string(),
+        )
+    }
+
+    pub fn banned(remaining: Duration) -> Self {
+        Self::new(
+            ResponseStatus::Banned,
+            format!("Banned for another {}s", remaining.as_secs()),
+        )
+    }
+
+    pub fn invalid_json(reason: String) -> Self {
+        Self::new(ResponseStatus::InvalidJson, reason)
+    }
+
+    pub fn quota_exceeded(quota: QuotaStatus) -> Self {
+        Self {
+            quota: Some(quota),
+            ..Self::new(
+                ResponseStatus::QuotaExceeded,
+                format!("Quota exceeded; resets at unix time {}", quota.resets_at),
+            )
+        }
+    }
+
+    pub fn overloaded(reason: String) -> Self {
+        Self::new(
+            ResponseStatus::Overloaded,
+            format!("Node is overloaded ({reason}), try again later or route elsewhere"),
+        )
+    }
+
+    pub fn model_not_allowed(model: &str, available_models: Vec<String>) -> Self {
+        let response = format!(
+            "Model {model} is not served by this node; available: {}",
+            available_models.join(", ")
+        );
+        Self {
+            available_models,
+            ..Self::new(ResponseStatus::ModelNotAllowed, response)
+        }
+    }
+
+    pub fn content_blocked(reason: String) -> Self {
+        Self::new(ResponseStatus::ContentBlocked, reason)
+    }
+
+    pub fn context_overflow(reason: String) -> Self {
+        Self::new(ResponseStatus::ContextOverflow, reason)
+    }
+
+    pub fn backend_out_of_memory(reason: String) -> Self {
+        Self::new(ResponseStatus::BackendOutOfMemory, reason)
+    }
+
+    pub fn deadline_exceeded(budget: Duration) -> Self {
+        Self::new(
+            ResponseStatus::DeadlineExceeded,
+            format!("Generation stopped after its budget of {budget:?}"),
+        )
+    }
+
+    pub fn internal(reason: String) -> Self {
+        Self::new(ResponseStatus::Internal, reason)
+    }
+
+    pub fn empty_response(model: &str) -> Self {
+        Self::new(
+            ResponseStatus::EmptyResponse,
+            format!("{model} finished without producing any output"),
+        )
+    }
+
+    pub fn template_error(reason: String) -> Self {
+        Self::new(ResponseStatus::TemplateError, reason)
+    }
+
+    pub fn unsupported_feature(reason: String) -> Self {
+        Self::new(ResponseStatus::UnsupportedFeature, reason)
+    }
+
+    pub fn not_cached() -> Self {
+        Self::new(
+            ResponseStatus::NotCached,
+            "Nothing is cached for this request".to_string(),
+        )
+    }
+
+    pub fn invalid_option(reason: String) -> Self {
+        Self::new(ResponseStatus::InvalidOption, reason)
+    }
+
+    pub fn response_too_large(reason: String) -> Self {
+        Self::new(ResponseStatus::ResponseTooLarge, reason)
+    }
+
+    pub fn standby() -> Self {
+        Self::new(
+            ResponseStatus::Standby,
+            "The node is on standby, try another node".to_string(),
+        )
+    }
+
+    pub fn maintenance(message: &str) -> Self {
+        Self::new(ResponseStatus::Maintenance, message.to_string())
+    }
+
+    pub fn unauthorized(reason: String) -> Self {
+        Self::new(ResponseStatus::Unauthorized, reason)
+    }
+
+    pub fn error(reason: String) -> Self {
+        Self::new(ResponseStatus::Error, reason)
+    }
+}
+
+/// Helpers for tests that run several nodes in one process over the memory
+/// transport, so ids and addresses are known without parsing log output.
+#[cfg(feature = "test-util")]
+pub mod testing {
+    use std::{
+        error::Error,
+        fs,
+        future::{self, Future},
+        path::{Path, PathBuf},
+    };
+
+    use futures::{StreamExt, stream::FuturesUnordered};
+    use libp2p::{
+        Multiaddr, PeerId, Swarm, identity::Keypair, request_response, swarm::SwarmEvent,
+    };
+
+    use crate::{
+        PromptRequest, PromptResponse,
+        node::{self, Behaviour, BehaviourEvent, NodeConfig, TransportKind},
+        server::Node,
+    };
+
+    /// An ed25519 keypair derived from `seed`, so the same seed always gives
+    /// the same `PeerId`.
+    pub fn keypair(seed: u8) -> Keypair {
+        Keypair::ed25519_from_bytes([seed; 32]).expect("32 bytes is a valid ed25519 secret")
+    }
+
+    /// Builds a memory-transport node from [`keypair`]`(seed)` and waits until
+    /// it is listening. Returns the swarm, which the caller must keep polling,
+    /// along with its `PeerId` and a dialable address ending in `/p2p/<id>`.
+    pub async fn listening_node(
+        seed: u8,
+        config: NodeConfig,
+    ) -> Result<(Swarm<Behaviour>, PeerId, Multiaddr), Box<dyn Error>> {
+        listening_node_with(keypair(seed), config).await
+    }
+
+    /// Like [`listening_node`], with the node's identity given outright,
+    /// e.g. a key of another type.
+    pub async fn listening_node_with(
+        keypair: Keypair,
+        config: NodeConfig,
+    ) -> Result<(Swarm<Behaviour>, PeerId, Multiaddr), Box<dyn Error>> {
+        let config = NodeConfig {
+            transports: vec![TransportKind::Memory],
+            ..config
+        };
+        let mut swarm = node::build_swarm(keypair, &config)?;
+        let peer_id = *swarm.local_peer_id();
+        for addr in config.default_listen_addrs() {
+            swarm.listen_on(addr)?;
+        }
+        loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = swarm.select_next_some().await {
+                let address = address
+                    .with_p2p(peer_id)
+                    .expect("listen address has no peer id");
+                return Ok((swarm, peer_id, address));
+            }
+        }
+    }
+
+    /// Waits until `node` is listening, then runs it in the background.
+    /// Returns its `PeerId` and a dialable address ending in `/p2p/<id>`.
+    pub async fn serving_node(mut node: Node) -> (PeerId, Multiaddr) {
+        let peer_id = node.local_peer_id();
+        let address = node
+            .first_listen_addr()
+            .await
+            .with_p2p(peer_id)
+            .expect("listen address has no peer id");
+        tokio::spawn(node.run());
+        (peer_id, address)
+    }
+
+    /// Starts `swarm` listening on a TCP port on loopback and waits for the
+    /// address, for tests that need a real IP, e.g. for AutoNAT dial-backs.
+    /// The swarm must have been built with [`TransportKind::Tcp`].
+    pub async fn listen_on_tcp(swarm: &mut Swarm<Behaviour>) -> Result<Multiaddr, Box<dyn Error>> {
+        swarm.listen_on("/ip4/127.0.0.1/tcp/0".parse()?)?;
+        loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = swarm.select_next_some().await {
+                return Ok(address);
+            }
+        }
+    }
+
+    /// Serves prompts on `swarm` until dropped, answering each with what
+    /// `answer` returns for it. Answers are awaited side by side, so a slow
+    /// one doesn't hold up the others and they may go out in any order.
+    pub async fn serve_prompts<F, Fut>(mut swarm: Swarm<Behaviour>, mut answer: F)
+    where
+        F: FnMut(PeerId, PromptRequest) -> Fut,
+        Fut: Future<Output = PromptResponse>,
+    {
+        let mut pending = FuturesUnordered::new();
+        loop {
+            tokio::select! {
+                event = swarm.select_next_some() => {
+                    if let SwarmEvent::Behaviour(BehaviourEvent::RequestResponse(
+                        request_response::Event::Message {
+                            peer,
+                            message: request_response::Message::Request { request, channel, .. },
+                            ..
+                        },
+                    )) = event
+                    {
+                        let answer = answer(peer, request);
+                        pending.push(async move { (channel, answer.await) });
+                    }
+                }
+                Some((channel, response)) = pending.next(), if !pending.is_empty() => {
+                    let _ = swarm
+                        .behaviour_mut()
+                        .request_response
+                        .send_response(channel, response);
+                }
+            }
+        }
+    }
+
+    /// Serves prompts on `swarm`, answering each with `echo: <prompt>`.
+    pub async fn echo_worker(swarm: Swarm<Behaviour>) {
+        serve_prompts(swarm, |_, request| {
+            future::ready(PromptResponse::ok(format!("echo: {}", request.prompt)))
+        })
+        .await
+    }
+
+    /// A fresh directory under the system's temp dir, removed along with
+    /// everything in it when dropped, even if the test fails.
+    pub struct TempDir(PathBuf);
+
+    impl TempDir {
+        /// `name` keeps the directories of tests running in the same
+        /// process apart; whatever a failed run left under it is cleared.
+        pub fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("mesh-ai-{name}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).expect("can create a temp dir");
+            Self(path)
+        }
+
+        pub fn path(&self) -> &Path {
+            &self.0
+        }
+
+        /// The path of `file` in this directory.
+        pub fn join(&self, file: &str) -> PathBuf {
+            self.0.join(file)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_output_leaves_the_api_key_out() {
+        let request = PromptRequest {
+            api_key: Some("sk-very-secret".to_string()),
+            priority: 3,
+            ..PromptRequest::new("hi".to_string())
+        };
+        let shown = format!("{request:?}");
+        assert!(!shown.contains("sk-very-secret"), "{shown}");
+        assert!(shown.contains("api_key: Some(\"<redacted>\")"), "{shown}");
+        assert!(shown.contains("priority: 3"), "{shown}");
+    }
+
+    #[test]
+    fn budget_is_capped_and_never_zero() {
+        let request = |ms: Option<u64>| PromptRequest {
+            max_duration_ms: ms,
+            ..PromptRequest::new("hi".to_string())
+        };
+        let max = Duration::from_secs(10);
+        assert_eq!(request(None).budget(max), max);
+        assert_eq!(request(Some(0)).budget(max), max);
+        assert_eq!(request(Some(500)).budget(max), Duration::from_millis(500));
+        assert_eq!(request(Some(60_000)).budget(max), max);
+    }
 }
@@ -0,0 +1,501 @@
+//! Helpers for keeping user content out of logs, and for logging each
+//! request with the same structured fields.
+//!
+//! Every request gets a [`RequestLog`] when it arrives. Its events carry
+//! `request_id`, `peer`, `kind`, `model` and `bytes_in`, and the one emitted
+//! when it is answered adds `bytes_out`, `duration_ms`, `outcome` and
+//! `delivered`. Prompts that name the application sending them also carry
+//! `client`, e.g. `batch-evaluator 0.3`, and those made with an API key carry
+//! its `team` and `key_id`. Once the peer's identify has arrived they carry
+//! its `agent`, e.g. `mesh-ai-node/0.1.0`. Prompts sent with a trace context
+//! carry `trace_id`, `span_id` and `parent_span_id`; see [`crate::trace`].
+//! The fields are tracing fields rather than text, so `--log-format json`
+//! gives logs that can be queried by them. Work done for the request runs
+//! inside [`RequestLog::span`], so whatever it logs carries the same fields.
+//! A request whose log is dropped without [`RequestLog::finished`] having
+//! been called is logged as unanswered, so none goes missing silently.
+
+use std::{
+    backtrace::Backtrace,
+    fmt,
+    hash::{DefaultHasher, Hash, Hasher},
+    panic,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Instant,
+};
+
+use libp2p::PeerId;
+use tracing::Span;
+
+use crate::{
+    ResponseStatus, Timing, api_keys::ApiKey, client_info::ClientInfo, trace::TraceContext,
+};
+
+/// Logs panics as error events with a backtrace, in place of the default
+/// message on stderr. A panic inside a request's span carries its fields.
+pub fn log_panics() {
+    panic::set_hook(Box::new(|info| {
+        tracing::error!(backtrace = %Backtrace::force_capture(), "{info}");
+    }));
+}
+
+/// How log events are written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// One human-readable line per event.
+    #[default]
+    Text,
+    /// One JSON object per event, with the structured fields as keys.
+    Json,
+}
+
+/// Formats a prompt for logging: verbatim, or as a short hash and length when
+/// redaction is on. The hash is stable within a build, so the same prompt can
+/// still be correlated across log lines without revealing its text.
+pub struct LoggedPrompt<'a> {
+    prompt: &'a str,
+    redact: bool,
+}
+
+impl<'a> LoggedPrompt<'a> {
+    pub fn new(prompt: &'a str, redact: bool) -> Self {
+        Self { prompt, redact }
+    }
+}
+
+impl fmt::Display for LoggedPrompt<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.redact {
+            return f.write_str(self.prompt);
+        }
+        let mut hasher = DefaultHasher::new();
+        self.prompt.hash(&mut hasher);
+        write!(
+            f,
+            "<redacted {:016x}, {} bytes>",
+            hasher.finish(),
+            self.prompt.len()
+        )
+    }
+}
+
+/// Characters kept by [`ShortPeer`]. Peer ids share their first characters,
+/// so it keeps the last.
+const SHORT_PEER_CHARS: usize = 8;
+
+/// A peer id cut to its last characters, enough to tell peers apart in logs.
+pub struct ShortPeer<'a>(pub &'a PeerId);
+
+impl fmt::Display for ShortPeer<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let id = self.0.to_string();
+        f.write_str(&id[id.len().saturating_sub(SHORT_PEER_CHARS)..])
+    }
+}
+
+/// One request's identity in the logs, from arrival until it is answered.
+///
+/// Built with everything known on arrival, so no event about the request can
+/// leave those out; [`finished`](Self::finished) takes the rest. Copies share
+/// whether it was called, and the last one dropped warns if it wasn't.
+#[derive(Debug, Clone)]
+pub struct RequestLog {
+    request_id: u64,
+    peer: PeerId,
+    kind: &'static str,
+    model: String,
+    bytes_in: usize,
+    /// The application the request says it comes from.
+    client: Option<String>,
+    /// The team of the API key the request carried.
+    team: Option<String>,
+    /// The id of that key.
+    key_id: Option<String>,
+    /// The software the peer announced through identify.
+    agent: Option<String>,
+    /// The trace the request belongs to, with this node's span in it and
+    /// the sender's span as its parent.
+    trace_id: Option<String>,
+    span_id: Option<String>,
+    parent_span_id: Option<String>,
+    /// The sender's span, which exported spans are linked under.
+    #[cfg(feature = "otlp")]
+    sender: Option<TraceContext>,
+    /// Where the time went, once the backend has answered.
+    timing: Option<Timing>,
+    received_at: Instant,
+    unfinished: Arc<Unfinished>,
+}
+
+/// Warns when dropped unless the request it names was logged as finished.
+#[derive(Debug)]
+struct Unfinished {
+    done: AtomicBool,
+    request_id: u64,
+    peer: PeerId,
+    kind: &'static str,
+}
+
+impl Drop for Unfinished {
+    fn drop(&mut self) {
+        if !*self.done.get_mut() {
+            tracing::warn!(
+                request_id = self.request_id,
+                peer = %ShortPeer(&self.peer),
+                kind = %self.kind,
+                "request dropped without an answer"
+            );
+        }
+    }
+}
+
+impl RequestLog {
+    /// `kind` names the protocol, e.g. `prompt`; `bytes_in` is the length
+    /// of the prompt or query.
+    pub fn new(
+        request_id: u64,
+        peer: PeerId,
+        kind: &'static str,
+        model: &str,
+        bytes_in: usize,
+    ) -> Self {
+        Self {
+            request_id,
+            peer,
+            kind,
+            model: model.to_string(),
+            bytes_in,
+            client: None,
+            team: None,
+            key_id: None,
+            agent: None,
+            trace_id: None,
+            span_id: None,
+            parent_span_id: None,
+            #[cfg(feature = "otlp")]
+            sender: None,
+            timing: None,
+            received_at: Instant::now(),
+            unfinished: Arc::new(Unfinished {
+                done: AtomicBool::new(false),
+                request_id,
+                peer,
+                kind,
+            }),
+        }
+    }
+
+    /// Logs the request as coming from `client`, as the request claims,
+    /// sanitized and cut to length however it was received.
+    pub fn with_client(mut self, client: Option<&ClientInfo>) -> Self {
+        self.client = client
+            .and_then(ClientInfo::sanitized)
+            .map(|client| client.to_string());
+        self
+    }
+
+    /// Logs the request as sent by `agent`, as the peer announced it. `None`
+    /// while the peer's identify hasn't arrived.
+    pub fn with_agent(mut self, agent: Option<&str>) -> Self {
+        self.agent = agent.map(str::to_string);
+        self
+    }
+
+    /// Logs the request as part of the sender's trace, in a new span whose
+    /// parent is the sender's.
+    pub fn with_trace(mut self, sender: Option<&TraceContext>) -> Self {
+        let Some(sender) = sender else {
+            return self;
+        };
+        self.trace_id = Some(sender.trace_id_hex());
+        self.span_id = Some(sender.child().span_id_hex());
+        self.parent_span_id = Some(sender.span_id_hex());
+        #[cfg(feature = "otlp")]
+        {
+            self.sender = Some(*sender);
+        }
+        self
+    }
+
+    /// Logs the request under the team and id of the API key it was
+    /// accepted with, if any.
+    pub fn with_api_key(mut self, key: Option<&ApiKey>) -> Self {
+        self.team = key.map(|key| key.team.clone());
+        self.key_id = key.map(|key| key.id.clone());
+        self
+    }
+
+    /// Logs the answer's split between queue wait and inference, if it has
+    /// one.
+    pub fn with_timing(mut self, timing: Option<Timing>) -> Self {
+        self.timing = timing;
+        self
+    }
+
+    /// Logs that the request was accepted, with its prompt as formatted by
+    /// [`LoggedPrompt`].
+    pub fn received(&self, prompt: LoggedPrompt<'_>) {
+        tracing::info!(
+            request_id = self.request_id,
+            peer = %ShortPeer(&self.peer),
+            kind = %self.kind,
+            model = %self.model,
+            bytes_in = self.bytes_in,
+            client = self.client.as_deref(),
+            team = self.team.as_deref(),
+            key_id = self.key_id.as_deref(),
+            agent = self.agent.as_deref(),
+            trace_id = self.trace_id.as_deref(),
+            span_id = self.span_id.as_deref(),
+            parent_span_id = self.parent_span_id.as_deref(),
+            prompt = %prompt,
+            "request received"
+        );
+    }
+
+    /// Logs the answer, whether served or refused. `bytes_out` is the length
+    /// of the answer, its text and any binary payload; `delivered` is whether it reached the peer, which
+    /// it doesn't if the peer stopped waiting.
+    pub fn finished(&self, outcome: ResponseStatus, bytes_out: usize, delivered: bool) {
+        self.unfinished.done.store(true, Ordering::Relaxed);
+        let duration_ms = self.received_at.elapsed().as_millis() as u64;
+        if delivered {
+            tracing::info!(
+                request_id = self.request_id,
+                peer = %ShortPeer(&self.peer),
+                kind = %self.kind,
+                model = %self.model,
+                bytes_in = self.bytes_in,
+                client = self.client.as_deref(),
+                team = self.team.as_deref(),
+                key_id = self.key_id.as_deref(),
+                agent = self.agent.as_deref(),
+                trace_id = self.trace_id.as_deref(),
+                span_id = self.span_id.as_deref(),
+                parent_span_id = self.parent_span_id.as_deref(),
+                bytes_out,
+                duration_ms,
+                queue_wait_ms = self.timing.map(|t| t.queue_wait_ms),
+                inference_ms = self.timing.map(|t| t.inference_ms),
+                outcome = ?outcome,
+                delivered,
+                "request finished"
+            );
+        } else {
+            tracing::warn!(
+                request_id = self.request_id,
+                peer = %ShortPeer(&self.peer),
+                kind = %self.kind,
+                model = %self.model,
+                bytes_in = self.bytes_in,
+                client = self.client.as_deref(),
+                team = self.team.as_deref(),
+                key_id = self.key_id.as_deref(),
+                agent = self.agent.as_deref(),
+                trace_id = self.trace_id.as_deref(),
+                span_id = self.span_id.as_deref(),
+                parent_span_id = self.parent_span_id.as_deref(),
+                bytes_out,
+                duration_ms,
+                queue_wait_ms = self.timing.map(|t| t.queue_wait_ms),
+                inference_ms = self.timing.map(|t| t.inference_ms),
+                outcome = ?outcome,
+                delivered,
+                "request finished, but the response was dropped"
+            );
+        }
+    }
+
+    /// Logs that the request will be answered by a run of the same prompt
+    /// already in progress, whose own log covers the answer. Takes the place
+    /// of [`finished`](Self::finished).
+    pub fn joined(&self) {
+        self.unfinished.done.store(true, Ordering::Relaxed);
+        tracing::info!(
+            request_id = self.request_id,
+            peer = %ShortPeer(&self.peer),
+            kind = %self.kind,
+            model = %self.model,
+            bytes_in = self.bytes_in,
+            client = self.client.as_deref(),
+            team = self.team.as_deref(),
+            key_id = self.key_id.as_deref(),
+            agent = self.agent.as_deref(),
+            trace_id = self.trace_id.as_deref(),
+            span_id = self.span_id.as_deref(),
+            parent_span_id = self.parent_span_id.as_deref(),
+            "request joined a run in progress"
+        );
+    }
+
+    /// The protocol, e.g. `prompt`.
+    pub fn kind(&self) -> &'static str {
+        self.kind
+    }
+
+    /// A span for work done on the request, so events logged inside it carry
+    /// its fields. Exported under the sender's span when exporting traces;
+    /// see [`crate::otlp`].
+    pub fn span(&self) -> Span {
+        let span = tracing::info_span!(
+            "request",
+            request_id = self.request_id,
+            peer = %ShortPeer(&self.peer),
+            kind = %self.kind,
+            model = %self.model,
+            bytes_in = self.bytes_in,
+            client = self.client.as_deref(),
+            team = self.team.as_deref(),
+            key_id = self.key_id.as_deref(),
+            agent = self.agent.as_deref(),
+            trace_id = self.trace_id.as_deref(),
+            span_id = self.span_id.as_deref(),
+            parent_span_id = self.parent_span_id.as_deref(),
+        );
+        #[cfg(feature = "otlp")]
+        if let Some(sender) = &self.sender {
+            crate::otlp::link(&span, sender);
+        }
+        span
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, sync::Mutex};
+
+    use super::*;
+
+    /// Log lines written while `f` runs, as JSON objects.
+    fn capture(f: impl FnOnce()) -> Vec<serde_json::Value> {
+        #[derive(Clone, Default)]
+        struct Buffer(Arc<Mutex<Vec<u8>>>);
+        impl io::Write for Buffer {
+            fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(bytes);
+                Ok(bytes.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .json()
+            .with_writer(move || writer.clone())
+            .finish();
+        tracing::subscriber::with_default(subscriber, f);
+        let text = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        text.lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    fn message(line: &serde_json::Value) -> &str {
+        line["fields"]["message"].as_str().unwrap()
+    }
+
+    #[test]
+    fn finished_requests_carry_their_fields() {
+        let peer = PeerId::random();
+        let lines = capture(|| {
+            let log = RequestLog::new(7, peer, "prompt", "llama3", 12);
+            log.received(LoggedPrompt::new("hello", true));
+            log.finished(ResponseStatus::Busy, 34, false);
+        });
+        assert_eq!(lines.len(), 2, "{lines:?}");
+        let fields = &lines[1]["fields"];
+        assert_eq!(
+            message(&lines[1]),
+            "request finished, but the response was dropped"
+        );
+        assert_eq!(lines[1]["level"], "WARN");
+        assert_eq!(fields["request_id"], 7);
+        assert_eq!(fields["peer"], ShortPeer(&peer).to_string());
+        assert_eq!(fields["kind"], "prompt");
+        assert_eq!(fields["model"], "llama3");
+        assert_eq!(fields["bytes_in"], 12);
+        assert_eq!(fields["bytes_out"], 34);
+        assert_eq!(fields["outcome"], "Busy");
+        assert_eq!(fields["delivered"], false);
+        assert!(
+            !lines[0]["fields"]["prompt"]
+                .as_str()
+                .unwrap()
+                .contains("hello")
+        );
+    }
+
+    #[test]
+    fn client_info_is_logged_sanitized() {
+        let client = ClientInfo {
+            app_name: format!("app\n\u{202E}{}", "x".repeat(500)),
+            app_version: "1.0".to_string(),
+        };
+        let lines = capture(|| {
+            let log =
+                RequestLog::new(1, PeerId::random(), "prompt", "m", 0).with_client(Some(&client));
+            log.finished(ResponseStatus::Ok, 0, true);
+        });
+        let logged = lines[0]["fields"]["client"].as_str().unwrap();
+        assert!(
+            !logged.chars().any(|c| c.is_control() || c == '\u{202E}'),
+            "{logged}"
+        );
+        assert_eq!(
+            logged.chars().count(),
+            crate::client_info::MAX_APP_NAME_CHARS + " 1.0".len()
+        );
+    }
+
+    #[test]
+    fn unfinished_requests_are_logged_once_the_last_copy_drops() {
+        let lines = capture(|| {
+            let log = RequestLog::new(1, PeerId::random(), "compare", "a,b", 0);
+            let copy = log.clone();
+            drop(log);
+            tracing::info!("copy still alive");
+            drop(copy);
+        });
+        let messages: Vec<_> = lines.iter().map(message).collect();
+        assert_eq!(
+            messages,
+            ["copy still alive", "request dropped without an answer"]
+        );
+        assert_eq!(lines[1]["fields"]["request_id"], 1);
+        assert_eq!(lines[1]["fields"]["kind"], "compare");
+    }
+
+    #[test]
+    fn finished_or_joined_requests_drop_quietly() {
+        let lines = capture(|| {
+            let log = RequestLog::new(1, PeerId::random(), "compare", "a,b", 0);
+            let copy = log.clone();
+            drop(log);
+            copy.finished(ResponseStatus::Ok, 0, true);
+            RequestLog::new(2, PeerId::random(), "prompt", "m", 0).joined();
+        });
+        let messages: Vec<_> = lines.iter().map(message).collect();
+        assert_eq!(
+            messages,
+            ["request finished", "request joined a run in progress"]
+        );
+    }
+
+    #[test]
+    fn redacted_prompts_keep_no_text() {
+        let prompt = "my secret plans";
+        assert_eq!(LoggedPrompt::new(prompt, false).to_string(), prompt);
+
+        let redacted = LoggedPrompt::new(prompt, true).to_string();
+        assert!(!redacted.contains("secret"), "{redacted}");
+        assert!(redacted.ends_with(", 15 bytes>"), "{redacted}");
+        // The same prompt can still be told apart from others.
+        assert_eq!(redacted, LoggedPrompt::new(prompt, true).to_string());
+        assert_ne!(redacted, LoggedPrompt::new("other plans", true).to_string());
+    }
+}
@@ -0,0 +1,914 @@
+//! Prometheus metrics for the inference path, served over plain HTTP.
+//!
+//! The same server answers `GET /state` with the node's [`NodeState`] as
+//! JSON, and `GET /readyz` with its [`Readiness`], when given a way to ask
+//! for them. It listens on TCP, or on a unix domain socket for deployments
+//! that only want it reachable from the host. Over the unix socket it also
+//! clears the response cache on `POST /cache/flush`, and re-reads the API
+//! keys on `POST /keys/reload`, so keys can be added or revoked by editing
+//! the file. Neither is served over TCP, where anyone who can scrape the
+//! metrics could call them.
+
+use std::{
+    collections::HashSet, fmt, io, net::SocketAddr, path::PathBuf, str::FromStr, sync::Arc,
+    time::Duration,
+};
+#[cfg(unix)]
+use std::{
+    fs,
+    os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+    path::Path,
+};
+
+use prometheus_client::{
+    encoding::{EncodeLabelSet, text::encode},
+    metrics::{
+        counter::Counter,
+        family::Family,
+        gauge::Gauge,
+        histogram::{Histogram, exponential_buckets},
+    },
+    registry::Registry,
+};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+    sync::{mpsc, oneshot},
+    time::timeout,
+};
+
+use crate::{
+    api_keys::ReloadRequest,
+    dedup::FlushRequest,
+    perf::Sample,
+    readiness::{self, Readiness, ReadinessRequest},
+    state::{NodeState, StateRequest},
+    usage::{UsageReport, UsageRequest},
+};
+
+/// How long `/state` waits for the swarm loop to build the snapshot.
+const STATE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a scraper has to send its request before it is hung up on, so
+/// connections that never send one don't pile up.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Label value used for any model outside the allowed set, so arbitrary
+/// client-supplied model names can't blow up series cardinality.
+pub const OTHER_MODEL: &str = "other";
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct ModelLabels {
+    pub model: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct RequestLabels {
+    pub model: String,
+    pub outcome: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct OutcomeLabels {
+    pub outcome: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct ChannelLabels {
+    pub protocol: String,
+    /// `inbound` or `outbound`.
+    pub direction: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct ProtocolLabels {
+    pub protocol: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct MapLabels {
+    /// Which per-peer table, e.g. `bans`.
+    pub map: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct FilterLabels {
+    /// `prompt` or `response`.
+    pub target: String,
+    pub action: String,
+}
+
+#[derive(Clone)]
+pub struct Metrics {
+    allowed_models: Arc<HashSet<String>>,
+    requests: Family<RequestLabels, Counter>,
+    inference_latency: Family<ModelLabels, Histogram>,
+    generation_rate: Family<ModelLabels, Histogram>,
+    prompt_rate: Family<ModelLabels, Histogram>,
+    queue_wait: Family<ModelLabels, Histogram>,
+    result_channel_occupancy: Gauge,
+    connection_lifetime: Histogram,
+    connections_evicted: Counter,
+    autonat_probes: Family<OutcomeLabels, Counter>,
+    content_filter_matches: Family<FilterLabels, Counter>,
+    open_requests: Family<ChannelLabels, Gauge>,
+    responses_dropped: Family<ProtocolLabels, Counter>,
+    handler_panics: Family<ProtocolLabels, Counter>,
+    peer_state_entries: Family<MapLabels, Gauge>,
+    peer_state_evictions: Family<MapLabels, Counter>,
+    cache_lookups: Family<OutcomeLabels, Counter>,
+}
+
+impl Metrics {
+    pub fn new(registry: &mut Registry, allowed_models: HashSet<String>) -> Self {
+        let requests = Family::<RequestLabels, Counter>::default();
+        registry.register(
+            "mesh_ai_requests",
+            "Inference requests handled, by model and outcome",
+            requests.clone(),
+        );
+
+        let inference_latency = Family::<ModelLabels, Histogram>::new_with_constructor(
+            (|| Histogram::new(exponential_buckets(0.05, 2.0, 14))) as fn() -> Histogram,
+        );
+        registry.register(
+            "mesh_ai_inference_latency_seconds",
+            "Time spent waiting on the inference backend, by model",
+            inference_latency.clone(),
+        );
+
+        // Tokens per second, from 1 to about 4000.
+        let rate_buckets =
+            (|| Histogram::new(exponential_buckets(1.0, 2.0, 13))) as fn() -> Histogram;
+        let generation_rate = Family::<ModelLabels, Histogram>::new_with_constructor(rate_buckets);
+        registry.register(
+            "mesh_ai_generation_tokens_per_second",
+            "Completion tokens generated per second of eval time, by model",
+            generation_rate.clone(),
+        );
+        let prompt_rate = Family::<ModelLabels, Histogram>::new_with_constructor(rate_buckets);
+        registry.register(
+            "mesh_ai_prompt_eval_tokens_per_second",
+            "Prompt tokens evaluated per second, by model",
+            prompt_rate.clone(),
+        );
+
+        let queue_wait = Family::<ModelLabels, Histogram>::new_with_constructor(
+            (|| Histogram::new(exponential_buckets(0.01, 2.0, 16))) as fn() -> Histogram,
+        );
+        registry.register(
+            "mesh_ai_queue_wait_seconds",
+            "Time admitted requests waited for a backend slot, by model",
+            queue_wait.clone(),
+        );
+
+        let result_channel_occupancy = Gauge::default();
+        registry.register(
+            "mesh_ai_result_channel_occupancy",
+            "Finished inferences waiting for the swarm loop to send them",
+            result_channel_occupancy.clone(),
+        );
+
+        // From one second to about a day; short-lived churn is the interesting part.
+        let connection_lifetime = Histogram::new(exponential_buckets(1.0, 4.0, 9));
+        registry.register(
+            "mesh_ai_connection_lifetime_seconds",
+            "How long connections stayed open before closing",
+            connection_lifetime.clone(),
+        );
+
+        let connections_evicted = Counter::default();
+        registry.register(
+            "mesh_ai_connections_evicted",
+            "Idle connections closed to make room under the connection limit",
+            connections_evicted.clone(),
+        );
+
+        let autonat_probes = Family::<OutcomeLabels, Counter>::default();
+        registry.register(
+            "mesh_ai_autonat_probes",
+            "AutoNAT probes served to other peers, by outcome",
+            autonat_probes.clone(),
+        );
+
+        let content_filter_matches = Family::<FilterLabels, Counter>::default();
+        registry.register(
+            "mesh_ai_content_filter_matches",
+            "Blocklist matches found by the content filter, by target and action",
+            content_filter_matches.clone(),
+        );
+
+        let open_requests = Family::<ChannelLabels, Gauge>::default();
+        registry.register(
+            "mesh_ai_open_requests",
+            "Request-response exchanges awaiting an answer, by protocol and direction",
+            open_requests.clone(),
+        );
+
+        let responses_dropped = Family::<ProtocolLabels, Counter>::default();
+        registry.register(
+            "mesh_ai_response_dropped",
+            "Responses that never reached the peer, usually because it stopped waiting, by protocol",
+            responses_dropped.clone(),
+        );
+
+        let handler_panics = Family::<ProtocolLabels, Counter>::default();
+        registry.register(
+            "mesh_ai_handler_panics",
+            "Requests whose handling panicked, by protocol",
+            handler_panics.clone(),
+        );
+
+        let peer_state_entries = Family::<MapLabels, Gauge>::default();
+        registry.register(
+            "mesh_ai_peer_state_entries",
+            "Entries kept in each bounded per-peer table",
+            peer_state_entries.clone(),
+        );
+
+        let peer_state_evictions = Family::<MapLabels, Counter>::default();
+        registry.register(
+            "mesh_ai_peer_state_evictions",
+            "Entries dropped from each bounded per-peer table, for age or for room",
+            peer_state_evictions.clone(),
+        );
+
+        let cache_lookups = Family::<OutcomeLabels, Counter>::default();
+        registry.register(
+            "mesh_ai_cache_lookups",
+            "Prompts with an idempotency key, by whether the response cache answered them: \
+             hit, joined (waited on a run in progress), miss or bypass",
+            cache_lookups.clone(),
+        );
+
+        Self {
+            allowed_models: Arc::new(allowed_models),
+            requests,
+            inference_latency,
+            generation_rate,
+            prompt_rate,
+            queue_wait,
+            result_channel_occupancy,
+            connection_lifetime,
+            connections_evicted,
+            autonat_probes,
+            content_filter_matches,
+            open_requests,
+            responses_dropped,
+            handler_panics,
+            peer_state_entries,
+            peer_state_evictions,
+            cache_lookups,
+        }
+    }
+
+    /// Records the size of a per-peer table after pruning, and how many
+    /// entries it dropped since the last time.
+    pub fn record_peer_state(&self, map: &str, entries: usize, evicted: u64) {
+        let labels = MapLabels {
+            map: map.to_string(),
+        };
+        self.peer_state_entries
+            .get_or_create(&labels)
+            .set(entries as i64);
+        self.peer_state_evictions
+            .get_or_create(&labels)
+            .inc_by(evicted);
+    }
+
+    /// Maps a model name to its label value, collapsing unknown models into
+    /// [`OTHER_MODEL`].
+    pub fn model_label(&self, model: &str) -> String {
+        if self.allowed_models.contains(model) {
+            model.to_string()
+        } else {
+            OTHER_MODEL.to_string()
+        }
+    }
+
+    pub fn record_request(&self, model: &str, outcome: &str) {
+        self.requests
+            .get_or_create(&RequestLabels {
+                model: self.model_label(model),
+                outcome: outcome.to_string(),
+            })
+            .inc();
+    }
+
+    pub fn observe_latency(&self, model: &str, seconds: f64) {
+        self.inference_latency
+            .get_or_create(&ModelLabels {
+                model: self.model_label(model),
+            })
+            .observe(seconds);
+    }
+
+    /// Records a finished generation's throughput. Durations of zero (e.g.
+    /// from a cached prompt) are skipped rather than counted as infinite.
+    pub fn observe_throughput(&self, model: &str, sample: &Sample) {
+        let labels = ModelLabels {
+            model: self.model_label(model),
+        };
+        if !sample.eval_duration.is_zero() {
+            self.generation_rate
+                .get_or_create(&labels)
+                .observe(sample.completion_tokens as f64 / sample.eval_duration.as_secs_f64());
+        }
+        if !sample.prompt_eval_duration.is_zero() {
+            self.prompt_rate
+                .get_or_create(&labels)
+                .observe(sample.prompt_tokens as f64 / sample.prompt_eval_duration.as_secs_f64());
+        }
+    }
+
+    pub fn observe_queue_wait(&self, model: &str, seconds: f64) {
+        self.queue_wait
+            .get_or_create(&ModelLabels {
+                model: self.model_label(model),
+            })
+            .observe(seconds);
+    }
+
+    pub fn observe_connection_lifetime(&self, seconds: f64) {
+        self.connection_lifetime.observe(seconds);
+    }
+
+    pub fn record_connection_evicted(&self) {
+        self.connections_evicted.inc();
+    }
+
+    /// Records a response cache lookup: `hit`, `joined` for a retry that
+    /// waits on a run in progress, `miss` or `bypass`.
+    pub fn record_cache(&self, outcome: &str) {
+        self.cache_lookups
+            .get_or_create(&OutcomeLabels {
+                outcome: outcome.to_string(),
+            })
+            .inc();
+    }
+
+    pub fn record_autonat_probe(&self, outcome: &str) {
+        self.autonat_probes
+            .get_or_create(&OutcomeLabels {
+                outcome: outcome.to_string(),
+            })
+            .inc();
+    }
+
+    pub fn record_content_filter_matches(&self, target: &str, action: &str, matches: u64) {
+        self.content_filter_matches
+            .get_or_create(&FilterLabels {
+                target: target.to_string(),
+                action: action.to_string(),
+            })
+            .inc_by(matches);
+    }
+
+    pub fn set_open_requests(&self, protocol: &str, inbound: usize, outbound: usize) {
+        for (direction, open) in [("inbound", inbound), ("outbound", outbound)] {
+            self.open_requests
+                .get_or_create(&ChannelLabels {
+                    protocol: protocol.to_string(),
+                    direction: direction.to_string(),
+                })
+                .set(open as i64);
+        }
+    }
+
+    pub fn record_response_dropped(&self, protocol: &str) {
+        self.responses_dropped
+            .get_or_create(&ProtocolLabels {
+                protocol: protocol.to_string(),
+            })
+            .inc();
+    }
+
+    pub fn record_handler_panic(&self, protocol: &str) {
+        self.handler_panics
+            .get_or_create(&ProtocolLabels {
+                protocol: protocol.to_string(),
+            })
+            .inc();
+    }
+
+    pub fn set_result_channel_occupancy(&self, len: usize) {
+        self.result_channel_occupancy.set(len as i64);
+    }
+}
+
+/// Where [`serve`] listens: a TCP address such as `127.0.0.1:9090`, or
+/// `unix:<path>` for a unix domain socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some("") => Err("expected a socket path after `unix:`".to_string()),
+            Some(path) => Ok(Self::Unix(PathBuf::from(path))),
+            None => s
+                .parse()
+                .map(Self::Tcp)
+                .map_err(|e| format!("expected HOST:PORT or unix:PATH, got `{s}`: {e}")),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Where the server passes on the requests it doesn't answer itself. Each is
+/// answered with 404 when missing.
+#[derive(Clone, Default)]
+pub struct Endpoints {
+    /// `GET /state`.
+    pub state: Option<mpsc::Sender<StateRequest>>,
+    /// `GET /usage`, if the node meters usage.
+    pub usage: Option<mpsc::Sender<UsageRequest>>,
+    /// `GET /readyz`, answered with 200 when ready and 503 when not.
+    pub readiness: Option<mpsc::Sender<ReadinessRequest>>,
+    /// `POST /cache/flush`, optionally with `?model=NAME`. Only served over
+    /// a unix socket.
+    pub cache: Option<mpsc::Sender<FlushRequest>>,
+    /// `POST /keys/reload`, if the node has API keys. Only served over a
+    /// unix socket.
+    pub keys: Option<mpsc::Sender<ReloadRequest>>,
+}
+
+impl Endpoints {
+    /// The endpoints served over TCP, without those anyone who can reach the
+    /// port shouldn't be able to call.
+    fn over_tcp(self) -> Self {
+        Self {
+            cache: None,
+            keys: None,
+            ..self
+        }
+    }
+}
+
+/// Serves the registry in the OpenMetrics text format on every request to
+/// `addr`, except those passed on to `endpoints`.
+///
+/// A unix socket is created with the permission bits `socket_mode`, and is
+/// never reachable with any others. A socket left at the path by an earlier
+/// run is replaced; any other file there is an error. Unix sockets are an
+/// error on platforms without them.
+pub async fn serve(
+    addr: ListenAddr,
+    socket_mode: u32,
+    registry: Registry,
+    endpoints: Endpoints,
+) -> io::Result<()> {
+    let registry = Arc::new(registry);
+    match addr {
+        ListenAddr::Tcp(addr) => {
+            let listener = TcpListener::bind(addr).await?;
+            let endpoints = endpoints.over_tcp();
+            loop {
+                let (stream, _) = listener.accept().await?;
+                tokio::spawn(answer(stream, registry.clone(), endpoints.clone()));
+            }
+        }
+        #[cfg(not(unix))]
+        ListenAddr::Unix(_) => {
+            let _ = (socket_mode, endpoints);
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "unix sockets aren't supported on this platform",
+            ))
+        }
+        #[cfg(unix)]
+        ListenAddr::Unix(path) => {
+            let listener = bind_unix(&path, socket_mode)?;
+            loop {
+                let (stream, _) = listener.accept().await?;
+                tokio::spawn(answer(stream, registry.clone(), endpoints.clone()));
+            }
+        }
+    }
+}
+
+/// Binds a unix socket at `path` with permission bits `mode`. The socket is
+/// made in a directory only this user can enter, given its mode there and
+/// only then moved into place, so it is never reachable with the default
+/// permissions, however briefly.
+#[cfg(unix)]
+fn bind_unix(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and isn't a socket", path.display()),
+            ));
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let name = path.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} doesn't name a socket file", path.display()),
+        )
+    })?;
+    let private = path.with_file_name(format!(
+        ".{}.{}.tmp",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    // A leftover from an interrupted start holds nothing worth keeping.
+    let _ = fs::remove_dir_all(&private);
+    fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let staged = private.join(name);
+    let bound = UnixListener::bind(&staged).and_then(|listener| {
+        fs::set_permissions(&staged, fs::Permissions::from_mode(mode))?;
+        fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_dir_all(&private);
+    bound
+}
+
+/// Answers one HTTP request on `stream`.
+async fn answer(
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    registry: Arc<Registry>,
+    endpoints: Endpoints,
+) {
+    let Endpoints {
+        state,
+        usage,
+        readiness,
+        cache,
+        keys,
+    } = endpoints;
+    // Only the path matters, so the rest of the request is discarded.
+    let mut buf = [0u8; 1024];
+    let Ok(read) = timeout(READ_TIMEOUT, stream.read(&mut buf)).await else {
+        return;
+    };
+    let n = read.unwrap_or(0);
+    let wants_state = buf[..n].starts_with(b"GET /state ");
+    let wants_usage = buf[..n].starts_with(b"GET /usage ");
+    let wants_readiness = buf[..n].starts_with(b"GET /readyz ");
+    let wants_reload = buf[..n].starts_with(b"POST /keys/reload ");
+    let flush = flush_target(&buf[..n]);
+
+    let response = match state {
+        _ if wants_reload => match keys {
+            Some(keys) => match reload_keys(&keys).await {
+                Some(Ok(count)) => http_response(
+                    "200 OK",
+                    "application/json",
+                    &format!("{{\"keys\": {count}}}\n"),
+                ),
+                Some(Err(e)) => http_response(
+                    "500 Internal Server Error",
+                    "text/plain; charset=utf-8",
+                    &format!("Failed to reload API keys, keeping the old ones: {e}\n"),
+                ),
+                None => http_response(
+                    "503 Service Unavailable",
+                    "text/plain; charset=utf-8",
+                    "The node didn't answer in time\n",
+                ),
+            },
+            None => http_response(
+                "404 Not Found",
+                "text/plain; charset=utf-8",
+                "API keys aren't managed here\n",
+            ),
+        },
+        _ if flush.is_some() => match cache {
+            Some(cache) => match flush_cache(&cache, flush.flatten()).await {
+                Some(flushed) => http_response(
+                    "200 OK",
+                    "application/json",
+                    &format!("{{\"flushed\": {flushed}}}\n"),
+                ),
+                None => http_response(
+                    "503 Service Unavailable",
+                    "text/plain; charset=utf-8",
+                    "The node didn't answer in time\n",
+                ),
+            },
+            None => http_response(
+                "404 Not Found",
+                "text/plain; charset=utf-8",
+                "The cache isn't managed here\n",
+            ),
+        },
+        _ if wants_readiness => match readiness {
+            Some(readiness) => match node_readiness(&readiness).await {
+                Some(readiness) => {
+                    let Ok(body) = serde_json::to_string_pretty(&readiness) else {
+                        return;
+                    };
+                    let status = if readiness.ready {
+                        "200 OK"
+                    } else {
+                        "503 Service Unavailable"
+                    };
+                    http_response(status, "application/json", &body)
+                }
+                None => http_response(
+                    "503 Service Unavailable",
+                    "text/plain; charset=utf-8",
+                    "The node didn't answer in time\n",
+                ),
+            },
+            None => http_response(
+                "404 Not Found",
+                "text/plain; charset=utf-8",
+                "Readiness isn't served here\n",
+            ),
+        },
+        _ if wants_usage => match usage {
+            Some(usage) => match usage_report(&usage).await {
+                Some(report) => {
+                    let Ok(body) = serde_json::to_string_pretty(&report) else {
+                        return;
+                    };
+                    http_response("200 OK", "application/json", &body)
+                }
+                None => http_response(
+                    "503 Service Unavailable",
+                    "text/plain; charset=utf-8",
+                    "The node didn't answer in time\n",
+                ),
+            },
+            None => http_response(
+                "404 Not Found",
+                "text/plain; charset=utf-8",
+                "Usage metering is off; start the node with --usage-file\n",
+            ),
+        },
+        Some(state) if wants_state => match node_state(&state).await {
+            Some(snapshot) => {
+                let Ok(body) = serde_json::to_string_pretty(&snapshot) else {
+                    return;
+                };
+                http_response("200 OK", "application/json", &body)
+            }
+            None => http_response(
+                "503 Service Unavailable",
+                "text/plain; charset=utf-8",
+                "The node didn't answer in time\n",
+            ),
+        },
+        _ => {
+            let mut body = String::new();
+            if encode(&mut body, &registry).is_err() {
+                return;
+            }
+            http_response(
+                "200 OK",
+                "application/openmetrics-text; version=1.0.0; charset=utf-8",
+                &body,
+            )
+        }
+    };
+    let _ = stream.write_all(response.as_bytes()).await;
+}
+
+/// Asks the swarm loop for a snapshot; `None` if it doesn't answer in time.
+async fn node_state(state: &mpsc::Sender<StateRequest>) -> Option<NodeState> {
+    let (reply, rx) = oneshot::channel();
+    state.send(reply).await.ok()?;
+    timeout(STATE_TIMEOUT, rx).await.ok()?.ok()
+}
+
+/// Asks the swarm loop for its part of the readiness and checks the backend;
+/// `None` if the swarm loop doesn't answer in time.
+async fn node_readiness(readiness: &mpsc::Sender<ReadinessRequest>) -> Option<Readiness> {
+    let (reply, rx) = oneshot::channel();
+    readiness.send(reply).await.ok()?;
+    let node = timeout(STATE_TIMEOUT, rx).await.ok()?.ok()?;
+    Some(readiness::check(node).await)
+}
+
+/// Asks the swarm loop for the usage ledger; `None` if it doesn't answer in
+/// time.
+async fn usage_report(usage: &mpsc::Sender<UsageRequest>) -> Option<UsageReport> {
+    let (reply, rx) = oneshot::channel();
+    usage.send(reply).await.ok()?;
+    timeout(STATE_TIMEOUT, rx).await.ok()?.ok()
+}
+
+/// Asks the swarm loop to drop cached results; `None` if it doesn't answer
+/// in time.
+async fn flush_cache(cache: &mpsc::Sender<FlushRequest>, model: Option<String>) -> Option<usize> {
+    let (reply, rx) = oneshot::channel();
+    cache.send(FlushRequest { model, reply }).await.ok()?;
+    timeout(STATE_TIMEOUT, rx).await.ok()?.ok()
+}
+
+/// Asks the swarm loop to re-read the API keys; `None` if it doesn't answer
+/// in time.
+async fn reload_keys(keys: &mpsc::Sender<ReloadRequest>) -> Option<Result<usize, String>> {
+    let (reply, rx) = oneshot::channel();
+    keys.send(reply).await.ok()?;
+    timeout(STATE_TIMEOUT, rx).await.ok()?.ok()
+}
+
+/// For a `POST /cache/flush` request, the model named by its `model` query
+/// parameter, if any.
+fn flush_target(request: &[u8]) -> Option<Option<String>> {
+    let rest = request.strip_prefix(b"POST /cache/flush")?;
+    let end = rest.iter().position(|b| *b == b' ')?;
+    let query = match &rest[..end] {
+        [] => return Some(None),
+        [b'?', query @ ..] => query,
+        _ => return None,
+    };
+    let model = query
+        .split(|b| *b == b'&')
+        .find_map(|pair| pair.strip_prefix(b"model="))
+        .map(percent_decode)
+        .filter(|model| !model.is_empty());
+    Some(model)
+}
+
+/// Decodes `%XX` escapes and `+` for space, as in a query string.
+fn percent_decode(value: &[u8]) -> String {
+    let mut decoded = Vec::with_capacity(value.len());
+    let mut bytes = value.iter();
+    while let Some(&b) = bytes.next() {
+        match b {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let hex = bytes
+                    .as_slice()
+                    .get(..2)
+                    .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+                match hex {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        bytes.nth(1);
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            b => decoded.push(b),
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn http_response(status: &str, content_type: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn parses_listen_addresses() {
+        assert_eq!(
+            "127.0.0.1:9090".parse(),
+            Ok(ListenAddr::Tcp("127.0.0.1:9090".parse().unwrap()))
+        );
+        assert_eq!(
+            "unix:/run/mesh.sock".parse(),
+            Ok(ListenAddr::Unix(PathBuf::from("/run/mesh.sock")))
+        );
+        assert!("unix:".parse::<ListenAddr>().is_err());
+        assert!("localhost".parse::<ListenAddr>().is_err());
+    }
+
+    /// What the server answers `request` with, given `endpoints`.
+    async fn exchange(request: &str, endpoints: Endpoints) -> String {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let served = tokio::spawn(answer(server, Arc::new(Registry::default()), endpoints));
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        served.await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn hangs_up_on_clients_that_send_nothing() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let served = tokio::spawn(answer(
+            server,
+            Arc::new(Registry::default()),
+            Endpoints::default(),
+        ));
+        timeout(READ_TIMEOUT * 2, served)
+            .await
+            .expect("the connection is given up on")
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert_eq!(response, "");
+    }
+
+    #[tokio::test]
+    async fn reloads_keys_only_where_allowed() {
+        let (keys, mut requests) = mpsc::channel::<ReloadRequest>(1);
+        tokio::spawn(async move {
+            let mut count = 0;
+            while let Some(reply) = requests.recv().await {
+                count += 1;
+                let _ = reply.send(if count == 1 {
+                    Ok(3)
+                } else {
+                    Err("bad line".to_string())
+                });
+            }
+        });
+        let endpoints = Endpoints {
+            keys: Some(keys),
+            ..Endpoints::default()
+        };
+        let request = "POST /keys/reload HTTP/1.1\r\n\r\n";
+
+        let reloaded = exchange(request, endpoints.clone()).await;
+        assert!(reloaded.starts_with("HTTP/1.1 200 OK"), "{reloaded}");
+        assert!(reloaded.ends_with("{\"keys\": 3}\n"), "{reloaded}");
+        let failed = exchange(request, endpoints.clone()).await;
+        assert!(failed.starts_with("HTTP/1.1 500"), "{failed}");
+        assert!(failed.contains("bad line"), "{failed}");
+
+        let over_tcp = exchange(request, endpoints.over_tcp()).await;
+        assert!(over_tcp.starts_with("HTTP/1.1 404"), "{over_tcp}");
+    }
+
+    #[tokio::test]
+    async fn flushes_the_cache_only_where_allowed() {
+        let (cache, mut requests) = mpsc::channel::<FlushRequest>(1);
+        tokio::spawn(async move {
+            while let Some(flush) = requests.recv().await {
+                let flushed = if flush.model.as_deref() == Some("llama3") {
+                    2
+                } else {
+                    5
+                };
+                let _ = flush.reply.send(flushed);
+            }
+        });
+        let endpoints = Endpoints {
+            cache: Some(cache),
+            ..Endpoints::default()
+        };
+
+        let all = exchange("POST /cache/flush HTTP/1.1\r\n\r\n", endpoints.clone()).await;
+        assert!(all.ends_with("{\"flushed\": 5}\n"), "{all}");
+        let model = "POST /cache/flush?model=llama3 HTTP/1.1\r\n\r\n";
+        let one = exchange(model, endpoints.clone()).await;
+        assert!(one.ends_with("{\"flushed\": 2}\n"), "{one}");
+
+        let over_tcp = exchange(model, endpoints.over_tcp()).await;
+        assert!(over_tcp.starts_with("HTTP/1.1 404"), "{over_tcp}");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_sockets_get_their_mode_and_leave_nothing_behind() {
+        let dir = TempDir::new("metrics");
+        let path = dir.join("metrics.sock");
+
+        let listener = bind_unix(&path, 0o600).unwrap();
+        let meta = fs::symlink_metadata(&path).unwrap();
+        assert!(meta.file_type().is_socket());
+        assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+        assert_eq!(
+            fs::read_dir(dir.path()).unwrap().count(),
+            1,
+            "staging directory left behind"
+        );
+        tokio::net::UnixStream::connect(&path).await.unwrap();
+
+        // A socket from an earlier run is replaced; other files are kept.
+        drop(listener);
+        bind_unix(&path, 0o660).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+        let file = dir.join("not-a-socket");
+        fs::write(&file, "keep").unwrap();
+        assert_eq!(
+            bind_unix(&file, 0o600).unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        assert_eq!(fs::read_to_string(&file).unwrap(), "keep");
+    }
+}
@@ -0,0 +1,433 @@
+//! Hooks around prompt handling.
+//!
+//! A [`Chain`] of [`Middleware`]s runs on the job's task for every admitted
+//! prompt, from the prompt protocol, the streaming protocol or either half of
+//! a comparison. The backend call sits at the end. Each middleware gets the
+//! request and a [`Next`] to hand it on with. It can rewrite the request on
+//! the way in and the [`Outcome`] on the way out, or answer by itself without
+//! calling `next`, which skips the rest of the chain and the backend.
+//! Middlewares run in the order they were added.
+//!
+//! Admission (bans, quotas, the model allowlist, load shedding) happens
+//! earlier, in the swarm loop, since it decides whether a job gets queued at
+//! all. Middlewares only see requests that got through. Checks that need
+//! nothing but the request, such as [`CheckImages`], are middlewares, so
+//! every kind of prompt gets them.
+//!
+//! For streamed prompts the text has already gone out chunk by chunk when
+//! the outcome comes back, so middlewares see an empty `response` there. A
+//! middleware that needs to see the text as it goes out provides a
+//! [`ChunkFilter`] instead.
+
+use std::{panic::AssertUnwindSafe, sync::Arc, time::Instant};
+
+use async_trait::async_trait;
+use futures::{FutureExt, future::BoxFuture};
+use libp2p::PeerId;
+
+use crate::{PromptRequest, PromptResponse, ResponseStatus, metrics::Metrics, perf::Sample};
+
+/// A prompt on its way to the backend.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub peer: PeerId,
+    /// The model the scheduler assigned; changing it has no effect.
+    pub model: String,
+    pub prompt: PromptRequest,
+    /// Set once the prompt is in the model's chat template, so the backend
+    /// takes it as is.
+    pub raw: bool,
+}
+
+/// What came of a prompt.
+#[derive(Debug, Clone)]
+pub struct Outcome {
+    pub response: PromptResponse,
+    /// Completion tokens charged to the peer's quota.
+    pub completion_tokens: u64,
+    /// Performance figures, when the backend answered.
+    pub sample: Option<Sample>,
+    /// The backend failed to answer, as opposed to the node or a middleware
+    /// turning the request down. Only such failures get the node's fallback
+    /// text.
+    pub backend_failed: bool,
+}
+
+impl Outcome {
+    /// An answer that didn't involve the backend, e.g. from a middleware
+    /// that turned the request down.
+    pub fn answered(response: PromptResponse) -> Self {
+        Self {
+            response,
+            completion_tokens: 0,
+            sample: None,
+            backend_failed: false,
+        }
+    }
+
+    /// A failure of the backend itself.
+    pub fn backend_failed(response: PromptResponse) -> Self {
+        Self {
+            backend_failed: true,
+            ..Self::answered(response)
+        }
+    }
+}
+
+#[async_trait]
+pub trait Middleware: Send + Sync {
+    async fn handle(&self, request: Request, next: Next<'_>) -> Outcome;
+
+    /// What the chunks of a streamed answer to `request` pass through on
+    /// their way to the peer, if this middleware looks at answers.
+    fn chunk_filter(&self, _request: &Request) -> Option<Box<dyn ChunkFilter>> {
+        None
+    }
+}
+
+/// Looks at a streamed answer piece by piece before it goes out. It may hold
+/// text back, e.g. up to a word boundary, as long as [`ChunkFilter::finish`]
+/// releases it.
+pub trait ChunkFilter: Send {
+    /// Takes the next piece of the answer. Returns the text that may go out
+    /// now, or the response to end the stream with instead.
+    fn chunk(&mut self, text: &str) -> Result<String, Box<PromptResponse>>;
+
+    /// Releases whatever was held back, once the answer is complete.
+    fn finish(&mut self) -> Result<String, Box<PromptResponse>>;
+}
+
+/// The rest of the chain, backend included.
+pub struct Next<'a> {
+    rest: &'a [Arc<dyn Middleware>],
+    endpoint: Box<dyn FnOnce(Request) -> BoxFuture<'a, Outcome> + Send + 'a>,
+}
+
+impl Next<'_> {
+    pub async fn run(self, request: Request) -> Outcome {
+        match self.rest.split_first() {
+            Some((middleware, rest)) => {
+                let next = Next {
+                    rest,
+                    endpoint: self.endpoint,
+                };
+                middleware.handle(request, next).await
+            }
+            None => (self.endpoint)(request).await,
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Chain {
+    middlewares: Vec<Arc<dyn Middleware>>,
+}
+
+impl Chain {
+    /// Adds `middleware` after those already in the chain, i.e. closer to
+    /// the backend.
+    pub fn push(&mut self, middleware: impl Middleware + 'static) {
+        self.middlewares.push(Arc::new(middleware));
+    }
+
+    pub fn with(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.push(middleware);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.middlewares.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.middlewares.is_empty()
+    }
+
+    /// The chunk filters for a streamed answer to `request`, closest to the
+    /// backend first, the order outcomes pass the middlewares in.
+    pub fn chunk_filters(&self, request: &Request) -> Vec<Box<dyn ChunkFilter>> {
+        self.middlewares
+            .iter()
+            .rev()
+            .filter_map(|middleware| middleware.chunk_filter(request))
+            .collect()
+    }
+
+    /// Runs `request` through the chain, with `endpoint` at the end.
+    pub async fn run<'a, F>(&'a self, request: Request, endpoint: F) -> Outcome
+    where
+        F: FnOnce(Request) -> BoxFuture<'a, Outcome> + Send + 'a,
+    {
+        Next {
+            rest: &self.middlewares,
+            endpoint: Box::new(endpoint),
+        }
+        .run(request)
+        .await
+    }
+}
+
+/// Runs `handle`, answering with `on_panic` of an `Internal` response if it
+/// panics, so the request still gets an answer and its scheduler slot is
+/// freed. What the panic said stays in the node's logs: it may name paths or
+/// other internals the peer has no business seeing.
+pub async fn catch_panics<T>(
+    handle: impl Future<Output = T>,
+    kind: &str,
+    metrics: &Metrics,
+    on_panic: impl FnOnce(PromptResponse) -> T,
+) -> T {
+    match AssertUnwindSafe(handle).catch_unwind().await {
+        Ok(answer) => answer,
+        Err(panic) => {
+            metrics.record_handler_panic(kind);
+            let message = panic
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string());
+            tracing::error!(kind, panic = %message, "Request handler panicked");
+            on_panic(PromptResponse::internal(
+                "The node failed while serving the request".to_string(),
+            ))
+        }
+    }
+}
+
+/// Logs one line per prompt: who asked, which model, how it went and how
+/// long the rest of the chain took.
+pub struct RequestLogger;
+
+#[async_trait]
+impl Middleware for RequestLogger {
+    async fn handle(&self, request: Request, next: Next<'_>) -> Outcome {
+        let peer = request.peer;
+        let model = request.model.clone();
+        let prompt_chars = request.prompt.prompt.chars().count();
+        let started = Instant::now();
+        let outcome = next.run(request).await;
+        tracing::info!(
+            "{peer} asked {model} ({prompt_chars} chars): {:?}, {} completion tokens in {:?}",
+            outcome.response.status,
+            outcome.completion_tokens,
+            started.elapsed()
+        );
+        outcome
+    }
+}
+
+/// Refuses prompts whose images add up to more than
+/// [`MAX_IMAGE_BYTES`](crate::MAX_IMAGE_BYTES).
+pub struct CheckImages;
+
+#[async_trait]
+impl Middleware for CheckImages {
+    async fn handle(&self, request: Request, next: Next<'_>) -> Outcome {
+        match request.prompt.check_images() {
+            Ok(()) => next.run(request).await,
+            Err(reason) => Outcome::answered(PromptResponse::error(reason)),
+        }
+    }
+}
+
+/// Cuts successful answers down to their first `max` words. Answers in a
+/// requested `format` are left whole, since cutting them would break it.
+pub struct MaxWords(pub usize);
+
+#[async_trait]
+impl Middleware for MaxWords {
+    async fn handle(&self, request: Request, next: Next<'_>) -> Outcome {
+        let formatted = request.prompt.format.is_some();
+        let mut outcome = next.run(request).await;
+        if !formatted
+            && outcome.response.status == ResponseStatus::Ok
+            && let Some(end) = nth_word_start(&outcome.response.response, self.0)
+        {
+            let response = &mut outcome.response.response;
+            response.truncate(end);
+            response.truncate(response.trim_end().len());
+        }
+        outcome
+    }
+}
+
+/// Byte offset of word `n` (counting from 0) in `text`, if it has that many.
+fn nth_word_start(text: &str, n: usize) -> Option<usize> {
+    let mut words = 0;
+    let mut in_word = false;
+    for (i, c) in text.char_indices() {
+        if c.is_whitespace() {
+            in_word = false;
+        } else if !in_word {
+            if words == n {
+                return Some(i);
+            }
+            words += 1;
+            in_word = true;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use futures::FutureExt;
+
+    use super::*;
+
+    fn request(json: serde_json::Value) -> Request {
+        Request {
+            peer: PeerId::random(),
+            model: "llama3".to_string(),
+            prompt: serde_json::from_value(json).unwrap(),
+            raw: false,
+        }
+    }
+
+    /// Records when the request passes it on the way in and out.
+    struct Trace(&'static str, Arc<Mutex<Vec<String>>>);
+
+    #[async_trait]
+    impl Middleware for Trace {
+        async fn handle(&self, request: Request, next: Next<'_>) -> Outcome {
+            self.1.lock().unwrap().push(format!("{} in", self.0));
+            let outcome = next.run(request).await;
+            self.1.lock().unwrap().push(format!("{} out", self.0));
+            outcome
+        }
+    }
+
+    /// Answers by itself.
+    struct Refuse;
+
+    #[async_trait]
+    impl Middleware for Refuse {
+        async fn handle(&self, _: Request, _: Next<'_>) -> Outcome {
+            Outcome::answered(PromptResponse::error("refused".to_string()))
+        }
+    }
+
+    async fn run(
+        chain: &Chain,
+        request: Request,
+        answer: &str,
+        calls: &Mutex<Vec<String>>,
+    ) -> Outcome {
+        chain
+            .run(request, |_| {
+                calls.lock().unwrap().push("backend".to_string());
+                let response = PromptResponse::ok(answer.to_string());
+                async move { Outcome::answered(response) }.boxed()
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn middlewares_run_in_the_order_they_were_added() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let chain = Chain::default()
+            .with(Trace("a", calls.clone()))
+            .with(Trace("b", calls.clone()));
+        let outcome = run(
+            &chain,
+            request(serde_json::json!({"prompt": "hi"})),
+            "hello",
+            &calls,
+        )
+        .await;
+        assert_eq!(outcome.response.response, "hello");
+        assert_eq!(
+            *calls.lock().unwrap(),
+            ["a in", "b in", "backend", "b out", "a out"]
+        );
+    }
+
+    #[tokio::test]
+    async fn answering_skips_the_rest_of_the_chain() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let chain = Chain::default()
+            .with(Trace("a", calls.clone()))
+            .with(Refuse)
+            .with(Trace("b", calls.clone()));
+        let outcome = run(
+            &chain,
+            request(serde_json::json!({"prompt": "hi"})),
+            "hello",
+            &calls,
+        )
+        .await;
+        assert_eq!(outcome.response.status, ResponseStatus::Error);
+        assert_eq!(outcome.response.response, "refused");
+        assert_eq!(*calls.lock().unwrap(), ["a in", "a out"]);
+    }
+
+    #[tokio::test]
+    async fn oversized_images_never_reach_the_backend() {
+        let calls = Mutex::new(Vec::new());
+        let chain = Chain::default().with(CheckImages);
+        let image = vec![0u8; crate::MAX_IMAGE_BYTES + 1];
+        let mut oversized = request(serde_json::json!({"prompt": "hi"}));
+        oversized.prompt.images = Some(vec![image]);
+        let outcome = run(&chain, oversized, "hello", &calls).await;
+        assert_eq!(outcome.response.status, ResponseStatus::Error);
+        assert!(calls.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn max_words_cuts_plain_answers_only() {
+        let calls = Mutex::new(Vec::new());
+        let chain = Chain::default().with(MaxWords(2));
+        let plain = request(serde_json::json!({"prompt": "hi"}));
+        let outcome = run(&chain, plain, "one two  three four", &calls).await;
+        assert_eq!(outcome.response.response, "one two");
+
+        let json = r#"{"a": 1, "b": 2}"#;
+        let formatted = request(serde_json::json!({"prompt": "hi", "format": "json"}));
+        let outcome = run(&chain, formatted, json, &calls).await;
+        assert_eq!(outcome.response.response, json);
+    }
+
+    /// Panics with internals in the message.
+    struct Panic;
+
+    #[async_trait]
+    impl Middleware for Panic {
+        async fn handle(&self, _: Request, _: Next<'_>) -> Outcome {
+            panic!("failed to open /var/lib/secret/state.db");
+        }
+    }
+
+    #[tokio::test]
+    async fn panics_are_answered_without_their_message() {
+        let mut registry = prometheus_client::registry::Registry::default();
+        let metrics = Metrics::new(&mut registry, Default::default());
+        let calls = Mutex::new(Vec::new());
+        let chain = Chain::default().with(Panic);
+        let handle = run(
+            &chain,
+            request(serde_json::json!({"prompt": "hi"})),
+            "",
+            &calls,
+        );
+        let outcome = catch_panics(handle, "prompt", &metrics, Outcome::answered).await;
+        assert_eq!(outcome.response.status, ResponseStatus::Internal);
+        assert!(!outcome.response.response.contains("secret"), "{outcome:?}");
+
+        let mut text = String::new();
+        prometheus_client::encoding::text::encode(&mut text, &registry).unwrap();
+        assert!(
+            text.contains(r#"mesh_ai_handler_panics_total{protocol="prompt"} 1"#),
+            "{text}"
+        );
+    }
+
+    #[test]
+    fn finds_the_start_of_the_nth_word() {
+        assert_eq!(nth_word_start("  one two three", 0), Some(2));
+        assert_eq!(nth_word_start("one\ttwo\nthree", 2), Some(8));
+        assert_eq!(nth_word_start("one two", 2), None);
+        assert_eq!(nth_word_start("", 0), None);
+    }
+}
@@ -0,0 +1,220 @@
+//! A stand-in for Ollama's HTTP API, for exercising the backend without a
+//! real model.
+//!
+//! Each path answers with a [`MockReply`], which can be swapped at any time.
+//! Point the backend at [`MockOllama::url`] with [`crate::ollama::init`].
+
+#[cfg(unix)]
+use std::path::Path;
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde_json::{Value, json};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+};
+
+#[derive(Debug, Clone)]
+pub enum MockReply {
+    /// 200 with a JSON body.
+    Json(Value),
+    /// An empty response with this status, e.g. 500.
+    Status(u16),
+    /// 200 with a body that isn't JSON.
+    Malformed,
+    /// Waits before replying, to trigger timeouts.
+    Delay(Duration, Box<MockReply>),
+    /// 200 with newline-delimited JSON chunks, sent `interval` apart, the way
+    /// Ollama streams.
+    Stream {
+        chunks: Vec<Value>,
+        interval: Duration,
+    },
+}
+
+pub struct MockOllama {
+    url: String,
+    routes: Arc<Mutex<HashMap<String, MockReply>>>,
+}
+
+impl MockOllama {
+    /// Starts the server on a free local port with canned replies for
+    /// `/api/generate`, `/api/chat`, `/api/tags`, `/api/embed` and
+    /// `/api/embeddings`. Unknown paths get a 404.
+    pub async fn start() -> io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let routes = Arc::new(Mutex::new(default_routes()));
+        let shared = routes.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, shared.clone()));
+            }
+        });
+        Ok(Self {
+            url: format!("http://{addr}"),
+            routes,
+        })
+    }
+
+    /// Like [`MockOllama::start`], but listening on a Unix socket at
+    /// `path`, as `unix://` backend URLs expect.
+    #[cfg(unix)]
+    pub async fn start_unix(path: &Path) -> io::Result<Self> {
+        let listener = UnixListener::bind(path)?;
+        let routes = Arc::new(Mutex::new(default_routes()));
+        let shared = routes.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, shared.clone()));
+            }
+        });
+        Ok(Self {
+            url: format!("unix://{}", path.display()),
+            routes,
+        })
+    }
+
+    pub fn url(&self) -> String {
+        self.url.clone()
+    }
+
+    /// Replaces the reply for `path`.
+    pub fn set(&self, path: &str, reply: MockReply) {
+        self.routes.lock().unwrap().insert(path.to_string(), reply);
+    }
+}
+
+fn default_routes() -> HashMap<String, MockReply> {
+    let routes = [
+        (
+            "/api/generate",
+            json!({
+                "model": "mock",
+                "response": "2",
+                "done": true,
+                "prompt_eval_count": 5,
+                "prompt_eval_duration": 1_000_000,
+                "eval_count": 1,
+                "eval_duration": 1_000_000,
+                "load_duration": 0,
+            }),
+        ),
+        (
+            "/api/chat",
+            json!({
+                "model": "mock",
+                "message": { "role": "assistant", "content": "2" },
+                "done": true,
+                "eval_count": 1,
+            }),
+        ),
+        ("/api/tags", json!({ "models": [{ "name": "mock" }] })),
+        (
+            "/api/embed",
+            json!({ "model": "mock", "embeddings": [[1.0, 0.0], [0.0, 1.0]] }),
+        ),
+        ("/api/embeddings", json!({ "embedding": [1.0, 0.0] })),
+    ];
+    routes
+        .into_iter()
+        .map(|(path, body)| (path.to_string(), MockReply::Json(body)))
+        .collect()
+}
+
+async fn serve(
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    routes: Arc<Mutex<HashMap<String, MockReply>>>,
+) {
+    // One request per connection: read the head, then as much body as it
+    // announces, which is discarded.
+    let mut request = Vec::new();
+    let mut buf = [0u8; 4096];
+    let head_end = loop {
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => request.extend_from_slice(&buf[..n]),
+        }
+        if let Some(pos) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+    };
+    let head = String::from_utf8_lossy(&request[..head_end]).to_string();
+    let content_length: usize = head
+        .lines()
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.eq_ignore_ascii_case("content-length")
+                .then(|| value.trim().parse().ok())?
+        })
+        .unwrap_or_default();
+    let mut remaining = content_length.saturating_sub(request.len() - head_end);
+    while remaining > 0 {
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => remaining = remaining.saturating_sub(n),
+        }
+    }
+
+    let path = head.split_whitespace().nth(1).unwrap_or("/").to_string();
+    let reply = routes
+        .lock()
+        .unwrap()
+        .get(&path)
+        .cloned()
+        .unwrap_or(MockReply::Status(404));
+    let _ = respond(&mut stream, reply).await;
+}
+
+async fn respond(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    mut reply: MockReply,
+) -> io::Result<()> {
+    while let MockReply::Delay(delay, inner) = reply {
+        tokio::time::sleep(delay).await;
+        reply = *inner;
+    }
+    match reply {
+        MockReply::Json(body) => write_body(stream, 200, &body.to_string()).await,
+        MockReply::Status(status) => write_body(stream, status, "").await,
+        MockReply::Malformed => write_body(stream, 200, "{not json").await,
+        MockReply::Stream { chunks, interval } => {
+            stream
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n",
+                )
+                .await?;
+            for (i, chunk) in chunks.iter().enumerate() {
+                if i > 0 {
+                    tokio::time::sleep(interval).await;
+                }
+                let line = format!("{chunk}\n");
+                stream
+                    .write_all(format!("{:x}\r\n{line}\r\n", line.len()).as_bytes())
+                    .await?;
+                stream.flush().await?;
+            }
+            stream.write_all(b"0\r\n\r\n").await
+        }
+        MockReply::Delay(..) => unreachable!("delays are unwrapped above"),
+    }
+}
+
+async fn write_body(
+    stream: &mut (impl AsyncWrite + Unpin),
+    status: u16,
+    body: &str,
+) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {status} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await
+}
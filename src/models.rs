@@ -0,0 +1,43 @@
+//! Resolution of which model serves a request.
+
+use std::collections::HashMap;
+
+use libp2p::PeerId;
+
+/// Default model selection, optionally varied by peer class.
+///
+/// Peers are grouped by tag (e.g. `cheap`, `premium`) and each tag may carry
+/// its own default model. Requests that name a model explicitly bypass this.
+#[derive(Debug, Clone)]
+pub struct ModelAssignments {
+    default_model: String,
+    peer_tags: HashMap<PeerId, String>,
+    tag_models: HashMap<String, String>,
+}
+
+impl ModelAssignments {
+    pub fn new(
+        default_model: String,
+        peer_tags: impl IntoIterator<Item = (PeerId, String)>,
+        tag_models: impl IntoIterator<Item = (String, String)>,
+    ) -> Self {
+        Self {
+            default_model,
+            peer_tags: peer_tags.into_iter().collect(),
+            tag_models: tag_models.into_iter().collect(),
+        }
+    }
+
+    /// The model to use for `peer` when its request doesn't name one.
+    pub fn default_for(&self, peer: &PeerId) -> &str {
+        self.peer_tags
+            .get(peer)
+            .and_then(|tag| self.tag_models.get(tag))
+            .unwrap_or(&self.default_model)
+    }
+
+    /// Every model some peer may be defaulted to.
+    pub fn models(&self) -> impl Iterator<Item = &String> {
+        std::iter::once(&self.default_model).chain(self.tag_models.values())
+    }
+}
@@ -0,0 +1,624 @@
+//! Swarm construction for a mesh node.
+
+use std::{error::Error, fmt, num::NonZeroU8, time::Duration};
+
+use futures::future::Either;
+use sha2::{Digest, Sha256};
+
+use libp2p::{
+    Multiaddr, PeerId, StreamProtocol, Swarm, allow_block_list, autonat, connection_limits,
+    core::{
+        Transport,
+        muxing::StreamMuxerBox,
+        transport::{Boxed, MemoryTransport, timeout::TransportTimeout},
+        upgrade,
+    },
+    dcutr,
+    dns::{ResolverConfig, ResolverOpts},
+    gossipsub, identify,
+    identity::{Keypair, PublicKey},
+    noise, ping, relay,
+    request_response::{self, ProtocolSupport},
+    swarm::{NetworkBehaviour, behaviour::toggle::Toggle},
+    tcp, upnp, yamux,
+};
+
+use crate::{
+    CompareRequest, CompareResponse, RerankRequest, RerankResponse,
+    discovery::DiscoveryConfig,
+    estimate::{EstimateRequest, EstimateResponse},
+    feedback::{Feedback, FeedbackAck},
+    labels::Labels,
+    pex::{PexRequest, PexResponse},
+    pin,
+    profile::{self, Profile},
+    proto::MeshCodec,
+    telemetry::{self, TelemetryMode},
+};
+
+pub const PROTOCOL_NAME: &str = "/mesh-ai/1.0.0";
+/// Versions of the prompt protocol this build speaks. Must match
+/// [`PROTOCOL_NAME`] and [`V2_PROTOCOL_NAME`].
+pub const PROTOCOL_VERSIONS: &[&str] = &["1.0.0", "2.0.0"];
+/// The prompt protocol encoded as protobuf rather than CBOR. See [`crate::proto`].
+pub const PROTO_PROTOCOL_NAME: &str = "/mesh-ai/1.0.0+proto";
+/// Version 2.0.0 of the prompt protocol, with its own message shapes. See
+/// [`crate::proto::v2`].
+pub const V2_PROTOCOL_NAME: &str = "/mesh-ai/2.0.0";
+pub const RERANK_PROTOCOL_NAME: &str = "/mesh-ai/rerank/1.0.0";
+pub const PEX_PROTOCOL_NAME: &str = "/mesh-ai/pex/1.0.0";
+pub const FEEDBACK_PROTOCOL_NAME: &str = "/mesh-ai/feedback/1.0.0";
+pub const COMPARE_PROTOCOL_NAME: &str = "/mesh-ai/compare/1.0.0";
+pub const ESTIMATE_PROTOCOL_NAME: &str = "/mesh-ai/estimate/1.0.0";
+pub const STREAM_PROTOCOL_NAME: &str = "/mesh-ai/stream/1.0.0";
+
+/// Marks the model list in the identify agent version, e.g.
+/// `mesh-ai-node/0.1.0 models=llama3:8b,phi3:mini`.
+const MODELS_MARKER: &str = " models=";
+/// Keys of the profile fields in the identify agent version. Values are
+/// escaped, and the fields come before the model list.
+const NICKNAME_KEY: &str = "nickname=";
+const CONTACT_KEY: &str = "contact=";
+/// Key of the label list, e.g. `labels=region=eu-west,tier=gpu`, with each
+/// value escaped. Comes before the model list too.
+const LABELS_KEY: &str = "labels=";
+/// Key of the list of models that take tools, as names or `prefix*`
+/// patterns, e.g. `tools=llama3.1*,qwen2.5:7b`. Comes before the model list.
+const TOOLS_KEY: &str = "tools=";
+/// Longest agent kept from a peer's agent version, in characters.
+const MAX_AGENT_CHARS: usize = 64;
+
+/// AutoNAT dial-backs served to any one peer per [`AUTONAT_THROTTLE_PERIOD`].
+const AUTONAT_PEER_MAX: usize = 3;
+/// AutoNAT dial-backs served in total per [`AUTONAT_THROTTLE_PERIOD`].
+const AUTONAT_GLOBAL_MAX: usize = 30;
+const AUTONAT_THROTTLE_PERIOD: Duration = Duration::from_secs(60);
+/// Wait before a probing node's first AutoNAT probe, so it has connections
+/// to probe through.
+const AUTONAT_BOOT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(NetworkBehaviour)]
+pub struct Behaviour {
+    pub ping: ping::Behaviour,
+    pub request_response: request_response::Behaviour<MeshCodec>,
+    pub rerank: request_response::cbor::Behaviour<RerankRequest, RerankResponse>,
+    pub pex: request_response::cbor::Behaviour<PexRequest, PexResponse>,
+    pub feedback: request_response::cbor::Behaviour<Feedback, FeedbackAck>,
+    pub compare: request_response::cbor::Behaviour<CompareRequest, CompareResponse>,
+    pub estimate: request_response::cbor::Behaviour<EstimateRequest, EstimateResponse>,
+    pub relay: relay::client::Behaviour,
+    pub identify: identify::Behaviour,
+    pub dcutr: dcutr::Behaviour,
+    pub upnp: upnp::tokio::Behaviour,
+    pub pin: pin::Behaviour,
+    pub blocked: allow_block_list::Behaviour<allow_block_list::BlockedPeers>,
+    /// Refuses connections beyond [`NodeConfig::max_incoming_connections`].
+    /// See [`crate::eviction`] for how room is made again.
+    pub limits: connection_limits::Behaviour,
+    pub autonat: Toggle<autonat::Behaviour>,
+    /// Raw streams, for protocols that don't fit request-response.
+    pub stream: libp2p_stream::Behaviour,
+    /// The telemetry topic, when [`NodeConfig::telemetry`] isn't off. See
+    /// [`crate::telemetry`].
+    pub telemetry: Toggle<gossipsub::Behaviour>,
+}
+
+/// A transport the swarm can run over. A node may run over several; see
+/// [`NodeConfig::transports`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TransportKind {
+    /// TCP sockets; what real deployments use.
+    #[default]
+    Tcp,
+    /// In-process `/memory/<n>` addresses with no OS sockets, for tests.
+    Memory,
+}
+
+impl TransportKind {
+    /// The address a node listens on when nothing more specific is configured.
+    pub fn default_listen_addr(self) -> Multiaddr {
+        match self {
+            TransportKind::Tcp => "/ip4/0.0.0.0/tcp/0".parse().unwrap(),
+            TransportKind::Memory => "/memory/0".parse().unwrap(),
+        }
+    }
+}
+
+/// Where `/dns4`, `/dns6` and `/dnsaddr` addresses are resolved. Only used
+/// when TCP is among the transports.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum DnsResolver {
+    /// The nameservers in `/etc/resolv.conf`.
+    #[default]
+    System,
+    Cloudflare,
+    Google,
+    Quad9,
+}
+
+#[derive(Debug, Clone)]
+pub struct NodeConfig {
+    /// The transports the swarm runs over, e.g. TCP, or only memory for
+    /// tests. At least one is needed.
+    pub transports: Vec<TransportKind>,
+    pub dns_resolver: DnsResolver,
+    /// Peers whose connections are kept open regardless of `idle_timeout`.
+    pub pinned_peers: Vec<PeerId>,
+    pub idle_timeout: Duration,
+    /// How long a dial may take to reach the peer, before the handshakes.
+    pub dial_timeout: Duration,
+    /// How long the noise and yamux handshakes, and the protocol
+    /// negotiation before each, may take on a new connection, in either
+    /// direction. Slow or lossy links may need more than the default.
+    pub handshake_timeout: Duration,
+    pub dial_concurrency: NonZeroU8,
+    /// How often identify is repeated, and how long its records are kept.
+    pub discovery: DiscoveryConfig,
+    /// How long a request may go unanswered before the request-response
+    /// protocol gives up on it. Inference can be slow, so this is generous.
+    pub request_timeout: Duration,
+    /// Models advertised to peers through identify.
+    pub announced_models: Vec<String>,
+    /// Models that take tools, as names or `prefix*` patterns, advertised
+    /// through identify. See [`crate::tools`].
+    pub tool_models: Vec<String>,
+    /// Nickname and operator contact advertised through identify.
+    pub profile: Profile,
+    /// Labels advertised through identify, for clients selecting workers.
+    pub labels: Labels,
+    /// Whether to accept rerank requests. Nodes that don't can still send
+    /// them, but don't advertise the protocol, so clients skip them.
+    pub serve_rerank: bool,
+    /// Answer other peers' AutoNAT probes by dialing them back. Meant for
+    /// publicly reachable nodes such as relays and gateways.
+    pub autonat_server: bool,
+    /// Ask connected peers to dial us back, to learn whether this node is
+    /// publicly reachable. Reported as [`autonat::Event::StatusChanged`].
+    pub autonat_client: bool,
+    /// Let AutoNAT probe and serve peers at private IP addresses, for a mesh
+    /// on one LAN, where those are as reachable as it gets. Probes need an
+    /// IP to dial back, so memory-transport peers are never served.
+    pub autonat_private_ips: bool,
+    /// Whether to answer peer exchange requests. Like reranking, the protocol
+    /// is only advertised when served.
+    pub serve_pex: bool,
+    /// Most connections accepted at once, or `None` for no limit. Beyond
+    /// it, connections are refused until idle ones are closed.
+    pub max_incoming_connections: Option<u32>,
+    /// Whether to join the telemetry topic. Nodes that publish join it too,
+    /// so reports reach watchers they aren't connected to.
+    pub telemetry: TelemetryMode,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        Self {
+            transports: vec![TransportKind::default()],
+            dns_resolver: DnsResolver::default(),
+            pinned_peers: Vec::new(),
+            idle_timeout: Duration::from_secs(60),
+            dial_timeout: Duration::from_secs(5),
+            handshake_timeout: Duration::from_secs(10),
+            dial_concurrency: NonZeroU8::new(8).unwrap(),
+            discovery: DiscoveryConfig::default(),
+            request_timeout: Duration::from_secs(300),
+            announced_models: Vec::new(),
+            tool_models: Vec::new(),
+            profile: Profile::default(),
+            labels: Labels::default(),
+            serve_rerank: false,
+            autonat_server: false,
+            autonat_client: false,
+            autonat_private_ips: false,
+            serve_pex: false,
+            max_incoming_connections: None,
+            telemetry: TelemetryMode::Off,
+        }
+    }
+}
+
+/// The identify agent version announcing `models`, those of them that take
+/// tools, `profile` and `labels`, e.g.
+/// `mesh-ai-node/0.1.0 nickname=gpu%20box labels=tier=gpu tools=llama3* models=llama3:8b`.
+pub fn agent_version(
+    models: &[String],
+    tool_models: &[String],
+    profile: &Profile,
+    labels: &Labels,
+) -> String {
+    let mut version = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")).to_string();
+    if let Some(nickname) = &profile.nickname {
+        version.push_str(&format!(" {NICKNAME_KEY}{}", profile::escape(nickname)));
+    }
+    if let Some(contact) = &profile.operator_contact {
+        version.push_str(&format!(" {CONTACT_KEY}{}", profile::escape(contact)));
+    }
+    if !labels.is_empty() {
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(key, value)| format!("{key}={}", profile::escape(value)))
+            .collect();
+        version.push_str(&format!(" {LABELS_KEY}{}", labels.join(",")));
+    }
+    if !tool_models.is_empty() {
+        let tool_models: Vec<String> = tool_models.iter().map(|m| profile::escape(m)).collect();
+        version.push_str(&format!(" {TOOLS_KEY}{}", tool_models.join(",")));
+    }
+    if !models.is_empty() {
+        version.push_str(&format!("{MODELS_MARKER}{}", models.join(",")));
+    }
+    version
+}
+
+/// The software a peer announced in its identify agent version, e.g.
+/// `mesh-ai-node/0.1.0`, without the fields after it. Sanitized.
+pub fn announced_agent(agent_version: &str) -> Option<String> {
+    let agent = agent_version.split(' ').next().unwrap_or_default();
+    profile::sanitize(agent, MAX_AGENT_CHARS)
+}
+
+/// The profile a peer announced in its identify agent version, sanitized.
+pub fn announced_profile(agent_version: &str) -> Profile {
+    let field = |key| announced_field(agent_version, key).map(profile::unescape);
+    Profile::new(
+        field(NICKNAME_KEY).as_deref(),
+        field(CONTACT_KEY).as_deref(),
+    )
+}
+
+/// The labels a peer announced in its identify agent version. Invalid ones
+/// are dropped.
+pub fn announced_labels(agent_version: &str) -> Labels {
+    let Some(field) = announced_field(agent_version, LABELS_KEY) else {
+        return Labels::default();
+    };
+    let pairs: Vec<(&str, String)> = field
+        .split(',')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| (key, profile::unescape(value)))
+        .collect();
+    Labels::from_untrusted(pairs.iter().map(|(key, value)| (*key, value.as_str())))
+}
+
+/// The models a peer announced taking tools, as names or `prefix*`
+/// patterns.
+pub fn announced_tool_models(agent_version: &str) -> Vec<String> {
+    announced_field(agent_version, TOOLS_KEY)
+        .map(|field| {
+            field
+                .split(',')
+                .map(profile::unescape)
+                .filter(|model| !model.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Start of every mesh protocol name.
+const PROTOCOL_PREFIX: &str = "/mesh-ai/";
+
+/// The prompt protocol versions among the protocols a peer listed in
+/// identify, e.g. `1.0.0` for `/mesh-ai/1.0.0` and `/mesh-ai/1.0.0+proto`.
+/// Side protocols such as `/mesh-ai/rerank/1.0.0` don't count.
+pub fn protocol_versions(protocols: &[StreamProtocol]) -> Vec<String> {
+    let mut versions: Vec<String> = protocols
+        .iter()
+        .filter_map(|protocol| protocol.as_ref().strip_prefix(PROTOCOL_PREFIX))
+        .filter(|rest| !rest.contains('/'))
+        .map(|rest| rest.split_once('+').map_or(rest, |(version, _)| version))
+        .map(str::to_string)
+        .collect();
+    versions.sort();
+    versions.dedup();
+    versions
+}
+
+/// A peer that speaks the prompt protocol, but no version of it we do.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionMismatch {
+    pub theirs: Vec<String>,
+}
+
+impl VersionMismatch {
+    /// The mismatch with a peer announcing `versions`, if there is one. A
+    /// peer announcing none doesn't serve prompts at all, e.g. a relay, so it
+    /// isn't a mismatch.
+    pub fn check(versions: &[String]) -> Option<Self> {
+        let shared = versions
+            .iter()
+            .any(|version| PROTOCOL_VERSIONS.contains(&version.as_str()));
+        (!versions.is_empty() && !shared).then(|| Self {
+            theirs: versions.to_vec(),
+        })
+    }
+
+    /// Whether the peer's newest version is ahead of ours, so it is this
+    /// side that needs upgrading.
+    pub fn peer_is_newer(&self) -> bool {
+        let parse = |version: &str| -> Vec<u64> {
+            version.split('.').map(|n| n.parse().unwrap_or(0)).collect()
+        };
+        let theirs = self.theirs.iter().map(|v| parse(v)).max();
+        let ours = PROTOCOL_VERSIONS.iter().map(|v| parse(v)).max();
+        theirs > ours
+    }
+}
+
+impl fmt::Display for VersionMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let theirs: Vec<String> = self
+            .theirs
+            .iter()
+            .map(|version| format!("{PROTOCOL_PREFIX}{version}"))
+            .collect();
+        write!(
+            f,
+            "speaks {}, we speak {} — {}",
+            theirs.join(", "),
+            PROTOCOL_VERSIONS.join("/"),
+            if self.peer_is_newer() {
+                "upgrade this side"
+            } else {
+                "the peer needs an upgrade"
+            }
+        )
+    }
+}
+
+/// The still escaped value of the `key` field, which comes before the model
+/// list.
+fn announced_field<'a>(agent_version: &'a str, key: &str) -> Option<&'a str> {
+    agent_version
+        .split_once(MODELS_MARKER)
+        .map_or(agent_version, |(fields, _)| fields)
+        .split(' ')
+        .find_map(|field| field.strip_prefix(key))
+}
+
+/// The models a peer announced in its identify agent version.
+pub fn announced_models(agent_version: &str) -> Vec<String> {
+    agent_version
+        .split_once(MODELS_MARKER)
+        .map(|(_, models)| models.split(',').map(str::to_string).collect())
+        .unwrap_or_default()
+}
+
+impl NodeConfig {
+    /// The address to listen on for each transport, when nothing more
+    /// specific is configured.
+    pub fn default_listen_addrs(&self) -> Vec<Multiaddr> {
+        self.transports
+            .iter()
+            .map(|transport| transport.default_listen_addr())
+            .collect()
+    }
+}
+
+/// `kinds` combined into one transport, each secured with noise and
+/// multiplexed with yamux. Dials go to the first that supports the address.
+/// A dial gets `dial_timeout` to connect and `handshake_timeout` on top to
+/// upgrade; an incoming connection gets `handshake_timeout`.
+fn transport(
+    kinds: &[TransportKind],
+    key: &Keypair,
+    dial_timeout: Duration,
+    handshake_timeout: Duration,
+) -> Result<Boxed<(PeerId, StreamMuxerBox)>, Box<dyn Error + Send + Sync>> {
+    let mut combined: Option<Boxed<(PeerId, StreamMuxerBox)>> = None;
+    for kind in kinds {
+        let next = match kind {
+            TransportKind::Tcp => TransportTimeout::with_outgoing_timeout(
+                tcp::tokio::Transport::new(tcp::Config::default()),
+                dial_timeout,
+            )
+            .upgrade(upgrade::Version::V1Lazy)
+            .authenticate(noise::Config::new(key)?)
+            .multiplex(yamux::Config::default())
+            .inbound_timeout(handshake_timeout)
+            .outbound_timeout(dial_timeout + handshake_timeout)
+            .map(|(peer, muxer), _| (peer, StreamMuxerBox::new(muxer)))
+            .boxed(),
+            TransportKind::Memory => MemoryTransport::default()
+                .upgrade(upgrade::Version::V1)
+                .authenticate(noise::Config::new(key)?)
+                .multiplex(yamux::Config::default())
+                .timeout(handshake_timeout)
+                .map(|(peer, muxer), _| (peer, StreamMuxerBox::new(muxer)))
+                .boxed(),
+        };
+        combined = Some(match combined {
+            None => next,
+            Some(combined) => combined
+                .or_transport(next)
+                .map(|either, _| match either {
+                    Either::Left(output) | Either::Right(output) => output,
+                })
+                .boxed(),
+        });
+    }
+    combined.ok_or_else(|| "no transport enabled; NodeConfig::transports needs at least one".into())
+}
+
+/// Gossipsub subscribed to [`telemetry::TOPIC`]. Messages carry no source or
+/// signature, so they are told apart by a hash of their content.
+fn telemetry_behaviour() -> Result<gossipsub::Behaviour, Box<dyn Error>> {
+    let config = gossipsub::ConfigBuilder::default()
+        .validation_mode(gossipsub::ValidationMode::Anonymous)
+        .max_transmit_size(telemetry::MAX_REPORT_BYTES)
+        .message_id_fn(|message| gossipsub::MessageId::new(&Sha256::digest(&message.data)))
+        .build()?;
+    let mut behaviour =
+        gossipsub::Behaviour::new(gossipsub::MessageAuthenticity::Anonymous, config)?;
+    behaviour.subscribe(&gossipsub::IdentTopic::new(telemetry::TOPIC))?;
+    Ok(behaviour)
+}
+
+/// Identify announcing what `config` lists.
+fn identify_behaviour(key: PublicKey, config: &NodeConfig) -> identify::Behaviour {
+    identify::Behaviour::new(
+        identify::Config::new(PROTOCOL_NAME.to_string(), key)
+            .with_interval(config.discovery.announce_interval)
+            .with_agent_version(agent_version(
+                &config.announced_models,
+                &config.tool_models,
+                &config.profile,
+                &config.labels,
+            )),
+    )
+}
+
+/// Announces what `config` lists now, e.g. a model that loaded late.
+/// Identify fixes a connection's agent version when it opens, so peers see
+/// the new one on their next connection, not on the ones already open.
+pub fn reannounce(swarm: &mut Swarm<Behaviour>, key: PublicKey, config: &NodeConfig) {
+    swarm.behaviour_mut().identify = identify_behaviour(key, config);
+}
+
+pub fn build_swarm(
+    keypair: Keypair,
+    config: &NodeConfig,
+) -> Result<Swarm<Behaviour>, Box<dyn Error>> {
+    config.discovery.validate()?;
+    let builder = libp2p::SwarmBuilder::with_existing_identity(keypair).with_tokio();
+    let telemetry = match config.telemetry {
+        TelemetryMode::Off => None,
+        TelemetryMode::On | TelemetryMode::Watch => Some(telemetry_behaviour()?),
+    };
+    let new_behaviour = |key: &Keypair, relay_behaviour| Behaviour {
+        ping: ping::Behaviour::default(),
+        // Outbound requests try the protocols in order, so they use 2.0.0
+        // with nodes that speak it and fall back to 1.0.0 CBOR with older
+        // ones. Inbound, a request is answered on whichever one the peer
+        // picked; the protobuf encoding is there for peers that ask for it.
+        request_response: request_response::Behaviour::with_codec(
+            MeshCodec::default(),
+            [
+                (StreamProtocol::new(V2_PROTOCOL_NAME), ProtocolSupport::Full),
+                (StreamProtocol::new(PROTOCOL_NAME), ProtocolSupport::Full),
+                (
+                    StreamProtocol::new(PROTO_PROTOCOL_NAME),
+                    ProtocolSupport::Full,
+                ),
+            ],
+            request_response::Config::default().with_request_timeout(config.request_timeout),
+        ),
+        rerank: request_response::cbor::Behaviour::new(
+            [(
+                StreamProtocol::new(RERANK_PROTOCOL_NAME),
+                if config.serve_rerank {
+                    ProtocolSupport::Full
+                } else {
+                    ProtocolSupport::Outbound
+                },
+            )],
+            request_response::Config::default().with_request_timeout(config.request_timeout),
+        ),
+        pex: request_response::cbor::Behaviour::new(
+            [(
+                StreamProtocol::new(PEX_PROTOCOL_NAME),
+                if config.serve_pex {
+                    ProtocolSupport::Full
+                } else {
+                    ProtocolSupport::Outbound
+                },
+            )],
+            request_response::Config::default(),
+        ),
+        feedback: request_response::cbor::Behaviour::new(
+            [(
+                StreamProtocol::new(FEEDBACK_PROTOCOL_NAME),
+                ProtocolSupport::Full,
+            )],
+            request_response::Config::default(),
+        ),
+        compare: request_response::cbor::Behaviour::new(
+            [(
+                StreamProtocol::new(COMPARE_PROTOCOL_NAME),
+                ProtocolSupport::Full,
+            )],
+            request_response::Config::default().with_request_timeout(config.request_timeout),
+        ),
+        estimate: request_response::cbor::Behaviour::new(
+            [(
+                StreamProtocol::new(ESTIMATE_PROTOCOL_NAME),
+                ProtocolSupport::Full,
+            )],
+            request_response::Config::default(),
+        ),
+        relay: relay_behaviour,
+        identify: identify_behaviour(key.public(), config),
+        dcutr: dcutr::Behaviour::new(key.public().to_peer_id()),
+        upnp: upnp::tokio::Behaviour::default(),
+        pin: pin::Behaviour::new(config.pinned_peers.iter().copied()),
+        blocked: allow_block_list::Behaviour::default(),
+        limits: connection_limits::Behaviour::new(
+            connection_limits::ConnectionLimits::default()
+                .with_max_established_incoming(config.max_incoming_connections),
+        ),
+        autonat: Toggle::from((config.autonat_server || config.autonat_client).then(|| {
+            // Probes go to connected peers only when probing is wanted. As a
+            // server, dial-backs only go to the requesting peer, at the IP it
+            // connected from; a node that isn't one refuses every request.
+            let (peer_max, global_max) = if config.autonat_server {
+                (AUTONAT_PEER_MAX, AUTONAT_GLOBAL_MAX)
+            } else {
+                (0, 0)
+            };
+            autonat::Behaviour::new(
+                key.public().to_peer_id(),
+                autonat::Config {
+                    use_connected: config.autonat_client,
+                    boot_delay: AUTONAT_BOOT_DELAY,
+                    throttle_clients_peer_max: peer_max,
+                    throttle_clients_global_max: global_max,
+                    throttle_clients_period: AUTONAT_THROTTLE_PERIOD,
+                    only_global_ips: !config.autonat_private_ips,
+                    ..Default::default()
+                },
+            )
+        })),
+        stream: libp2p_stream::Behaviour::new(),
+        telemetry: Toggle::from(telemetry),
+    };
+    let swarm_config = |cfg: libp2p::swarm::Config| {
+        cfg.with_idle_connection_timeout(config.idle_timeout)
+            .with_dial_concurrency_factor(config.dial_concurrency)
+    };
+
+    let public_resolver = match config.dns_resolver {
+        DnsResolver::System => None,
+        DnsResolver::Cloudflare => Some(ResolverConfig::cloudflare()),
+        DnsResolver::Google => Some(ResolverConfig::google()),
+        DnsResolver::Quad9 => Some(ResolverConfig::quad9()),
+    };
+    let transports = config.transports.clone();
+    let (dial_timeout, handshake_timeout) = (config.dial_timeout, config.handshake_timeout);
+    let builder = builder
+        .with_other_transport(|key| transport(&transports, key, dial_timeout, handshake_timeout))?;
+    // Bounds connections over the relay too, which don't go through
+    // `transport`'s timeouts.
+    let connection_timeout = dial_timeout + handshake_timeout;
+    let swarm = match (transports.contains(&TransportKind::Tcp), public_resolver) {
+        (false, _) => builder
+            .with_relay_client(noise::Config::new, yamux::Config::default)?
+            .with_behaviour(new_behaviour)?
+            .with_swarm_config(swarm_config)
+            .with_connection_timeout(connection_timeout)
+            .build(),
+        (true, None) => builder
+            .with_dns()?
+            .with_relay_client(noise::Config::new, yamux::Config::default)?
+            .with_behaviour(new_behaviour)?
+            .with_swarm_config(swarm_config)
+            .with_connection_timeout(connection_timeout)
+            .build(),
+        (true, Some(resolver)) => builder
+            .with_dns_config(resolver, ResolverOpts::default())
+            .with_relay_client(noise::Config::new, yamux::Config::default)?
+            .with_behaviour(new_behaviour)?
+            .with_swarm_config(swarm_config)
+            .with_connection_timeout(connection_timeout)
+            .build(),
+    };
+    Ok(swarm)
+}
@@ -0,0 +1,174 @@
+//! Bookkeeping for the addresses other peers observe us at via identify.
+//!
+//! A single peer's observation is cheap to forge or simply wrong (e.g. it sits
+//! behind the same NAT), so an address is only believed once enough distinct
+//! peers report it. Reports of an address nobody has repeated for
+//! [`REPORT_TTL`] are dropped, as are the oldest once the capacity is reached,
+//! so peers reporting made-up addresses can't grow the table without bound.
+
+use std::{
+    collections::HashSet,
+    time::{Duration, Instant},
+};
+
+use libp2p::{Multiaddr, PeerId, multiaddr::Protocol};
+
+use crate::bounded::{BoundedConfig, BoundedMap};
+
+/// How long reports of an address are kept after the last one.
+pub const REPORT_TTL: Duration = Duration::from_secs(60 * 60);
+
+pub struct ObservedAddrs {
+    reports: BoundedMap<Multiaddr, HashSet<PeerId>>,
+    confirmations: usize,
+}
+
+impl ObservedAddrs {
+    /// `confirmations` is the number of distinct peers that must report an
+    /// address before it is considered confirmed; `capacity` bounds how many
+    /// addresses are tracked.
+    pub fn new(confirmations: usize, capacity: usize) -> Self {
+        Self {
+            reports: BoundedMap::new(BoundedConfig {
+                capacity,
+                ttl: Some(REPORT_TTL),
+            }),
+            confirmations: confirmations.max(1),
+        }
+    }
+
+    /// Records that `reporter`, reached at `reporter_addr`, observed us at `observed`.
+    ///
+    /// Returns `true` exactly once per address, when it crosses the
+    /// confirmation threshold. Local (loopback/private) observations are only
+    /// accepted from peers that are themselves local.
+    pub fn report(
+        &mut self,
+        reporter: PeerId,
+        reporter_addr: &Multiaddr,
+        observed: Multiaddr,
+        now: Instant,
+    ) -> bool {
+        if is_local(&observed) && !is_local(reporter_addr) {
+            return false;
+        }
+        let reporters = self.reports.get_or_insert_with(observed, now, HashSet::new);
+        // Reporters past the threshold change nothing, so aren't kept.
+        reporters.len() < self.confirmations
+            && reporters.insert(reporter)
+            && reporters.len() == self.confirmations
+    }
+
+    /// Drops reports past their TTL, returning how many addresses were
+    /// dropped, for age or for room, since the last call.
+    pub fn prune(&mut self, now: Instant) -> u64 {
+        self.reports.prune(now);
+        self.reports.take_evicted()
+    }
+
+    /// Addresses with reports kept.
+    pub fn len(&self) -> usize {
+        self.reports.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.reports.is_empty()
+    }
+}
+
+/// Whether the address is loopback, private, link-local or otherwise not
+/// routable on the public internet.
+pub fn is_local(addr: &Multiaddr) -> bool {
+    addr.iter().any(|p| match p {
+        Protocol::Ip4(ip) => {
+            ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified()
+        }
+        Protocol::Ip6(ip) => {
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local()
+        }
+        _ => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> Multiaddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn confirms_once_enough_peers_report_an_address() {
+        let mut observed = ObservedAddrs::new(2, 16);
+        let now = Instant::now();
+        let public = addr("/ip4/203.0.113.7/tcp/4001");
+        let reporter_addr = addr("/ip4/198.51.100.1/tcp/4001");
+        let (a, b, c) = (PeerId::random(), PeerId::random(), PeerId::random());
+
+        assert!(!observed.report(a, &reporter_addr, public.clone(), now));
+        // The same peer again doesn't count twice.
+        assert!(!observed.report(a, &reporter_addr, public.clone(), now));
+        assert!(observed.report(b, &reporter_addr, public.clone(), now));
+        // Only the crossing is news.
+        assert!(!observed.report(c, &reporter_addr, public, now));
+    }
+
+    #[test]
+    fn local_observations_only_count_from_local_peers() {
+        let mut observed = ObservedAddrs::new(1, 16);
+        let now = Instant::now();
+        let private = addr("/ip4/192.168.1.20/tcp/4001");
+
+        let remote = addr("/ip4/198.51.100.1/tcp/4001");
+        assert!(!observed.report(PeerId::random(), &remote, private.clone(), now));
+        assert!(observed.is_empty());
+
+        let neighbour = addr("/ip4/192.168.1.30/tcp/4001");
+        assert!(observed.report(PeerId::random(), &neighbour, private, now));
+    }
+
+    #[test]
+    fn many_made_up_addresses_stay_within_capacity() {
+        let mut observed = ObservedAddrs::new(3, 8);
+        let now = Instant::now();
+        let reporter_addr = addr("/ip4/198.51.100.1/tcp/4001");
+        for port in 0..1000 {
+            let made_up = addr(&format!("/ip4/203.0.113.7/tcp/{port}"));
+            observed.report(PeerId::random(), &reporter_addr, made_up, now);
+        }
+        assert_eq!(observed.len(), 8);
+        assert_eq!(observed.prune(now), 992);
+    }
+
+    #[test]
+    fn reports_expire_after_the_ttl() {
+        let mut observed = ObservedAddrs::new(2, 16);
+        let now = Instant::now();
+        let reporter_addr = addr("/ip4/198.51.100.1/tcp/4001");
+        let public = addr("/ip4/203.0.113.7/tcp/4001");
+        observed.report(PeerId::random(), &reporter_addr, public.clone(), now);
+
+        let later = now + REPORT_TTL + Duration::from_secs(1);
+        assert_eq!(observed.prune(later), 1);
+        assert!(observed.is_empty());
+        // A second report after the first expired starts over.
+        assert!(!observed.report(PeerId::random(), &reporter_addr, public, later));
+    }
+
+    #[test]
+    fn recognises_local_addresses() {
+        assert!(is_local(&addr("/ip4/127.0.0.1/tcp/1")));
+        assert!(is_local(&addr("/ip4/10.1.2.3/tcp/1")));
+        assert!(is_local(&addr("/ip4/169.254.0.1/tcp/1")));
+        assert!(is_local(&addr("/ip6/::1/tcp/1")));
+        assert!(is_local(&addr("/ip6/fd00::1/tcp/1")));
+        assert!(is_local(&addr("/ip6/fe80::1/tcp/1")));
+        assert!(!is_local(&addr("/ip4/203.0.113.7/tcp/1")));
+        assert!(!is_local(&addr("/ip6/2001:db8::1/tcp/1")));
+        assert!(!is_local(&addr("/dns4/example.com/tcp/1")));
+    }
+}
@@ -0,0 +1,851 @@
+//! Client for the Ollama inference backend, local by default.
+
+use std::{error::Error, num::NonZeroUsize, sync::OnceLock, time::Duration};
+#[cfg(unix)]
+use std::{
+    fs,
+    io::ErrorKind,
+    os::unix::{fs::FileTypeExt, net::UnixStream},
+    path::{Path, PathBuf},
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use serde::Serialize;
+use tokio::sync::{Semaphore, SemaphorePermit, mpsc};
+
+use crate::{
+    BinaryPayload, PromptRequest,
+    http_client::HttpClientConfig,
+    tools::{ToolCall, ToolDefinition},
+};
+
+pub type BackendError = Box<dyn Error + Send + Sync>;
+
+/// TCP keepalive for pooled connections to Ollama, so idle ones between
+/// requests aren't silently dropped by the OS or a proxy.
+const TCP_KEEPALIVE: Duration = Duration::from_secs(30);
+
+pub const DEFAULT_URL: &str = "http://localhost:11434";
+
+/// Starts a backend URL naming a Unix socket rather than a host, e.g.
+/// `unix:///run/ollama/ollama.sock`.
+const UNIX_SCHEME: &str = "unix://";
+
+/// What requests over a Unix socket are addressed to. The host only ends up
+/// in the `Host` header.
+const UNIX_BASE_URL: &str = "http://localhost";
+
+/// Requests sent to Ollama at once unless [`init`] says otherwise.
+pub const DEFAULT_MAX_REQUESTS: usize = 4;
+
+/// How many requests to send the backend at once: `configured` if given,
+/// otherwise enough for `max_concurrent` inferences and a model lookup
+/// beside them, and never under [`DEFAULT_MAX_REQUESTS`].
+pub fn max_requests(configured: Option<NonZeroUsize>, max_concurrent: usize) -> NonZeroUsize {
+    configured.unwrap_or_else(|| {
+        NonZeroUsize::new(max_concurrent.saturating_add(1).max(DEFAULT_MAX_REQUESTS))
+            .expect("at least the default")
+    })
+}
+
+/// Why `max_requests` holds back `max_concurrent` inferences, if it does.
+pub fn max_requests_warning(max_requests: NonZeroUsize, max_concurrent: usize) -> Option<String> {
+    (max_requests.get() < max_concurrent).then(|| {
+        format!(
+            "--max-backend-requests {max_requests} is under --max-concurrent {max_concurrent}: \
+             at most {max_requests} inferences reach the backend at once, and the rest wait here"
+        )
+    })
+}
+
+struct Backend {
+    client: reqwest::Client,
+    url: String,
+    /// One permit per request Ollama may be sent at once, held until its
+    /// answer has been read, so the backend never sees more than this
+    /// whatever the mesh side admits.
+    requests: Semaphore,
+    max_requests: usize,
+}
+
+impl Backend {
+    fn new(client: reqwest::Client, url: &str, max_requests: usize) -> Self {
+        Self {
+            client,
+            url: url.trim_end_matches('/').to_string(),
+            requests: Semaphore::new(max_requests),
+            max_requests,
+        }
+    }
+}
+
+fn client_builder(max_requests: usize) -> reqwest::ClientBuilder {
+    // At most `max_requests` connections are ever busy, so there's no use
+    // keeping more idle.
+    reqwest::Client::builder()
+        .tcp_keepalive(TCP_KEEPALIVE)
+        .pool_max_idle_per_host(max_requests)
+}
+
+/// How much of the backend's request limit is in use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Usage {
+    pub in_flight: usize,
+    pub max_requests: usize,
+}
+
+static BACKEND: OnceLock<Backend> = OnceLock::new();
+
+/// Sets the backend's base URL and request limit, and builds the HTTP client
+/// shared by every backend call. A `unix://` URL names a Unix socket to send
+/// the requests over instead of TCP; the socket must exist and accept
+/// connections by now; such URLs are refused on platforms without Unix
+/// sockets. With `http2` the client speaks HTTP/2 without
+/// negotiating it first, which only works when something in front of Ollama
+/// (e.g. a proxy) accepts h2c; Ollama itself only speaks HTTP/1.1. Has no
+/// effect once the backend is set up, i.e. after the first call or a previous
+/// `init`. `http` adds credentials and TLS options for a backend behind a
+/// proxy.
+pub fn init(
+    url: &str,
+    http2: bool,
+    max_requests: NonZeroUsize,
+    http: &HttpClientConfig,
+) -> Result<(), BackendError> {
+    let mut builder = http.apply(client_builder(max_requests.get()))?;
+    let mut base_url = url;
+    if let Some(path) = url.strip_prefix(UNIX_SCHEME) {
+        #[cfg(unix)]
+        {
+            builder = builder.unix_socket(unix_socket(path)?);
+        }
+        #[cfg(not(unix))]
+        return Err(format!("can't reach {path}: Unix sockets aren't supported here").into());
+        base_url = UNIX_BASE_URL;
+    }
+    if http2 {
+        builder = builder.http2_prior_knowledge();
+    }
+    let _ = BACKEND.set(Backend::new(builder.build()?, base_url, max_requests.get()));
+    Ok(())
+}
+
+/// Checks that `path` is a Unix socket this process can connect to, saying
+/// what to fix if not.
+#[cfg(unix)]
+fn unix_socket(path: &str) -> Result<PathBuf, String> {
+    let path = Path::new(path);
+    let shown = path.display();
+    if !path.is_absolute() {
+        return Err(format!(
+            "the socket path `{shown}` must be absolute, e.g. unix:///run/ollama/ollama.sock"
+        ));
+    }
+    let metadata = fs::metadata(path).map_err(|e| match e.kind() {
+        ErrorKind::NotFound => {
+            format!("there is no socket at {shown}; is the backend running and listening there?")
+        }
+        ErrorKind::PermissionDenied => {
+            format!("can't reach {shown}: permission denied on a directory leading to it")
+        }
+        _ => format!("can't reach {shown}: {e}"),
+    })?;
+    if !metadata.file_type().is_socket() {
+        return Err(format!("{shown} exists but isn't a Unix socket"));
+    }
+    UnixStream::connect(path).map_err(|e| match e.kind() {
+        ErrorKind::PermissionDenied => format!(
+            "permission denied connecting to {shown}; this user needs write access to the socket, \
+             e.g. by joining its group"
+        ),
+        ErrorKind::ConnectionRefused => {
+            format!("nothing is listening on {shown}; is the backend running?")
+        }
+        _ => format!("can't connect to {shown}: {e}"),
+    })?;
+    Ok(path.to_path_buf())
+}
+
+fn backend() -> &'static Backend {
+    BACKEND.get_or_init(|| {
+        let client = client_builder(DEFAULT_MAX_REQUESTS)
+            .build()
+            .unwrap_or_default();
+        Backend::new(client, DEFAULT_URL, DEFAULT_MAX_REQUESTS)
+    })
+}
+
+pub fn usage() -> Usage {
+    let backend = backend();
+    Usage {
+        in_flight: backend.max_requests - backend.requests.available_permits(),
+        max_requests: backend.max_requests,
+    }
+}
+
+/// Waits for room under the request limit. Hold the permit until the answer
+/// has been read.
+async fn permit() -> SemaphorePermit<'static> {
+    backend()
+        .requests
+        .acquire()
+        .await
+        .expect("the request limit is never closed")
+}
+
+fn post(path: &str) -> reqwest::RequestBuilder {
+    let backend = backend();
+    backend.client.post(format!("{}{path}", backend.url))
+}
+
+fn get(path: &str) -> reqwest::RequestBuilder {
+    let backend = backend();
+    backend.client.get(format!("{}{path}", backend.url))
+}
+
+#[derive(Debug, Clone)]
+pub struct Generation {
+    pub text: String,
+    /// Tokens the model produced (Ollama's `eval_count`).
+    pub completion_tokens: u64,
+    /// Time spent generating those tokens (`eval_duration`).
+    pub eval_duration: Duration,
+    /// Tokens in the prompt (`prompt_eval_count`).
+    pub prompt_tokens: u64,
+    pub prompt_eval_duration: Duration,
+    /// Time Ollama spent loading the model (`load_duration`). Near zero when
+    /// the model was already resident; large after a model swap.
+    pub load_duration: Duration,
+    /// An image the model made, for models that generate them.
+    pub binary: Option<BinaryPayload>,
+    /// Calls the model made to the tools it was offered.
+    pub tool_calls: Vec<ToolCall>,
+}
+
+/// What goes to `/api/generate` with a prompt.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GenerateOptions<'a> {
+    /// For vision models to look at.
+    pub images: &'a [Vec<u8>],
+    /// Passed through as Ollama's `format` parameter: either the string
+    /// `json` or a JSON schema. When it is set, the model's output is
+    /// checked to actually be JSON and [`InvalidJson`] is returned
+    /// otherwise.
+    pub format: Option<&'a str>,
+    /// Put in the model's template by Ollama, ahead of the prompt. Only set
+    /// for requests that asked for a template or offer tools.
+    pub system: Option<&'a str>,
+    /// The prompt is already in the model's chat template, so Ollama must
+    /// not apply it again. Never asked for by a request; the node sets it
+    /// once it applied a template itself.
+    pub raw: bool,
+    /// Functions the model may call. Offered through `/api/chat`, which
+    /// always applies the model's template, so `raw` doesn't go with them.
+    pub tools: &'a [ToolDefinition],
+    /// The context window to run the model with, in place of its own.
+    pub num_ctx: Option<u64>,
+}
+
+impl<'a> GenerateOptions<'a> {
+    /// The options `request` asks for, for a prompt not yet in a template.
+    pub fn of(request: &'a PromptRequest) -> Self {
+        Self {
+            images: request.images.as_deref().unwrap_or_default(),
+            format: request.format.as_deref(),
+            // A prompt sent without `apply_template` is taken as complete,
+            // e.g. already in a template of the client's own, so a system
+            // prompt would only go through Ollama's template on top of it.
+            // Tools always go through the model's template, which places it.
+            system: request
+                .system
+                .as_deref()
+                .filter(|_| request.apply_template || request.tools.is_some()),
+            raw: false,
+            tools: request.tools.as_deref().unwrap_or_default(),
+            num_ctx: request.num_ctx,
+        }
+    }
+}
+
+/// Runs `prompt` on `model`. With tools, through [`chat`].
+pub async fn generate(
+    model: &str,
+    prompt: String,
+    options: GenerateOptions<'_>,
+) -> Result<Generation, BackendError> {
+    if !options.tools.is_empty() {
+        return chat(model, prompt, options).await;
+    }
+    let body = generate_body(model, prompt, options, false);
+    let _permit = permit().await;
+    let res = post("/api/generate").json(&body).send().await?;
+
+    if !res.status().is_success() {
+        return Err(error_response(res).await);
+    }
+
+    let body: serde_json::Value = res.json().await?;
+    let text = response_text(&body)?;
+    if text.is_empty() && body.get("image").is_none() {
+        tracing::warn!("Ollama returned an empty response for {model}");
+    }
+    let text = text.to_string();
+    finish_generation(text, &body, options.format)
+}
+
+/// Runs `prompt` on `model` through `/api/chat`, as the one user message
+/// after the system prompt, offering the model `options.tools`. The model's
+/// calls come back in [`Generation::tool_calls`], and its text, often empty
+/// when it calls a tool, in [`Generation::text`].
+pub async fn chat(
+    model: &str,
+    prompt: String,
+    options: GenerateOptions<'_>,
+) -> Result<Generation, BackendError> {
+    let body = chat_body(model, prompt, options);
+    let _permit = permit().await;
+    let res = post("/api/chat").json(&body).send().await?;
+
+    if !res.status().is_success() {
+        return Err(error_response(res).await);
+    }
+
+    let body: serde_json::Value = res.json().await?;
+    let message = &body["message"];
+    let text = match &message["content"] {
+        serde_json::Value::String(text) => text.clone(),
+        serde_json::Value::Null => String::new(),
+        other => {
+            return Err(format!(
+                "Ollama's `message.content` field is {}, not a string",
+                json_type(other)
+            )
+            .into());
+        }
+    };
+    let tool_calls = tool_calls(message)?;
+    // A tool call stands in for the answer, so there's no JSON to check.
+    let format = options.format.filter(|_| tool_calls.is_empty());
+    let mut generation = finish_generation(text, &body, format)?;
+    generation.tool_calls = tool_calls;
+    Ok(generation)
+}
+
+fn chat_body(model: &str, prompt: String, options: GenerateOptions<'_>) -> serde_json::Value {
+    let mut messages = Vec::new();
+    if let Some(system) = options.system {
+        messages.push(serde_json::json!({ "role": "system", "content": system }));
+    }
+    let mut user = serde_json::json!({ "role": "user", "content": prompt });
+    if !options.images.is_empty() {
+        user["images"] = options
+            .images
+            .iter()
+            .map(|image| STANDARD.encode(image))
+            .collect();
+    }
+    messages.push(user);
+    let tools: Vec<serde_json::Value> = options
+        .tools
+        .iter()
+        .map(|tool| {
+            // Checked to be a JSON object when the request came in.
+            let parameters = tool
+                .parameters
+                .as_deref()
+                .and_then(|parameters| serde_json::from_str(parameters).ok())
+                .unwrap_or_else(|| serde_json::json!({ "type": "object", "properties": {} }));
+            serde_json::json!({
+                "type": "function",
+                "function": {
+                    "name": tool.name,
+                    "description": tool.description,
+                    "parameters": parameters,
+                }
+            })
+        })
+        .collect();
+    let mut body = serde_json::json!({
+        "model": model,
+        "messages": messages,
+        "tools": tools,
+        "stream": false
+    });
+    set_format(&mut body, options.format);
+    set_num_ctx(&mut body, options.num_ctx);
+    body
+}
+
+/// The calls in a chat reply's `message`, with their arguments as JSON text.
+fn tool_calls(message: &serde_json::Value) -> Result<Vec<ToolCall>, BackendError> {
+    let Some(calls) = message["tool_calls"].as_array() else {
+        return Ok(Vec::new());
+    };
+    calls
+        .iter()
+        .map(|call| {
+            let function = &call["function"];
+            let name = function["name"]
+                .as_str()
+                .ok_or("a tool call in Ollama's reply has no function name")?;
+            // Ollama sends the arguments as an object; some models put a
+            // string of JSON there instead.
+            let arguments = match &function["arguments"] {
+                serde_json::Value::String(arguments) => arguments.clone(),
+                serde_json::Value::Null => "{}".to_string(),
+                arguments => arguments.to_string(),
+            };
+            Ok(ToolCall {
+                name: name.to_string(),
+                arguments,
+            })
+        })
+        .collect()
+}
+
+/// Like [`generate`], but sends each piece of output on `chunks` as Ollama
+/// produces it. Sending waits for room, so a slow receiver slows reading from
+/// Ollama down instead of buffering; if the receiver is dropped, the
+/// generation is abandoned.
+pub async fn generate_stream(
+    model: &str,
+    prompt: String,
+    options: GenerateOptions<'_>,
+    chunks: mpsc::Sender<String>,
+) -> Result<Generation, BackendError> {
+    let body = generate_body(model, prompt, options, true);
+    let _permit = permit().await;
+    let mut res = post("/api/generate").json(&body).send().await?;
+
+    if !res.status().is_success() {
+        return Err(error_response(res).await);
+    }
+
+    // Ollama streams one JSON object per line; the last has `done` set and
+    // carries the usage figures.
+    let mut buf = Vec::new();
+    let mut text = String::new();
+    while let Some(bytes) = res.chunk().await? {
+        buf.extend_from_slice(&bytes);
+        while let Some(end) = buf.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = buf.drain(..=end).collect();
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            let chunk: serde_json::Value = serde_json::from_slice(&line)?;
+            if let Some(error) = chunk["error"].as_str() {
+                return Err(backend_error(format!("Ollama returned error: {error}")));
+            }
+            let piece = response_text(&chunk)?;
+            if !piece.is_empty() {
+                text.push_str(piece);
+                chunks
+                    .send(piece.to_string())
+                    .await
+                    .map_err(|_| "stream receiver went away")?;
+            }
+            if chunk["done"].as_bool().unwrap_or_default() {
+                return finish_generation(text, &chunk, options.format);
+            }
+        }
+    }
+    Err("Ollama ended the stream before it was done".into())
+}
+
+fn generate_body(
+    model: &str,
+    prompt: String,
+    options: GenerateOptions<'_>,
+    stream: bool,
+) -> serde_json::Value {
+    let mut body = serde_json::json!({
+        "model": model,
+        "prompt": prompt,
+        "stream": stream
+    });
+    if !options.images.is_empty() {
+        // Ollama takes images base64-encoded.
+        body["images"] = options
+            .images
+            .iter()
+            .map(|image| STANDARD.encode(image))
+            .collect();
+    }
+    if options.raw {
+        body["raw"] = true.into();
+    } else if let Some(system) = options.system {
+        body["system"] = system.into();
+    }
+    set_format(&mut body, options.format);
+    set_num_ctx(&mut body, options.num_ctx);
+    body
+}
+
+fn set_num_ctx(body: &mut serde_json::Value, num_ctx: Option<u64>) {
+    if let Some(num_ctx) = num_ctx {
+        body["options"] = serde_json::json!({ "num_ctx": num_ctx });
+    }
+}
+
+fn set_format(body: &mut serde_json::Value, format: Option<&str>) {
+    if let Some(format) = format {
+        // A schema is sent as an object; anything else (i.e. "json") as a string.
+        body["format"] = serde_json::from_str::<serde_json::Value>(format)
+            .ok()
+            .filter(|v| v.is_object())
+            .unwrap_or_else(|| format.into());
+    }
+}
+
+/// The generated text in a response object. A missing or non-string
+/// `response` field is an error, naming what was found instead, since it
+/// usually means the URL points at something other than Ollama's generate
+/// endpoint or at an incompatible version of it.
+fn response_text(body: &serde_json::Value) -> Result<&str, BackendError> {
+    match body.get("response") {
+        Some(serde_json::Value::String(text)) => Ok(text),
+        Some(other) => Err(format!(
+            "Ollama's `response` field is {}, not a string",
+            json_type(other)
+        )
+        .into()),
+        None => {
+            let keys: Vec<&str> = body
+                .as_object()
+                .map(|object| object.keys().map(String::as_str).collect())
+                .unwrap_or_default();
+            Err(format!(
+                "Ollama's reply has no `response` field (found {}: {})",
+                json_type(body),
+                keys.join(", ")
+            )
+            .into())
+        }
+    }
+}
+
+fn json_type(value: &serde_json::Value) -> &'static str {
+    match value {
+        serde_json::Value::Null => "null",
+        serde_json::Value::Bool(_) => "a boolean",
+        serde_json::Value::Number(_) => "a number",
+        serde_json::Value::String(_) => "a string",
+        serde_json::Value::Array(_) => "an array",
+        serde_json::Value::Object(_) => "an object",
+    }
+}
+
+/// Checks the output against `format` and collects the usage figures from
+/// the final response object.
+fn finish_generation(
+    text: String,
+    body: &serde_json::Value,
+    format: Option<&str>,
+) -> Result<Generation, BackendError> {
+    if format.is_some() && serde_json::from_str::<serde_json::Value>(&text).is_err() {
+        return Err(Box::new(InvalidJson(text)));
+    }
+    Ok(Generation {
+        text,
+        completion_tokens: body["eval_count"].as_u64().unwrap_or_default(),
+        eval_duration: nanos(&body["eval_duration"]),
+        prompt_tokens: body["prompt_eval_count"].as_u64().unwrap_or_default(),
+        prompt_eval_duration: nanos(&body["prompt_eval_duration"]),
+        load_duration: nanos(&body["load_duration"]),
+        binary: image(body)?,
+        tool_calls: Vec::new(),
+    })
+}
+
+/// The image in a final response object, which image generation models
+/// return base64-encoded in `image`.
+fn image(body: &serde_json::Value) -> Result<Option<BinaryPayload>, BackendError> {
+    let Some(encoded) = body["image"].as_str().filter(|s| !s.is_empty()) else {
+        return Ok(None);
+    };
+    let data = STANDARD
+        .decode(encoded)
+        .map_err(|e| format!("Ollama's `image` field isn't base64: {e}"))?;
+    Ok(Some(BinaryPayload {
+        mime_type: sniff_mime_type(&data).to_string(),
+        data,
+    }))
+}
+
+/// The MIME type of an image, from its first bytes.
+fn sniff_mime_type(data: &[u8]) -> &'static str {
+    match data {
+        [0x89, b'P', b'N', b'G', ..] => "image/png",
+        [0xff, 0xd8, 0xff, ..] => "image/jpeg",
+        [b'G', b'I', b'F', b'8', ..] => "image/gif",
+        [
+            b'R',
+            b'I',
+            b'F',
+            b'F',
+            _,
+            _,
+            _,
+            _,
+            b'W',
+            b'E',
+            b'B',
+            b'P',
+            ..,
+        ] => "image/webp",
+        _ => "application/octet-stream",
+    }
+}
+
+/// What `/api/show` says about a model.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ModelInfo {
+    /// `num_ctx` from the model's parameters, if its Modelfile sets one.
+    pub num_ctx: Option<u64>,
+    /// The context length the model was trained for.
+    pub context_length: Option<u64>,
+    /// Whether the model came with a chat template, which Ollama applies to
+    /// prompts that aren't raw. Base models often don't.
+    pub has_template: bool,
+}
+
+pub async fn show(model: &str) -> Result<ModelInfo, BackendError> {
+    let _permit = permit().await;
+    let res = post("/api/show")
+        .json(&serde_json::json!({ "model": model }))
+        .send()
+        .await?;
+
+    if !res.status().is_success() {
+        return Err(error_response(res).await);
+    }
+
+    let body: serde_json::Value = res.json().await?;
+    // Parameters come as Modelfile lines, e.g. "num_ctx 8192\nstop ...".
+    let num_ctx = body["parameters"].as_str().and_then(|parameters| {
+        parameters.lines().find_map(|line| {
+            let mut parts = line.split_whitespace();
+            (parts.next() == Some("num_ctx"))
+                .then(|| parts.next()?.parse().ok())
+                .flatten()
+        })
+    });
+    // Keyed by architecture, e.g. "llama.context_length".
+    let context_length = body["model_info"].as_object().and_then(|info| {
+        info.iter()
+            .find(|(key, _)| key.ends_with(".context_length"))
+            .and_then(|(_, value)| value.as_u64())
+    });
+    let has_template = body["template"]
+        .as_str()
+        .is_some_and(|template| !template.trim().is_empty());
+    Ok(ModelInfo {
+        num_ctx,
+        context_length,
+        has_template,
+    })
+}
+
+/// Loads `model` into memory through a generate request without a prompt,
+/// and pins it there: with a `keep_alive` of -1 it has no expiry, so it stays
+/// loaded however long it goes unused, until Ollama restarts or is told to
+/// unload it.
+pub async fn preload(model: &str) -> Result<(), BackendError> {
+    let _permit = permit().await;
+    let res = post("/api/generate")
+        .json(&serde_json::json!({ "model": model, "keep_alive": -1 }))
+        .send()
+        .await?;
+
+    if !res.status().is_success() {
+        return Err(error_response(res).await);
+    }
+    Ok(())
+}
+
+/// The models Ollama has, from `/api/tags`. Doesn't wait for room under
+/// the request limit, being only a listing.
+pub async fn local_models() -> Result<Vec<String>, BackendError> {
+    model_names(get("/api/tags").send().await?).await
+}
+
+/// The models Ollama has loaded in memory, from `/api/ps`. Doesn't wait for
+/// room under the request limit.
+pub async fn loaded_models() -> Result<Vec<String>, BackendError> {
+    model_names(get("/api/ps").send().await?).await
+}
+
+async fn model_names(res: reqwest::Response) -> Result<Vec<String>, BackendError> {
+    if !res.status().is_success() {
+        return Err(error_response(res).await);
+    }
+
+    #[derive(serde::Deserialize)]
+    struct Models {
+        models: Vec<Model>,
+    }
+    #[derive(serde::Deserialize)]
+    struct Model {
+        name: String,
+    }
+    let body: Models = res.json().await?;
+    Ok(body.models.into_iter().map(|model| model.name).collect())
+}
+
+/// Embeds each of `inputs` with `model`, returning one vector per input.
+pub async fn embed(model: &str, inputs: &[String]) -> Result<Vec<Vec<f32>>, BackendError> {
+    let _permit = permit().await;
+    let res = post("/api/embed")
+        .json(&serde_json::json!({
+            "model": model,
+            "input": inputs,
+        }))
+        .send()
+        .await?;
+
+    if !res.status().is_success() {
+        return Err(error_response(res).await);
+    }
+
+    #[derive(serde::Deserialize)]
+    struct EmbedResponse {
+        embeddings: Vec<Vec<f32>>,
+    }
+    let body: EmbedResponse = res.json().await?;
+    if body.embeddings.len() != inputs.len() {
+        return Err(format!(
+            "Ollama returned {} embeddings for {} inputs",
+            body.embeddings.len(),
+            inputs.len()
+        )
+        .into());
+    }
+    Ok(body.embeddings)
+}
+
+/// The error for a non-success reply, keeping the message Ollama sent with
+/// it, e.g. `{"error": "..."}`.
+async fn error_response(res: reqwest::Response) -> BackendError {
+    let status = res.status();
+    let body = res.text().await.unwrap_or_default();
+    let message = serde_json::from_str::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|body| body["error"].as_str().map(str::to_string))
+        .unwrap_or(body);
+    if message.trim().is_empty() {
+        return format!("Ollama returned error: {status}").into();
+    }
+    backend_error(format!("Ollama returned error: {status}: {message}"))
+}
+
+/// Wraps an error message as [`OutOfMemory`] when that's what it reports.
+fn backend_error(message: String) -> BackendError {
+    if is_out_of_memory(&message) {
+        Box::new(OutOfMemory(message))
+    } else {
+        message.into()
+    }
+}
+
+/// What llama.cpp and Ollama say when a model doesn't fit: allocation
+/// failures on the GPU or host, and the runner being killed, which is how
+/// the kernel's OOM killer shows up.
+const OUT_OF_MEMORY_MARKERS: &[&str] = &[
+    "out of memory",
+    "cudamalloc failed",
+    "failed to allocate",
+    "unable to allocate",
+    "requires more system memory",
+    "insufficient memory",
+    "signal: killed",
+];
+
+fn is_out_of_memory(message: &str) -> bool {
+    let message = message.to_lowercase();
+    OUT_OF_MEMORY_MARKERS
+        .iter()
+        .any(|marker| message.contains(marker))
+}
+
+/// Ollama reports durations in nanoseconds.
+fn nanos(value: &serde_json::Value) -> Duration {
+    Duration::from_nanos(value.as_u64().unwrap_or_default())
+}
+
+/// The model was asked for JSON but produced something that doesn't parse.
+#[derive(Debug)]
+pub struct InvalidJson(pub String);
+
+impl std::fmt::Display for InvalidJson {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "model output is not valid JSON")
+    }
+}
+
+impl Error for InvalidJson {}
+
+/// The backend ran out of memory serving the request. Carries Ollama's
+/// message.
+#[derive(Debug)]
+pub struct OutOfMemory(pub String);
+
+impl std::fmt::Display for OutOfMemory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for OutOfMemory {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn n(value: usize) -> NonZeroUsize {
+        NonZeroUsize::new(value).unwrap()
+    }
+
+    #[test]
+    fn backend_requests_follow_max_concurrent() {
+        assert_eq!(max_requests(None, 1), n(DEFAULT_MAX_REQUESTS));
+        assert_eq!(max_requests(None, 4), n(5));
+        assert_eq!(max_requests(None, 16), n(17));
+        assert_eq!(max_requests(None, usize::MAX), n(usize::MAX));
+        assert_eq!(max_requests(Some(n(2)), 16), n(2));
+    }
+
+    #[test]
+    fn warns_when_backend_requests_cap_inferences() {
+        assert_eq!(max_requests_warning(max_requests(None, 16), 16), None);
+        assert_eq!(max_requests_warning(n(4), 4), None);
+        let warning = max_requests_warning(n(2), 16).unwrap();
+        assert!(warning.contains("at most 2 inferences"), "{warning}");
+    }
+
+    #[test]
+    fn system_prompts_only_go_with_a_template() {
+        let mut request: PromptRequest =
+            serde_json::from_value(serde_json::json!({ "prompt": "hi", "system": "Be brief." }))
+                .unwrap();
+        let body = generate_body("m", "hi".to_string(), GenerateOptions::of(&request), false);
+        assert!(body.get("system").is_none(), "{body}");
+
+        request.apply_template = true;
+        let body = generate_body("m", "hi".to_string(), GenerateOptions::of(&request), false);
+        assert_eq!(body["system"], "Be brief.");
+
+        // Once the node put it in a template of its own, it's in the prompt.
+        let raw = GenerateOptions {
+            raw: true,
+            system: None,
+            ..GenerateOptions::of(&request)
+        };
+        let body = generate_body("m", "hi".to_string(), raw, false);
+        assert!(body.get("system").is_none(), "{body}");
+        assert_eq!(body["raw"], true);
+
+        request.apply_template = false;
+        request.tools = Some(Vec::new());
+        assert_eq!(GenerateOptions::of(&request).system, Some("Be brief."));
+    }
+}
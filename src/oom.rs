@@ -0,0 +1,59 @@
+//! Protective throttling after the backend runs out of memory.
+//!
+//! A request that OOMs the GPU is usually followed by another just like it.
+//! After an out-of-memory failure the node runs one generation at a time for
+//! a cool-down window, so the backend gets a chance to recover instead of
+//! being handed the same load again. Another OOM during the window extends
+//! it.
+
+use std::time::{Duration, Instant};
+
+pub struct OomGuard {
+    cooldown: Duration,
+    count: u64,
+    until: Option<Instant>,
+}
+
+impl OomGuard {
+    pub fn new(cooldown: Duration) -> Self {
+        Self {
+            cooldown,
+            count: 0,
+            until: None,
+        }
+    }
+
+    /// Records an out-of-memory failure and (re)starts the cool-down.
+    /// Returns whether the cool-down was newly entered.
+    pub fn record(&mut self, now: Instant) -> bool {
+        self.count += 1;
+        let entered = self.until.is_none();
+        self.until = Some(now + self.cooldown);
+        entered
+    }
+
+    /// When the cool-down ends, if one is running.
+    pub fn until(&self) -> Option<Instant> {
+        self.until
+    }
+
+    /// Ends the cool-down if it is over. Returns whether it just ended.
+    pub fn expire(&mut self, now: Instant) -> bool {
+        match self.until {
+            Some(until) if now >= until => {
+                self.until = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.until.is_some()
+    }
+
+    /// Out-of-memory failures since start.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+}
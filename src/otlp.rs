@@ -0,0 +1,108 @@
+//! Export of request spans to an OpenTelemetry collector over OTLP/HTTP.
+//!
+//! Built only with the `otlp` feature, so nodes that don't export traces
+//! don't carry the dependencies. With `--otlp-endpoint` set, every
+//! [`RequestLog::span`](crate::logging::RequestLog::span) is exported, and a
+//! request sent with a [`TraceContext`] is exported as a child of the
+//! client's span, so a trace viewer shows the client's call and the node's
+//! work on it as one trace. The exported span gets an id of its own, which
+//! isn't the `span_id` in the node's log lines.
+
+use std::error::Error;
+
+use opentelemetry::{
+    Context, KeyValue,
+    trace::{
+        SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState, TracerProvider,
+    },
+};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{Resource, trace::SdkTracerProvider};
+use tracing::{Span, Subscriber};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{Layer, registry::LookupSpan};
+
+use crate::trace::TraceContext;
+
+/// The service name spans are exported under.
+const SERVICE_NAME: &str = "mesh-ai-node";
+
+/// Flushes the spans still buffered when dropped.
+pub struct Exporter {
+    provider: SdkTracerProvider,
+}
+
+impl Drop for Exporter {
+    fn drop(&mut self) {
+        if let Err(e) = self.provider.shutdown() {
+            eprintln!("Failed to flush traces to the OTLP collector: {e}");
+        }
+    }
+}
+
+/// A layer exporting spans to the OTLP/HTTP collector at `endpoint`, e.g.
+/// `http://localhost:4318/v1/traces`, and the exporter to keep until exit.
+pub fn layer<S>(endpoint: &str) -> Result<(impl Layer<S>, Exporter), Box<dyn Error>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder()
+                .with_attribute(KeyValue::new("service.name", SERVICE_NAME))
+                .build(),
+        )
+        .build();
+    let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer(SERVICE_NAME));
+    Ok((layer, Exporter { provider }))
+}
+
+/// Exports `span` as a child of the sender's span. Does nothing unless
+/// spans are being exported.
+pub fn link(span: &Span, sender: &TraceContext) {
+    let flags = if sender.sampled {
+        TraceFlags::SAMPLED
+    } else {
+        TraceFlags::default()
+    };
+    let remote = SpanContext::new(
+        TraceId::from(sender.trace_id),
+        SpanId::from(sender.span_id),
+        flags,
+        true,
+        TraceState::default(),
+    );
+    span.set_parent(Context::new().with_remote_span_context(remote));
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::trace::TraceContextExt;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+
+    #[test]
+    fn request_spans_join_the_senders_trace() {
+        let provider = SdkTracerProvider::builder().build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let sender: TraceContext = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+            .parse()
+            .unwrap();
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("request");
+            link(&span, &sender);
+            let context = span.context();
+            let exported = context.span().span_context().clone();
+            assert_eq!(exported.trace_id(), TraceId::from(sender.trace_id));
+            assert_ne!(exported.span_id(), SpanId::from(sender.span_id));
+            assert!(exported.is_sampled());
+        });
+    }
+}
@@ -0,0 +1,284 @@
+//! Rolling per-model performance figures for the status report.
+//!
+//! Each model keeps two fixed-size windows, the current one and the one before
+//! it, so memory stays constant however many requests are served. Latency
+//! percentiles are read off a bucketed histogram and are therefore upper
+//! bounds rather than exact values.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant},
+};
+
+/// Upper bounds, in seconds, of the latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0,
+];
+
+/// One finished inference.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sample {
+    /// Time spent waiting on the backend.
+    pub latency: Duration,
+    /// Time spent queued before the backend was called.
+    pub queue_wait: Duration,
+    pub completion_tokens: u64,
+    pub eval_duration: Duration,
+    pub prompt_tokens: u64,
+    pub prompt_eval_duration: Duration,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Window {
+    /// One count per bucket plus an overflow bucket.
+    latency: [u64; LATENCY_BUCKETS.len() + 1],
+    requests: u64,
+    completion_tokens: u64,
+    eval_secs: f64,
+    prompt_tokens: u64,
+    prompt_eval_secs: f64,
+    queue_wait_secs: f64,
+    latency_secs: f64,
+}
+
+impl Window {
+    fn record(&mut self, sample: &Sample) {
+        let secs = sample.latency.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| secs <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.latency[bucket] += 1;
+        self.requests += 1;
+        self.completion_tokens += sample.completion_tokens;
+        self.eval_secs += sample.eval_duration.as_secs_f64();
+        self.prompt_tokens += sample.prompt_tokens;
+        self.prompt_eval_secs += sample.prompt_eval_duration.as_secs_f64();
+        self.queue_wait_secs += sample.queue_wait.as_secs_f64();
+        self.latency_secs += secs;
+    }
+
+    fn merge(&self, other: &Window) -> Window {
+        let mut merged = self.clone();
+        for (a, b) in merged.latency.iter_mut().zip(other.latency) {
+            *a += b;
+        }
+        merged.requests += other.requests;
+        merged.completion_tokens += other.completion_tokens;
+        merged.eval_secs += other.eval_secs;
+        merged.prompt_tokens += other.prompt_tokens;
+        merged.prompt_eval_secs += other.prompt_eval_secs;
+        merged.queue_wait_secs += other.queue_wait_secs;
+        merged.latency_secs += other.latency_secs;
+        merged
+    }
+
+    /// Upper bound of the bucket holding the `q` quantile. `None` if the window
+    /// is empty or the quantile falls in the overflow bucket.
+    fn quantile(&self, q: f64) -> Option<f64> {
+        let rank = (q * self.requests as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (count, bound) in self.latency.iter().zip(LATENCY_BUCKETS) {
+            seen += count;
+            if seen >= rank {
+                return Some(bound);
+            }
+        }
+        None
+    }
+}
+
+/// A model's figures over the last one to two windows.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PerfSummary {
+    pub requests: u64,
+    pub generation_tokens_per_sec: f64,
+    pub prompt_tokens_per_sec: f64,
+    pub avg_queue_wait: Duration,
+    pub avg_latency: Duration,
+    pub avg_completion_tokens: f64,
+    pub p50: Option<f64>,
+    pub p95: Option<f64>,
+    pub p99: Option<f64>,
+}
+
+#[derive(Default)]
+struct ModelPerf {
+    current: Window,
+    previous: Window,
+}
+
+pub struct PerfStats {
+    window: Duration,
+    window_start: Instant,
+    models: HashMap<String, ModelPerf>,
+}
+
+impl PerfStats {
+    pub fn new(window: Duration, now: Instant) -> Self {
+        Self {
+            window,
+            window_start: now,
+            models: HashMap::new(),
+        }
+    }
+
+    fn rotate(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed < self.window {
+            return;
+        }
+        // After two or more windows of silence the previous one is stale too.
+        let stale = elapsed >= self.window * 2;
+        for perf in self.models.values_mut() {
+            perf.previous = if stale {
+                Window::default()
+            } else {
+                std::mem::take(&mut perf.current)
+            };
+            perf.current = Window::default();
+        }
+        self.models
+            .retain(|_, perf| perf.previous.requests > 0 || perf.current.requests > 0);
+        self.window_start = now;
+    }
+
+    pub fn record(&mut self, model: &str, sample: Sample, now: Instant) {
+        self.rotate(now);
+        if !self.models.contains_key(model) {
+            self.models.insert(model.to_string(), ModelPerf::default());
+        }
+        self.models.get_mut(model).unwrap().current.record(&sample);
+    }
+
+    pub fn summary(&mut self, now: Instant) -> BTreeMap<&str, PerfSummary> {
+        self.rotate(now);
+        self.models
+            .iter()
+            .map(|(model, perf)| {
+                let w = perf.current.merge(&perf.previous);
+                let rate = |tokens: u64, secs: f64| {
+                    if secs > 0.0 {
+                        tokens as f64 / secs
+                    } else {
+                        0.0
+                    }
+                };
+                let requests = w.requests.max(1) as f64;
+                let summary = PerfSummary {
+                    requests: w.requests,
+                    generation_tokens_per_sec: rate(w.completion_tokens, w.eval_secs),
+                    prompt_tokens_per_sec: rate(w.prompt_tokens, w.prompt_eval_secs),
+                    avg_queue_wait: Duration::from_secs_f64(w.queue_wait_secs / requests),
+                    avg_latency: Duration::from_secs_f64(w.latency_secs / requests),
+                    avg_completion_tokens: w.completion_tokens as f64 / requests,
+                    p50: w.quantile(0.50),
+                    p95: w.quantile(0.95),
+                    p99: w.quantile(0.99),
+                };
+                (model.as_str(), summary)
+            })
+            .collect()
+    }
+
+    /// The average queue wait over every model's last one to two windows.
+    /// `None` without requests.
+    pub fn avg_queue_wait(&mut self, now: Instant) -> Option<Duration> {
+        self.rotate(now);
+        let (requests, secs) = self
+            .models
+            .values()
+            .map(|perf| perf.current.merge(&perf.previous))
+            .fold((0, 0.0), |(requests, secs), w| {
+                (requests + w.requests, secs + w.queue_wait_secs)
+            });
+        (requests > 0).then(|| Duration::from_secs_f64(secs / requests as f64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_secs(60);
+
+    fn took(secs: f64) -> Sample {
+        Sample {
+            latency: Duration::from_secs_f64(secs),
+            ..Sample::default()
+        }
+    }
+
+    fn window_of(latencies: &[f64]) -> Window {
+        let mut window = Window::default();
+        for secs in latencies {
+            window.record(&took(*secs));
+        }
+        window
+    }
+
+    #[test]
+    fn an_empty_window_has_no_quantiles() {
+        assert_eq!(Window::default().quantile(0.5), None);
+        assert_eq!(Window::default().quantile(0.0), None);
+    }
+
+    #[test]
+    fn quantiles_are_bucket_upper_bounds() {
+        let window = window_of(&[0.05, 0.3, 0.3, 2.0]);
+        // A zero quantile still means the first request.
+        assert_eq!(window.quantile(0.0), Some(0.1));
+        assert_eq!(window.quantile(0.25), Some(0.1));
+        // Rank 1.2 rounds up to the second request.
+        assert_eq!(window.quantile(0.3), Some(0.5));
+        assert_eq!(window.quantile(0.75), Some(0.5));
+        assert_eq!(window.quantile(1.0), Some(2.5));
+        // A latency on a bound falls in that bound's bucket.
+        assert_eq!(window_of(&[0.25]).quantile(0.5), Some(0.25));
+    }
+
+    #[test]
+    fn quantiles_in_the_overflow_bucket_are_unknown() {
+        let window = window_of(&[1.0, 1000.0]);
+        assert_eq!(window.quantile(0.5), Some(1.0));
+        assert_eq!(window.quantile(0.99), None);
+    }
+
+    #[test]
+    fn a_summary_covers_the_previous_window_too() {
+        let start = Instant::now();
+        let mut perf = PerfStats::new(WINDOW, start);
+        perf.record("m", took(1.0), start);
+        perf.record("m", took(3.0), start + WINDOW);
+        let summary = perf.summary(start + WINDOW + WINDOW / 2)["m"];
+        assert_eq!(summary.requests, 2);
+        assert_eq!(summary.avg_latency, Duration::from_secs(2));
+        // One window later only the second request is left.
+        let summary = perf.summary(start + WINDOW * 2)["m"];
+        assert_eq!(summary.requests, 1);
+        assert_eq!(summary.avg_latency, Duration::from_secs(3));
+    }
+
+    #[test]
+    fn two_windows_of_silence_forget_everything() {
+        let start = Instant::now();
+        let mut perf = PerfStats::new(WINDOW, start);
+        perf.record("m", took(1.0), start);
+        assert!(perf.summary(start + WINDOW * 2).is_empty());
+        assert_eq!(perf.avg_queue_wait(start + WINDOW * 2), None);
+    }
+
+    #[test]
+    fn idle_models_are_dropped() {
+        let start = Instant::now();
+        let mut perf = PerfStats::new(WINDOW, start);
+        perf.record("old", took(1.0), start);
+        perf.record("new", took(1.0), start + WINDOW);
+        let models: Vec<_> = perf.summary(start + WINDOW).into_keys().collect();
+        assert_eq!(models, ["new", "old"]);
+        perf.record("new", took(1.0), start + WINDOW * 2);
+        let models: Vec<_> = perf.summary(start + WINDOW * 2).into_keys().collect();
+        assert_eq!(models, ["new"]);
+        assert!(!perf.models.contains_key("old"));
+    }
+}
@@ -0,0 +1,426 @@
+//! Peer exchange: learning about other workers from the peers we know.
+//!
+//! A node that serves `/mesh-ai/pex/1.0.0` answers with the workers it has
+//! seen identify themselves, capped at [`MAX_SHARED_PEERS`]. What it shares is
+//! up to its [`PexMode`]. Learned peers go into an [`AddressBook`] that records
+//! who told us about each one, so bad hints can be traced to their source.
+
+use std::time::{Duration, Instant};
+
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bounded::{BoundedConfig, BoundedMap},
+    labels::Labels,
+    profile::Profile,
+};
+
+/// Most workers a node returns in one answer.
+pub const MAX_SHARED_PEERS: usize = 32;
+/// Most workers an [`AddressBook`] keeps.
+pub const MAX_BOOK_ENTRIES: usize = 1024;
+/// How long an [`AddressBook`] keeps a hint nobody has repeated.
+pub const BOOK_ENTRY_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// What a node shares about the workers it knows.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum PexMode {
+    /// Don't serve peer exchange at all.
+    #[default]
+    Off,
+    /// Share PeerIds and the models they announce, but not their addresses.
+    ModelsOnly,
+    /// Share PeerIds, addresses, and models.
+    Full,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PexRequest {
+    /// Return at most this many peers; the node's cap applies regardless.
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PexResponse {
+    pub peers: Vec<PexPeer>,
+}
+
+/// A worker as one node describes it to another. Ids and addresses are sent
+/// as strings; entries that don't parse are dropped on receipt.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PexPeer {
+    pub peer_id: String,
+    #[serde(default)]
+    pub addrs: Vec<String>,
+    #[serde(default)]
+    pub models: Vec<String>,
+    /// The worker's nickname and contact, as it announced them.
+    #[serde(default)]
+    pub profile: Profile,
+}
+
+/// What a worker announced through identify.
+#[derive(Debug, Clone, Default)]
+pub struct KnownWorker {
+    pub addrs: Vec<Multiaddr>,
+    pub models: Vec<String>,
+    pub profile: Profile,
+    pub labels: Labels,
+    /// The models the worker takes tools for, as names or `prefix*`
+    /// patterns.
+    pub tool_models: Vec<String>,
+}
+
+/// Workers this node has seen, as reported by identify. A worker's record
+/// goes stale once it hasn't identified for the record TTL, and the least
+/// recently identified make way once the capacity is reached.
+#[derive(Debug)]
+pub struct KnownWorkers {
+    workers: BoundedMap<PeerId, KnownWorker>,
+}
+
+impl KnownWorkers {
+    pub fn new(capacity: usize, record_ttl: Duration) -> Self {
+        Self {
+            workers: BoundedMap::new(BoundedConfig {
+                capacity,
+                ttl: Some(record_ttl),
+            }),
+        }
+    }
+
+    /// Keeps `peer`'s record however long ago it identified, until it is
+    /// removed, e.g. for the relay.
+    pub fn exempt(&mut self, peer: PeerId) {
+        self.workers.exempt(peer);
+    }
+
+    pub fn insert(&mut self, peer: PeerId, worker: KnownWorker, now: Instant) {
+        self.workers.insert(peer, worker, now);
+    }
+
+    pub fn get(&self, peer: &PeerId) -> Option<&KnownWorker> {
+        self.workers.get(peer)
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = &PeerId> {
+        self.workers.keys()
+    }
+
+    /// What `peer` announced about itself, if it is a known worker.
+    pub fn profile(&self, peer: &PeerId) -> Option<&Profile> {
+        self.workers.get(peer).map(|worker| &worker.profile)
+    }
+
+    pub fn remove(&mut self, peer: &PeerId) {
+        self.workers.remove(peer);
+    }
+
+    /// Drops stale records, returning how many were dropped, for age or for
+    /// room, since the last call.
+    pub fn prune(&mut self, now: Instant) -> u64 {
+        self.workers.prune(now);
+        self.workers.take_evicted()
+    }
+
+    pub fn len(&self) -> usize {
+        self.workers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.workers.is_empty()
+    }
+
+    /// The answer to `request` from `requester`, who is never told about
+    /// itself. Stale records aren't shared, pruned or not.
+    pub fn answer(
+        &self,
+        mode: PexMode,
+        requester: &PeerId,
+        request: &PexRequest,
+        now: Instant,
+    ) -> PexResponse {
+        let limit = request
+            .limit
+            .unwrap_or(MAX_SHARED_PEERS)
+            .min(MAX_SHARED_PEERS);
+        let peers = self
+            .workers
+            .fresh(now)
+            .filter(|(peer, _)| *peer != requester)
+            .take(limit)
+            .map(|(peer, worker)| PexPeer {
+                peer_id: peer.to_string(),
+                addrs: match mode {
+                    PexMode::Full => worker.addrs.iter().map(ToString::to_string).collect(),
+                    PexMode::ModelsOnly | PexMode::Off => Vec::new(),
+                },
+                models: worker.models.clone(),
+                profile: worker.profile.clone(),
+            })
+            .collect();
+        PexResponse { peers }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BookEntry {
+    pub addrs: Vec<Multiaddr>,
+    pub models: Vec<String>,
+    /// As the source relayed it, sanitized again on receipt.
+    pub profile: Profile,
+    /// The peer that told us about this one.
+    pub source: PeerId,
+}
+
+/// Workers learned through peer exchange. Hints are dropped after
+/// [`BOOK_ENTRY_TTL`] without being repeated, and the oldest make way once
+/// [`MAX_BOOK_ENTRIES`] are kept.
+#[derive(Debug)]
+pub struct AddressBook {
+    entries: BoundedMap<PeerId, BookEntry>,
+}
+
+impl Default for AddressBook {
+    fn default() -> Self {
+        Self {
+            entries: BoundedMap::new(BoundedConfig {
+                capacity: MAX_BOOK_ENTRIES,
+                ttl: Some(BOOK_ENTRY_TTL),
+            }),
+        }
+    }
+}
+
+impl AddressBook {
+    /// Adds what `source` told us, replacing earlier hints about the same
+    /// peers. Returns the peers that came with at least one usable address.
+    pub fn merge(
+        &mut self,
+        source: PeerId,
+        local: &PeerId,
+        response: PexResponse,
+    ) -> Vec<(PeerId, Vec<Multiaddr>)> {
+        let now = Instant::now();
+        self.entries.prune(now);
+        let mut dialable = Vec::new();
+        for hint in response.peers.into_iter().take(MAX_SHARED_PEERS) {
+            let Ok(peer) = hint.peer_id.parse::<PeerId>() else {
+                continue;
+            };
+            if peer == *local || peer == source {
+                continue;
+            }
+            let addrs: Vec<Multiaddr> = hint.addrs.iter().filter_map(|a| a.parse().ok()).collect();
+            if !addrs.is_empty() {
+                dialable.push((peer, addrs.clone()));
+            }
+            self.entries.insert(
+                peer,
+                BookEntry {
+                    addrs,
+                    models: hint.models,
+                    profile: Profile::new(
+                        hint.profile.nickname.as_deref(),
+                        hint.profile.operator_contact.as_deref(),
+                    ),
+                    source,
+                },
+                now,
+            );
+        }
+        dialable
+    }
+
+    pub fn get(&self, peer: &PeerId) -> Option<&BookEntry> {
+        self.entries.get(peer)
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (&PeerId, &BookEntry)> {
+        self.entries.iter()
+    }
+
+    /// Forgets everything learned from `source`, e.g. once its hints turn out
+    /// to be bad.
+    pub fn forget_source(&mut self, source: &PeerId) {
+        self.entries.retain(|_, entry| entry.source != *source);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn worker(addr: &str, model: &str) -> KnownWorker {
+        KnownWorker {
+            addrs: vec![addr.parse().unwrap()],
+            models: vec![model.to_string()],
+            ..Default::default()
+        }
+    }
+
+    fn hint(peer: &PeerId, addrs: &[&str]) -> PexPeer {
+        PexPeer {
+            peer_id: peer.to_string(),
+            addrs: addrs.iter().map(ToString::to_string).collect(),
+            models: vec!["llama3".to_string()],
+            profile: Profile::default(),
+        }
+    }
+
+    #[test]
+    fn answers_follow_the_mode_and_skip_the_requester() {
+        let now = Instant::now();
+        let (requester, other) = (PeerId::random(), PeerId::random());
+        let mut known = KnownWorkers::new(16, Duration::from_secs(60));
+        known.insert(requester, worker("/ip4/10.0.0.1/tcp/1", "a"), now);
+        known.insert(other, worker("/ip4/10.0.0.2/tcp/1", "b"), now);
+
+        let full = known.answer(PexMode::Full, &requester, &PexRequest::default(), now);
+        assert_eq!(full.peers.len(), 1);
+        assert_eq!(full.peers[0].peer_id, other.to_string());
+        assert_eq!(full.peers[0].addrs, ["/ip4/10.0.0.2/tcp/1"]);
+        assert_eq!(full.peers[0].models, ["b"]);
+
+        let models_only =
+            known.answer(PexMode::ModelsOnly, &requester, &PexRequest::default(), now);
+        assert!(models_only.peers[0].addrs.is_empty());
+        assert_eq!(models_only.peers[0].models, ["b"]);
+    }
+
+    #[test]
+    fn answers_are_capped() {
+        let now = Instant::now();
+        let mut known = KnownWorkers::new(100, Duration::from_secs(60));
+        for i in 0..50 {
+            known.insert(
+                PeerId::random(),
+                worker(&format!("/ip4/10.0.0.{i}/tcp/1"), "m"),
+                now,
+            );
+        }
+        let requester = PeerId::random();
+        let answer = known.answer(PexMode::Full, &requester, &PexRequest::default(), now);
+        assert_eq!(answer.peers.len(), MAX_SHARED_PEERS);
+        let request = PexRequest { limit: Some(5) };
+        assert_eq!(
+            known
+                .answer(PexMode::Full, &requester, &request, now)
+                .peers
+                .len(),
+            5
+        );
+        let request = PexRequest { limit: Some(1000) };
+        let answer = known.answer(PexMode::Full, &requester, &request, now);
+        assert_eq!(answer.peers.len(), MAX_SHARED_PEERS);
+    }
+
+    #[test]
+    fn stale_records_are_not_shared() {
+        let now = Instant::now();
+        let ttl = Duration::from_secs(60);
+        let mut known = KnownWorkers::new(16, ttl);
+        known.insert(PeerId::random(), worker("/ip4/10.0.0.1/tcp/1", "a"), now);
+        let later = now + ttl;
+        let answer = known.answer(
+            PexMode::Full,
+            &PeerId::random(),
+            &PexRequest::default(),
+            later,
+        );
+        assert!(answer.peers.is_empty());
+        assert_eq!(known.prune(later), 1);
+        assert!(known.is_empty());
+    }
+
+    #[test]
+    fn known_workers_stay_within_capacity() {
+        let now = Instant::now();
+        let relay = PeerId::random();
+        let mut known = KnownWorkers::new(8, Duration::from_secs(60));
+        known.exempt(relay);
+        known.insert(relay, worker("/ip4/10.0.0.1/tcp/1", "r"), now);
+        for _ in 0..1000 {
+            known.insert(PeerId::random(), worker("/ip4/10.0.0.2/tcp/1", "m"), now);
+        }
+        assert_eq!(known.len(), 8);
+        assert!(known.get(&relay).is_some());
+        assert_eq!(known.prune(now), 993);
+    }
+
+    #[test]
+    fn merge_keeps_attributed_hints_and_returns_dialable_ones() {
+        let (local, source) = (PeerId::random(), PeerId::random());
+        let (dialable, addressless) = (PeerId::random(), PeerId::random());
+        let response = PexResponse {
+            peers: vec![
+                hint(&dialable, &["/ip4/10.0.0.1/tcp/1", "not an address"]),
+                hint(&addressless, &[]),
+                hint(&local, &["/ip4/10.0.0.2/tcp/1"]),
+                hint(&source, &["/ip4/10.0.0.3/tcp/1"]),
+                PexPeer {
+                    peer_id: "not a peer".to_string(),
+                    ..hint(&dialable, &[])
+                },
+            ],
+        };
+        let mut book = AddressBook::default();
+        let learned = book.merge(source, &local, response);
+        assert_eq!(
+            learned,
+            [(dialable, vec!["/ip4/10.0.0.1/tcp/1".parse().unwrap()])]
+        );
+        assert_eq!(book.entries().count(), 2);
+        assert_eq!(book.get(&dialable).unwrap().source, source);
+        assert!(book.get(&addressless).unwrap().addrs.is_empty());
+        assert!(book.get(&local).is_none());
+        assert!(book.get(&source).is_none());
+    }
+
+    #[test]
+    fn merge_takes_at_most_a_full_answer() {
+        let response = PexResponse {
+            peers: (0..100)
+                .map(|_| hint(&PeerId::random(), &["/ip4/10.0.0.1/tcp/1"]))
+                .collect(),
+        };
+        let mut book = AddressBook::default();
+        let learned = book.merge(PeerId::random(), &PeerId::random(), response);
+        assert_eq!(learned.len(), MAX_SHARED_PEERS);
+        assert_eq!(book.entries().count(), MAX_SHARED_PEERS);
+    }
+
+    #[test]
+    fn address_book_stays_within_capacity() {
+        let (local, source) = (PeerId::random(), PeerId::random());
+        let mut book = AddressBook::default();
+        for _ in 0..(MAX_BOOK_ENTRIES / MAX_SHARED_PEERS + 10) {
+            let response = PexResponse {
+                peers: (0..MAX_SHARED_PEERS)
+                    .map(|_| hint(&PeerId::random(), &["/ip4/10.0.0.1/tcp/1"]))
+                    .collect(),
+            };
+            book.merge(source, &local, response);
+        }
+        assert_eq!(book.entries().count(), MAX_BOOK_ENTRIES);
+    }
+
+    #[test]
+    fn forgetting_a_source_drops_only_its_hints() {
+        let local = PeerId::random();
+        let (bad, good) = (PeerId::random(), PeerId::random());
+        let (from_bad, from_good) = (PeerId::random(), PeerId::random());
+        let mut book = AddressBook::default();
+        for (source, peer) in [(bad, from_bad), (good, from_good)] {
+            let response = PexResponse {
+                peers: vec![hint(&peer, &["/ip4/10.0.0.1/tcp/1"])],
+            };
+            book.merge(source, &local, response);
+        }
+        book.forget_source(&bad);
+        assert!(book.get(&from_bad).is_none());
+        assert!(book.get(&from_good).is_some());
+    }
+}
@@ -0,0 +1,282 @@
+//! Keeps connections to designated infrastructure peers (relays, scheduler) open
+//! regardless of the swarm's idle connection timeout.
+//!
+//! Ping alone no longer keeps a connection alive, so every connection gets a
+//! small handler whose only job is to vote on keep-alive. Connections to pinned
+//! peers vote "yes"; everything else is left to expire normally.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    convert::Infallible,
+    task::{Context, Poll},
+};
+
+use libp2p::{
+    Multiaddr, PeerId,
+    core::{Endpoint, transport::PortUse, upgrade::DeniedUpgrade},
+    swarm::{
+        ConnectionDenied, ConnectionHandlerEvent, ConnectionId, FromSwarm, NetworkBehaviour,
+        NotifyHandler, SubstreamProtocol, THandler, THandlerInEvent, THandlerOutEvent, ToSwarm,
+        handler::ConnectionEvent,
+    },
+};
+
+/// Tracks the set of pinned peers and keeps their connections alive.
+#[derive(Default)]
+pub struct Behaviour {
+    pinned: HashSet<PeerId>,
+    connections: HashMap<ConnectionId, PeerId>,
+    pending: VecDeque<ToSwarm<Infallible, bool>>,
+}
+
+impl Behaviour {
+    pub fn new(peers: impl IntoIterator<Item = PeerId>) -> Self {
+        Self {
+            pinned: peers.into_iter().collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Pins `peer`. Returns `false` if it was already pinned.
+    pub fn pin(&mut self, peer: PeerId) -> bool {
+        if !self.pinned.insert(peer) {
+            return false;
+        }
+        self.notify(peer, true);
+        true
+    }
+
+    /// Unpins `peer`, letting its connections expire once idle.
+    pub fn unpin(&mut self, peer: &PeerId) -> bool {
+        if !self.pinned.remove(peer) {
+            return false;
+        }
+        self.notify(*peer, false);
+        true
+    }
+
+    pub fn is_pinned(&self, peer: &PeerId) -> bool {
+        self.pinned.contains(peer)
+    }
+
+    pub fn pinned(&self) -> impl Iterator<Item = &PeerId> {
+        self.pinned.iter()
+    }
+
+    fn notify(&mut self, peer: PeerId, keep_alive: bool) {
+        for (id, p) in &self.connections {
+            if *p == peer {
+                self.pending.push_back(ToSwarm::NotifyHandler {
+                    peer_id: peer,
+                    handler: NotifyHandler::One(*id),
+                    event: keep_alive,
+                });
+            }
+        }
+    }
+
+    fn handler_for(&mut self, connection_id: ConnectionId, peer: PeerId) -> Handler {
+        self.connections.insert(connection_id, peer);
+        Handler {
+            keep_alive: self.pinned.contains(&peer),
+        }
+    }
+}
+
+impl NetworkBehaviour for Behaviour {
+    type ConnectionHandler = Handler;
+    type ToSwarm = Infallible;
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        _: &Multiaddr,
+        _: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(self.handler_for(connection_id, peer))
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        _: &Multiaddr,
+        _: Endpoint,
+        _: PortUse,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(self.handler_for(connection_id, peer))
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        _: PeerId,
+        _: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        match event {}
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm) {
+        if let FromSwarm::ConnectionClosed(closed) = event {
+            self.connections.remove(&closed.connection_id);
+        }
+    }
+
+    fn poll(&mut self, _: &mut Context<'_>) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        match self.pending.pop_front() {
+            Some(event) => Poll::Ready(event),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Per-connection handler that speaks no protocol and only reports keep-alive.
+pub struct Handler {
+    keep_alive: bool,
+}
+
+impl libp2p::swarm::ConnectionHandler for Handler {
+    type FromBehaviour = bool;
+    type ToBehaviour = Infallible;
+    type InboundProtocol = DeniedUpgrade;
+    type OutboundProtocol = DeniedUpgrade;
+    type InboundOpenInfo = ();
+    type OutboundOpenInfo = ();
+
+    fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol> {
+        SubstreamProtocol::new(DeniedUpgrade, ())
+    }
+
+    fn connection_keep_alive(&self) -> bool {
+        self.keep_alive
+    }
+
+    fn on_behaviour_event(&mut self, keep_alive: bool) {
+        self.keep_alive = keep_alive;
+    }
+
+    fn poll(
+        &mut self,
+        _: &mut Context<'_>,
+    ) -> Poll<ConnectionHandlerEvent<Self::OutboundProtocol, (), Self::ToBehaviour>> {
+        Poll::Pending
+    }
+
+    fn on_connection_event(
+        &mut self,
+        _: ConnectionEvent<Self::InboundProtocol, Self::OutboundProtocol>,
+    ) {
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::{
+        core::ConnectedPoint,
+        swarm::{ConnectionHandler, behaviour::ConnectionClosed},
+    };
+
+    use super::*;
+
+    /// The keep-alive votes the behaviour has queued, by connection.
+    fn votes(behaviour: &mut Behaviour) -> Vec<(ConnectionId, bool)> {
+        let mut cx = Context::from_waker(std::task::Waker::noop());
+        let mut votes = Vec::new();
+        while let Poll::Ready(event) = NetworkBehaviour::poll(behaviour, &mut cx) {
+            match event {
+                ToSwarm::NotifyHandler {
+                    handler: NotifyHandler::One(id),
+                    event,
+                    ..
+                } => votes.push((id, event)),
+                _ => panic!("only handler notifications are queued"),
+            }
+        }
+        votes.sort_by_key(|(id, _)| *id);
+        votes
+    }
+
+    fn close(behaviour: &mut Behaviour, peer: PeerId, connection: ConnectionId) {
+        let endpoint = ConnectedPoint::Dialer {
+            address: "/memory/1".parse().unwrap(),
+            role_override: Endpoint::Dialer,
+            port_use: PortUse::Reuse,
+        };
+        behaviour.on_swarm_event(FromSwarm::ConnectionClosed(ConnectionClosed {
+            peer_id: peer,
+            connection_id: connection,
+            endpoint: &endpoint,
+            cause: None,
+            remaining_established: 0,
+        }));
+    }
+
+    #[test]
+    fn only_pinned_peers_are_kept_alive() {
+        let relay = PeerId::random();
+        let mut behaviour = Behaviour::new([relay]);
+        let pinned = behaviour.handler_for(ConnectionId::new_unchecked(1), relay);
+        let other = behaviour.handler_for(ConnectionId::new_unchecked(2), PeerId::random());
+        assert!(pinned.connection_keep_alive());
+        assert!(!other.connection_keep_alive());
+    }
+
+    #[test]
+    fn pinning_tells_each_of_the_peers_connections() {
+        let (peer, other) = (PeerId::random(), PeerId::random());
+        let mut behaviour = Behaviour::default();
+        let (first, second) = (
+            ConnectionId::new_unchecked(1),
+            ConnectionId::new_unchecked(2),
+        );
+        behaviour.handler_for(first, peer);
+        behaviour.handler_for(second, peer);
+        behaviour.handler_for(ConnectionId::new_unchecked(3), other);
+
+        assert!(behaviour.pin(peer));
+        assert_eq!(votes(&mut behaviour), [(first, true), (second, true)]);
+        assert!(!behaviour.pin(peer), "already pinned");
+        assert!(votes(&mut behaviour).is_empty());
+        assert_eq!(behaviour.pinned().collect::<Vec<_>>(), [&peer]);
+
+        assert!(behaviour.unpin(&peer));
+        assert_eq!(votes(&mut behaviour), [(first, false), (second, false)]);
+        assert!(!behaviour.unpin(&peer), "no longer pinned");
+        assert!(!behaviour.is_pinned(&peer));
+    }
+
+    #[test]
+    fn closed_connections_are_forgotten() {
+        let peer = PeerId::random();
+        let mut behaviour = Behaviour::default();
+        let (first, second) = (
+            ConnectionId::new_unchecked(1),
+            ConnectionId::new_unchecked(2),
+        );
+        behaviour.handler_for(first, peer);
+        behaviour.handler_for(second, peer);
+        close(&mut behaviour, peer, first);
+        behaviour.pin(peer);
+        assert_eq!(votes(&mut behaviour), [(second, true)]);
+    }
+
+    #[test]
+    fn a_peer_pinned_before_it_connects_is_kept_alive() {
+        let peer = PeerId::random();
+        let mut behaviour = Behaviour::default();
+        behaviour.pin(peer);
+        assert!(votes(&mut behaviour).is_empty());
+        let handler = behaviour.handler_for(ConnectionId::new_unchecked(1), peer);
+        assert!(handler.connection_keep_alive());
+    }
+
+    #[test]
+    fn the_handler_follows_the_behaviours_vote() {
+        let mut handler = Handler { keep_alive: false };
+        handler.on_behaviour_event(true);
+        assert!(handler.connection_keep_alive());
+        handler.on_behaviour_event(false);
+        assert!(!handler.connection_keep_alive());
+    }
+}
@@ -0,0 +1,575 @@
+//! A set of workers a [`Client`] spreads requests over.
+//!
+//! Sessions are mapped to workers with rendezvous hashing: every worker gets a
+//! score for the session and the highest score wins. The mapping is stable for
+//! as long as the worker is alive, and when one dies only its sessions move.
+//!
+//! The pool can grow itself through peer exchange: [`PeerPool::learn_from`]
+//! asks a worker for the others it knows and adds the dialable ones, up to
+//! [`MAX_POOL_PEERS`] workers in all, so a peer can't grow the pool without
+//! bound.
+//!
+//! It can also follow a static [`WorkerList`]: [`PeerPool::check_workers`]
+//! probes the workers that are due and keeps the pool to the healthy ones,
+//! and [`PeerPool::reload_workers`] picks up edits to the list's file. A
+//! long list is dialed in waves, a bounded number at a time, so the first
+//! check doesn't open hundreds of connections at once.
+//!
+//! A [`LabelFilter`] narrows the choice further. Workers without a required
+//! label are skipped, and among the rest those with more preferred labels
+//! win; the session's hash only decides between equally preferred workers.
+//! A worker's labels are learned when it is connected to, so one not
+//! connected to yet is ranked as though it had every preferred label, and
+//! reconsidered once they are known.
+//!
+//! Requests outside a session have no worker to stick to, so
+//! [`PeerPool::send_prompt`] picks one by [`Strategy`] among the workers the
+//! label filter ranks highest. For that the pool keeps, per worker, how many
+//! of its requests are in flight and a running average of how long answers
+//! take.
+
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    io,
+    num::NonZeroUsize,
+    sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use futures::{StreamExt, stream};
+use libp2p::{Multiaddr, PeerId};
+
+use crate::{
+    PromptRequest, PromptResponse, ResponseStatus, backoff,
+    bounded::{BoundedConfig, BoundedMap},
+    client::{Client, ClientError},
+    labels::{LabelFilter, Labels},
+    pex::{AddressBook, BookEntry, PexRequest},
+    workers::{Health, WorkerList},
+};
+
+/// Most workers the pool grows to through peer exchange. Workers added
+/// directly or from a worker list count towards it but aren't refused.
+pub const MAX_POOL_PEERS: usize = 1024;
+
+/// Weight of the newest answer in a worker's average latency.
+const LATENCY_SMOOTHING: f64 = 0.3;
+
+/// Workers [`PeerPool::check_workers`] dials at once by default.
+pub const DEFAULT_DIAL_CONCURRENCY: usize = 16;
+
+/// How [`PeerPool::send_prompt`] picks a worker.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Strategy {
+    /// Each worker in turn.
+    RoundRobin,
+    /// The worker with the fewest requests in flight from this pool, the
+    /// faster one on a tie.
+    LeastLoaded,
+    /// A random worker, with odds proportional to the inverse of its
+    /// latency: fast workers get more requests, but not all of them. Workers
+    /// without answers yet get the fastest's odds, so they are tried.
+    #[default]
+    WeightedRandom,
+}
+
+/// What the pool has seen of one worker.
+#[derive(Debug, Clone, Copy, Default)]
+struct Load {
+    in_flight: usize,
+    /// Average time to a successful answer; `None` until there is one.
+    latency: Option<Duration>,
+}
+
+pub struct PeerPool {
+    client: Client,
+    peers: Mutex<Vec<(PeerId, Vec<Multiaddr>)>>,
+    address_book: Mutex<AddressBook>,
+    filter: LabelFilter,
+    /// Labels of the workers connected to so far.
+    labels: Mutex<BoundedMap<PeerId, Labels>>,
+    strategy: Strategy,
+    loads: Mutex<BoundedMap<PeerId, Load>>,
+    /// Where [`Strategy::RoundRobin`] goes next.
+    next: AtomicUsize,
+    /// Workers dialed at once when checking a worker list.
+    dial_concurrency: usize,
+}
+
+impl PeerPool {
+    pub fn new(client: Client, peers: impl IntoIterator<Item = (PeerId, Vec<Multiaddr>)>) -> Self {
+        Self {
+            client,
+            peers: Mutex::new(peers.into_iter().collect()),
+            address_book: Mutex::new(AddressBook::default()),
+            filter: LabelFilter::default(),
+            labels: Mutex::new(per_peer()),
+            strategy: Strategy::default(),
+            loads: Mutex::new(per_peer()),
+            next: AtomicUsize::new(0),
+            dial_concurrency: DEFAULT_DIAL_CONCURRENCY,
+        }
+    }
+
+    /// Only uses workers `filter` admits, preferring those it prefers.
+    pub fn with_label_filter(mut self, filter: LabelFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Picks workers for requests outside a session by `strategy`.
+    pub fn with_strategy(mut self, strategy: Strategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Dials at most `limit` workers at once when checking a worker list.
+    pub fn with_dial_concurrency(mut self, limit: NonZeroUsize) -> Self {
+        self.dial_concurrency = limit.get();
+        self
+    }
+
+    pub fn add_peer(&self, peer: PeerId, addrs: Vec<Multiaddr>) {
+        let mut peers = self.peers.lock().unwrap();
+        peers.retain(|(p, _)| *p != peer);
+        peers.push((peer, addrs));
+    }
+
+    pub fn remove_peer(&self, peer: &PeerId) {
+        self.peers.lock().unwrap().retain(|(p, _)| p != peer);
+        self.labels.lock().unwrap().remove(peer);
+        self.loads.lock().unwrap().remove(peer);
+    }
+
+    pub fn peers(&self) -> Vec<PeerId> {
+        self.peers.lock().unwrap().iter().map(|(p, _)| *p).collect()
+    }
+
+    /// The labels `peer` announced, if it has been connected to.
+    pub fn labels(&self, peer: &PeerId) -> Option<Labels> {
+        self.labels.lock().unwrap().get(peer).cloned()
+    }
+
+    /// Stores the labels `peer` announced. Call once connected.
+    async fn learn_labels(&self, peer: PeerId) {
+        let labels = self.client.labels(peer).await.unwrap_or_default();
+        self.labels
+            .lock()
+            .unwrap()
+            .insert(peer, labels, Instant::now());
+    }
+
+    /// Asks `source` for the workers it knows and adds those that came with
+    /// addresses, while the pool has fewer than [`MAX_POOL_PEERS`]. Returns
+    /// how many were added or updated.
+    pub async fn learn_from(&self, source: PeerId) -> Result<usize, ClientError> {
+        let response = self
+            .client
+            .exchange_peers(source, PexRequest::default())
+            .await?;
+        let local = self.client.local_peer_id();
+        let learned = self
+            .address_book
+            .lock()
+            .unwrap()
+            .merge(source, &local, response);
+        let mut peers = self.peers.lock().unwrap();
+        let mut added = 0;
+        for (peer, addrs) in learned {
+            let full = peers.len() >= MAX_POOL_PEERS;
+            match peers.iter_mut().find(|(p, _)| *p == peer) {
+                Some((_, known)) => *known = addrs,
+                None if !full => peers.push((peer, addrs)),
+                None => continue,
+            }
+            added += 1;
+        }
+        Ok(added)
+    }
+
+    /// Checks the workers in `workers` that are due, up to the dial
+    /// concurrency at a time, then adds those that came up to the pool and
+    /// removes those that went down. Returns the workers whose health
+    /// changed.
+    pub async fn check_workers(&self, workers: &mut WorkerList) -> Vec<(PeerId, Health)> {
+        let due = workers.due(Instant::now());
+        let results: Vec<_> = stream::iter(due)
+            .map(|(peer, addrs)| async move {
+                let result = self.client.connect(peer, addrs.clone()).await;
+                (peer, addrs, result.map_err(|e| e.to_string()))
+            })
+            .buffer_unordered(self.dial_concurrency)
+            .collect()
+            .await;
+        let mut changed = Vec::new();
+        for (peer, addrs, result) in results {
+            if result.is_ok() {
+                self.learn_labels(peer).await;
+            }
+            let Some(health) = workers.record(&peer, result, Instant::now()) else {
+                continue;
+            };
+            match health {
+                Health::Up => self.add_peer(peer, addrs),
+                Health::Down | Health::Unknown => self.remove_peer(&peer),
+            }
+            changed.push((peer, health));
+        }
+        changed
+    }
+
+    /// Re-reads `workers`' file, dropping workers no longer listed from the
+    /// pool and giving those still up their new addresses. Added workers join
+    /// the pool once a check finds them up. Returns the workers added and
+    /// removed; on error the list and the pool are left as they were.
+    pub fn reload_workers(
+        &self,
+        workers: &mut WorkerList,
+    ) -> io::Result<(Vec<PeerId>, Vec<PeerId>)> {
+        let (added, removed) = workers.reload()?;
+        for peer in &removed {
+            self.remove_peer(peer);
+        }
+        for (peer, addrs) in workers.healthy() {
+            self.add_peer(peer, addrs);
+        }
+        Ok((added, removed))
+    }
+
+    /// Everything learned through peer exchange, with who it came from.
+    pub fn address_book(&self) -> Vec<(PeerId, BookEntry)> {
+        self.address_book
+            .lock()
+            .unwrap()
+            .entries()
+            .map(|(peer, entry)| (*peer, entry.clone()))
+            .collect()
+    }
+
+    /// The workers the label filter admits with the most preferred labels,
+    /// in pool order.
+    fn candidates(&self) -> Vec<(PeerId, Vec<Multiaddr>)> {
+        let labels = self.labels.lock().unwrap();
+        let ranked: Vec<_> = self
+            .peers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(peer, addrs)| {
+                let preference = match labels.get(peer) {
+                    Some(labels) if !self.filter.admits(labels) => return None,
+                    Some(labels) => self.filter.preference(labels),
+                    None => self.filter.prefer.len(),
+                };
+                Some((preference, (*peer, addrs.clone())))
+            })
+            .collect();
+        let best = ranked.iter().map(|(preference, _)| *preference).max();
+        ranked
+            .into_iter()
+            .filter(|(preference, _)| Some(*preference) == best)
+            .map(|(_, peer)| peer)
+            .collect()
+    }
+
+    /// The worker `session` currently sticks to: of those the label filter
+    /// admits, the one with the most preferred labels, and of those the one
+    /// `session` hashes to.
+    pub fn peer_for_session(&self, session: &str) -> Option<(PeerId, Vec<Multiaddr>)> {
+        self.candidates()
+            .into_iter()
+            .max_by_key(|(peer, _)| session_score(session, peer))
+    }
+
+    /// The worker the pool's [`Strategy`] picks for a request outside a
+    /// session, among those the label filter ranks highest.
+    pub fn pick_peer(&self) -> Option<(PeerId, Vec<Multiaddr>)> {
+        let mut candidates = self.candidates();
+        if candidates.is_empty() {
+            return None;
+        }
+        let loads = self.loads.lock().unwrap();
+        let load = |peer: &PeerId| loads.get(peer).copied().unwrap_or_default();
+        let index = match self.strategy {
+            Strategy::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed) % candidates.len(),
+            Strategy::LeastLoaded => (0..candidates.len())
+                .min_by_key(|&i| {
+                    let load = load(&candidates[i].0);
+                    (load.in_flight, load.latency.unwrap_or_default())
+                })
+                .unwrap_or_default(),
+            Strategy::WeightedRandom => {
+                let latencies: Vec<_> = candidates
+                    .iter()
+                    .map(|(peer, _)| load(peer).latency)
+                    .collect();
+                let fastest = latencies.iter().flatten().min().copied();
+                let weights: Vec<f64> = latencies
+                    .iter()
+                    .map(|latency| match latency.or(fastest) {
+                        // Floored so one instant answer can't take every request.
+                        Some(latency) => 1.0 / latency.as_secs_f64().max(0.001),
+                        None => 1.0,
+                    })
+                    .collect();
+                let mut target = backoff::random_fraction() * weights.iter().sum::<f64>();
+                weights
+                    .iter()
+                    .position(|weight| {
+                        target -= weight;
+                        target < 0.0
+                    })
+                    .unwrap_or(candidates.len() - 1)
+            }
+        };
+        Some(candidates.swap_remove(index))
+    }
+
+    /// How many of this pool's requests each worker has in flight, and its
+    /// average latency, if it has answered yet.
+    pub fn loads(&self) -> Vec<(PeerId, usize, Option<Duration>)> {
+        self.loads
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(peer, load)| (*peer, load.in_flight, load.latency))
+            .collect()
+    }
+
+    /// Sends `request` to the worker `session` hashes to. If that worker is
+    /// unreachable it is dropped from the pool and the session moves to the
+    /// next worker in its ranking. With a label filter, a worker whose labels
+    /// aren't known yet is connected to first and the choice made again.
+    pub async fn send_prompt_with_session(
+        &self,
+        session: &str,
+        request: PromptRequest,
+    ) -> Result<(PeerId, PromptResponse), ClientError> {
+        self.send_to(|| self.peer_for_session(session), request)
+            .await
+    }
+
+    /// Sends `request` to the worker the pool's [`Strategy`] picks, with the
+    /// same fallback as [`PeerPool::send_prompt_with_session`].
+    pub async fn send_prompt(
+        &self,
+        request: PromptRequest,
+    ) -> Result<(PeerId, PromptResponse), ClientError> {
+        self.send_to(|| self.pick_peer(), request).await
+    }
+
+    /// Sends `request` to the worker `pick` chooses, picking again when one
+    /// turns out unreachable or its labels have to be learned first.
+    async fn send_to(
+        &self,
+        pick: impl Fn() -> Option<(PeerId, Vec<Multiaddr>)>,
+        request: PromptRequest,
+    ) -> Result<(PeerId, PromptResponse), ClientError> {
+        loop {
+            let (peer, addrs) = pick().ok_or(ClientError::NoPeers)?;
+            let unlabelled = !self.filter.is_empty() && self.labels(&peer).is_none();
+            let result = match self.client.connect(peer, addrs).await {
+                Ok(()) if unlabelled => {
+                    self.learn_labels(peer).await;
+                    continue;
+                }
+                Ok(()) => {
+                    let _in_flight = InFlight::start(&self.loads, peer);
+                    let started = Instant::now();
+                    let result = self.client.send_prompt(peer, request.clone()).await;
+                    if let Ok(response) = &result
+                        && response.status == ResponseStatus::Ok
+                    {
+                        self.record_latency(peer, started.elapsed());
+                    }
+                    result
+                }
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(response) => return Ok((peer, response)),
+                Err(e) if e.is_peer_failure() => self.remove_peer(&peer),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn record_latency(&self, peer: PeerId, latency: Duration) {
+        let mut loads = self.loads.lock().unwrap();
+        let load = loads.get_or_insert_with(peer, Instant::now(), Load::default);
+        load.latency = Some(match load.latency {
+            Some(average) => {
+                average.mul_f64(1.0 - LATENCY_SMOOTHING) + latency.mul_f64(LATENCY_SMOOTHING)
+            }
+            None => latency,
+        });
+    }
+}
+
+/// Counts a request as in flight to a worker until dropped.
+struct InFlight<'a> {
+    loads: &'a Mutex<BoundedMap<PeerId, Load>>,
+    peer: PeerId,
+}
+
+impl<'a> InFlight<'a> {
+    fn start(loads: &'a Mutex<BoundedMap<PeerId, Load>>, peer: PeerId) -> Self {
+        loads
+            .lock()
+            .unwrap()
+            .get_or_insert_with(peer, Instant::now(), Load::default)
+            .in_flight += 1;
+        Self { loads, peer }
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        // The worker may have been removed, and its load with it, meanwhile.
+        if let Some(load) = self
+            .loads
+            .lock()
+            .unwrap()
+            .get_mut(&self.peer, Instant::now())
+        {
+            load.in_flight = load.in_flight.saturating_sub(1);
+        }
+    }
+}
+
+/// A map of what the pool keeps per worker, bounded like the pool itself.
+fn per_peer<V>() -> BoundedMap<PeerId, V> {
+    BoundedMap::new(BoundedConfig {
+        capacity: MAX_POOL_PEERS,
+        ttl: None,
+    })
+}
+
+fn session_score(session: &str, peer: &PeerId) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    session.hash(&mut hasher);
+    peer.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{client::ClientConfig, node::NodeConfig, testing::listening_node};
+
+    async fn pool(filter: LabelFilter, workers: &[(PeerId, &[(&str, &str)])]) -> PeerPool {
+        let (swarm, _, _) = listening_node(1, NodeConfig::default()).await.unwrap();
+        let client = Client::new(swarm, ClientConfig::default());
+        let pool = PeerPool::new(client, workers.iter().map(|(peer, _)| (*peer, Vec::new())))
+            .with_label_filter(filter);
+        for (peer, labels) in workers {
+            pool.labels.lock().unwrap().insert(
+                *peer,
+                Labels::from_untrusted(labels.iter().copied()),
+                Instant::now(),
+            );
+        }
+        pool
+    }
+
+    fn filter(require: &[&str], prefer: &[&str]) -> LabelFilter {
+        LabelFilter {
+            require: require.iter().map(|l| l.parse().unwrap()).collect(),
+            prefer: prefer.iter().map(|l| l.parse().unwrap()).collect(),
+        }
+    }
+
+    fn peers(candidates: Vec<(PeerId, Vec<Multiaddr>)>) -> Vec<PeerId> {
+        candidates.into_iter().map(|(peer, _)| peer).collect()
+    }
+
+    #[tokio::test]
+    async fn skips_workers_without_required_labels() {
+        let (eu, us, bare) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let pool = pool(
+            filter(&["region=eu"], &[]),
+            &[
+                (eu, &[("region", "eu")]),
+                (us, &[("region", "us")]),
+                (bare, &[]),
+            ],
+        )
+        .await;
+        assert_eq!(peers(pool.candidates()), [eu]);
+        for session in ["a", "b", "c", "d"] {
+            assert_eq!(pool.peer_for_session(session).unwrap().0, eu);
+        }
+        assert_eq!(pool.pick_peer().unwrap().0, eu);
+    }
+
+    #[tokio::test]
+    async fn ranks_workers_by_preferred_labels() {
+        let (both, one, none) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let pool = pool(
+            filter(&[], &["region=eu", "tier=gpu"]),
+            &[
+                (none, &[("region", "us")]),
+                (one, &[("tier", "gpu")]),
+                (both, &[("region", "eu"), ("tier", "gpu")]),
+            ],
+        )
+        .await;
+        assert_eq!(peers(pool.candidates()), [both]);
+
+        pool.remove_peer(&both);
+        assert_eq!(peers(pool.candidates()), [one]);
+        pool.remove_peer(&one);
+        assert_eq!(peers(pool.candidates()), [none]);
+    }
+
+    #[tokio::test]
+    async fn hash_decides_between_equally_preferred_workers() {
+        let (a, b, c) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let pool = pool(
+            filter(&[], &["tier=gpu"]),
+            &[(a, &[("tier", "gpu")]), (b, &[("tier", "gpu")]), (c, &[])],
+        )
+        .await;
+        assert_eq!(peers(pool.candidates()), [a, b]);
+        for session in (0..50).map(|i| format!("session-{i}")) {
+            let expected = if session_score(&session, &a) > session_score(&session, &b) {
+                a
+            } else {
+                b
+            };
+            assert_eq!(pool.peer_for_session(&session).unwrap().0, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn unlabelled_workers_rank_as_fully_preferred() {
+        let (known, unknown) = (PeerId::random(), PeerId::random());
+        let pool = pool(
+            filter(&["region=eu"], &["tier=gpu"]),
+            &[(known, &[("region", "eu")])],
+        )
+        .await;
+        pool.add_peer(unknown, Vec::new());
+        // `known` lacks the preferred label; `unknown` might have it.
+        assert_eq!(peers(pool.candidates()), [unknown]);
+
+        pool.labels.lock().unwrap().insert(
+            unknown,
+            Labels::from_untrusted([("region", "us"), ("tier", "gpu")]),
+            Instant::now(),
+        );
+        assert_eq!(peers(pool.candidates()), [known]);
+    }
+
+    #[tokio::test]
+    async fn no_admitted_workers_means_no_pick() {
+        let peer = PeerId::random();
+        let pool = pool(filter(&["region=eu"], &[]), &[(peer, &[("region", "us")])]).await;
+        assert!(pool.candidates().is_empty());
+        assert!(pool.pick_peer().is_none());
+        assert!(pool.peer_for_session("s").is_none());
+    }
+}
@@ -0,0 +1,90 @@
+//! A human-readable name and contact for a node, so operators and dashboards
+//! aren't left reading peer ids.
+//!
+//! Both are announced through identify and end up in other nodes' logs,
+//! status reports and peer exchange answers. Whatever a peer announces is
+//! therefore treated as hostile: control characters are dropped and the
+//! length is capped, both when announcing and again when received.
+
+use serde::{Deserialize, Serialize};
+
+/// Longest nickname kept, in characters.
+pub const MAX_NICKNAME_CHARS: usize = 32;
+/// Longest operator contact kept, in characters.
+pub const MAX_CONTACT_CHARS: usize = 64;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Profile {
+    #[serde(default)]
+    pub nickname: Option<String>,
+    /// How to reach whoever runs the node, e.g. an email address.
+    #[serde(default)]
+    pub operator_contact: Option<String>,
+}
+
+impl Profile {
+    /// A profile from untrusted values, sanitized. Values left empty are
+    /// dropped.
+    pub fn new(nickname: Option<&str>, operator_contact: Option<&str>) -> Self {
+        Self {
+            nickname: nickname.and_then(|n| sanitize(n, MAX_NICKNAME_CHARS)),
+            operator_contact: operator_contact.and_then(|c| sanitize(c, MAX_CONTACT_CHARS)),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nickname.is_none() && self.operator_contact.is_none()
+    }
+
+    /// `peer` labelled with the nickname, if there is one, e.g.
+    /// `gpu-box-3 (12D3KooW...)`.
+    pub fn label(&self, peer: impl std::fmt::Display) -> String {
+        match &self.nickname {
+            Some(nickname) => format!("{nickname} ({peer})"),
+            None => peer.to_string(),
+        }
+    }
+}
+
+/// `value` without control characters, bidirectional overrides or
+/// surrounding whitespace, cut to `max_chars`. `None` if nothing is left.
+pub fn sanitize(value: &str, max_chars: usize) -> Option<String> {
+    let cleaned: String = value
+        .chars()
+        .filter(|c| !c.is_control() && !is_bidi_control(*c))
+        .collect();
+    let cleaned: String = cleaned.trim().chars().take(max_chars).collect();
+    let cleaned = cleaned.trim_end();
+    (!cleaned.is_empty()).then(|| cleaned.to_string())
+}
+
+/// Characters that reorder the text around them, which could make a log
+/// line read differently from what it says.
+fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{200E}' | '\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}')
+}
+
+/// Escapes `value` for a space-separated `key=value` list: `%`, spaces, `=`
+/// and `,` are percent-encoded.
+pub(crate) fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '%' => escaped.push_str("%25"),
+            ' ' => escaped.push_str("%20"),
+            '=' => escaped.push_str("%3D"),
+            ',' => escaped.push_str("%2C"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Reverses [`escape`]. Unknown escapes are kept as they are.
+pub(crate) fn unescape(value: &str) -> String {
+    value
+        .replace("%20", " ")
+        .replace("%3D", "=")
+        .replace("%2C", ",")
+        .replace("%25", "%")
+}
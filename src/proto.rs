@@ -0,0 +1,1148 @@
+//! Wire codecs for the prompt protocol.
+//!
+//! Version 1.0.0 is served in two encodings: CBOR on [`PROTOCOL_NAME`] and
+//! protobuf on [`PROTO_PROTOCOL_NAME`], for peers whose libp2p stacks handle
+//! protobuf better. Version 2.0.0, on [`V2_PROTOCOL_NAME`], groups the
+//! request's fields and keeps error text apart from answers; see [`v2`].
+//! All three are registered on the one request-response behaviour, and
+//! [`MeshCodec`] picks the message shape from the protocol each request was
+//! negotiated on, answering in the same one. Handlers only ever see
+//! [`PromptRequest`] and [`PromptResponse`], so a node serves old and new
+//! clients alike while they migrate.
+//!
+//! A further version is added the same way: its name in [`crate::node`],
+//! its messages here with conversions to and from the handler types, and a
+//! branch in [`MeshCodec`].
+//!
+//! The protobuf messages in [`wire`] are written out by hand to match
+//! `proto/mesh_ai.proto`, so building needs no `protoc`.
+
+use std::io;
+
+use async_trait::async_trait;
+use futures::prelude::*;
+use libp2p::{StreamProtocol, request_response};
+use prost::Message;
+
+use crate::{
+    BinaryPayload, MAX_IMAGE_BYTES, PromptRequest, PromptResponse, QuotaStatus, ResponseStatus,
+    Timing,
+    client_info::ClientInfo,
+    dedup::CacheMode,
+    tools::{ToolCall, ToolChoice, ToolDefinition},
+};
+
+pub use crate::node::{PROTO_PROTOCOL_NAME, PROTOCOL_NAME, V2_PROTOCOL_NAME};
+
+/// A megabyte of prompt besides the images, in both encodings. Responses
+/// get the CBOR codec's default limit.
+const REQUEST_SIZE_MAXIMUM: u64 = 1024 * 1024 + MAX_IMAGE_BYTES as u64;
+const RESPONSE_SIZE_MAXIMUM: u64 = 10 * 1024 * 1024;
+
+/// Messages from `proto/mesh_ai.proto`.
+pub mod wire {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PromptRequest {
+        #[prost(string, tag = "1")]
+        pub prompt: String,
+        #[prost(string, optional, tag = "2")]
+        pub model: Option<String>,
+        #[prost(string, optional, tag = "3")]
+        pub format: Option<String>,
+        #[prost(string, optional, tag = "4")]
+        pub idempotency_key: Option<String>,
+        #[prost(bool, tag = "5")]
+        pub allow_truncate: bool,
+        #[prost(uint64, optional, tag = "6")]
+        pub max_duration_ms: Option<u64>,
+        #[prost(message, optional, tag = "7")]
+        pub client_info: Option<ClientInfo>,
+        #[prost(string, optional, tag = "8")]
+        pub api_key: Option<String>,
+        #[prost(string, optional, tag = "9")]
+        pub trace_context: Option<String>,
+        #[prost(bytes = "vec", repeated, tag = "10")]
+        pub images: Vec<Vec<u8>>,
+        #[prost(bool, tag = "11")]
+        pub apply_template: bool,
+        #[prost(string, optional, tag = "12")]
+        pub system: Option<String>,
+        #[prost(message, repeated, tag = "13")]
+        pub tools: Vec<ToolDefinition>,
+        #[prost(message, optional, tag = "14")]
+        pub tool_choice: Option<ToolChoice>,
+        #[prost(enumeration = "CacheMode", tag = "15")]
+        pub cache: i32,
+        #[prost(uint64, optional, tag = "16")]
+        pub cache_ttl_ms: Option<u64>,
+        #[prost(uint64, optional, tag = "17")]
+        pub num_ctx: Option<u64>,
+        #[prost(uint32, tag = "18")]
+        pub priority: u32,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum CacheMode {
+        Prefer = 0,
+        Bypass = 1,
+        Only = 2,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ToolDefinition {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(string, tag = "2")]
+        pub description: String,
+        #[prost(string, optional, tag = "3")]
+        pub parameters: Option<String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ToolChoice {
+        #[prost(enumeration = "ToolChoiceMode", tag = "1")]
+        pub mode: i32,
+        /// The tool to call, with [`ToolChoiceMode::Tool`].
+        #[prost(string, tag = "2")]
+        pub tool: String,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum ToolChoiceMode {
+        Auto = 0,
+        None = 1,
+        Required = 2,
+        Tool = 3,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ToolCall {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(string, tag = "2")]
+        pub arguments: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ClientInfo {
+        #[prost(string, tag = "1")]
+        pub app_name: String,
+        #[prost(string, tag = "2")]
+        pub app_version: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PromptResponse {
+        #[prost(string, tag = "1")]
+        pub response: String,
+        #[prost(enumeration = "ResponseStatus", tag = "2")]
+        pub status: i32,
+        #[prost(message, optional, tag = "3")]
+        pub quota: Option<QuotaStatus>,
+        #[prost(string, repeated, tag = "4")]
+        pub available_models: Vec<String>,
+        #[prost(bool, tag = "5")]
+        pub truncated: bool,
+        #[prost(bool, tag = "6")]
+        pub is_fallback: bool,
+        #[prost(message, optional, tag = "7")]
+        pub binary: Option<BinaryPayload>,
+        #[prost(message, optional, tag = "8")]
+        pub timing: Option<Timing>,
+        #[prost(string, optional, tag = "9")]
+        pub template: Option<String>,
+        #[prost(message, repeated, tag = "10")]
+        pub tool_calls: Vec<ToolCall>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Timing {
+        #[prost(uint64, tag = "1")]
+        pub queue_wait_ms: u64,
+        #[prost(uint64, tag = "2")]
+        pub inference_ms: u64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct BinaryPayload {
+        #[prost(string, tag = "1")]
+        pub mime_type: String,
+        #[prost(bytes = "vec", tag = "2")]
+        pub data: Vec<u8>,
+    }
+
+    #[derive(Clone, Copy, PartialEq, prost::Message)]
+    pub struct QuotaStatus {
+        #[prost(uint64, optional, tag = "1")]
+        pub remaining_requests: Option<u64>,
+        #[prost(uint64, optional, tag = "2")]
+        pub remaining_tokens: Option<u64>,
+        #[prost(uint64, tag = "3")]
+        pub resets_at: u64,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum ResponseStatus {
+        Ok = 0,
+        Busy = 1,
+        Error = 2,
+        Banned = 3,
+        InvalidJson = 4,
+        QuotaExceeded = 5,
+        Overloaded = 6,
+        ModelNotAllowed = 7,
+        ContentBlocked = 8,
+        ContextOverflow = 9,
+        BackendOutOfMemory = 10,
+        DeadlineExceeded = 11,
+        Internal = 12,
+        Unauthorized = 13,
+        EmptyResponse = 14,
+        Maintenance = 15,
+        TemplateError = 16,
+        UnsupportedFeature = 17,
+        NotCached = 18,
+        Standby = 19,
+        InvalidOption = 20,
+        ResponseTooLarge = 21,
+    }
+}
+
+impl From<PromptRequest> for wire::PromptRequest {
+    fn from(request: PromptRequest) -> Self {
+        Self {
+            prompt: request.prompt,
+            model: request.model,
+            format: request.format,
+            idempotency_key: request.idempotency_key,
+            allow_truncate: request.allow_truncate,
+            max_duration_ms: request.max_duration_ms,
+            client_info: request.client_info.map(|info| wire::ClientInfo {
+                app_name: info.app_name,
+                app_version: info.app_version,
+            }),
+            api_key: request.api_key,
+            trace_context: request.trace_context,
+            images: request.images.unwrap_or_default(),
+            apply_template: request.apply_template,
+            system: request.system,
+            tools: request
+                .tools
+                .unwrap_or_default()
+                .into_iter()
+                .map(|tool| wire::ToolDefinition {
+                    name: tool.name,
+                    description: tool.description,
+                    parameters: tool.parameters,
+                })
+                .collect(),
+            tool_choice: request.tool_choice.map(Into::into),
+            cache: wire::CacheMode::from(request.cache) as i32,
+            cache_ttl_ms: request.cache_ttl_ms,
+            num_ctx: request.num_ctx,
+            priority: request.priority.into(),
+        }
+    }
+}
+
+impl From<wire::PromptRequest> for PromptRequest {
+    fn from(request: wire::PromptRequest) -> Self {
+        Self {
+            prompt: request.prompt,
+            model: request.model,
+            format: request.format,
+            idempotency_key: request.idempotency_key,
+            allow_truncate: request.allow_truncate,
+            max_duration_ms: request.max_duration_ms,
+            client_info: request.client_info.map(|info| ClientInfo {
+                app_name: info.app_name,
+                app_version: info.app_version,
+            }),
+            api_key: request.api_key,
+            trace_context: request.trace_context,
+            // Protobuf can't tell no list from an empty one.
+            images: (!request.images.is_empty()).then_some(request.images),
+            apply_template: request.apply_template,
+            system: request.system,
+            tools: (!request.tools.is_empty()).then(|| {
+                request
+                    .tools
+                    .into_iter()
+                    .map(|tool| ToolDefinition {
+                        name: tool.name,
+                        description: tool.description,
+                        parameters: tool.parameters,
+                    })
+                    .collect()
+            }),
+            tool_choice: request.tool_choice.map(Into::into),
+            // A mode this build doesn't know uses the cache as usual.
+            cache: wire::CacheMode::try_from(request.cache).map_or(CacheMode::Prefer, Into::into),
+            cache_ttl_ms: request.cache_ttl_ms,
+            num_ctx: request.num_ctx,
+            // Capped by the node anyway.
+            priority: request.priority.try_into().unwrap_or(u8::MAX),
+        }
+    }
+}
+
+impl From<CacheMode> for wire::CacheMode {
+    fn from(mode: CacheMode) -> Self {
+        match mode {
+            CacheMode::Prefer => Self::Prefer,
+            CacheMode::Bypass => Self::Bypass,
+            CacheMode::Only => Self::Only,
+        }
+    }
+}
+
+impl From<wire::CacheMode> for CacheMode {
+    fn from(mode: wire::CacheMode) -> Self {
+        match mode {
+            wire::CacheMode::Prefer => Self::Prefer,
+            wire::CacheMode::Bypass => Self::Bypass,
+            wire::CacheMode::Only => Self::Only,
+        }
+    }
+}
+
+impl From<ToolChoice> for wire::ToolChoice {
+    fn from(choice: ToolChoice) -> Self {
+        let (mode, tool) = match choice {
+            ToolChoice::Auto => (wire::ToolChoiceMode::Auto, String::new()),
+            ToolChoice::None => (wire::ToolChoiceMode::None, String::new()),
+            ToolChoice::Required => (wire::ToolChoiceMode::Required, String::new()),
+            ToolChoice::Tool(tool) => (wire::ToolChoiceMode::Tool, tool),
+        };
+        Self {
+            mode: mode as i32,
+            tool,
+        }
+    }
+}
+
+impl From<wire::ToolChoice> for ToolChoice {
+    fn from(choice: wire::ToolChoice) -> Self {
+        // A mode this build doesn't know leaves the choice to the model.
+        match wire::ToolChoiceMode::try_from(choice.mode) {
+            Ok(wire::ToolChoiceMode::None) => Self::None,
+            Ok(wire::ToolChoiceMode::Required) => Self::Required,
+            Ok(wire::ToolChoiceMode::Tool) => Self::Tool(choice.tool),
+            Ok(wire::ToolChoiceMode::Auto) | Err(_) => Self::Auto,
+        }
+    }
+}
+
+impl From<PromptResponse> for wire::PromptResponse {
+    fn from(response: PromptResponse) -> Self {
+        Self {
+            response: response.response,
+            status: wire::ResponseStatus::from(response.status) as i32,
+            quota: response.quota.map(Into::into),
+            available_models: response.available_models,
+            truncated: response.truncated,
+            is_fallback: response.is_fallback,
+            binary: response.binary.map(|binary| wire::BinaryPayload {
+                mime_type: binary.mime_type,
+                data: binary.data,
+            }),
+            timing: response.timing.map(|timing| wire::Timing {
+                queue_wait_ms: timing.queue_wait_ms,
+                inference_ms: timing.inference_ms,
+            }),
+            template: response.template,
+            tool_calls: response
+                .tool_calls
+                .into_iter()
+                .map(|call| wire::ToolCall {
+                    name: call.name,
+                    arguments: call.arguments,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<wire::PromptResponse> for PromptResponse {
+    fn from(response: wire::PromptResponse) -> Self {
+        Self {
+            // A status this build doesn't know is reported as such rather
+            // than mistaken for success.
+            status: wire::ResponseStatus::try_from(response.status)
+                .map_or(ResponseStatus::Unknown, Into::into),
+            response: response.response,
+            quota: response.quota.map(Into::into),
+            available_models: response.available_models,
+            truncated: response.truncated,
+            is_fallback: response.is_fallback,
+            binary: response.binary.map(|binary| BinaryPayload {
+                mime_type: binary.mime_type,
+                data: binary.data,
+            }),
+            timing: response.timing.map(|timing| Timing {
+                queue_wait_ms: timing.queue_wait_ms,
+                inference_ms: timing.inference_ms,
+            }),
+            template: response.template,
+            tool_calls: response
+                .tool_calls
+                .into_iter()
+                .map(|call| ToolCall {
+                    name: call.name,
+                    arguments: call.arguments,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<QuotaStatus> for wire::QuotaStatus {
+    fn from(quota: QuotaStatus) -> Self {
+        Self {
+            remaining_requests: quota.remaining_requests,
+            remaining_tokens: quota.remaining_tokens,
+            resets_at: quota.resets_at,
+        }
+    }
+}
+
+impl From<wire::QuotaStatus> for QuotaStatus {
+    fn from(quota: wire::QuotaStatus) -> Self {
+        Self {
+            remaining_requests: quota.remaining_requests,
+            remaining_tokens: quota.remaining_tokens,
+            resets_at: quota.resets_at,
+        }
+    }
+}
+
+impl From<ResponseStatus> for wire::ResponseStatus {
+    fn from(status: ResponseStatus) -> Self {
+        match status {
+            ResponseStatus::Ok => Self::Ok,
+            ResponseStatus::Busy => Self::Busy,
+            ResponseStatus::Error => Self::Error,
+            ResponseStatus::Banned => Self::Banned,
+            ResponseStatus::InvalidJson => Self::InvalidJson,
+            ResponseStatus::QuotaExceeded => Self::QuotaExceeded,
+            ResponseStatus::Overloaded => Self::Overloaded,
+            ResponseStatus::ModelNotAllowed => Self::ModelNotAllowed,
+            ResponseStatus::ContentBlocked => Self::ContentBlocked,
+            ResponseStatus::ContextOverflow => Self::ContextOverflow,
+            ResponseStatus::BackendOutOfMemory => Self::BackendOutOfMemory,
+            ResponseStatus::DeadlineExceeded => Self::DeadlineExceeded,
+            ResponseStatus::Internal => Self::Internal,
+            ResponseStatus::Unauthorized => Self::Unauthorized,
+            ResponseStatus::EmptyResponse => Self::EmptyResponse,
+            ResponseStatus::Maintenance => Self::Maintenance,
+            ResponseStatus::TemplateError => Self::TemplateError,
+            ResponseStatus::UnsupportedFeature => Self::UnsupportedFeature,
+            ResponseStatus::NotCached => Self::NotCached,
+            ResponseStatus::Standby => Self::Standby,
+            ResponseStatus::InvalidOption => Self::InvalidOption,
+            ResponseStatus::ResponseTooLarge => Self::ResponseTooLarge,
+            // The status it stood for is lost; it was a refusal all the same.
+            ResponseStatus::Unknown => Self::Error,
+        }
+    }
+}
+
+impl From<wire::ResponseStatus> for ResponseStatus {
+    fn from(status: wire::ResponseStatus) -> Self {
+        match status {
+            wire::ResponseStatus::Ok => Self::Ok,
+            wire::ResponseStatus::Busy => Self::Busy,
+            wire::ResponseStatus::Error => Self::Error,
+            wire::ResponseStatus::Banned => Self::Banned,
+            wire::ResponseStatus::InvalidJson => Self::InvalidJson,
+            wire::ResponseStatus::QuotaExceeded => Self::QuotaExceeded,
+            wire::ResponseStatus::Overloaded => Self::Overloaded,
+            wire::ResponseStatus::ModelNotAllowed => Self::ModelNotAllowed,
+            wire::ResponseStatus::ContentBlocked => Self::ContentBlocked,
+            wire::ResponseStatus::ContextOverflow => Self::ContextOverflow,
+            wire::ResponseStatus::BackendOutOfMemory => Self::BackendOutOfMemory,
+            wire::ResponseStatus::DeadlineExceeded => Self::DeadlineExceeded,
+            wire::ResponseStatus::Internal => Self::Internal,
+            wire::ResponseStatus::Unauthorized => Self::Unauthorized,
+            wire::ResponseStatus::EmptyResponse => Self::EmptyResponse,
+            wire::ResponseStatus::Maintenance => Self::Maintenance,
+            wire::ResponseStatus::TemplateError => Self::TemplateError,
+            wire::ResponseStatus::UnsupportedFeature => Self::UnsupportedFeature,
+            wire::ResponseStatus::NotCached => Self::NotCached,
+            wire::ResponseStatus::Standby => Self::Standby,
+            wire::ResponseStatus::InvalidOption => Self::InvalidOption,
+            wire::ResponseStatus::ResponseTooLarge => Self::ResponseTooLarge,
+        }
+    }
+}
+
+/// Messages of version 2.0.0 of the prompt protocol, sent as CBOR.
+///
+/// A request carries the prompt and images as its `input`, generation
+/// settings as `options` and bookkeeping as `meta`. A response has the
+/// answer in `output` only when it succeeded, and the reason in `error`
+/// otherwise.
+pub mod v2 {
+    use serde::{Deserialize, Serialize};
+
+    use crate::{
+        BinaryPayload, QuotaStatus, ResponseStatus, Timing,
+        client_info::ClientInfo,
+        dedup::CacheMode,
+        tools::{ToolCall, ToolChoice, ToolDefinition},
+    };
+
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct PromptRequest {
+        #[serde(default)]
+        pub model: Option<String>,
+        pub input: Input,
+        #[serde(default)]
+        pub options: Options,
+        #[serde(default)]
+        pub meta: Meta,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct Input {
+        pub prompt: String,
+        #[serde(default, with = "crate::binary_list")]
+        pub images: Option<Vec<Vec<u8>>>,
+        #[serde(default)]
+        pub system: Option<String>,
+        #[serde(default)]
+        pub tools: Option<Vec<ToolDefinition>>,
+    }
+
+    #[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct Options {
+        #[serde(default)]
+        pub format: Option<String>,
+        #[serde(default)]
+        pub allow_truncate: bool,
+        #[serde(default)]
+        pub max_duration_ms: Option<u64>,
+        #[serde(default)]
+        pub apply_template: bool,
+        #[serde(default)]
+        pub tool_choice: Option<ToolChoice>,
+        #[serde(default)]
+        pub cache: CacheMode,
+        #[serde(default)]
+        pub cache_ttl_ms: Option<u64>,
+        #[serde(default)]
+        pub num_ctx: Option<u64>,
+        #[serde(default)]
+        pub priority: u8,
+    }
+
+    #[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct Meta {
+        #[serde(default)]
+        pub idempotency_key: Option<String>,
+        #[serde(default)]
+        pub client_info: Option<ClientInfo>,
+        #[serde(default)]
+        pub api_key: Option<String>,
+        #[serde(default)]
+        pub trace_context: Option<String>,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct PromptResponse {
+        pub status: ResponseStatus,
+        /// The answer, when `status` is `Ok`.
+        #[serde(default)]
+        pub output: Option<Output>,
+        /// Why there is no answer, when `status` isn't `Ok`.
+        #[serde(default)]
+        pub error: Option<String>,
+        #[serde(default)]
+        pub quota: Option<QuotaStatus>,
+        #[serde(default)]
+        pub available_models: Vec<String>,
+        #[serde(default)]
+        pub truncated: bool,
+        #[serde(default)]
+        pub is_fallback: bool,
+        #[serde(default)]
+        pub timing: Option<Timing>,
+        #[serde(default)]
+        pub template: Option<String>,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct Output {
+        pub text: String,
+        #[serde(default)]
+        pub binary: Option<BinaryPayload>,
+        #[serde(default)]
+        pub tool_calls: Vec<ToolCall>,
+    }
+}
+
+impl From<PromptRequest> for v2::PromptRequest {
+    fn from(request: PromptRequest) -> Self {
+        Self {
+            model: request.model,
+            input: v2::Input {
+                prompt: request.prompt,
+                images: request.images,
+                system: request.system,
+                tools: request.tools,
+            },
+            options: v2::Options {
+                format: request.format,
+                allow_truncate: request.allow_truncate,
+                max_duration_ms: request.max_duration_ms,
+                apply_template: request.apply_template,
+                tool_choice: request.tool_choice,
+                cache: request.cache,
+                cache_ttl_ms: request.cache_ttl_ms,
+                num_ctx: request.num_ctx,
+                priority: request.priority,
+            },
+            meta: v2::Meta {
+                idempotency_key: request.idempotency_key,
+                client_info: request.client_info,
+                api_key: request.api_key,
+                trace_context: request.trace_context,
+            },
+        }
+    }
+}
+
+impl From<v2::PromptRequest> for PromptRequest {
+    fn from(request: v2::PromptRequest) -> Self {
+        Self {
+            prompt: request.input.prompt,
+            images: request.input.images,
+            model: request.model,
+            format: request.options.format,
+            allow_truncate: request.options.allow_truncate,
+            max_duration_ms: request.options.max_duration_ms,
+            idempotency_key: request.meta.idempotency_key,
+            client_info: request.meta.client_info,
+            api_key: request.meta.api_key,
+            trace_context: request.meta.trace_context,
+            apply_template: request.options.apply_template,
+            system: request.input.system,
+            tools: request.input.tools,
+            tool_choice: request.options.tool_choice,
+            cache: request.options.cache,
+            cache_ttl_ms: request.options.cache_ttl_ms,
+            num_ctx: request.options.num_ctx,
+            priority: request.options.priority,
+        }
+    }
+}
+
+impl From<PromptResponse> for v2::PromptResponse {
+    fn from(response: PromptResponse) -> Self {
+        let (output, error) = if response.status == ResponseStatus::Ok {
+            let output = v2::Output {
+                text: response.response,
+                binary: response.binary,
+                tool_calls: response.tool_calls,
+            };
+            (Some(output), None)
+        } else {
+            (None, Some(response.response))
+        };
+        Self {
+            status: response.status,
+            output,
+            error,
+            quota: response.quota,
+            available_models: response.available_models,
+            truncated: response.truncated,
+            is_fallback: response.is_fallback,
+            timing: response.timing,
+            template: response.template,
+        }
+    }
+}
+
+impl From<v2::PromptResponse> for PromptResponse {
+    fn from(response: v2::PromptResponse) -> Self {
+        let (text, binary, tool_calls) = response
+            .output
+            .map_or((String::new(), None, Vec::new()), |output| {
+                (output.text, output.binary, output.tool_calls)
+            });
+        Self {
+            response: response.error.unwrap_or(text),
+            status: response.status,
+            quota: response.quota,
+            available_models: response.available_models,
+            truncated: response.truncated,
+            is_fallback: response.is_fallback,
+            binary,
+            timing: response.timing,
+            template: response.template,
+            tool_calls,
+        }
+    }
+}
+
+/// Reads and writes prompts in the message shape of the negotiated
+/// protocol: CBOR or protobuf for version 1.0.0, or the [`v2`] messages.
+#[derive(Clone)]
+pub struct MeshCodec {
+    cbor: request_response::cbor::codec::Codec<PromptRequest, PromptResponse>,
+    v2: request_response::cbor::codec::Codec<v2::PromptRequest, v2::PromptResponse>,
+}
+
+impl Default for MeshCodec {
+    fn default() -> Self {
+        Self {
+            cbor: request_response::cbor::codec::Codec::default()
+                .set_request_size_maximum(REQUEST_SIZE_MAXIMUM),
+            v2: request_response::cbor::codec::Codec::default()
+                .set_request_size_maximum(REQUEST_SIZE_MAXIMUM),
+        }
+    }
+}
+
+/// The message shapes [`MeshCodec`] speaks.
+enum Shape {
+    Cbor,
+    Proto,
+    V2,
+}
+
+impl Shape {
+    fn of(protocol: &StreamProtocol) -> Self {
+        match protocol.as_ref() {
+            PROTO_PROTOCOL_NAME => Self::Proto,
+            V2_PROTOCOL_NAME => Self::V2,
+            _ => Self::Cbor,
+        }
+    }
+}
+
+async fn read_message<T, M>(io: &mut T, limit: u64) -> io::Result<M>
+where
+    T: AsyncRead + Unpin + Send,
+    M: Message + Default,
+{
+    let mut bytes = Vec::new();
+    // One byte past the limit tells an oversized message from one that fits
+    // exactly, so it is refused rather than decoded truncated.
+    io.take(limit.saturating_add(1))
+        .read_to_end(&mut bytes)
+        .await?;
+    if bytes.len() as u64 > limit {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("message exceeds {limit} bytes"),
+        ));
+    }
+    M::decode(bytes.as_slice()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[async_trait]
+impl request_response::Codec for MeshCodec {
+    type Protocol = StreamProtocol;
+    type Request = PromptRequest;
+    type Response = PromptResponse;
+
+    async fn read_request<T>(
+        &mut self,
+        protocol: &Self::Protocol,
+        io: &mut T,
+    ) -> io::Result<PromptRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        match Shape::of(protocol) {
+            Shape::Cbor => self.cbor.read_request(protocol, io).await,
+            Shape::Proto => read_message::<_, wire::PromptRequest>(io, REQUEST_SIZE_MAXIMUM)
+                .await
+                .map(Into::into),
+            Shape::V2 => self.v2.read_request(protocol, io).await.map(Into::into),
+        }
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        protocol: &Self::Protocol,
+        io: &mut T,
+    ) -> io::Result<PromptResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        match Shape::of(protocol) {
+            Shape::Cbor => self.cbor.read_response(protocol, io).await,
+            Shape::Proto => read_message::<_, wire::PromptResponse>(io, RESPONSE_SIZE_MAXIMUM)
+                .await
+                .map(Into::into),
+            Shape::V2 => self.v2.read_response(protocol, io).await.map(Into::into),
+        }
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        protocol: &Self::Protocol,
+        io: &mut T,
+        request: PromptRequest,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        match Shape::of(protocol) {
+            Shape::Cbor => self.cbor.write_request(protocol, io, request).await,
+            Shape::Proto => {
+                io.write_all(&wire::PromptRequest::from(request).encode_to_vec())
+                    .await
+            }
+            Shape::V2 => self.v2.write_request(protocol, io, request.into()).await,
+        }
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        protocol: &Self::Protocol,
+        io: &mut T,
+        response: PromptResponse,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        match Shape::of(protocol) {
+            Shape::Cbor => self.cbor.write_response(protocol, io, response).await,
+            Shape::Proto => {
+                io.write_all(&wire::PromptResponse::from(response).encode_to_vec())
+                    .await
+            }
+            Shape::V2 => self.v2.write_response(protocol, io, response.into()).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::io::Cursor;
+    use request_response::Codec;
+
+    use super::*;
+
+    fn encoded(prompt: &str) -> Vec<u8> {
+        wire::PromptRequest {
+            prompt: prompt.to_string(),
+            ..Default::default()
+        }
+        .encode_to_vec()
+    }
+
+    #[tokio::test]
+    async fn reads_a_message_that_fits_exactly() {
+        let bytes = encoded("hello");
+        let limit = bytes.len() as u64;
+        let message: wire::PromptRequest =
+            read_message(&mut Cursor::new(bytes), limit).await.unwrap();
+        assert_eq!(message.prompt, "hello");
+    }
+
+    #[tokio::test]
+    async fn refuses_an_oversized_message() {
+        let bytes = encoded("hello");
+        let limit = bytes.len() as u64 - 1;
+        let e = read_message::<_, wire::PromptRequest>(&mut Cursor::new(bytes), limit)
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(e.to_string().contains("exceeds"), "{e}");
+    }
+
+    #[test]
+    fn priority_survives_every_encoding() {
+        let request: PromptRequest =
+            serde_json::from_value(serde_json::json!({ "prompt": "hi", "priority": 7 })).unwrap();
+        let wire = wire::PromptRequest::from(request.clone());
+        assert_eq!(wire.priority, 7);
+        assert_eq!(PromptRequest::from(wire).priority, 7);
+        assert_eq!(
+            PromptRequest::from(v2::PromptRequest::from(request)).priority,
+            7
+        );
+
+        // Out of range on the wire is as high as it gets; the node caps it.
+        let huge = wire::PromptRequest {
+            priority: 1000,
+            ..Default::default()
+        };
+        assert_eq!(PromptRequest::from(huge).priority, u8::MAX);
+    }
+
+    #[test]
+    fn the_largest_binary_answer_fits_a_response() {
+        let largest = PromptResponse::ok(String::new()).with_binary(Some(BinaryPayload {
+            mime_type: "image/png".to_string(),
+            data: vec![0xff; crate::MAX_BINARY_BYTES],
+        }));
+        let proto = wire::PromptResponse::from(largest.clone()).encode_to_vec();
+        assert!((proto.len() as u64) < RESPONSE_SIZE_MAXIMUM);
+        let frame = serde_json::to_vec(&crate::stream::StreamFrame::End(largest)).unwrap();
+        assert!(frame.len() < crate::stream::MAX_FRAME_BYTES);
+
+        let refused = PromptResponse::response_too_large("too large".to_string());
+        let wire = wire::PromptResponse::from(refused);
+        assert_eq!(
+            PromptResponse::from(wire).status,
+            ResponseStatus::ResponseTooLarge
+        );
+    }
+
+    #[test]
+    fn tools_survive_every_encoding() {
+        for choice in [
+            None,
+            Some(ToolChoice::Auto),
+            Some(ToolChoice::None),
+            Some(ToolChoice::Required),
+            Some(ToolChoice::Tool("weather".to_string())),
+        ] {
+            let request: PromptRequest = serde_json::from_value(serde_json::json!({
+                "prompt": "Weather in Paris?",
+                "tools": [
+                    {
+                        "name": "weather",
+                        "description": "Current weather",
+                        "parameters": r#"{"type":"object"}"#,
+                    },
+                    { "name": "now" },
+                ],
+                "tool_choice": choice,
+            }))
+            .unwrap();
+            let bytes = wire::PromptRequest::from(request.clone()).encode_to_vec();
+            let decoded = wire::PromptRequest::decode(bytes.as_slice()).unwrap();
+            assert!(PromptRequest::from(decoded) == request, "{choice:?}");
+            let v2 = v2::PromptRequest::from(request.clone());
+            assert!(PromptRequest::from(v2) == request, "{choice:?}");
+        }
+
+        let mut response = PromptResponse::ok(String::new());
+        response.tool_calls = vec![ToolCall {
+            name: "weather".to_string(),
+            arguments: r#"{"city":"Paris"}"#.to_string(),
+        }];
+        let bytes = wire::PromptResponse::from(response.clone()).encode_to_vec();
+        let decoded = wire::PromptResponse::decode(bytes.as_slice()).unwrap();
+        assert_eq!(PromptResponse::from(decoded), response);
+        assert_eq!(
+            PromptResponse::from(v2::PromptResponse::from(response.clone())),
+            response
+        );
+    }
+
+    /// Every protocol [`MeshCodec`] speaks.
+    fn protocols() -> [StreamProtocol; 3] {
+        [PROTOCOL_NAME, PROTO_PROTOCOL_NAME, V2_PROTOCOL_NAME].map(StreamProtocol::new)
+    }
+
+    async fn request_round_trip(
+        protocol: &StreamProtocol,
+        request: PromptRequest,
+    ) -> PromptRequest {
+        let mut codec = MeshCodec::default();
+        let mut bytes = Cursor::new(Vec::new());
+        codec
+            .write_request(protocol, &mut bytes, request)
+            .await
+            .unwrap();
+        let mut bytes = Cursor::new(bytes.into_inner());
+        codec.read_request(protocol, &mut bytes).await.unwrap()
+    }
+
+    async fn response_round_trip(
+        protocol: &StreamProtocol,
+        response: PromptResponse,
+    ) -> PromptResponse {
+        let mut codec = MeshCodec::default();
+        let mut bytes = Cursor::new(Vec::new());
+        codec
+            .write_response(protocol, &mut bytes, response)
+            .await
+            .unwrap();
+        let mut bytes = Cursor::new(bytes.into_inner());
+        codec.read_response(protocol, &mut bytes).await.unwrap()
+    }
+
+    /// Every status. The match fails to compile once a status is added, until
+    /// it's listed here too.
+    fn statuses() -> Vec<ResponseStatus> {
+        let all = vec![
+            ResponseStatus::Ok,
+            ResponseStatus::Busy,
+            ResponseStatus::Error,
+            ResponseStatus::Banned,
+            ResponseStatus::InvalidJson,
+            ResponseStatus::QuotaExceeded,
+            ResponseStatus::Overloaded,
+            ResponseStatus::ModelNotAllowed,
+            ResponseStatus::ContentBlocked,
+            ResponseStatus::ContextOverflow,
+            ResponseStatus::BackendOutOfMemory,
+            ResponseStatus::DeadlineExceeded,
+            ResponseStatus::Internal,
+            ResponseStatus::Unauthorized,
+            ResponseStatus::EmptyResponse,
+            ResponseStatus::Maintenance,
+            ResponseStatus::TemplateError,
+            ResponseStatus::UnsupportedFeature,
+            ResponseStatus::NotCached,
+            ResponseStatus::Standby,
+            ResponseStatus::InvalidOption,
+            ResponseStatus::ResponseTooLarge,
+        ];
+        for status in &all {
+            match status {
+                ResponseStatus::Ok
+                | ResponseStatus::Busy
+                | ResponseStatus::Error
+                | ResponseStatus::Banned
+                | ResponseStatus::InvalidJson
+                | ResponseStatus::QuotaExceeded
+                | ResponseStatus::Overloaded
+                | ResponseStatus::ModelNotAllowed
+                | ResponseStatus::ContentBlocked
+                | ResponseStatus::ContextOverflow
+                | ResponseStatus::BackendOutOfMemory
+                | ResponseStatus::DeadlineExceeded
+                | ResponseStatus::Internal
+                | ResponseStatus::Unauthorized
+                | ResponseStatus::EmptyResponse
+                | ResponseStatus::Maintenance
+                | ResponseStatus::TemplateError
+                | ResponseStatus::UnsupportedFeature
+                | ResponseStatus::NotCached
+                | ResponseStatus::Standby
+                | ResponseStatus::InvalidOption
+                | ResponseStatus::ResponseTooLarge => {}
+                // Never sent as itself; see the test below.
+                ResponseStatus::Unknown => unreachable!(),
+            }
+        }
+        all
+    }
+
+    #[tokio::test]
+    async fn a_full_request_survives_every_protocol() {
+        let mut request: PromptRequest = serde_json::from_value(serde_json::json!({
+            "prompt": "Describe the picture.",
+            "model": "llava:7b",
+            "format": "json",
+            "idempotency_key": "0123456789abcdef",
+            "allow_truncate": true,
+            "max_duration_ms": 30_000,
+            "client_info": { "app_name": "mesh-chat", "app_version": "1.2.3" },
+            "api_key": "sk-team",
+            "trace_context": "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "apply_template": true,
+            "system": "Be brief.",
+            "tools": [{ "name": "weather", "description": "Current weather", "parameters": "{}" }],
+            "tool_choice": { "Tool": "weather" },
+            "cache": "Bypass",
+            "cache_ttl_ms": 5_000,
+            "num_ctx": 8192,
+            "priority": 9,
+        }))
+        .unwrap();
+        request.images = Some(vec![vec![137, 80, 78, 71], vec![255, 216, 255]]);
+        for protocol in protocols() {
+            let decoded = request_round_trip(&protocol, request.clone()).await;
+            assert!(decoded == request, "{protocol}: {decoded:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn a_full_response_survives_every_protocol() {
+        let response = PromptResponse {
+            quota: Some(QuotaStatus {
+                remaining_requests: Some(41),
+                remaining_tokens: Some(9_000),
+                resets_at: 1_700_000_000,
+            }),
+            available_models: vec!["llava:7b".to_string(), "phi3".to_string()],
+            truncated: true,
+            is_fallback: true,
+            timing: Some(Timing {
+                queue_wait_ms: 12,
+                inference_ms: 3_400,
+            }),
+            template: Some("chatml".to_string()),
+            ..PromptResponse::ok("A cat on a mat.".to_string())
+        }
+        .with_binary(Some(BinaryPayload {
+            mime_type: "image/png".to_string(),
+            data: vec![137, 80, 78, 71],
+        }))
+        .with_tool_calls(vec![ToolCall {
+            name: "weather".to_string(),
+            arguments: r#"{"city":"Paris"}"#.to_string(),
+        }]);
+        for protocol in protocols() {
+            assert_eq!(
+                response_round_trip(&protocol, response.clone()).await,
+                response,
+                "{protocol}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn every_status_survives_every_protocol() {
+        for status in statuses() {
+            let response = PromptResponse {
+                status,
+                ..PromptResponse::ok("why".to_string())
+            };
+            for protocol in protocols() {
+                let decoded = response_round_trip(&protocol, response.clone()).await;
+                assert_eq!(decoded.status, status, "{protocol}");
+                assert_eq!(decoded.response, "why", "{protocol}");
+            }
+        }
+        // And every protobuf status number is one of them.
+        let numbers: Vec<_> = (0..)
+            .map_while(|n| wire::ResponseStatus::try_from(n).ok())
+            .map(ResponseStatus::from)
+            .collect();
+        assert_eq!(numbers, statuses());
+    }
+
+    #[test]
+    fn statuses_from_newer_nodes_decode_as_unknown() {
+        let response: PromptResponse = serde_json::from_value(serde_json::json!({
+            "status": "SomethingNew",
+            "response": "refused for a new reason",
+        }))
+        .unwrap();
+        assert_eq!(response.status, ResponseStatus::Unknown);
+        assert_eq!(response.response, "refused for a new reason");
+
+        let response: v2::PromptResponse = serde_json::from_value(serde_json::json!({
+            "status": "SomethingNew",
+            "error": "refused for a new reason",
+        }))
+        .unwrap();
+        assert_eq!(response.status, ResponseStatus::Unknown);
+
+        let wire = wire::PromptResponse {
+            status: 99,
+            ..Default::default()
+        };
+        let response = PromptResponse::from(wire);
+        assert_eq!(response.status, ResponseStatus::Unknown);
+        // Passed on, it's still a refusal.
+        assert_eq!(
+            wire::PromptResponse::from(response).status,
+            wire::ResponseStatus::Error as i32
+        );
+    }
+}
@@ -0,0 +1,568 @@
+//! Per-peer request and token quotas over a rolling window (e.g. the last
+//! hour or day).
+//!
+//! Requests made with an API key count against the key's team instead of the
+//! peer; see [`api_keys`](crate::api_keys). Usage is counted in [`SLICES`]
+//! slices of the window, and a slice stops counting once it is a whole
+//! window old, so allowance comes back gradually rather than all at once at
+//! a reset a burst could be timed around. Usage is kept in wall-clock time so
+//! it can be saved to disk and survive restarts. A peer's usage is forgotten
+//! once it has gone a whole window without a request, by when none of it
+//! counts anyway. Usage that still counts is never forgotten to make room:
+//! while every account kept has some, peers without usage of their own
+//! share one allowance, so a peer can't shed its usage by coming back under
+//! new ids. The usage of teams with API keys is always kept; once a team's
+//! last key is removed its usage is forgotten like a peer's, so the map
+//! stays bounded.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt, fs, io,
+    path::Path,
+    str::FromStr,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    QuotaStatus,
+    bounded::{BoundedConfig, BoundedMap},
+};
+
+/// Limits for one peer or team. `None` means unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    pub requests: Option<u64>,
+    pub tokens: Option<u64>,
+}
+
+impl FromStr for Limits {
+    type Err = String;
+
+    /// Parses `REQUESTS:TOKENS`, where an empty side means unlimited.
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (requests, tokens) = spec.split_once(':').unwrap_or((spec, ""));
+        let parse = |s: &str| {
+            (!s.is_empty())
+                .then(|| {
+                    s.parse()
+                        .map_err(|e| format!("invalid quota `{spec}`: {e}"))
+                })
+                .transpose()
+        };
+        Ok(Self {
+            requests: parse(requests)?,
+            tokens: parse(tokens)?,
+        })
+    }
+}
+
+/// Whose allowance a request counts against.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Account {
+    Peer(PeerId),
+    /// The team of the API key the request carried.
+    Team(String),
+}
+
+impl fmt::Display for Account {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Peer(peer) => write!(f, "{peer}"),
+            Self::Team(team) => write!(f, "team:{team}"),
+        }
+    }
+}
+
+impl FromStr for Account {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("team:") {
+            Some(team) => Ok(Self::Team(team.to_string())),
+            None => s.parse().map(Self::Peer).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// One team's usage in the current window.
+#[derive(Debug, Clone)]
+pub struct TeamUsage {
+    pub team: String,
+    pub requests: u64,
+    pub tokens: u64,
+    pub limits: Limits,
+}
+
+/// Slices a window is counted in.
+pub const SLICES: u64 = 60;
+
+/// Usage within one slice of the window.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Slice {
+    /// Seconds since the Unix epoch at which the slice began.
+    #[serde(alias = "window_start")]
+    start: u64,
+    requests: u64,
+    tokens: u64,
+}
+
+/// An account's usage, by slice, oldest first.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(from = "SavedUsage")]
+struct Usage {
+    slices: VecDeque<Slice>,
+}
+
+/// Usage as saved by this build, or by one that counted a fixed window, which
+/// is read as a single slice.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SavedUsage {
+    Rolling { slices: VecDeque<Slice> },
+    Fixed(Slice),
+}
+
+impl From<SavedUsage> for Usage {
+    fn from(saved: SavedUsage) -> Self {
+        let slices = match saved {
+            SavedUsage::Rolling { slices } => slices,
+            SavedUsage::Fixed(slice) => VecDeque::from([slice]),
+        };
+        Self { slices }
+    }
+}
+
+impl Usage {
+    /// Slices still within the window ending at `now`.
+    fn current(&self, now: u64, window: u64) -> impl Iterator<Item = &Slice> {
+        self.slices
+            .iter()
+            .filter(move |slice| now < slice.start.saturating_add(window))
+    }
+
+    fn requests(&self, now: u64, window: u64) -> u64 {
+        self.current(now, window)
+            .fold(0, |sum, slice| sum.saturating_add(slice.requests))
+    }
+
+    fn tokens(&self, now: u64, window: u64) -> u64 {
+        self.current(now, window)
+            .fold(0, |sum, slice| sum.saturating_add(slice.tokens))
+    }
+
+    /// When the oldest usage still counting stops counting, or `now` if
+    /// nothing counts.
+    fn frees_at(&self, now: u64, window: u64) -> u64 {
+        self.current(now, window)
+            .next()
+            .map_or(now, |slice| slice.start.saturating_add(window))
+    }
+
+    /// Drops the slices out of the window and returns the one `now` falls
+    /// in, starting it if need be.
+    fn slice(&mut self, now: u64, window: u64) -> &mut Slice {
+        while self
+            .slices
+            .front()
+            .is_some_and(|slice| now >= slice.start.saturating_add(window))
+        {
+            self.slices.pop_front();
+        }
+        let length = (window / SLICES).max(1);
+        let start = now - now % length;
+        if self.slices.back().is_none_or(|slice| slice.start != start) {
+            self.slices.push_back(Slice {
+                start,
+                ..Default::default()
+            });
+        }
+        self.slices.back_mut().expect("just pushed")
+    }
+}
+
+pub struct Quotas {
+    window: Duration,
+    default_limits: Limits,
+    peer_limits: HashMap<PeerId, Limits>,
+    team_limits: HashMap<String, Limits>,
+    /// Teams with API keys, whose usage is never evicted.
+    teams: HashSet<String>,
+    usage: BoundedMap<Account, Usage>,
+    max_accounts: usize,
+    /// Usage of the accounts that found no room in `usage`, together.
+    overflow: Usage,
+}
+
+impl Quotas {
+    /// `max_accounts` bounds how many peers' and teams' usage is kept at once.
+    pub fn new(
+        window: Duration,
+        default_limits: Limits,
+        peer_limits: impl IntoIterator<Item = (PeerId, Limits)>,
+        max_accounts: usize,
+    ) -> Self {
+        Self {
+            window,
+            default_limits,
+            peer_limits: peer_limits.into_iter().collect(),
+            team_limits: HashMap::new(),
+            teams: HashSet::new(),
+            usage: BoundedMap::new(BoundedConfig {
+                capacity: max_accounts,
+                ttl: Some(window),
+            }),
+            max_accounts,
+            overflow: Usage::default(),
+        }
+    }
+
+    /// Keeps `peer`'s usage however many other peers come along, e.g. for
+    /// the relay.
+    pub fn exempt(&mut self, peer: PeerId) {
+        self.usage.exempt(Account::Peer(peer));
+    }
+
+    /// Replaces the teams with API keys and their own limits, e.g. after the
+    /// keys are reloaded. Teams without a limit get the default limits.
+    /// Teams no longer listed keep their usage only while there is room.
+    pub fn set_teams(
+        &mut self,
+        teams: impl IntoIterator<Item = String>,
+        limits: impl IntoIterator<Item = (String, Limits)>,
+    ) {
+        let teams: HashSet<String> = teams.into_iter().collect();
+        for gone in self.teams.difference(&teams) {
+            self.usage.unexempt(&Account::Team(gone.clone()));
+        }
+        for team in &teams {
+            self.usage.exempt(Account::Team(team.clone()));
+        }
+        self.teams = teams;
+        self.team_limits = limits.into_iter().collect();
+    }
+
+    fn limits(&self, account: &Account) -> Limits {
+        let limits = match account {
+            Account::Peer(peer) => self.peer_limits.get(peer),
+            Account::Team(team) => self.team_limits.get(team),
+        };
+        limits.copied().unwrap_or(self.default_limits)
+    }
+
+    /// `account`'s usage, or the shared overflow if the map is full of
+    /// usage that still counts.
+    fn usage(&mut self, account: &Account) -> &mut Usage {
+        let now = Instant::now();
+        if self.usage.get(account).is_none() && self.usage.len() >= self.max_accounts {
+            self.usage.prune(now);
+            if self.usage.len() >= self.max_accounts {
+                return &mut self.overflow;
+            }
+        }
+        self.usage
+            .get_or_insert_with(account.clone(), now, Usage::default)
+    }
+
+    /// Counts a request against `account` if it is within quota. Otherwise
+    /// nothing is counted and the account's quota status, with its reset
+    /// time, is returned.
+    pub fn admit(&mut self, account: &Account) -> Result<QuotaStatus, QuotaStatus> {
+        self.admit_many(account, 1)
+    }
+
+    /// Like [`Quotas::admit`], for a request that counts as `requests`, such
+    /// as a comparison running two generations. All or none are counted.
+    pub fn admit_many(
+        &mut self,
+        account: &Account,
+        requests: u64,
+    ) -> Result<QuotaStatus, QuotaStatus> {
+        let limits = self.limits(account);
+        let window = self.window.as_secs();
+        let now = unix_now();
+        let usage = self.usage(account);
+        let exceeded = limits
+            .requests
+            .is_some_and(|max| usage.requests(now, window).saturating_add(requests) > max)
+            || limits
+                .tokens
+                .is_some_and(|max| usage.tokens(now, window) >= max);
+        if !exceeded {
+            let slice = usage.slice(now, window);
+            slice.requests = slice.requests.saturating_add(requests);
+        }
+        let status = status(limits, usage, now, window);
+        if exceeded { Err(status) } else { Ok(status) }
+    }
+
+    /// Adds completion tokens to `account`'s usage once a request finishes.
+    pub fn record_tokens(&mut self, account: &Account, tokens: u64) -> QuotaStatus {
+        let limits = self.limits(account);
+        let window = self.window.as_secs();
+        let now = unix_now();
+        let usage = self.usage(account);
+        let slice = usage.slice(now, window);
+        slice.tokens = slice.tokens.saturating_add(tokens);
+        status(limits, usage, now, window)
+    }
+
+    /// Usage of every team in its current window, by team name.
+    pub fn team_usage(&self) -> Vec<TeamUsage> {
+        let now = unix_now();
+        let window = self.window.as_secs();
+        let mut teams: Vec<TeamUsage> = self
+            .usage
+            .iter()
+            .filter_map(|(account, usage)| match account {
+                Account::Team(team) if usage.current(now, window).next().is_some() => {
+                    Some(TeamUsage {
+                        team: team.clone(),
+                        requests: usage.requests(now, window),
+                        tokens: usage.tokens(now, window),
+                        limits: self.limits(account),
+                    })
+                }
+                _ => None,
+            })
+            .collect();
+        teams.sort_by(|a, b| a.team.cmp(&b.team));
+        teams
+    }
+
+    /// Restores usage saved by [`Quotas::save`]. A missing file is not an error.
+    pub fn load(&mut self, path: &Path) -> io::Result<()> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let saved: HashMap<String, Usage> = serde_json::from_slice(&data)?;
+        let now = Instant::now();
+        self.usage.retain(|_, _| false);
+        for (account, usage) in saved {
+            if let Ok(account) = account.parse::<Account>() {
+                self.usage.insert(account, usage, now);
+            }
+        }
+        Ok(())
+    }
+
+    /// Forgets usage a window old, returning how many accounts were
+    /// dropped, for age or for room, since the last call.
+    pub fn prune(&mut self) -> u64 {
+        self.usage.prune(Instant::now());
+        self.usage.take_evicted()
+    }
+
+    /// Accounts with usage kept.
+    pub fn len(&self) -> usize {
+        self.usage.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.usage.is_empty()
+    }
+
+    /// Writes usage to `path` through a temporary file, so a crash
+    /// mid-write doesn't lose what was saved before.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let saved: HashMap<String, &Usage> = self
+            .usage
+            .iter()
+            .map(|(account, usage)| (account.to_string(), usage))
+            .collect();
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(&saved)?)?;
+        fs::rename(tmp, path)
+    }
+}
+
+/// `resets_at` is when the oldest usage stops counting, which is when some
+/// allowance comes back.
+fn status(limits: Limits, usage: &Usage, now: u64, window: u64) -> QuotaStatus {
+    QuotaStatus {
+        remaining_requests: limits
+            .requests
+            .map(|max| max.saturating_sub(usage.requests(now, window))),
+        remaining_tokens: limits
+            .tokens
+            .map(|max| max.saturating_sub(usage.tokens(now, window))),
+        resets_at: usage.frees_at(now, window),
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    const WINDOW: u64 = 3600;
+
+    #[test]
+    fn usage_leaves_the_window_gradually() {
+        let mut usage = Usage::default();
+        let start = 1_000_000 * WINDOW;
+        usage.slice(start, WINDOW).requests += 3;
+        usage.slice(start + WINDOW / 2, WINDOW).requests += 2;
+        assert_eq!(usage.requests(start + WINDOW / 2, WINDOW), 5);
+        assert_eq!(usage.frees_at(start + WINDOW / 2, WINDOW), start + WINDOW);
+
+        // The first requests stop counting a window after they were made,
+        // not at a reset shared by all of them.
+        assert_eq!(usage.requests(start + WINDOW, WINDOW), 2);
+        assert_eq!(
+            usage.frees_at(start + WINDOW, WINDOW),
+            start + WINDOW / 2 + WINDOW
+        );
+        assert_eq!(usage.requests(start + 2 * WINDOW, WINDOW), 0);
+        assert_eq!(
+            usage.frees_at(start + 2 * WINDOW, WINDOW),
+            start + 2 * WINDOW
+        );
+    }
+
+    #[test]
+    fn usage_keeps_at_most_a_window_of_slices() {
+        let mut usage = Usage::default();
+        let slice = WINDOW / SLICES;
+        for i in 0..10 * SLICES {
+            usage.slice(i * slice, WINDOW).tokens += 1;
+        }
+        assert_eq!(usage.slices.len() as u64, SLICES);
+        assert_eq!(usage.tokens(10 * SLICES * slice - 1, WINDOW), SLICES);
+    }
+
+    #[test]
+    fn refuses_past_the_limits_without_counting() {
+        let peer = PeerId::random();
+        let limits = Limits {
+            requests: Some(2),
+            tokens: Some(100),
+        };
+        let mut quotas = Quotas::new(
+            Duration::from_secs(WINDOW),
+            Limits::default(),
+            [(peer, limits)],
+            16,
+        );
+        let account = Account::Peer(peer);
+        assert!(quotas.admit_many(&account, 3).is_err());
+        assert_eq!(quotas.admit(&account).unwrap().remaining_requests, Some(1));
+        assert_eq!(
+            quotas.record_tokens(&account, 100).remaining_tokens,
+            Some(0)
+        );
+        // Out of tokens, so refused though a request is left.
+        let refused = quotas.admit(&account).unwrap_err();
+        assert_eq!(refused.remaining_requests, Some(1));
+        assert!(refused.resets_at > unix_now());
+
+        // Others get the default, unlimited here.
+        let other = Account::Peer(PeerId::random());
+        assert!(quotas.admit_many(&other, 1000).is_ok());
+    }
+
+    #[test]
+    fn keeps_teams_with_keys_and_bounds_everyone_else() {
+        let mut quotas = Quotas::new(Duration::from_secs(WINDOW), Limits::default(), [], 4);
+        let (kept, dropped) = (
+            Account::Team("kept".to_string()),
+            Account::Team("dropped".to_string()),
+        );
+        quotas.set_teams(["kept".to_string(), "dropped".to_string()], []);
+        quotas.admit(&kept).unwrap();
+        quotas.admit(&dropped).unwrap();
+        for _ in 0..100 {
+            quotas.admit(&Account::Peer(PeerId::random())).unwrap();
+        }
+        assert_eq!(quotas.len(), 4);
+        assert!(quotas.usage.get(&kept).is_some());
+        assert!(quotas.usage.get(&dropped).is_some());
+
+        // Once its keys are gone a team is forgotten like a peer, when its
+        // usage stops counting.
+        quotas.set_teams(["kept".to_string()], []);
+        for _ in 0..100 {
+            quotas.admit(&Account::Peer(PeerId::random())).unwrap();
+        }
+        assert_eq!(quotas.len(), 4);
+        assert!(quotas.usage.get(&dropped).is_some());
+        quotas
+            .usage
+            .prune(Instant::now() + Duration::from_secs(WINDOW));
+        assert!(quotas.usage.get(&kept).is_some());
+        assert!(quotas.usage.get(&dropped).is_none());
+        assert_eq!(quotas.len(), 1);
+    }
+
+    #[test]
+    fn peers_cant_shed_usage_by_changing_ids() {
+        let limits = Limits {
+            requests: Some(3),
+            tokens: None,
+        };
+        let mut quotas = Quotas::new(Duration::from_secs(WINDOW), limits, [], 2);
+        let regulars = [PeerId::random(), PeerId::random()];
+        for peer in regulars {
+            quotas.admit(&Account::Peer(peer)).unwrap();
+        }
+        // With no room left, newcomers share one allowance rather than
+        // pushing out usage that still counts.
+        for _ in 0..3 {
+            quotas.admit(&Account::Peer(PeerId::random())).unwrap();
+        }
+        assert!(quotas.admit(&Account::Peer(PeerId::random())).is_err());
+        assert_eq!(quotas.len(), 2);
+        for peer in regulars {
+            let status = quotas.admit(&Account::Peer(peer)).unwrap();
+            assert_eq!(status.remaining_requests, Some(1));
+        }
+    }
+
+    #[test]
+    fn usage_survives_a_save_and_load() {
+        let dir = TempDir::new("quota-save");
+        let path = dir.join("usage.json");
+        let limits = Limits {
+            requests: Some(10),
+            tokens: None,
+        };
+        let team = Account::Team("research".to_string());
+        let mut quotas = Quotas::new(Duration::from_secs(WINDOW), limits, [], 16);
+        quotas.admit_many(&team, 4).unwrap();
+        quotas.save(&path).unwrap();
+
+        let mut restarted = Quotas::new(Duration::from_secs(WINDOW), limits, [], 16);
+        restarted.load(&path).unwrap();
+        assert_eq!(restarted.admit(&team).unwrap().remaining_requests, Some(5));
+    }
+
+    #[test]
+    fn reads_usage_saved_for_a_fixed_window() {
+        let now = unix_now();
+        let saved = format!(
+            r#"{{"team:research": {{"window_start": {now}, "requests": 7, "tokens": 3}}}}"#
+        );
+        let dir = TempDir::new("quota-fixed");
+        let path = dir.join("usage.json");
+        fs::write(&path, saved).unwrap();
+        let limits = Limits {
+            requests: Some(10),
+            tokens: None,
+        };
+        let mut quotas = Quotas::new(Duration::from_secs(WINDOW), limits, [], 16);
+        quotas.load(&path).unwrap();
+        let team = Account::Team("research".to_string());
+        assert_eq!(quotas.admit(&team).unwrap().remaining_requests, Some(2));
+    }
+}
@@ -0,0 +1,121 @@
+//! Whether the node is ready to take prompts, and if not, why, for
+//! orchestrators probing `GET /readyz`.
+//!
+//! The swarm loop answers a [`ReadinessRequest`] with what it knows
+//! ([`NodeReadiness`]); the backend is then asked directly, without waiting
+//! for room under its request limit, so a busy node still answers promptly.
+//! The node isn't ready while it drains, is in maintenance or on standby,
+//! while it means to hold a relay reservation and doesn't, or while Ollama is
+//! unreachable or doesn't have the default model. Whether the model is
+//! loaded in memory is only reported: Ollama unloads idle models, and the
+//! next prompt loads it again.
+
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::{sync::oneshot, time::timeout};
+
+use crate::ollama;
+
+/// A request for a [`NodeReadiness`], answered on the channel.
+pub type ReadinessRequest = oneshot::Sender<NodeReadiness>;
+
+/// How long each backend check may take before Ollama counts as unreachable.
+const BACKEND_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The swarm loop's part of the answer.
+#[derive(Debug, Clone)]
+pub struct NodeReadiness {
+    /// The model prompts get when they don't name one.
+    pub model: String,
+    /// Whether the node is listening through its relay; `None` when it
+    /// doesn't mean to hold a reservation.
+    pub relay_connected: Option<bool>,
+    pub in_flight: usize,
+    pub queued: usize,
+    pub draining: bool,
+    pub maintenance: bool,
+    pub standby: bool,
+}
+
+/// The answer to `GET /readyz`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    /// What keeps the node from being ready, one entry per failing check.
+    pub failing: Vec<String>,
+    pub relay_connected: Option<bool>,
+    pub ollama_reachable: bool,
+    pub model: String,
+    /// Whether Ollama has the model, pulled or created.
+    pub model_available: bool,
+    /// Whether the model is loaded in memory.
+    pub model_loaded: bool,
+    /// Inferences running, and waiting for a slot.
+    pub in_flight: usize,
+    pub queued: usize,
+    pub draining: bool,
+    pub maintenance: bool,
+    /// Whether the node waits to be promoted before serving.
+    pub standby: bool,
+}
+
+/// Completes `node` with the backend checks.
+pub async fn check(node: NodeReadiness) -> Readiness {
+    let mut failing = Vec::new();
+    if node.draining {
+        failing.push("draining, to shut down".to_string());
+    }
+    if node.maintenance {
+        failing.push("in maintenance mode".to_string());
+    }
+    if node.standby {
+        failing.push("on standby, until promoted".to_string());
+    }
+    if node.relay_connected == Some(false) {
+        failing.push("not listening through the relay".to_string());
+    }
+    let available = timeout(BACKEND_TIMEOUT, ollama::local_models()).await;
+    let (ollama_reachable, model_available) = match available {
+        Ok(Ok(models)) => (true, models.iter().any(|m| same_model(m, &node.model))),
+        Ok(Err(e)) => {
+            failing.push(format!("Ollama is unreachable: {e}"));
+            (false, false)
+        }
+        Err(_) => {
+            failing.push(format!(
+                "Ollama didn't answer within {}s",
+                BACKEND_TIMEOUT.as_secs()
+            ));
+            (false, false)
+        }
+    };
+    if ollama_reachable && !model_available {
+        failing.push(format!("Ollama doesn't have {}", node.model));
+    }
+    let model_loaded = ollama_reachable
+        && matches!(
+            timeout(BACKEND_TIMEOUT, ollama::loaded_models()).await,
+            Ok(Ok(models)) if models.iter().any(|m| same_model(m, &node.model))
+        );
+    Readiness {
+        ready: failing.is_empty(),
+        failing,
+        relay_connected: node.relay_connected,
+        ollama_reachable,
+        model: node.model,
+        model_available,
+        model_loaded,
+        in_flight: node.in_flight,
+        queued: node.queued,
+        draining: node.draining,
+        maintenance: node.maintenance,
+        standby: node.standby,
+    }
+}
+
+/// Whether Ollama's `listed` name is `model`, which may leave out the
+/// `:latest` tag.
+fn same_model(listed: &str, model: &str) -> bool {
+    listed == model || listed.strip_suffix(":latest") == Some(model)
+}
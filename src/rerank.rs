@@ -0,0 +1,76 @@
+//! Relevance scoring of documents against a query.
+//!
+//! Ollama has no dedicated reranking endpoint, so scores are the cosine
+//! similarity between the query's embedding and each document's.
+
+use crate::{
+    RerankMethod, RerankRequest, RerankResponse, RerankScore, ResponseStatus,
+    ollama::{self, BackendError},
+};
+
+/// Caps on what a single rerank request may ask for.
+#[derive(Debug, Clone, Copy)]
+pub struct RerankLimits {
+    pub max_documents: usize,
+    pub max_document_bytes: usize,
+}
+
+impl RerankLimits {
+    /// Checks `request` against the caps, describing the first one it exceeds.
+    pub fn check(&self, request: &RerankRequest) -> Result<(), String> {
+        if request.documents.len() > self.max_documents {
+            return Err(format!(
+                "{} documents exceeds the limit of {}",
+                request.documents.len(),
+                self.max_documents
+            ));
+        }
+        if let Some((index, doc)) = request
+            .documents
+            .iter()
+            .enumerate()
+            .find(|(_, doc)| doc.len() > self.max_document_bytes)
+        {
+            return Err(format!(
+                "document {index} is {} bytes, over the limit of {}",
+                doc.len(),
+                self.max_document_bytes
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Scores every document in `request` with `model` and orders them best first.
+pub async fn rerank(model: &str, request: RerankRequest) -> Result<RerankResponse, BackendError> {
+    let top_n = request.top_n.unwrap_or(request.documents.len());
+    let mut inputs = request.documents;
+    inputs.insert(0, request.query);
+    let embeddings = ollama::embed(model, &inputs).await?;
+    let (query, documents) = embeddings.split_first().ok_or("no query embedding")?;
+
+    let mut results: Vec<RerankScore> = documents
+        .iter()
+        .enumerate()
+        .map(|(index, doc)| RerankScore {
+            index,
+            score: cosine_similarity(query, doc),
+        })
+        .collect();
+    results.sort_by(|a, b| b.score.total_cmp(&a.score));
+    results.truncate(top_n);
+    Ok(RerankResponse {
+        results,
+        method: Some(RerankMethod::EmbeddingSimilarity),
+        status: ResponseStatus::Ok,
+        error: None,
+        available_models: Vec::new(),
+    })
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let denom = norm(a) * norm(b);
+    if denom == 0.0 { 0.0 } else { dot / denom }
+}
@@ -0,0 +1,239 @@
+//! Retrying prompts safely.
+//!
+//! A [`RetryPolicy`] retries an operation only when trying again can help and
+//! can't cause harm:
+//!
+//! - transport failures: the dial failed, the connection dropped, or a phase
+//!   timed out;
+//! - responses the worker marks as transient: [`ResponseStatus::Busy`],
+//!   [`ResponseStatus::Overloaded`], [`ResponseStatus::BackendOutOfMemory`],
+//!   [`ResponseStatus::Maintenance`] and [`ResponseStatus::Standby`].
+//!
+//! Every other response, including errors like
+//! [`ResponseStatus::InvalidJson`], is returned as is: the worker did the
+//! work, and running it again would just cost the same again.
+//!
+//! All attempts and the backoff between them share one budget. Once it is
+//! spent the last error or response is returned, even if retries remain.
+//!
+//! Each request gets an idempotency key, reused on every attempt. When a
+//! response deadline fires but the worker finishes anyway, the retry carries
+//! the same key and the worker answers it from its dedup cache rather than
+//! running the prompt a second time.
+
+use std::time::{Duration, Instant};
+
+use libp2p::{Multiaddr, PeerId};
+use tokio::time::{sleep, timeout};
+
+use crate::{
+    PromptRequest, PromptResponse, ResponseStatus,
+    backoff::random_u64,
+    client::{Client, ClientError, FailureKind},
+};
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Attempts after the first one.
+    pub max_retries: u32,
+    /// Wall-clock time for all attempts and backoff together.
+    pub budget: Duration,
+    /// Wait before the first retry; doubles for each one after.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            budget: Duration::from_secs(600),
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A single attempt, no retries.
+    pub fn none() -> Self {
+        Self {
+            max_retries: 0,
+            ..Self::default()
+        }
+    }
+
+    /// Whether a failed attempt may be retried.
+    pub fn is_retryable_error(error: &ClientError) -> bool {
+        match error {
+            ClientError::Dial(_)
+            | ClientError::ConnectTimeout(_)
+            | ClientError::ProtocolTimeout(_)
+            | ClientError::ResponseTimeout(_) => true,
+            ClientError::Outbound(e) => e.kind() != FailureKind::UnsupportedProtocols,
+            ClientError::ProtocolUnsupported
+            | ClientError::ProtocolMismatch { .. }
+            | ClientError::NoPeers
+            | ClientError::Closed => false,
+        }
+    }
+
+    /// Whether a response asks the client to come back later.
+    pub fn is_retryable_response(response: &PromptResponse) -> bool {
+        matches!(
+            response.status,
+            ResponseStatus::Busy
+                | ResponseStatus::Overloaded
+                | ResponseStatus::BackendOutOfMemory
+                | ResponseStatus::Maintenance
+                | ResponseStatus::Standby
+        )
+    }
+
+    fn backoff(&self, retry: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_backoff)
+    }
+}
+
+/// A fresh random idempotency key.
+pub fn idempotency_key() -> String {
+    format!("{:016x}{:016x}", random_u64(), random_u64())
+}
+
+impl Client {
+    /// Connects to `peer` and sends `request`, retrying as `policy` allows.
+    /// An idempotency key is attached unless the request already has one.
+    pub async fn send_prompt_with_retry(
+        &self,
+        peer: PeerId,
+        addrs: Vec<Multiaddr>,
+        mut request: PromptRequest,
+        policy: &RetryPolicy,
+    ) -> Result<PromptResponse, ClientError> {
+        request.idempotency_key.get_or_insert_with(idempotency_key);
+        let deadline = Instant::now() + policy.budget;
+        let mut retry = 0;
+        let mut last = None;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let attempt = async {
+                self.connect(peer, addrs.clone()).await?;
+                self.send_prompt(peer, request.clone()).await
+            };
+            let Ok(result) = timeout(remaining, attempt).await else {
+                // The budget ran out during a retry, which may have had next
+                // to no time; the attempt before it says more.
+                return last.unwrap_or(Err(ClientError::ResponseTimeout(policy.budget)));
+            };
+            let retryable = match &result {
+                Ok(response) => RetryPolicy::is_retryable_response(response),
+                Err(e) => RetryPolicy::is_retryable_error(e),
+            };
+            let backoff = policy.backoff(retry);
+            if !retryable || retry >= policy.max_retries || Instant::now() + backoff >= deadline {
+                return result;
+            }
+            last = Some(result);
+            sleep(backoff).await;
+            retry += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{client::OutboundError, node::VersionMismatch};
+
+    use super::*;
+
+    fn outbound(kind: FailureKind) -> ClientError {
+        ClientError::Outbound(Box::new(OutboundError::stream(
+            PeerId::random(),
+            kind,
+            Instant::now(),
+            String::new(),
+        )))
+    }
+
+    #[test]
+    fn retries_transport_failures_but_not_protocol_ones() {
+        let second = Duration::from_secs(1);
+        for error in [
+            ClientError::Dial("refused".to_string()),
+            ClientError::ConnectTimeout(second),
+            ClientError::ProtocolTimeout(second),
+            ClientError::ResponseTimeout(second),
+            outbound(FailureKind::DialFailure),
+            outbound(FailureKind::Timeout),
+            outbound(FailureKind::ConnectionClosed),
+            outbound(FailureKind::Io),
+        ] {
+            assert!(RetryPolicy::is_retryable_error(&error), "{error:?}");
+        }
+        for error in [
+            outbound(FailureKind::UnsupportedProtocols),
+            ClientError::ProtocolUnsupported,
+            ClientError::ProtocolMismatch {
+                peer: PeerId::random(),
+                mismatch: VersionMismatch {
+                    theirs: vec!["0.1.0".to_string()],
+                },
+            },
+            ClientError::NoPeers,
+            ClientError::Closed,
+        ] {
+            assert!(!RetryPolicy::is_retryable_error(&error), "{error:?}");
+        }
+    }
+
+    #[test]
+    fn retries_only_responses_asking_to_come_back() {
+        for response in [
+            PromptResponse::busy(),
+            PromptResponse::overloaded("queue full".to_string()),
+            PromptResponse::backend_out_of_memory("oom".to_string()),
+            PromptResponse::maintenance("upgrading"),
+            PromptResponse::standby(),
+        ] {
+            assert!(
+                RetryPolicy::is_retryable_response(&response),
+                "{response:?}"
+            );
+        }
+        for response in [
+            PromptResponse::ok("done".to_string()),
+            PromptResponse::invalid_json("not json".to_string()),
+            PromptResponse::context_overflow("too long".to_string()),
+            PromptResponse::deadline_exceeded(Duration::from_secs(1)),
+            PromptResponse::internal("bug".to_string()),
+            PromptResponse::error("failed".to_string()),
+        ] {
+            assert!(
+                !RetryPolicy::is_retryable_response(&response),
+                "{response:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let policy = RetryPolicy::default();
+        let waits: Vec<_> = (0..8).map(|retry| policy.backoff(retry)).collect();
+        assert_eq!(
+            waits,
+            [500, 1000, 2000, 4000, 8000, 10_000, 10_000, 10_000].map(Duration::from_millis)
+        );
+        // Far past the point of overflowing.
+        assert_eq!(policy.backoff(u32::MAX), policy.max_backoff);
+    }
+
+    #[test]
+    fn idempotency_keys_are_fresh_and_hex() {
+        let key = idempotency_key();
+        assert_eq!(key.len(), 32);
+        assert!(key.chars().all(|c| c.is_ascii_hexdigit()), "{key}");
+        assert_ne!(key, idempotency_key());
+    }
+}
@@ -0,0 +1,306 @@
+//! Orders admitted inference jobs onto the backend.
+//!
+//! A global limit caps total concurrency and per-model limits sit underneath
+//! it. Switching models makes Ollama swap weights in and out of VRAM, so jobs
+//! for the model that is already loaded are preferred over older jobs for a
+//! different one, as long as that model has a free slot. Priority comes
+//! before either: of the jobs that may start, those with the highest
+//! priority go first.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+/// A concurrency limit for models matching `pattern`, which is either an exact
+/// model name or a prefix followed by `*` (e.g. `phi3:*`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModelLimit {
+    pub pattern: String,
+    pub limit: usize,
+}
+
+impl ModelLimit {
+    fn matches(&self, model: &str) -> bool {
+        match self.pattern.strip_suffix('*') {
+            Some(prefix) => model.starts_with(prefix),
+            None => model == self.pattern,
+        }
+    }
+}
+
+impl std::str::FromStr for ModelLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, limit) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected MODEL=LIMIT, got `{s}`"))?;
+        let limit = limit
+            .parse()
+            .map_err(|e| format!("invalid limit in `{s}`: {e}"))?;
+        // A model that may never run would hold its requests in the queue
+        // until they time out.
+        if limit == 0 {
+            return Err(format!("limit in `{s}` must be at least 1"));
+        }
+        Ok(Self {
+            pattern: pattern.to_string(),
+            limit,
+        })
+    }
+}
+
+/// In-flight and queued job counts for one model.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ModelLoad {
+    pub in_flight: usize,
+    pub queued: usize,
+}
+
+pub struct Scheduler<J> {
+    max_concurrent: usize,
+    limits: Vec<ModelLimit>,
+    /// Jobs with their model and priority, oldest first.
+    queue: VecDeque<(String, u8, J)>,
+    running: HashMap<String, usize>,
+    running_total: usize,
+    /// The model most recently sent to the backend, assumed to be resident.
+    loaded: Option<String>,
+}
+
+impl<J> Scheduler<J> {
+    pub fn new(max_concurrent: usize, limits: Vec<ModelLimit>) -> Self {
+        Self {
+            max_concurrent: max_concurrent.max(1),
+            limits,
+            queue: VecDeque::new(),
+            running: HashMap::new(),
+            running_total: 0,
+            loaded: None,
+        }
+    }
+
+    /// Changes the global limit. Jobs already running beyond a lowered limit
+    /// are left to finish.
+    pub fn set_max_concurrent(&mut self, max_concurrent: usize) {
+        self.max_concurrent = max_concurrent.max(1);
+    }
+
+    /// The limit for `model`. Exact matches win over prefix patterns; models
+    /// without a limit are only bound by the global one.
+    fn limit_for(&self, model: &str) -> usize {
+        self.limits
+            .iter()
+            .find(|l| l.pattern == model)
+            .or_else(|| self.limits.iter().find(|l| l.matches(model)))
+            .map_or(self.max_concurrent, |l| l.limit)
+    }
+
+    fn has_slot(&self, model: &str) -> bool {
+        self.running.get(model).copied().unwrap_or_default() < self.limit_for(model)
+    }
+
+    pub fn enqueue(&mut self, model: String, job: J) {
+        self.enqueue_with_priority(model, 0, job);
+    }
+
+    /// Queues `job` to run ahead of those with a lower `priority`.
+    pub fn enqueue_with_priority(&mut self, model: String, priority: u8, job: J) {
+        self.queue.push_back((model, priority, job));
+    }
+
+    /// Takes the next job that may start now, if any, and counts it as running.
+    /// Jobs for a model at its limit are passed over, not waited behind.
+    pub fn start_next(&mut self) -> Option<(String, J)> {
+        if self.running_total >= self.max_concurrent {
+            return None;
+        }
+        let priority = self
+            .queue
+            .iter()
+            .filter(|(m, _, _)| self.has_slot(m))
+            .map(|(_, p, _)| *p)
+            .max()?;
+        let index = self
+            .loaded
+            .as_ref()
+            .filter(|loaded| self.has_slot(loaded))
+            .and_then(|loaded| {
+                self.queue
+                    .iter()
+                    .position(|(m, p, _)| *p == priority && m == loaded)
+            })
+            .or_else(|| {
+                self.queue
+                    .iter()
+                    .position(|(m, p, _)| *p == priority && self.has_slot(m))
+            })?;
+        let (model, _, job) = self.queue.remove(index)?;
+        *self.running.entry(model.clone()).or_default() += 1;
+        self.running_total += 1;
+        self.loaded = Some(model.clone());
+        Some((model, job))
+    }
+
+    /// Marks a job for `model` as finished, freeing its slot.
+    pub fn finish(&mut self, model: &str) {
+        if let Some(count) = self.running.get_mut(model) {
+            *count -= 1;
+            if *count == 0 {
+                self.running.remove(model);
+            }
+            self.running_total -= 1;
+        }
+    }
+
+    /// Jobs queued or running.
+    pub fn len(&self) -> usize {
+        self.queue.len() + self.running_total
+    }
+
+    /// Jobs waiting for a slot.
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Jobs running.
+    pub fn running(&self) -> usize {
+        self.running_total
+    }
+
+    pub fn max_concurrent(&self) -> usize {
+        self.max_concurrent
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn loaded_model(&self) -> Option<&str> {
+        self.loaded.as_deref()
+    }
+
+    pub fn load_by_model(&self) -> BTreeMap<&str, ModelLoad> {
+        let mut load: BTreeMap<&str, ModelLoad> = BTreeMap::new();
+        for (model, count) in &self.running {
+            load.entry(model).or_default().in_flight = *count;
+        }
+        for (model, _, _) in &self.queue {
+            load.entry(model).or_default().queued += 1;
+        }
+        load
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(specs: &[&str]) -> Vec<ModelLimit> {
+        specs.iter().map(|s| s.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn parses_limits() {
+        assert_eq!(
+            "phi3:*=4".parse::<ModelLimit>(),
+            Ok(ModelLimit {
+                pattern: "phi3:*".to_string(),
+                limit: 4
+            })
+        );
+        assert!("llama3".parse::<ModelLimit>().is_err());
+        assert!("llama3=many".parse::<ModelLimit>().is_err());
+        assert!("llama3=0".parse::<ModelLimit>().is_err());
+    }
+
+    #[test]
+    fn a_model_at_its_limit_does_not_hold_up_the_others() {
+        let mut scheduler = Scheduler::new(4, limits(&["big=1"]));
+        scheduler.enqueue("big".to_string(), 1);
+        scheduler.enqueue("big".to_string(), 2);
+        scheduler.enqueue("small".to_string(), 3);
+        scheduler.enqueue("big".to_string(), 4);
+        scheduler.enqueue("small".to_string(), 5);
+
+        assert_eq!(scheduler.start_next(), Some(("big".to_string(), 1)));
+        assert_eq!(scheduler.start_next(), Some(("small".to_string(), 3)));
+        assert_eq!(scheduler.start_next(), Some(("small".to_string(), 5)));
+        assert_eq!(scheduler.start_next(), None);
+        assert_eq!(scheduler.queued(), 2);
+
+        scheduler.finish("big");
+        assert_eq!(scheduler.start_next(), Some(("big".to_string(), 2)));
+    }
+
+    #[test]
+    fn prefers_the_loaded_model_while_it_has_room() {
+        let mut scheduler = Scheduler::new(1, Vec::new());
+        scheduler.enqueue("a".to_string(), 1);
+        scheduler.enqueue("b".to_string(), 2);
+        scheduler.enqueue("a".to_string(), 3);
+
+        assert_eq!(scheduler.start_next(), Some(("a".to_string(), 1)));
+        assert_eq!(scheduler.start_next(), None, "the global limit holds");
+        scheduler.finish("a");
+        assert_eq!(scheduler.start_next(), Some(("a".to_string(), 3)));
+        scheduler.finish("a");
+        assert_eq!(scheduler.start_next(), Some(("b".to_string(), 2)));
+        assert_eq!(scheduler.loaded_model(), Some("b"));
+    }
+
+    #[test]
+    fn higher_priority_goes_first_even_for_another_model() {
+        let mut scheduler = Scheduler::new(1, Vec::new());
+        scheduler.enqueue("a".to_string(), 1);
+        assert_eq!(scheduler.start_next(), Some(("a".to_string(), 1)));
+        scheduler.finish("a");
+
+        scheduler.enqueue("a".to_string(), 2);
+        scheduler.enqueue_with_priority("b".to_string(), 3, 3);
+        scheduler.enqueue_with_priority("a".to_string(), 3, 4);
+        scheduler.enqueue_with_priority("b".to_string(), 5, 5);
+        let mut order = Vec::new();
+        while let Some((_, job)) = scheduler.start_next() {
+            order.push(job);
+            scheduler.finish(if job % 2 == 0 { "a" } else { "b" });
+        }
+        // Within a priority the loaded model still goes first.
+        assert_eq!(order, [5, 3, 4, 2]);
+    }
+
+    #[test]
+    fn a_priority_job_at_its_model_limit_does_not_block() {
+        let mut scheduler = Scheduler::new(4, limits(&["big=1"]));
+        scheduler.enqueue("big".to_string(), 1);
+        assert_eq!(scheduler.start_next(), Some(("big".to_string(), 1)));
+        scheduler.enqueue_with_priority("big".to_string(), 9, 2);
+        scheduler.enqueue("small".to_string(), 3);
+        assert_eq!(scheduler.start_next(), Some(("small".to_string(), 3)));
+        assert_eq!(scheduler.start_next(), None);
+    }
+
+    #[test]
+    fn exact_limits_win_over_patterns() {
+        let mut scheduler = Scheduler::new(8, limits(&["phi3:*=1", "phi3:mini=2"]));
+        for job in 0..3 {
+            scheduler.enqueue("phi3:mini".to_string(), job);
+            scheduler.enqueue("phi3:medium".to_string(), job);
+        }
+        let started: Vec<_> = std::iter::from_fn(|| scheduler.start_next()).collect();
+        let load = scheduler.load_by_model();
+        assert_eq!(started.len(), 3);
+        assert_eq!(
+            load["phi3:mini"],
+            ModelLoad {
+                in_flight: 2,
+                queued: 1
+            }
+        );
+        assert_eq!(
+            load["phi3:medium"],
+            ModelLoad {
+                in_flight: 1,
+                queued: 2
+            }
+        );
+    }
+}
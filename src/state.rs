@@ -0,0 +1,80 @@
+//! A snapshot of what the node knows about the mesh, for working out why
+//! prompts aren't routing where expected.
+//!
+//! The swarm loop builds a [`NodeState`] when asked with a [`StateRequest`];
+//! the metrics server asks on `GET /state` and answers with it as JSON.
+
+use serde::Serialize;
+use tokio::sync::oneshot;
+
+use crate::{bans::Standing, labels::Labels, ollama, profile::Profile};
+
+/// A request for a [`NodeState`], answered on the channel.
+pub type StateRequest = oneshot::Sender<NodeState>;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NodeState {
+    pub peer_id: String,
+    pub profile: Profile,
+    pub labels: Labels,
+    pub announced_models: Vec<String>,
+    pub listen_addrs: Vec<String>,
+    pub external_addrs: Vec<String>,
+    /// Every peer the node is connected to, knows as a worker, pins or bans,
+    /// ordered by peer id.
+    pub peers: Vec<PeerState>,
+    pub relay: RelayState,
+    /// Requests to the inference backend in flight, against its limit.
+    pub backend: ollama::Usage,
+    /// How long requests waited in the queue before running, on average over
+    /// the last few minutes. `None` without requests in that time.
+    pub avg_queue_wait_ms: Option<u64>,
+    /// What prompts are refused with while the node is in maintenance mode.
+    pub maintenance: Option<String>,
+    /// Whether the node is a warm standby, refusing prompts until promoted.
+    pub standby: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerState {
+    pub peer_id: String,
+    pub connected: bool,
+    pub pinned: bool,
+    /// Prompt protocol versions the peer listed in identify, while connected.
+    pub protocol_versions: Vec<String>,
+    /// The software the peer announced through identify, e.g.
+    /// `mesh-ai-node/0.1.0`, while connected.
+    pub agent: Option<String>,
+    /// What the peer announced through identify, if it serves prompts.
+    pub capabilities: Option<Capabilities>,
+    pub standing: Standing,
+}
+
+/// A worker's announcement, as cached from identify.
+#[derive(Debug, Clone, Serialize)]
+pub struct Capabilities {
+    pub addrs: Vec<String>,
+    pub models: Vec<String>,
+    pub profile: Profile,
+    pub labels: Labels,
+    /// Models that take tools, as names or `prefix*` patterns.
+    pub tool_models: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RelayState {
+    /// The `--relay-address`, if one was given.
+    pub address: Option<String>,
+    /// Whether `--auto-relay` decides if a reservation is needed.
+    pub auto: bool,
+    /// Whether the node means to hold a reservation.
+    pub wanted: bool,
+    /// Open connections to the relay.
+    pub connections: usize,
+    /// Whether the node is listening through the relay.
+    pub reserved: bool,
+    /// Seconds until the relay is redialed, while it is unreachable.
+    pub redial_in_secs: Option<u64>,
+    /// What AutoNAT found, e.g. `Private`, or `None` when it isn't running.
+    pub nat_status: Option<String>,
+}
@@ -0,0 +1,188 @@
+//! Streamed prompts: the answer arrives piece by piece as the model writes it.
+//!
+//! Served on [`STREAM_PROTOCOL_NAME`], one prompt per stream. The client
+//! writes a [`PromptRequest`]; the node answers with numbered
+//! [`StreamFrame::Chunk`]s and ends with a [`StreamFrame::End`] carrying the
+//! status. Frames are JSON, each prefixed with its length as a big-endian
+//! `u32`.
+//!
+//! Flow control is the stream's own: a client that reads slowly makes the
+//! node's writes wait, which in turn pauses reading from the backend.
+
+use std::{io, sync::Arc, time::Duration};
+
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, StreamExt};
+use libp2p::{PeerId, Stream, StreamProtocol};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use tokio::{
+    sync::{Semaphore, mpsc},
+    time::timeout,
+};
+
+use crate::{
+    MAX_IMAGE_BYTES, PromptRequest, PromptResponse,
+    admission::{Admission, Permit},
+    node::STREAM_PROTOCOL_NAME,
+};
+
+/// Largest frame either side accepts: a megabyte, plus room for a request's
+/// images, which JSON carries as base64.
+pub const MAX_FRAME_BYTES: usize = 1024 * 1024 + MAX_IMAGE_BYTES.div_ceil(3) * 4;
+
+/// How long a new stream may take to send its request.
+const REQUEST_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long refusing a stream may take before it is simply dropped.
+const REFUSAL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Refusals written at once. Streams refused beyond this are dropped
+/// unanswered, so a flood can't pile up refusal tasks either.
+const MAX_REFUSALS: usize = 64;
+
+/// Requests read but not yet picked up by the swarm loop.
+const INCOMING_CAPACITY: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StreamFrame {
+    /// The next piece of the answer. `seq` counts up from 0.
+    Chunk { seq: u64, text: String },
+    /// The last frame. On success `response` is empty, since the chunks
+    /// already carried the text; otherwise it says why the request failed.
+    End(PromptResponse),
+}
+
+pub async fn write_frame<W, T>(io: &mut W, frame: &T) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let bytes = serde_json::to_vec(frame)?;
+    if bytes.len() > MAX_FRAME_BYTES {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "frame exceeds MAX_FRAME_BYTES",
+        ));
+    }
+    io.write_all(&(bytes.len() as u32).to_be_bytes()).await?;
+    io.write_all(&bytes).await?;
+    io.flush().await
+}
+
+/// Writes `frame` like [`write_frame`], but fails with
+/// [`io::ErrorKind::TimedOut`] if the peer doesn't take it within the time
+/// a new stream gets to send its request, so a peer that stops reading
+/// can't hold an answer open forever.
+pub async fn write_frame_in_time<W, T>(io: &mut W, frame: &T) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    timeout(REQUEST_READ_TIMEOUT, write_frame(io, frame))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "peer stopped reading"))?
+}
+
+/// Reads the next frame, or `None` if the stream ended cleanly before it.
+pub async fn read_frame<R, T>(io: &mut R) -> io::Result<Option<T>>
+where
+    R: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    let mut len = [0u8; 4];
+    match io.read_exact(&mut len).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_BYTES {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "frame exceeds MAX_FRAME_BYTES",
+        ));
+    }
+    let mut bytes = vec![0u8; len];
+    io.read_exact(&mut bytes).await?;
+    Ok(Some(serde_json::from_slice(&bytes)?))
+}
+
+/// Accepts streamed prompts and hands them over, request already read, on
+/// the returned channel, each with its [`Permit`]. Streams that don't send a
+/// valid request in time are dropped; those beyond what `admission` allows
+/// are answered `Busy` without being read, on a task of their own so a slow
+/// reader doesn't hold up the streams behind it.
+pub fn incoming_requests(
+    mut control: libp2p_stream::Control,
+    admission: Admission,
+) -> Result<IncomingRequests, libp2p_stream::AlreadyRegistered> {
+    let mut incoming = control.accept(StreamProtocol::new(STREAM_PROTOCOL_NAME))?;
+    let (tx, rx) = mpsc::channel(INCOMING_CAPACITY);
+    let refusals = Arc::new(Semaphore::new(MAX_REFUSALS));
+    tokio::spawn(async move {
+        while let Some((peer, mut stream)) = incoming.next().await {
+            let Some(permit) = admission.try_admit() else {
+                if let Ok(refusal) = refusals.clone().try_acquire_owned() {
+                    tokio::spawn(async move {
+                        let busy = StreamFrame::End(PromptResponse::busy());
+                        let _ = timeout(REFUSAL_TIMEOUT, write_frame(&mut stream, &busy)).await;
+                        drop(refusal);
+                    });
+                }
+                continue;
+            };
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                if let Ok(Ok(Some(request))) =
+                    timeout(REQUEST_READ_TIMEOUT, read_frame(&mut stream)).await
+                {
+                    let _ = tx.send((peer, stream, request, permit)).await;
+                }
+            });
+        }
+    });
+    Ok(rx)
+}
+
+/// Streamed prompts as [`incoming_requests`] hands them over.
+pub type IncomingRequests = mpsc::Receiver<(PeerId, Stream, PromptRequest, Permit)>;
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use super::*;
+
+    /// A peer that stopped reading: nothing written to it ever goes through.
+    struct Stalled;
+
+    impl AsyncWrite for Stalled {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            _: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Pending
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Pending
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Pending
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_to_a_peer_that_stopped_reading_time_out() {
+        let chunk = StreamFrame::Chunk {
+            seq: 0,
+            text: "hi".to_string(),
+        };
+        let err = write_frame_in_time(&mut Stalled, &chunk).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,362 @@
+//! Opt-in telemetry: coarse aggregates that nodes publish on the [`TOPIC`]
+//! gossipsub topic, so the mesh's overall capacity can be seen.
+//!
+//! A [`Report`] carries what can be said about a node without saying anything
+//! about who uses it: the requests it served in the last hour, the models it
+//! offers, its version and, if the operator gives one, its country. It has no
+//! field that could hold prompt content or client peer ids, and its location
+//! is a [`Region`], which holds a country code and nothing finer. Reports are
+//! published without a signature or source, and tell nodes apart by a
+//! [`Report::reporter`] number drawn at random each run.
+//!
+//! A node watching the topic keeps each reporter's latest report in a
+//! [`Summary`] and prints network-wide totals from it. Anyone can make up
+//! reporter numbers, so once a summary is full it ignores new reporters
+//! rather than dropping those it counts; made-up ones age out with the TTL.
+//!
+//! Reports are JSON and carry their [`SCHEMA_VERSION`]. A new schema may only
+//! add fields, which older reports are read without, so nodes on either side
+//! of a bump can share the topic; anything else needs a new topic.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    backoff::random_u64,
+    bounded::{BoundedConfig, BoundedMap},
+};
+
+/// The gossipsub topic reports are published on.
+pub const TOPIC: &str = "mesh-ai/telemetry";
+/// Version of the [`Report`] layout this build publishes.
+pub const SCHEMA_VERSION: u32 = 1;
+/// Largest report accepted from the topic, in bytes.
+pub const MAX_REPORT_BYTES: usize = 16 * 1024;
+/// Most models a report lists; the rest are left out.
+const MAX_MODELS: usize = 64;
+/// Most reporters a [`Summary`] keeps track of at once.
+const MAX_REPORTERS: usize = 10_000;
+/// The window [`Served`] counts requests over.
+const SERVED_WINDOW: Duration = Duration::from_secs(60 * 60);
+/// Granularity of [`Served`]'s window.
+const SERVED_BUCKET: Duration = Duration::from_secs(60);
+
+/// Whether a node takes part in telemetry.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TelemetryMode {
+    /// Neither publish nor listen.
+    #[default]
+    Off,
+    /// Publish this node's reports.
+    On,
+    /// Publish nothing; collect other nodes' reports and print summaries.
+    Watch,
+}
+
+/// A country, as an ISO 3166-1 alpha-2 code such as `DE`. Nothing finer
+/// fits in it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Region([u8; 2]);
+
+impl FromStr for Region {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.as_bytes() {
+            [a, b] if a.is_ascii_alphabetic() && b.is_ascii_alphabetic() => {
+                Ok(Self([a.to_ascii_uppercase(), b.to_ascii_uppercase()]))
+            }
+            _ => Err(format!(
+                "invalid region {s:?}: expected a two-letter country code such as DE"
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for Region {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Region> for String {
+    fn from(region: Region) -> Self {
+        region.to_string()
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.0[0] as char, self.0[1] as char)
+    }
+}
+
+/// One node's telemetry for one interval.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Report {
+    /// The [`SCHEMA_VERSION`] the sender was built with.
+    pub schema: u32,
+    /// Random per run, unrelated to the node's peer id, so a [`Summary`]
+    /// counts each node once.
+    pub reporter: u64,
+    /// Counts the reporter's reports, so the newest wins.
+    pub seq: u64,
+    /// Version of mesh-ai-node the reporter runs.
+    #[serde(default)]
+    pub version: String,
+    #[serde(default)]
+    pub models: Vec<String>,
+    #[serde(default)]
+    pub requests_last_hour: u64,
+    #[serde(default)]
+    pub region: Option<Region>,
+}
+
+impl Report {
+    fn new(
+        reporter: &Reporter,
+        requests_last_hour: u64,
+        models: &[String],
+        region: Option<Region>,
+    ) -> Self {
+        Self {
+            schema: SCHEMA_VERSION,
+            reporter: reporter.id,
+            seq: reporter.seq,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            models: models.iter().take(MAX_MODELS).cloned().collect(),
+            requests_last_hour,
+            region,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("reports serialize")
+    }
+
+    /// Reads a report of any schema. Fields this build doesn't know are
+    /// ignored, and those an older sender didn't have take their defaults.
+    pub fn decode(bytes: &[u8]) -> Result<Self, serde_json::Error> {
+        serde_json::from_slice(bytes)
+    }
+}
+
+/// This run's identity in telemetry, and how many reports it has made.
+#[derive(Debug)]
+pub struct Reporter {
+    id: u64,
+    seq: u64,
+}
+
+impl Reporter {
+    pub fn new() -> Self {
+        Self {
+            id: random_u64(),
+            seq: 0,
+        }
+    }
+
+    /// The next report, counting it.
+    pub fn report(&mut self, served: u64, models: &[String], region: Option<Region>) -> Report {
+        self.seq += 1;
+        Report::new(self, served, models, region)
+    }
+}
+
+impl Default for Reporter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Counts requests served over the last hour, by the minute.
+#[derive(Debug, Default)]
+pub struct Served {
+    /// Start of each minute with requests, and how many, oldest first.
+    buckets: VecDeque<(Instant, u64)>,
+}
+
+impl Served {
+    pub fn record(&mut self, now: Instant) {
+        match self.buckets.back_mut() {
+            Some((start, count)) if now.saturating_duration_since(*start) < SERVED_BUCKET => {
+                *count += 1;
+            }
+            _ => self.buckets.push_back((now, 1)),
+        }
+        self.prune(now);
+    }
+
+    pub fn last_hour(&mut self, now: Instant) -> u64 {
+        self.prune(now);
+        self.buckets.iter().map(|(_, count)| count).sum()
+    }
+
+    fn prune(&mut self, now: Instant) {
+        while let Some((start, _)) = self.buckets.front()
+            && now.saturating_duration_since(*start) >= SERVED_WINDOW
+        {
+            self.buckets.pop_front();
+        }
+    }
+}
+
+/// The latest report of each node heard from, for network-wide totals.
+#[derive(Debug)]
+pub struct Summary {
+    reports: BoundedMap<u64, Report>,
+    capacity: usize,
+    /// Whether new reporters are being ignored for lack of room.
+    full: bool,
+}
+
+/// Network-wide totals over the reports in a [`Summary`].
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Totals {
+    pub nodes: usize,
+    pub requests_last_hour: u64,
+    /// Nodes offering each model.
+    pub models: BTreeMap<String, usize>,
+    pub versions: BTreeMap<String, usize>,
+    /// Nodes per region, with `None` for those that didn't give one.
+    pub regions: BTreeMap<Option<Region>, usize>,
+    pub schemas: BTreeMap<u32, usize>,
+}
+
+impl Summary {
+    /// A summary that forgets nodes not heard from for `ttl`.
+    pub fn new(ttl: Duration) -> Self {
+        Self::with_capacity(ttl, MAX_REPORTERS)
+    }
+
+    fn with_capacity(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            reports: BoundedMap::new(BoundedConfig {
+                capacity,
+                ttl: Some(ttl),
+            }),
+            capacity,
+            full: false,
+        }
+    }
+
+    /// Keeps `report` unless one at least as new from the same reporter is
+    /// already kept, or it's from a new reporter and there's no room.
+    pub fn record(&mut self, report: Report, now: Instant) {
+        match self.reports.get(&report.reporter) {
+            Some(kept) if kept.seq >= report.seq => return,
+            Some(_) => {}
+            None => {
+                if self.reports.len() >= self.capacity {
+                    self.reports.prune(now);
+                }
+                if self.reports.len() >= self.capacity {
+                    if !self.full {
+                        self.full = true;
+                        tracing::warn!(
+                            "Telemetry summary full at {} nodes, ignoring new ones",
+                            self.capacity
+                        );
+                    }
+                    return;
+                }
+                self.full = false;
+            }
+        }
+        self.reports.insert(report.reporter, report, now);
+    }
+
+    /// Drops nodes not heard from recently. Returns how many.
+    pub fn prune(&mut self, now: Instant) -> usize {
+        self.reports.prune(now)
+    }
+
+    /// Totals over the nodes heard from within the TTL.
+    pub fn totals(&self, now: Instant) -> Totals {
+        let mut totals = Totals::default();
+        for (_, report) in self.reports.fresh(now) {
+            totals.nodes += 1;
+            totals.requests_last_hour = totals
+                .requests_last_hour
+                .saturating_add(report.requests_last_hour);
+            for model in &report.models {
+                *totals.models.entry(model.clone()).or_default() += 1;
+            }
+            *totals.versions.entry(report.version.clone()).or_default() += 1;
+            *totals.regions.entry(report.region).or_default() += 1;
+            *totals.schemas.entry(report.schema).or_default() += 1;
+        }
+        totals
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(reporter: u64, seq: u64, requests_last_hour: u64) -> Report {
+        Report {
+            schema: SCHEMA_VERSION,
+            reporter,
+            seq,
+            version: "1.0.0".to_string(),
+            models: vec!["m".to_string()],
+            requests_last_hour,
+            region: None,
+        }
+    }
+
+    #[test]
+    fn a_full_summary_keeps_the_nodes_it_counts() {
+        let ttl = Duration::from_secs(60);
+        let mut summary = Summary::with_capacity(ttl, 2);
+        let now = Instant::now();
+        summary.record(report(1, 1, 10), now);
+        summary.record(report(2, 1, 20), now);
+
+        // Made-up reporters don't push the real ones out...
+        for forged in 100..200 {
+            summary.record(report(forged, 1, 0), now);
+        }
+        // ...and those counted still update.
+        summary.record(report(1, 2, 15), now);
+        let totals = summary.totals(now);
+        assert_eq!(totals.nodes, 2);
+        assert_eq!(totals.requests_last_hour, 35);
+
+        // Room comes back as reporters age out.
+        summary.record(report(2, 2, 20), now + ttl / 2);
+        let later = now + ttl;
+        summary.record(report(3, 1, 5), later);
+        let totals = summary.totals(later);
+        assert_eq!(totals.nodes, 2);
+        assert_eq!(totals.requests_last_hour, 25);
+    }
+
+    #[test]
+    fn totals_saturate_rather_than_overflow() {
+        let mut summary = Summary::new(Duration::from_secs(60));
+        let now = Instant::now();
+        summary.record(report(1, 1, u64::MAX), now);
+        summary.record(report(2, 1, 1), now);
+        assert_eq!(summary.totals(now).requests_last_hour, u64::MAX);
+    }
+
+    #[test]
+    fn older_reports_dont_replace_newer_ones() {
+        let mut summary = Summary::new(Duration::from_secs(60));
+        let now = Instant::now();
+        summary.record(report(1, 2, 7), now);
+        summary.record(report(1, 1, 99), now);
+        assert_eq!(summary.totals(now).requests_last_hour, 7);
+    }
+}
@@ -0,0 +1,491 @@
+//! Chat templates the node puts prompts in, for clients that send a bare
+//! prompt to an instruct model.
+//!
+//! A request opts in with [`PromptRequest::apply_template`]. The node then
+//! looks up the template for its model, assigned with
+//! `--model-template MODEL=NAME` (`MODEL` may end in `*` to match a prefix,
+//! and the first match wins). `NAME` is a template file `NAME.tmpl` in the
+//! templates directory, or [`OLLAMA_TEMPLATE`] for the template the model
+//! came with. Models without an assignment get the model's own.
+//!
+//! A template file is text with `{system}` and `{prompt}` placeholders, e.g.
+//! for ChatML:
+//!
+//! ```text
+//! <|im_start|>system
+//! {system}<|im_end|>
+//! <|im_start|>user
+//! {prompt}<|im_end|>
+//! <|im_start|>assistant
+//! ```
+//!
+//! `{{` and `}}` stand for literal braces; any other brace is an error, so a
+//! typo is caught when the file is loaded rather than sent to the model. The
+//! file is used as is, final newline included. Placeholders are filled in
+//! one pass, so braces in the prompt itself are never taken for
+//! placeholders. The rendered prompt goes to Ollama raw, past the model's
+//! own template.
+//!
+//! The model's own template is left to Ollama to apply, with the system
+//! prompt passed alongside, once `/api/show` confirms the model has one.
+//! That lookup is the one [`ContextPolicy`] keeps for the context window.
+//!
+//! A template that can't be applied, e.g. one without `{system}` for a
+//! request with a system prompt, is answered with
+//! [`ResponseStatus::TemplateError`]. The applied template's name comes back
+//! in [`PromptResponse::template`]. Template files are re-read on SIGHUP.
+//!
+//! [`PromptRequest::apply_template`]: crate::PromptRequest::apply_template
+//! [`ResponseStatus::TemplateError`]: crate::ResponseStatus::TemplateError
+//! [`PromptResponse::template`]: crate::PromptResponse::template
+
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use async_trait::async_trait;
+
+use crate::{
+    PromptResponse,
+    context::ContextPolicy,
+    middleware::{Middleware, Next, Outcome, Request},
+};
+
+/// Names the template a model came with, as kept by Ollama.
+pub const OLLAMA_TEMPLATE: &str = "ollama";
+
+/// Extension of template files.
+const EXTENSION: &str = "tmpl";
+
+/// The template for models whose name is `pattern`, or starts with it if it
+/// ends in `*` (e.g. `llama3*`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModelTemplate {
+    pub pattern: String,
+    pub name: String,
+}
+
+impl ModelTemplate {
+    fn matches(&self, model: &str) -> bool {
+        match self.pattern.strip_suffix('*') {
+            Some(prefix) => model.starts_with(prefix),
+            None => model == self.pattern,
+        }
+    }
+}
+
+impl std::str::FromStr for ModelTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, name) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected MODEL=TEMPLATE, got `{s}`"))?;
+        if pattern.is_empty() || name.is_empty() {
+            return Err(format!("expected MODEL=TEMPLATE, got `{s}`"));
+        }
+        Ok(Self {
+            pattern: pattern.to_string(),
+            name: name.to_string(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    System,
+    Prompt,
+}
+
+/// A parsed template file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = source.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err("unclosed `{`; write `{{` for a brace".to_string()),
+                        }
+                    }
+                    let part = match name.as_str() {
+                        "system" => Part::System,
+                        "prompt" => Part::Prompt,
+                        other => {
+                            return Err(format!(
+                                "unknown placeholder `{{{other}}}`; there are {{system}} and \
+                                 {{prompt}}, and `{{{{` for a brace"
+                            ));
+                        }
+                    };
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(part);
+                }
+                '}' => return Err("unmatched `}`; write `}}` for a brace".to_string()),
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        if !parts.contains(&Part::Prompt) {
+            return Err("no {prompt} placeholder".to_string());
+        }
+        Ok(Self { parts })
+    }
+
+    /// The template filled in with `system` and `prompt`. A template
+    /// without `{system}` can't take a system prompt; one with it and no
+    /// system prompt gets it empty.
+    pub fn render(&self, system: Option<&str>, prompt: &str) -> Result<String, String> {
+        if system.is_some() && !self.parts.contains(&Part::System) {
+            return Err("the template has no {system} placeholder for the system prompt".into());
+        }
+        let mut rendered = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => rendered.push_str(text),
+                Part::System => rendered.push_str(system.unwrap_or_default()),
+                Part::Prompt => rendered.push_str(prompt),
+            }
+        }
+        Ok(rendered)
+    }
+}
+
+/// Why a request's prompt couldn't be put in a template.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TemplateError {
+    pub model: String,
+    pub template: String,
+    pub reason: String,
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Can't apply template {} for {}: {}",
+            self.template, self.model, self.reason
+        )
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+/// The node's templates and which models they are for.
+pub struct Templates {
+    dir: Option<PathBuf>,
+    assignments: Vec<ModelTemplate>,
+    loaded: RwLock<HashMap<String, Arc<Template>>>,
+    /// Where whether a model has a template of its own is looked up.
+    models: Arc<ContextPolicy>,
+}
+
+impl Templates {
+    /// Loads the `*.tmpl` files in `dir`, if given, and checks that every
+    /// assignment names one of them or [`OLLAMA_TEMPLATE`]. Models are
+    /// looked up through `models`.
+    pub fn load(
+        dir: Option<&Path>,
+        assignments: Vec<ModelTemplate>,
+        models: Arc<ContextPolicy>,
+    ) -> Result<Self, String> {
+        let templates = Self {
+            dir: dir.map(Path::to_path_buf),
+            assignments,
+            loaded: RwLock::new(HashMap::new()),
+            models,
+        };
+        templates.reload()?;
+        Ok(templates)
+    }
+
+    /// Re-reads the templates directory, and forgets what models were
+    /// looked up, in case they were pulled again. On an error the templates
+    /// in use are kept. Returns how many templates were loaded.
+    pub fn reload(&self) -> Result<usize, String> {
+        let loaded = match &self.dir {
+            Some(dir) => read_dir(dir)?,
+            None => HashMap::new(),
+        };
+        if let Some(missing) = self
+            .assignments
+            .iter()
+            .find(|a| a.name != OLLAMA_TEMPLATE && !loaded.contains_key(&a.name))
+        {
+            return Err(format!(
+                "{} is assigned template {}, but there is no {}.{EXTENSION}",
+                missing.pattern, missing.name, missing.name
+            ));
+        }
+        let count = loaded.len();
+        *self.loaded.write().unwrap() = loaded;
+        self.models.forget();
+        Ok(count)
+    }
+
+    /// The name of the template for `model`.
+    pub fn name_for(&self, model: &str) -> &str {
+        self.assignments
+            .iter()
+            .find(|a| a.matches(model))
+            .map_or(OLLAMA_TEMPLATE, |a| a.name.as_str())
+    }
+
+    /// Puts `request`'s prompt in its model's template, or leaves it for
+    /// Ollama to, and returns the template's name.
+    pub async fn apply(&self, request: &mut Request) -> Result<String, TemplateError> {
+        let model = request.model.as_str();
+        let name = self.name_for(model).to_string();
+        let error = |reason: String| TemplateError {
+            model: model.to_string(),
+            template: name.clone(),
+            reason,
+        };
+        if name == OLLAMA_TEMPLATE {
+            return match self.models.info(model).await {
+                Some(info) if info.has_template => Ok(name),
+                Some(_) => Err(error(
+                    "the model has no chat template of its own; assign one with --model-template"
+                        .to_string(),
+                )),
+                None => Err(error(
+                    "couldn't look up the model's template; see the node's log".to_string(),
+                )),
+            };
+        }
+        // Checked on load, but the file may have gone in a reload since.
+        let template = self.loaded.read().unwrap().get(&name).cloned();
+        let template = template.ok_or_else(|| error("the template is gone".to_string()))?;
+        let prompt = &mut request.prompt;
+        prompt.prompt = template
+            .render(prompt.system.as_deref(), &prompt.prompt)
+            .map_err(error)?;
+        prompt.system = None;
+        request.raw = true;
+        Ok(name)
+    }
+}
+
+fn read_dir(dir: &Path) -> Result<HashMap<String, Arc<Template>>, String> {
+    let entries = fs::read_dir(dir).map_err(|e| format!("can't read {}: {e}", dir.display()))?;
+    let mut templates = HashMap::new();
+    for entry in entries {
+        let path = entry
+            .map_err(|e| format!("can't read {}: {e}", dir.display()))?
+            .path();
+        if path.extension().is_none_or(|ext| ext != EXTENSION) {
+            continue;
+        }
+        let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+        let source =
+            fs::read_to_string(&path).map_err(|e| format!("can't read {}: {e}", path.display()))?;
+        let template = Template::parse(&source)
+            .map_err(|e| format!("bad template {}: {e}", path.display()))?;
+        templates.insert(name.to_string(), Arc::new(template));
+    }
+    Ok(templates)
+}
+
+/// Applies chat templates to the prompts that ask for them. Goes last in
+/// the chain, so the other middlewares see the prompt as sent.
+pub struct ApplyTemplates(pub Arc<Templates>);
+
+#[async_trait]
+impl Middleware for ApplyTemplates {
+    async fn handle(&self, mut request: Request, next: Next<'_>) -> Outcome {
+        if !request.prompt.apply_template {
+            return next.run(request).await;
+        }
+        let template = match self.0.apply(&mut request).await {
+            Ok(template) => template,
+            Err(e) => {
+                tracing::warn!("{e}");
+                return Outcome::answered(PromptResponse::template_error(e.to_string()));
+            }
+        };
+        let mut outcome = next.run(request).await;
+        outcome.response.template = Some(template);
+        outcome
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{context::TruncateStrategy, testing::TempDir};
+
+    const CHATML: &str = "<|im_start|>system\n{system}<|im_end|>\n<|im_start|>user\n\
+                          {prompt}<|im_end|>\n<|im_start|>assistant\n";
+    const LLAMA3: &str = "<|begin_of_text|><|start_header_id|>system<|end_header_id|>\n\n\
+                          {system}<|eot_id|><|start_header_id|>user<|end_header_id|>\n\n\
+                          {prompt}<|eot_id|><|start_header_id|>assistant<|end_header_id|>\n\n";
+    const MISTRAL: &str = "[INST] {prompt} [/INST]";
+
+    /// A templates directory holding `files`, removed when dropped.
+    fn templates_dir(name: &str, files: &[(&str, &str)]) -> TempDir {
+        let dir = TempDir::new(&format!("templates-{name}"));
+        for (file, source) in files {
+            fs::write(dir.join(file), source).unwrap();
+        }
+        dir
+    }
+
+    fn models() -> Arc<ContextPolicy> {
+        Arc::new(ContextPolicy::new(TruncateStrategy::Off, 2048))
+    }
+
+    fn request(model: &str, prompt: &str, system: Option<&str>) -> Request {
+        Request {
+            peer: libp2p::PeerId::random(),
+            model: model.to_string(),
+            prompt: serde_json::from_value(serde_json::json!({
+                "prompt": prompt,
+                "system": system,
+                "apply_template": true,
+            }))
+            .unwrap(),
+            raw: false,
+        }
+    }
+
+    #[test]
+    fn renders_known_templates() {
+        let chatml = Template::parse(CHATML).unwrap();
+        assert_eq!(
+            chatml.render(Some("Be brief."), "Hi").unwrap(),
+            "<|im_start|>system\nBe brief.<|im_end|>\n<|im_start|>user\n\
+             Hi<|im_end|>\n<|im_start|>assistant\n"
+        );
+        // No system prompt leaves its place empty.
+        assert_eq!(
+            chatml.render(None, "Hi").unwrap(),
+            "<|im_start|>system\n<|im_end|>\n<|im_start|>user\n\
+             Hi<|im_end|>\n<|im_start|>assistant\n"
+        );
+
+        let llama3 = Template::parse(LLAMA3).unwrap();
+        assert_eq!(
+            llama3.render(Some("Be brief."), "Hi").unwrap(),
+            "<|begin_of_text|><|start_header_id|>system<|end_header_id|>\n\n\
+             Be brief.<|eot_id|><|start_header_id|>user<|end_header_id|>\n\n\
+             Hi<|eot_id|><|start_header_id|>assistant<|end_header_id|>\n\n"
+        );
+
+        let mistral = Template::parse(MISTRAL).unwrap();
+        assert_eq!(mistral.render(None, "Hi").unwrap(), "[INST] Hi [/INST]");
+        let err = mistral.render(Some("Be brief."), "Hi").unwrap_err();
+        assert!(err.contains("no {system}"), "{err}");
+    }
+
+    #[test]
+    fn braces_in_the_prompt_are_left_alone() {
+        let template = Template::parse(CHATML).unwrap();
+        let prompt = "Fill in {system} and {prompt}, then print {{}} and }{";
+        let rendered = template.render(Some("{prompt}"), prompt).unwrap();
+        assert_eq!(
+            rendered,
+            format!(
+                "<|im_start|>system\n{{prompt}}<|im_end|>\n<|im_start|>user\n\
+                 {prompt}<|im_end|>\n<|im_start|>assistant\n"
+            )
+        );
+    }
+
+    #[test]
+    fn doubled_braces_are_literal_and_stray_ones_refused() {
+        let template = Template::parse("{{\"q\": \"{prompt}\"}}").unwrap();
+        assert_eq!(template.render(None, "hi").unwrap(), "{\"q\": \"hi\"}");
+
+        for (source, expected) in [
+            ("{prompt} {", "unclosed `{`"),
+            ("{prompt} }", "unmatched `}`"),
+            ("{prompt} {user}", "unknown placeholder `{user}`"),
+            ("{system} only", "no {prompt}"),
+        ] {
+            let err = Template::parse(source).unwrap_err();
+            assert!(err.contains(expected), "{source}: {err}");
+        }
+    }
+
+    #[tokio::test]
+    async fn applies_the_assigned_template() {
+        let dir = templates_dir(
+            "apply",
+            &[("chatml.tmpl", CHATML), ("mistral.tmpl", MISTRAL)],
+        );
+        let assignments = vec![
+            "qwen*=chatml".parse().unwrap(),
+            "mistral=mistral".parse().unwrap(),
+        ];
+        let templates = Templates::load(Some(dir.path()), assignments, models()).unwrap();
+        assert_eq!(templates.name_for("qwen2.5:7b"), "chatml");
+        assert_eq!(templates.name_for("mistral"), "mistral");
+        assert_eq!(templates.name_for("mistral:7b"), OLLAMA_TEMPLATE);
+
+        let mut qwen = request("qwen2.5:7b", "{prompt}?", Some("Be brief."));
+        let name = templates.apply(&mut qwen).await.unwrap();
+        assert_eq!(name, "chatml");
+        assert!(qwen.raw);
+        let prompt = &qwen.prompt;
+        assert_eq!(prompt.system, None);
+        assert!(
+            prompt
+                .prompt
+                .contains("Be brief.<|im_end|>\n<|im_start|>user\n{prompt}?<|im_end|>"),
+            "{}",
+            prompt.prompt
+        );
+
+        let mut mistral = request("mistral", "Hi", Some("Be brief."));
+        let err = templates.apply(&mut mistral).await.unwrap_err();
+        assert_eq!(err.template, "mistral");
+        assert!(err.reason.contains("no {system}"), "{err}");
+        assert!(!mistral.raw);
+        assert_eq!(mistral.prompt.prompt, "Hi");
+    }
+
+    #[test]
+    fn refuses_assignments_without_a_template_file() {
+        let dir = templates_dir("missing", &[("chatml.tmpl", CHATML)]);
+        let assignments = vec!["llama3*=llama3".parse().unwrap()];
+        let err = Templates::load(Some(dir.path()), assignments, models())
+            .err()
+            .unwrap();
+        assert!(err.contains("no llama3.tmpl"), "{err}");
+
+        let dir = templates_dir("bad", &[("broken.tmpl", "{prompt} {")]);
+        let err = Templates::load(Some(dir.path()), Vec::new(), models())
+            .err()
+            .unwrap();
+        assert!(err.contains("broken.tmpl"), "{err}");
+    }
+}
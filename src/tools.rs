@@ -0,0 +1,243 @@
+//! Tool calling, for agent frameworks that hand the model functions to call.
+//!
+//! A prompt carries its tools in [`PromptRequest::tools`]. The node passes
+//! them to Ollama's chat API, and the model's calls come back as structured
+//! [`PromptResponse::tool_calls`] rather than in the text. Only models the
+//! operator marks with `--tool-model` take tools, since Ollama drops them
+//! quietly for models whose template has no place for them. Those models are
+//! announced through identify, and a prompt with tools for any other model is
+//! answered with [`ResponseStatus::UnsupportedFeature`] rather than run
+//! without them.
+//!
+//! Ollama has no way to force a call, so [`ToolChoice::Required`] and
+//! [`ToolChoice::Tool`] are refused the same way; [`ToolChoice::None`] runs
+//! the prompt as if it had no tools. Tools aren't streamed. A tool whose
+//! parameters aren't a JSON schema object is answered with
+//! [`ResponseStatus::InvalidOption`], since no node would take it.
+//!
+//! [`PromptRequest::tools`]: crate::PromptRequest::tools
+//! [`PromptResponse::tool_calls`]: crate::PromptResponse::tool_calls
+//! [`ResponseStatus::UnsupportedFeature`]: crate::ResponseStatus::UnsupportedFeature
+//! [`ResponseStatus::InvalidOption`]: crate::ResponseStatus::InvalidOption
+
+use std::{fs, io, path::Path};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    PromptResponse,
+    middleware::{Middleware, Next, Outcome, Request},
+};
+
+/// A function the model may call.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ToolDefinition {
+    pub name: String,
+    /// What the function does, for the model to decide when to call it.
+    #[serde(default)]
+    pub description: String,
+    /// The function's arguments as a JSON schema, in JSON text. `None` for a
+    /// function without arguments.
+    #[serde(default)]
+    pub parameters: Option<String>,
+}
+
+/// Whether the model may call the request's tools.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ToolChoice {
+    /// The model decides.
+    #[default]
+    Auto,
+    /// The model answers in text; the tools are left out.
+    None,
+    /// The model must call one of the tools.
+    Required,
+    /// The model must call the named tool.
+    Tool(String),
+}
+
+/// A call the model made to one of the request's tools.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub name: String,
+    /// The arguments as a JSON object, in JSON text.
+    pub arguments: String,
+}
+
+/// Reads tool definitions from a JSON file holding an array of
+/// `{"name": ..., "description": ..., "parameters": {...}}` objects, with
+/// `parameters` a JSON schema, as agent frameworks write them.
+pub fn load_tools(path: &Path) -> io::Result<Vec<ToolDefinition>> {
+    #[derive(Deserialize)]
+    struct Tool {
+        name: String,
+        #[serde(default)]
+        description: String,
+        #[serde(default)]
+        parameters: Option<serde_json::Value>,
+    }
+    let tools: Vec<Tool> = serde_json::from_slice(&fs::read(path)?)?;
+    Ok(tools
+        .into_iter()
+        .map(|tool| ToolDefinition {
+            name: tool.name,
+            description: tool.description,
+            parameters: tool.parameters.map(|parameters| parameters.to_string()),
+        })
+        .collect())
+}
+
+/// Whether `model` takes tools, given the `--tool-model` patterns: model
+/// names, or prefixes ending in `*`.
+pub fn supports_tools(patterns: &[String], model: &str) -> bool {
+    patterns
+        .iter()
+        .any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => model.starts_with(prefix),
+            None => model == pattern,
+        })
+}
+
+/// Answers prompts with tools the node can't pass on, and drops the tools
+/// from prompts that don't want them called.
+pub struct ToolSupport {
+    /// The `--tool-model` patterns.
+    pub models: Vec<String>,
+}
+
+impl ToolSupport {
+    /// Why `request`'s tools can't be used, if they can't.
+    fn refusal(&self, request: &Request) -> Option<PromptResponse> {
+        let prompt = &request.prompt;
+        let tools = prompt.tools.as_deref().unwrap_or_default();
+        if let Some(tool) = tools.iter().find(|tool| {
+            tool.parameters.as_deref().is_some_and(|parameters| {
+                !serde_json::from_str::<serde_json::Value>(parameters).is_ok_and(|v| v.is_object())
+            })
+        }) {
+            return Some(PromptResponse::invalid_option(format!(
+                "The parameters of tool {} aren't a JSON schema object",
+                tool.name
+            )));
+        }
+        let reason = if !supports_tools(&self.models, &request.model) {
+            format!("{} doesn't take tools on this node", request.model)
+        } else if matches!(
+            prompt.tool_choice,
+            Some(ToolChoice::Required | ToolChoice::Tool(_))
+        ) {
+            "The backend can't force a tool call; use tool choice Auto".to_string()
+        } else if prompt.apply_template {
+            "Prompts with tools always get the model's own chat template; leave apply_template off"
+                .to_string()
+        } else {
+            return None;
+        };
+        Some(PromptResponse::unsupported_feature(reason))
+    }
+}
+
+#[async_trait]
+impl Middleware for ToolSupport {
+    async fn handle(&self, mut request: Request, next: Next<'_>) -> Outcome {
+        if request.prompt.tools.as_ref().is_none_or(Vec::is_empty) {
+            return next.run(request).await;
+        }
+        if request.prompt.tool_choice == Some(ToolChoice::None) {
+            request.prompt.tools = None;
+            return next.run(request).await;
+        }
+        match self.refusal(&request) {
+            Some(refusal) => {
+                tracing::info!(model = %request.model, "Refusing tools: {}", refusal.response);
+                Outcome::answered(refusal)
+            }
+            None => next.run(request).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::PeerId;
+
+    use super::*;
+    use crate::{ResponseStatus, testing::TempDir};
+
+    fn request(model: &str, prompt: serde_json::Value) -> Request {
+        Request {
+            peer: PeerId::random(),
+            model: model.to_string(),
+            prompt: serde_json::from_value(prompt).unwrap(),
+            raw: false,
+        }
+    }
+
+    fn weather(parameters: serde_json::Value) -> serde_json::Value {
+        serde_json::json!({
+            "prompt": "Weather in Paris?",
+            "tools": [{ "name": "weather", "parameters": parameters }],
+        })
+    }
+
+    #[test]
+    fn refuses_tools_with_a_status_of_their_own() {
+        let support = ToolSupport {
+            models: vec!["llama3*".to_string()],
+        };
+        let schema = serde_json::json!(r#"{"type": "object"}"#);
+        assert_eq!(
+            support.refusal(&request("llama3.1", weather(schema.clone()))),
+            None
+        );
+
+        // Not an error of the backend's, so never swapped for the fallback.
+        let refusal = support
+            .refusal(&request("llama3.1", weather(serde_json::json!("[1, 2]"))))
+            .unwrap();
+        assert_eq!(refusal.status, ResponseStatus::InvalidOption);
+        assert!(
+            refusal.response.contains("tool weather"),
+            "{}",
+            refusal.response
+        );
+        let refusal = support
+            .refusal(&request(
+                "llama3.1",
+                weather(serde_json::json!("{not json")),
+            ))
+            .unwrap();
+        assert_eq!(refusal.status, ResponseStatus::InvalidOption);
+
+        let refusal = support
+            .refusal(&request("mistral", weather(schema.clone())))
+            .unwrap();
+        assert_eq!(refusal.status, ResponseStatus::UnsupportedFeature);
+        let mut forced = weather(schema);
+        forced["tool_choice"] = serde_json::json!("Required");
+        let refusal = support.refusal(&request("llama3.1", forced)).unwrap();
+        assert_eq!(refusal.status, ResponseStatus::UnsupportedFeature);
+    }
+
+    #[test]
+    fn loads_tools_as_agent_frameworks_write_them() {
+        let dir = TempDir::new("tools");
+        let path = dir.join("tools.json");
+        let tools = serde_json::json!([
+            {
+                "name": "weather",
+                "description": "Current weather",
+                "parameters": { "type": "object", "properties": { "city": { "type": "string" } } },
+            },
+            { "name": "now" },
+        ]);
+        fs::write(&path, tools.to_string()).unwrap();
+        let loaded = load_tools(&path).unwrap();
+        assert_eq!(loaded.len(), 2);
+        let parameters: serde_json::Value =
+            serde_json::from_str(loaded[0].parameters.as_deref().unwrap()).unwrap();
+        assert_eq!(parameters, tools[0]["parameters"]);
+        assert_eq!(loaded[1].parameters, None);
+    }
+}
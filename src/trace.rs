@@ -0,0 +1,193 @@
+//! Trace context carried with prompts, so that what a client logs about a
+//! request and what the worker logs about running it can be put together.
+//!
+//! A client that traces its requests sends a [`TraceContext`] in the W3C
+//! `traceparent` format, e.g.
+//! `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`. The worker
+//! logs the request with its `trace_id`, the client's span as
+//! `parent_span_id`, and a `span_id` of its own, so both sides' logs can be
+//! searched by the trace id, and a collector that reads them can link the
+//! worker's span under the client's. A context that doesn't parse is ignored,
+//! as tracing is only ever observed.
+
+use std::{fmt, str::FromStr};
+
+use crate::backoff::random_u64;
+
+/// A W3C trace context: which trace a request belongs to and the span that
+/// sent it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: u128,
+    pub span_id: u64,
+    /// Whether the sender records this trace, so receivers should too.
+    pub sampled: bool,
+}
+
+impl TraceContext {
+    /// A context starting a new trace, sampled.
+    pub fn new_root() -> Self {
+        Self {
+            trace_id: (u128::from(random_id()) << 64) | u128::from(random_id()),
+            span_id: random_id(),
+            sampled: true,
+        }
+    }
+
+    /// A context for a new span in the same trace, for work done on behalf
+    /// of this one.
+    pub fn child(&self) -> Self {
+        Self {
+            span_id: random_id(),
+            ..*self
+        }
+    }
+
+    pub fn trace_id_hex(&self) -> String {
+        format!("{:032x}", self.trace_id)
+    }
+
+    pub fn span_id_hex(&self) -> String {
+        format!("{:016x}", self.span_id)
+    }
+}
+
+impl fmt::Display for TraceContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "00-{:032x}-{:016x}-{:02x}",
+            self.trace_id,
+            self.span_id,
+            u8::from(self.sampled)
+        )
+    }
+}
+
+impl FromStr for TraceContext {
+    type Err = String;
+
+    /// Parses a `traceparent`. Fields that later versions of the format may
+    /// append are ignored, as the format asks.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expected = || format!("`{s}` is not a traceparent, e.g. 00-<32 hex>-<16 hex>-01");
+        let mut fields = s.split('-');
+        let (Some(version), Some(trace_id), Some(span_id), Some(flags)) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return Err(expected());
+        };
+        // Lowercase only, as the format requires.
+        let is_hex = |field: &str, len: usize| {
+            field.len() == len
+                && field
+                    .bytes()
+                    .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+        };
+        let well_formed = is_hex(version, 2)
+            && is_hex(trace_id, 32)
+            && is_hex(span_id, 16)
+            && is_hex(flags, 2)
+            && version != "ff"
+            && (version != "00" || fields.next().is_none());
+        if !well_formed {
+            return Err(expected());
+        }
+        // Hex of these lengths always fits.
+        let trace_id = u128::from_str_radix(trace_id, 16).map_err(|_| expected())?;
+        let span_id = u64::from_str_radix(span_id, 16).map_err(|_| expected())?;
+        let flags = u8::from_str_radix(flags, 16).map_err(|_| expected())?;
+        if trace_id == 0 || span_id == 0 {
+            return Err(format!("`{s}` has an all-zero id"));
+        }
+        Ok(Self {
+            trace_id,
+            span_id,
+            sampled: flags & 1 == 1,
+        })
+    }
+}
+
+/// A random id, never zero, which the format reserves.
+fn random_id() -> u64 {
+    loop {
+        let id = random_u64();
+        if id != 0 {
+            return id;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACE: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const SPAN: &str = "00f067aa0ba902b7";
+
+    fn parse(s: &str) -> Result<TraceContext, String> {
+        s.parse()
+    }
+
+    #[test]
+    fn parses_a_traceparent() {
+        let context = parse(&format!("00-{TRACE}-{SPAN}-01")).unwrap();
+        assert_eq!(context.trace_id_hex(), TRACE);
+        assert_eq!(context.span_id_hex(), SPAN);
+        assert!(context.sampled);
+        // Only the lowest flag says whether it's sampled.
+        assert!(!parse(&format!("00-{TRACE}-{SPAN}-02")).unwrap().sampled);
+    }
+
+    #[test]
+    fn rejects_malformed_traceparents() {
+        for bad in [
+            format!("ff-{TRACE}-{SPAN}-01"),
+            format!("00-{}-{SPAN}-01", TRACE.to_uppercase()),
+            format!("00-{TRACE}-{}-01", SPAN.to_uppercase()),
+            format!("0-{TRACE}-{SPAN}-01"),
+            format!("00-{}-{SPAN}-01", &TRACE[1..]),
+            format!("00-{TRACE}0-{SPAN}-01"),
+            format!("00-{TRACE}-{}-01", &SPAN[1..]),
+            format!("00-{TRACE}-{SPAN}-1"),
+            format!("00-{TRACE}-{SPAN}"),
+            format!("00-{TRACE}-{SPAN}-01-extra"),
+            format!("00-{TRACE}-{SPAN}-0g"),
+            format!(" 00-{TRACE}-{SPAN}-01"),
+            String::new(),
+        ] {
+            assert!(parse(&bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn rejects_all_zero_ids() {
+        let zero_trace = format!("00-{}-{SPAN}-01", "0".repeat(32));
+        assert!(parse(&zero_trace).unwrap_err().contains("all-zero"));
+        let zero_span = format!("00-{TRACE}-{}-01", "0".repeat(16));
+        assert!(parse(&zero_span).unwrap_err().contains("all-zero"));
+    }
+
+    #[test]
+    fn ignores_fields_a_later_version_appends() {
+        let context = parse(&format!("01-{TRACE}-{SPAN}-01-whatever-else")).unwrap();
+        assert_eq!(context.trace_id_hex(), TRACE);
+        assert_eq!(context.span_id_hex(), SPAN);
+    }
+
+    #[test]
+    fn display_round_trips() {
+        let root = TraceContext::new_root();
+        assert_eq!(parse(&root.to_string()), Ok(root));
+        let child = root.child();
+        assert_eq!(child.trace_id, root.trace_id);
+        assert_ne!(child.span_id, root.span_id);
+        let unsampled = TraceContext {
+            sampled: false,
+            ..root
+        };
+        assert_eq!(parse(&unsampled.to_string()), Ok(unsampled));
+        let text = format!("00-{TRACE}-{SPAN}-01");
+        assert_eq!(parse(&text).unwrap().to_string(), text);
+    }
+}
@@ -0,0 +1,323 @@
+//! Per-peer usage metering, for operators billing for a paid mesh.
+//!
+//! The ledger counts, for every peer served, the generations run for it and
+//! the prompt and completion tokens Ollama reported for them. Unlike
+//! [`quota`](crate::quota), which only keeps what it needs to enforce limits
+//! and forgets idle peers, the ledger keeps every peer until its billing
+//! period ends. Each generation counts as a request, so a comparison of three
+//! models counts three; answers replayed from the dedup cache cost nothing
+//! and count nothing.
+//!
+//! Without a period, counts accumulate for as long as the ledger file is
+//! kept. With one, periods start at whole multiples of it since the Unix
+//! epoch, so `86400` resets at midnight UTC. When a period ends its counts
+//! move to `previous`, where they stay until the next one ends, so it can
+//! still be billed after the reset.
+//!
+//! A period keeps at most `max_peers` peers by name. Peers that show up once
+//! it is full are counted together under `overflow`, so the totals still add
+//! up and a flood of fresh PeerIds can't grow the ledger without bound.
+//!
+//! A run stopped before the backend reported its token counts, e.g. a stream
+//! the peer hung up on or a generation past its deadline, is still billed:
+//! with the pieces of answer it produced, and the prompt's length estimated
+//! from its text. Such runs are also counted as `partial_requests`, since
+//! their counts are estimates.
+
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+/// Asks the swarm loop for a [`UsageReport`].
+pub type UsageRequest = oneshot::Sender<UsageReport>;
+
+/// What one peer, or everyone together, used.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerUsage {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    /// Of `requests`, the runs stopped before the backend reported their
+    /// token counts, which are estimated.
+    #[serde(default)]
+    pub partial_requests: u64,
+}
+
+impl PeerUsage {
+    fn add(&mut self, run: &Run) {
+        self.requests += 1;
+        self.prompt_tokens = self.prompt_tokens.saturating_add(run.prompt_tokens);
+        self.completion_tokens = self.completion_tokens.saturating_add(run.completion_tokens);
+        self.partial_requests += u64::from(run.partial);
+    }
+}
+
+/// One generation to bill.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Run {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    /// Whether the run was stopped before the backend reported its token
+    /// counts, so they are estimates.
+    pub partial: bool,
+}
+
+/// Usage over one billing period, with times in seconds since the Unix
+/// epoch.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Period {
+    pub start: u64,
+    /// When the period ends; `None` without a period, when usage only
+    /// accumulates.
+    pub end: Option<u64>,
+    pub total: PeerUsage,
+    /// By PeerId.
+    pub peers: BTreeMap<String, PeerUsage>,
+    /// Peers that showed up once `peers` was full, together.
+    #[serde(default)]
+    pub overflow: PeerUsage,
+}
+
+/// The answer to `GET /usage`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageReport {
+    pub generated_at: u64,
+    pub current: Period,
+    /// The last period that ended, if any.
+    pub previous: Option<Period>,
+}
+
+/// The part of the ledger saved to disk.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Saved {
+    current: Period,
+    previous: Option<Period>,
+}
+
+#[derive(Debug)]
+pub struct UsageLedger {
+    period: Option<Duration>,
+    max_peers: usize,
+    current: Period,
+    previous: Option<Period>,
+    /// Whether the current period has been reported full.
+    full: bool,
+}
+
+impl UsageLedger {
+    /// A ledger starting now, resetting every `period` if given, keeping at
+    /// most `max_peers` peers by name in a period.
+    pub fn new(period: Option<Duration>, max_peers: usize) -> Self {
+        let mut ledger = Self {
+            period,
+            max_peers,
+            current: Period::default(),
+            previous: None,
+            full: false,
+        };
+        ledger.current = ledger.period_at(unix_now());
+        ledger
+    }
+
+    /// Counts a generation run for `peer`.
+    pub fn record(&mut self, peer: PeerId, run: Run) {
+        self.roll();
+        self.current.total.add(&run);
+        let key = peer.to_string();
+        let usage =
+            if self.current.peers.len() < self.max_peers || self.current.peers.contains_key(&key) {
+                self.current.peers.entry(key).or_default()
+            } else {
+                if !self.full {
+                    self.full = true;
+                    tracing::warn!(
+                        max_peers = self.max_peers,
+                        "Usage ledger full for this period, counting new peers together as overflow"
+                    );
+                }
+                &mut self.current.overflow
+            };
+        usage.add(&run);
+    }
+
+    pub fn report(&mut self) -> UsageReport {
+        self.roll();
+        UsageReport {
+            generated_at: unix_now(),
+            current: self.current.clone(),
+            previous: self.previous.clone(),
+        }
+    }
+
+    /// Ends the current period if its time is up, moving its counts to
+    /// `previous`.
+    pub fn roll(&mut self) {
+        let now = unix_now();
+        if let Some(end) = self.current.end
+            && now >= end
+        {
+            let next = self.period_at(now);
+            let ended = std::mem::replace(&mut self.current, next);
+            self.full = false;
+            tracing::info!(
+                start = ended.start,
+                end,
+                peers = ended.peers.len(),
+                overflow_requests = ended.overflow.requests,
+                requests = ended.total.requests,
+                prompt_tokens = ended.total.prompt_tokens,
+                completion_tokens = ended.total.completion_tokens,
+                "Usage period ended"
+            );
+            self.previous = Some(ended);
+        }
+    }
+
+    /// Restores a ledger saved by [`UsageLedger::save`]. A missing file is
+    /// not an error. The current period keeps its start but ends by the
+    /// configured period, which may have changed since it was saved.
+    pub fn load(&mut self, path: &Path) -> io::Result<()> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let saved: Saved = serde_json::from_slice(&data)?;
+        self.current = Period {
+            end: self.period_at(saved.current.start).end,
+            ..saved.current
+        };
+        self.previous = saved.previous;
+        self.roll();
+        Ok(())
+    }
+
+    /// Writes the ledger to `path` through a temporary file, so a crash
+    /// mid-write doesn't lose what was saved before.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let saved = Saved {
+            current: self.current.clone(),
+            previous: self.previous.clone(),
+        };
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(&saved)?)?;
+        fs::rename(tmp, path)
+    }
+
+    /// An empty period holding the time `at`.
+    fn period_at(&self, at: u64) -> Period {
+        match self.period.map(|period| period.as_secs().max(1)) {
+            Some(period) => {
+                let start = at - at % period;
+                Period {
+                    start,
+                    end: Some(start + period),
+                    ..Period::default()
+                }
+            }
+            None => Period {
+                start: at,
+                ..Period::default()
+            },
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn run(prompt_tokens: u64, completion_tokens: u64) -> Run {
+        Run {
+            prompt_tokens,
+            completion_tokens,
+            partial: false,
+        }
+    }
+
+    #[test]
+    fn peers_beyond_the_bound_are_billed_as_overflow() {
+        let mut ledger = UsageLedger::new(None, 2);
+        let (a, b) = (PeerId::random(), PeerId::random());
+        ledger.record(a, run(10, 5));
+        ledger.record(b, run(10, 5));
+        for _ in 0..100 {
+            ledger.record(PeerId::random(), run(1, 1));
+        }
+        // Peers already counted keep being counted by name.
+        ledger.record(a, run(10, 5));
+
+        let report = ledger.report();
+        assert_eq!(report.current.peers.len(), 2);
+        assert_eq!(report.current.peers[&a.to_string()].requests, 2);
+        assert_eq!(report.current.overflow.requests, 100);
+        assert_eq!(report.current.total.requests, 103);
+        assert_eq!(report.current.total.prompt_tokens, 130);
+    }
+
+    #[test]
+    fn partial_runs_are_billed_and_marked() {
+        let mut ledger = UsageLedger::new(None, 8);
+        let peer = PeerId::random();
+        ledger.record(peer, run(20, 30));
+        ledger.record(
+            peer,
+            Run {
+                prompt_tokens: 12,
+                completion_tokens: 7,
+                partial: true,
+            },
+        );
+        let usage = ledger.report().current.peers[&peer.to_string()];
+        assert_eq!(
+            usage,
+            PeerUsage {
+                requests: 2,
+                prompt_tokens: 32,
+                completion_tokens: 37,
+                partial_requests: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn loads_a_ledger_saved_before_overflow_was_kept() {
+        let dir = TempDir::new("usage-overflow");
+        let path = dir.join("usage.json");
+        let peer = PeerId::random();
+        let saved = serde_json::json!({
+            "current": {
+                "start": unix_now(),
+                "end": null,
+                "total": { "requests": 1, "prompt_tokens": 2, "completion_tokens": 3 },
+                "peers": {
+                    peer.to_string(): { "requests": 1, "prompt_tokens": 2, "completion_tokens": 3 }
+                },
+            },
+            "previous": null,
+        });
+        fs::write(&path, saved.to_string()).unwrap();
+        let mut ledger = UsageLedger::new(None, 8);
+        ledger.load(&path).unwrap();
+
+        ledger.record(peer, run(1, 1));
+        let report = ledger.report();
+        assert_eq!(report.current.total.requests, 2);
+        assert_eq!(report.current.overflow, PeerUsage::default());
+    }
+}
@@ -0,0 +1,313 @@
+//! A static list of workers, kept to the healthy ones by periodic checks.
+//!
+//! The file holds one multiaddr per line, each ending in `/p2p/<PeerId>`;
+//! blank lines and `#` comments are ignored, and several lines for the same
+//! peer are merged. A worker is checked by connecting and confirming it speaks
+//! the prompt protocol. After [`HealthConfig::failure_threshold`] failures in a
+//! row it is marked down and only re-probed every
+//! [`HealthConfig::reprobe_interval`]; one success brings it back up.
+
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use libp2p::{Multiaddr, PeerId, multiaddr::Protocol};
+
+#[derive(Debug, Clone)]
+pub struct HealthConfig {
+    /// Time between checks of workers that are up.
+    pub interval: Duration,
+    /// Failures in a row before a worker is marked down.
+    pub failure_threshold: u32,
+    /// Time between checks of workers that are down.
+    pub reprobe_interval: Duration,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30),
+            failure_threshold: 3,
+            reprobe_interval: Duration::from_secs(120),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Health {
+    /// Not checked successfully yet; not routed to.
+    Unknown,
+    Up,
+    Down,
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Health::Unknown => write!(f, "unknown"),
+            Health::Up => write!(f, "up"),
+            Health::Down => write!(f, "down"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WorkerStatus {
+    pub peer: PeerId,
+    pub addrs: Vec<Multiaddr>,
+    pub health: Health,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    pub last_checked: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub struct WorkerList {
+    path: PathBuf,
+    config: HealthConfig,
+    workers: Vec<WorkerStatus>,
+}
+
+impl WorkerList {
+    pub fn load(path: &Path, config: HealthConfig) -> io::Result<Self> {
+        let workers = read(path)?
+            .into_iter()
+            .map(|(peer, addrs)| WorkerStatus {
+                peer,
+                addrs,
+                health: Health::Unknown,
+                consecutive_failures: 0,
+                last_error: None,
+                last_checked: None,
+            })
+            .collect();
+        Ok(Self {
+            path: path.to_path_buf(),
+            config,
+            workers,
+        })
+    }
+
+    /// Re-reads the file, returning the workers that were added and removed.
+    /// Workers that stay keep their health. On error the current list is kept.
+    pub fn reload(&mut self) -> io::Result<(Vec<PeerId>, Vec<PeerId>)> {
+        let listed = read(&self.path)?;
+        let removed = self
+            .workers
+            .iter()
+            .map(|w| w.peer)
+            .filter(|peer| !listed.iter().any(|(p, _)| p == peer))
+            .collect();
+        let mut added = Vec::new();
+        let mut workers = Vec::with_capacity(listed.len());
+        for (peer, addrs) in listed {
+            match self.workers.iter().find(|w| w.peer == peer) {
+                Some(existing) => workers.push(WorkerStatus {
+                    addrs,
+                    ..existing.clone()
+                }),
+                None => {
+                    added.push(peer);
+                    workers.push(WorkerStatus {
+                        peer,
+                        addrs,
+                        health: Health::Unknown,
+                        consecutive_failures: 0,
+                        last_error: None,
+                        last_checked: None,
+                    });
+                }
+            }
+        }
+        self.workers = workers;
+        Ok((added, removed))
+    }
+
+    /// Workers whose next check is due at `now`.
+    pub fn due(&self, now: Instant) -> Vec<(PeerId, Vec<Multiaddr>)> {
+        self.workers
+            .iter()
+            .filter(|w| {
+                let every = match w.health {
+                    Health::Down => self.config.reprobe_interval,
+                    Health::Unknown | Health::Up => self.config.interval,
+                };
+                w.last_checked
+                    .is_none_or(|checked| now.duration_since(checked) >= every)
+            })
+            .map(|w| (w.peer, w.addrs.clone()))
+            .collect()
+    }
+
+    /// Records the outcome of a check. Returns the worker's new health if it
+    /// changed.
+    pub fn record(
+        &mut self,
+        peer: &PeerId,
+        result: Result<(), String>,
+        now: Instant,
+    ) -> Option<Health> {
+        let threshold = self.config.failure_threshold.max(1);
+        let worker = self.workers.iter_mut().find(|w| w.peer == *peer)?;
+        worker.last_checked = Some(now);
+        let health = match result {
+            Ok(()) => {
+                worker.consecutive_failures = 0;
+                worker.last_error = None;
+                Health::Up
+            }
+            Err(e) => {
+                worker.consecutive_failures += 1;
+                worker.last_error = Some(e);
+                if worker.consecutive_failures >= threshold {
+                    Health::Down
+                } else {
+                    worker.health
+                }
+            }
+        };
+        (health != worker.health).then(|| {
+            worker.health = health;
+            health
+        })
+    }
+
+    /// Workers that can be routed to.
+    pub fn healthy(&self) -> Vec<(PeerId, Vec<Multiaddr>)> {
+        self.workers
+            .iter()
+            .filter(|w| w.health == Health::Up)
+            .map(|w| (w.peer, w.addrs.clone()))
+            .collect()
+    }
+
+    /// Every configured worker, in file order.
+    pub fn statuses(&self) -> &[WorkerStatus] {
+        &self.workers
+    }
+}
+
+fn read(path: &Path) -> io::Result<Vec<(PeerId, Vec<Multiaddr>)>> {
+    let mut workers: Vec<(PeerId, Vec<Multiaddr>)> = Vec::new();
+    let lines = fs::read_to_string(path)?;
+    for line in lines
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+    {
+        let invalid =
+            |e: String| io::Error::new(io::ErrorKind::InvalidData, format!("{line}: {e}"));
+        let addr: Multiaddr = line.parse().map_err(|e| invalid(format!("{e}")))?;
+        let Some(Protocol::P2p(peer)) = addr.iter().last() else {
+            return Err(invalid("address must end in /p2p/<PeerId>".to_string()));
+        };
+        match workers.iter_mut().find(|(p, _)| *p == peer) {
+            Some((_, addrs)) => addrs.push(addr),
+            None => workers.push((peer, vec![addr])),
+        }
+    }
+    Ok(workers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    /// A workers file holding `contents`, in a directory removed when
+    /// dropped.
+    fn workers_file(name: &str, contents: &str) -> (TempDir, PathBuf) {
+        let dir = TempDir::new(&format!("workers-{name}"));
+        let file = dir.join("workers");
+        fs::write(&file, contents).unwrap();
+        (dir, file)
+    }
+
+    fn line(peer: &PeerId, port: u16) -> String {
+        format!("/ip4/10.0.0.1/tcp/{port}/p2p/{peer}\n")
+    }
+
+    #[test]
+    fn reads_one_worker_per_peer() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let (_dir, file) = workers_file(
+            "read",
+            &format!("# workers\n{}\n{}{}", line(&a, 1), line(&b, 2), line(&a, 3)),
+        );
+        let list = WorkerList::load(&file, HealthConfig::default()).unwrap();
+        let statuses = list.statuses();
+        assert_eq!(statuses.len(), 2);
+        assert_eq!((statuses[0].peer, statuses[0].addrs.len()), (a, 2));
+        assert_eq!((statuses[1].peer, statuses[1].addrs.len()), (b, 1));
+        assert!(statuses.iter().all(|w| w.health == Health::Unknown));
+    }
+
+    #[test]
+    fn rejects_addresses_without_a_peer_id() {
+        let (_dir, file) = workers_file("no-peer", "/ip4/10.0.0.1/tcp/1\n");
+        let e = WorkerList::load(&file, HealthConfig::default()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn reload_keeps_the_health_of_workers_that_stay() {
+        let (kept, dropped, added) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let (_dir, file) = workers_file("reload", &(line(&kept, 1) + &line(&dropped, 2)));
+        let mut list = WorkerList::load(&file, HealthConfig::default()).unwrap();
+        list.record(&kept, Ok(()), Instant::now());
+
+        fs::write(&file, &(line(&kept, 5) + &line(&added, 3))).unwrap();
+        assert_eq!(list.reload().unwrap(), (vec![added], vec![dropped]));
+        let statuses = list.statuses();
+        assert_eq!(statuses[0].peer, kept);
+        assert_eq!(statuses[0].health, Health::Up);
+        assert_eq!(
+            statuses[0].addrs,
+            [line(&kept, 5).trim().parse::<Multiaddr>().unwrap()]
+        );
+        assert_eq!(
+            (statuses[1].peer, statuses[1].health),
+            (added, Health::Unknown)
+        );
+    }
+
+    #[test]
+    fn a_bad_reload_keeps_the_current_list() {
+        let peer = PeerId::random();
+        let (_dir, file) = workers_file("bad-reload", &line(&peer, 1));
+        let mut list = WorkerList::load(&file, HealthConfig::default()).unwrap();
+        fs::write(&file, "not an address\n").unwrap();
+        assert!(list.reload().is_err());
+        assert_eq!(list.statuses().len(), 1);
+        assert_eq!(list.statuses()[0].peer, peer);
+    }
+
+    #[test]
+    fn workers_go_down_after_the_threshold_and_are_reprobed_later() {
+        let peer = PeerId::random();
+        let (_dir, file) = workers_file("health", &line(&peer, 1));
+        let config = HealthConfig::default();
+        let mut list = WorkerList::load(&file, config.clone()).unwrap();
+        let now = Instant::now();
+        assert_eq!(list.record(&peer, Ok(()), now), Some(Health::Up));
+        assert_eq!(list.healthy().len(), 1);
+        for _ in 1..config.failure_threshold {
+            assert_eq!(list.record(&peer, Err("refused".into()), now), None);
+        }
+        assert_eq!(
+            list.record(&peer, Err("refused".into()), now),
+            Some(Health::Down)
+        );
+        assert!(list.healthy().is_empty());
+        assert_eq!(list.statuses()[0].last_error.as_deref(), Some("refused"));
+
+        assert!(list.due(now + config.interval).is_empty());
+        assert_eq!(list.due(now + config.reprobe_interval).len(), 1);
+        assert_eq!(
+            list.record(&peer, Ok(()), now + config.reprobe_interval),
+            Some(Health::Up)
+        );
+    }
+}
@@ -0,0 +1,93 @@
+//! A public node tells its peers whether they are reachable.
+//!
+//! AutoNAT dials back the IP a probe came from, so this runs over TCP on
+//! loopback rather than the memory transport, with private IPs allowed.
+
+use std::{net::TcpListener, time::Duration};
+
+use futures::StreamExt;
+use libp2p::{
+    Swarm,
+    autonat::{self, NatStatus},
+    swarm::SwarmEvent,
+};
+use mesh_ai_node::{
+    node::{self, Behaviour, BehaviourEvent, NodeConfig, TransportKind},
+    testing::{keypair, listen_on_tcp},
+};
+
+fn config(server: bool) -> NodeConfig {
+    NodeConfig {
+        transports: vec![TransportKind::Tcp],
+        autonat_server: server,
+        autonat_client: !server,
+        autonat_private_ips: true,
+        ..Default::default()
+    }
+}
+
+/// Polls `swarm` until AutoNAT settles on a status other than unknown.
+async fn nat_status(swarm: &mut Swarm<Behaviour>) -> NatStatus {
+    loop {
+        if let SwarmEvent::Behaviour(BehaviourEvent::Autonat(autonat::Event::StatusChanged {
+            new,
+            ..
+        })) = swarm.select_next_some().await
+            && new != NatStatus::Unknown
+        {
+            return new;
+        }
+    }
+}
+
+#[tokio::test]
+async fn reachable_and_unreachable_peers_learn_which_they_are() {
+    let mut server = node::build_swarm(keypair(1), &config(true)).unwrap();
+    let server_addr = listen_on_tcp(&mut server).await.unwrap();
+    let server_id = *server.local_peer_id();
+    tokio::spawn(async move {
+        loop {
+            server.select_next_some().await;
+        }
+    });
+
+    // Listens where the server can dial it back.
+    let mut public = node::build_swarm(keypair(2), &config(false)).unwrap();
+    listen_on_tcp(&mut public).await.unwrap();
+    public.dial(server_addr.clone()).unwrap();
+
+    // Only dials out, and believes itself reachable at a port nothing
+    // listens on, as behind a NAT.
+    let mut natted = node::build_swarm(keypair(3), &config(false)).unwrap();
+    let closed_port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    natted.dial(server_addr).unwrap();
+    loop {
+        if let SwarmEvent::ConnectionEstablished { peer_id, .. } = natted.select_next_some().await
+            && peer_id == server_id
+        {
+            break;
+        }
+    }
+    natted
+        .behaviour_mut()
+        .autonat
+        .as_mut()
+        .unwrap()
+        .probe_address(format!("/ip4/127.0.0.1/tcp/{closed_port}").parse().unwrap());
+
+    let statuses = tokio::time::timeout(Duration::from_secs(60), async {
+        tokio::join!(nat_status(&mut public), nat_status(&mut natted))
+    })
+    .await
+    .expect("both nodes reach a conclusion");
+    assert!(
+        matches!(statuses.0, NatStatus::Public(_)),
+        "{:?}",
+        statuses.0
+    );
+    assert_eq!(statuses.1, NatStatus::Private);
+}
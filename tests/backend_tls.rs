@@ -0,0 +1,147 @@
+//! The backend client against a TLS stub whose certificate comes from a CA
+//! of its own, as with a backend behind an internal proxy.
+
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use mesh_ai_node::{
+    http_client::{BackendAuth, HttpClientConfig},
+    testing::TempDir,
+};
+use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+use tokio_native_tls::{TlsAcceptor, native_tls};
+
+/// A TLS server answering every request with `ok`, keeping the
+/// `Authorization` header it was sent, if any.
+struct TlsStub {
+    url: String,
+    /// Holds the CA bundle until the stub is dropped.
+    _dir: TempDir,
+    ca_bundle: PathBuf,
+    authorization: Arc<Mutex<Option<String>>>,
+}
+
+impl TlsStub {
+    async fn start() -> Self {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca_params
+            .distinguished_name
+            .push(DnType::CommonName, "mesh-ai test CA");
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "localhost");
+        let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+
+        let dir = TempDir::new("tls-ca");
+        let ca_bundle = dir.join("ca.pem");
+        fs::write(&ca_bundle, ca.pem()).unwrap();
+        let identity =
+            native_tls::Identity::from_pkcs8(cert.pem().as_bytes(), key.serialize_pem().as_bytes())
+                .unwrap();
+        let acceptor = TlsAcceptor::from(native_tls::TlsAcceptor::new(identity).unwrap());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let authorization = Arc::new(Mutex::new(None));
+        let seen = authorization.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                let seen = seen.clone();
+                tokio::spawn(async move {
+                    // Clients that don't trust the certificate hang up here.
+                    let Ok(mut stream) = acceptor.accept(stream).await else {
+                        return;
+                    };
+                    let mut buf = [0u8; 4096];
+                    let n = stream.read(&mut buf).await.unwrap_or(0);
+                    let head = String::from_utf8_lossy(&buf[..n]);
+                    *seen.lock().unwrap() = head.lines().find_map(|line| {
+                        let (name, value) = line.split_once(':')?;
+                        name.eq_ignore_ascii_case("authorization")
+                            .then(|| value.trim().to_string())
+                    });
+                    let _ = stream
+                        .write_all(
+                            b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok",
+                        )
+                        .await;
+                    let _ = stream.shutdown().await;
+                });
+            }
+        });
+        Self {
+            url: format!("https://localhost:{port}/api/tags"),
+            _dir: dir,
+            ca_bundle,
+            authorization,
+        }
+    }
+
+    async fn get(&self, config: &HttpClientConfig) -> reqwest::Result<String> {
+        let client = config
+            .apply(reqwest::Client::builder().no_proxy())
+            .unwrap()
+            .build()
+            .unwrap();
+        client
+            .get(&self.url)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await
+    }
+}
+
+#[tokio::test]
+async fn trusts_the_backend_through_its_ca_bundle() {
+    let stub = TlsStub::start().await;
+
+    // The stub's CA isn't a system one.
+    let err = stub.get(&HttpClientConfig::default()).await.unwrap_err();
+    assert!(err.is_connect(), "{err:?}");
+
+    let trusted = HttpClientConfig {
+        ca_bundle: Some(stub.ca_bundle.clone()),
+        auth: Some(BackendAuth::Bearer("secret".to_string())),
+        ..Default::default()
+    };
+    assert_eq!(stub.get(&trusted).await.unwrap(), "ok");
+    assert_eq!(
+        stub.authorization.lock().unwrap().as_deref(),
+        Some("Bearer secret")
+    );
+
+    let insecure = HttpClientConfig {
+        insecure_skip_tls_verify: true,
+        ..Default::default()
+    };
+    assert_eq!(stub.get(&insecure).await.unwrap(), "ok");
+    assert_eq!(*stub.authorization.lock().unwrap(), None);
+}
+
+#[test]
+fn refuses_a_bundle_without_certificates() {
+    let dir = TempDir::new("tls-empty");
+    let path = dir.join("ca.pem");
+    fs::write(&path, "").unwrap();
+    let config = HttpClientConfig {
+        ca_bundle: Some(path.clone()),
+        ..Default::default()
+    };
+    let err = config.apply(reqwest::Client::builder()).err().unwrap();
+    assert!(err.contains("no certificates"), "{err}");
+}
@@ -0,0 +1,93 @@
+//! The client against in-process workers on the memory transport.
+
+use std::time::Duration;
+
+use futures::future::join_all;
+use libp2p::{PeerId, Swarm};
+use mesh_ai_node::{
+    PromptRequest, PromptResponse, ResponseStatus,
+    client::{Client, ClientConfig},
+    node::{Behaviour, NodeConfig},
+    testing::{listening_node, serve_prompts},
+};
+
+fn prompt(text: &str) -> PromptRequest {
+    PromptRequest::new(text.to_string())
+}
+
+/// Answers every prompt with `<name>: <prompt>`, holding each answer back for
+/// a delay taken from the prompt's trailing number, so answers come back in
+/// a different order than the requests went out.
+async fn run_worker(swarm: Swarm<Behaviour>, name: &'static str) {
+    serve_prompts(swarm, move |_, request| async move {
+        let n: u64 = request.prompt.rsplit(' ').next().unwrap().parse().unwrap();
+        tokio::time::sleep(Duration::from_millis(200 - 10 * (n % 20))).await;
+        PromptResponse::ok(format!("{name}: {}", request.prompt))
+    })
+    .await
+}
+
+#[tokio::test]
+async fn interleaved_requests_get_their_own_answers() {
+    let mut workers: Vec<(PeerId, &str)> = Vec::new();
+    let mut addrs = Vec::new();
+    for (seed, name) in [(1, "a"), (2, "b")] {
+        let (swarm, peer, addr) = listening_node(seed, NodeConfig::default()).await.unwrap();
+        tokio::spawn(run_worker(swarm, name));
+        workers.push((peer, name));
+        addrs.push(addr);
+    }
+
+    let (swarm, _, _) = listening_node(3, NodeConfig::default()).await.unwrap();
+    let client = Client::new(
+        swarm,
+        ClientConfig {
+            max_in_flight: 20,
+            ..Default::default()
+        },
+    );
+    for ((peer, _), addr) in workers.iter().zip(&addrs) {
+        client.connect(*peer, vec![addr.clone()]).await.unwrap();
+    }
+
+    let requests = (0..20).map(|i| {
+        let (peer, name) = workers[i % 2];
+        let client = client.clone();
+        async move {
+            let text = format!("prompt {i}");
+            let response = client.send_prompt(peer, prompt(&text)).await.unwrap();
+            (format!("{name}: {text}"), response.response)
+        }
+    });
+    let answers = tokio::time::timeout(Duration::from_secs(30), join_all(requests))
+        .await
+        .expect("all requests answered");
+    for (expected, answer) in answers {
+        assert_eq!(answer, expected);
+    }
+}
+
+#[tokio::test]
+async fn streams_from_a_worker_that_doesnt_stream_in_one_chunk() {
+    // The worker only answers whole prompts; it never accepts the
+    // streaming protocol.
+    let (worker, worker_id, addr) = listening_node(4, NodeConfig::default()).await.unwrap();
+    tokio::spawn(run_worker(worker, "a"));
+    let (swarm, _, _) = listening_node(5, NodeConfig::default()).await.unwrap();
+    let client = Client::new(swarm, ClientConfig::default());
+    client.connect(worker_id, vec![addr]).await.unwrap();
+
+    let mut chunks = Vec::new();
+    let response = tokio::time::timeout(
+        Duration::from_secs(30),
+        client.prompt_stream(worker_id, prompt("whole 1"), |chunk| {
+            chunks.push(chunk.to_string())
+        }),
+    )
+    .await
+    .expect("the worker answers")
+    .unwrap();
+    assert_eq!(response.status, ResponseStatus::Ok);
+    assert_eq!(response.response, "a: whole 1");
+    assert_eq!(chunks, ["a: whole 1"]);
+}
@@ -0,0 +1,48 @@
+//! Context window lookups against a mock Ollama whose `/api/show` fails at
+//! first.
+
+use std::{num::NonZeroUsize, time::Duration};
+
+use mesh_ai_node::{
+    context::{ContextPolicy, TruncateStrategy},
+    http_client::HttpClientConfig,
+    mock_ollama::{MockOllama, MockReply},
+    ollama,
+};
+use serde_json::json;
+
+#[tokio::test]
+async fn failed_lookups_are_retried_and_successes_kept() {
+    let mock = MockOllama::start().await.unwrap();
+    ollama::init(
+        &mock.url(),
+        false,
+        NonZeroUsize::new(2).unwrap(),
+        &HttpClientConfig::default(),
+    )
+    .unwrap();
+    let retry = Duration::from_millis(200);
+    let policy = ContextPolicy::new(TruncateStrategy::Off, 4096).with_lookup_retry(retry);
+
+    mock.set("/api/show", MockReply::Status(500));
+    assert!(policy.info("mock").await.is_none());
+
+    // The failure stands until the retry interval is up.
+    mock.set(
+        "/api/show",
+        MockReply::Json(json!({
+            "parameters": "num_ctx 8192\nstop \"<|end|>\"",
+            "model_info": { "llama.context_length": 131072 },
+        })),
+    );
+    assert!(policy.info("mock").await.is_none());
+    tokio::time::sleep(retry).await;
+    let info = policy.info("mock").await.unwrap();
+    assert_eq!(info.num_ctx, Some(8192));
+    assert_eq!(info.context_length, Some(131072));
+    assert_eq!(policy.limit("mock").await, Some(8192));
+
+    // A success is kept, whatever Ollama says later.
+    mock.set("/api/show", MockReply::Status(500));
+    assert_eq!(policy.info("mock").await.unwrap().num_ctx, Some(8192));
+}
@@ -0,0 +1,54 @@
+//! Dialing a worker by name: a `/dns4` address goes through the DNS
+//! transport, which resolves `localhost` from the hosts file.
+
+use std::time::Duration;
+
+use futures::StreamExt;
+use libp2p::{Multiaddr, multiaddr::Protocol, swarm::SwarmEvent};
+use mesh_ai_node::{
+    PromptRequest, ResponseStatus,
+    client::{Client, ClientConfig},
+    node::{self, NodeConfig, TransportKind},
+    testing::{echo_worker, keypair},
+};
+
+fn config() -> NodeConfig {
+    NodeConfig {
+        transports: vec![TransportKind::Tcp],
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn dials_a_dns4_address_and_completes_a_prompt() {
+    let mut worker = node::build_swarm(keypair(1), &config()).unwrap();
+    let worker_id = *worker.local_peer_id();
+    worker
+        .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+        .unwrap();
+    let port = loop {
+        if let SwarmEvent::NewListenAddr { address, .. } = worker.select_next_some().await
+            && let Some(Protocol::Tcp(port)) = address.iter().nth(1)
+        {
+            break port;
+        }
+    };
+    tokio::spawn(echo_worker(worker));
+
+    let addr: Multiaddr = format!("/dns4/localhost/tcp/{port}/p2p/{worker_id}")
+        .parse()
+        .unwrap();
+    let client = Client::new(
+        node::build_swarm(keypair(2), &config()).unwrap(),
+        ClientConfig::default(),
+    );
+    let request = PromptRequest::new("hi".to_string());
+    let response = tokio::time::timeout(Duration::from_secs(30), async {
+        client.connect(worker_id, vec![addr]).await.unwrap();
+        client.send_prompt(worker_id, request).await.unwrap()
+    })
+    .await
+    .expect("the worker answers");
+    assert_eq!(response.status, ResponseStatus::Ok);
+    assert_eq!(response.response, "echo: hi");
+}
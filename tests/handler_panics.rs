@@ -0,0 +1,72 @@
+//! A node that panics while serving one prompt: the peer is answered
+//! `Internal`, and the node goes on serving.
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use clap::Parser;
+use mesh_ai_node::{
+    PromptRequest, ResponseStatus,
+    client::{Client, ClientConfig},
+    middleware::{Middleware, Next, Outcome, Request},
+    mock_ollama::MockOllama,
+    node::{self, NodeConfig, TransportKind},
+    server::{Node, Opt},
+    testing::{keypair, serving_node},
+};
+
+fn prompt(text: &str) -> PromptRequest {
+    PromptRequest::new(text.to_string())
+}
+
+/// Panics, with internals in the message, when asked to; otherwise hands
+/// the prompt on to the backend.
+struct PanicOnRequest;
+
+#[async_trait]
+impl Middleware for PanicOnRequest {
+    async fn handle(&self, request: Request, next: Next<'_>) -> Outcome {
+        if request.prompt.prompt == "panic" {
+            panic!("backend state at /var/lib/secret/state.db is corrupt");
+        }
+        next.run(request).await
+    }
+}
+
+#[tokio::test]
+async fn a_panicking_prompt_is_answered_internal_and_the_node_stays_up() {
+    let backend = MockOllama::start().await.unwrap();
+    let opt = Opt::parse_from([
+        "mesh-ai-node",
+        "--model",
+        "mock",
+        "--ollama-url",
+        &backend.url(),
+    ]);
+    let node = Node::new(opt).await.unwrap().with(PanicOnRequest);
+    let (worker_id, addr) = serving_node(node).await;
+    let config = NodeConfig {
+        transports: vec![TransportKind::Tcp],
+        ..Default::default()
+    };
+    let client = Client::new(
+        node::build_swarm(keypair(2), &config).unwrap(),
+        ClientConfig::default(),
+    );
+
+    tokio::time::timeout(Duration::from_secs(30), async {
+        client.connect(worker_id, vec![addr]).await.unwrap();
+        let failed = client
+            .send_prompt(worker_id, prompt("panic"))
+            .await
+            .unwrap();
+        assert_eq!(failed.status, ResponseStatus::Internal);
+        assert_eq!(failed.response, "The node failed while serving the request");
+
+        let served = client.send_prompt(worker_id, prompt("hi")).await.unwrap();
+        assert_eq!(served.status, ResponseStatus::Ok);
+        assert_eq!(served.response, "2");
+    })
+    .await
+    .expect("both prompts answered");
+}
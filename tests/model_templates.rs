@@ -0,0 +1,73 @@
+//! Prompts left to the model's own template, against a mock Ollama: whether
+//! the model has one comes from the lookup the context window shares.
+
+use std::{num::NonZeroUsize, sync::Arc};
+
+use mesh_ai_node::{
+    PromptRequest,
+    context::{ContextPolicy, TruncateStrategy},
+    http_client::HttpClientConfig,
+    middleware::Request,
+    mock_ollama::{MockOllama, MockReply},
+    ollama,
+    templates::{OLLAMA_TEMPLATE, Templates},
+};
+use serde_json::json;
+
+fn request() -> Request {
+    Request {
+        peer: libp2p::PeerId::random(),
+        model: "mock".to_string(),
+        prompt: PromptRequest {
+            system: Some("Be brief.".to_string()),
+            apply_template: true,
+            ..PromptRequest::new("Hi".to_string())
+        },
+        raw: false,
+    }
+}
+
+#[tokio::test]
+async fn the_models_own_template_reuses_the_cached_lookup() {
+    let mock = MockOllama::start().await.unwrap();
+    ollama::init(
+        &mock.url(),
+        false,
+        NonZeroUsize::new(2).unwrap(),
+        &HttpClientConfig::default(),
+    )
+    .unwrap();
+    let models = Arc::new(ContextPolicy::new(TruncateStrategy::Off, 4096));
+    let templates = Templates::load(None, Vec::new(), models.clone()).unwrap();
+
+    mock.set(
+        "/api/show",
+        MockReply::Json(json!({
+            "template": "{{ .System }} {{ .Prompt }}",
+            "model_info": { "llama.context_length": 8192 },
+        })),
+    );
+    // Looked up once, as the node does for the models it serves at startup.
+    assert_eq!(
+        models.info("mock").await.unwrap().context_length,
+        Some(8192)
+    );
+    mock.set("/api/show", MockReply::Status(500));
+
+    let mut served = request();
+    let name = templates.apply(&mut served).await.unwrap();
+    assert_eq!(name, OLLAMA_TEMPLATE);
+    // Left for Ollama to apply, system prompt and all.
+    assert!(!served.raw);
+    assert_eq!(served.prompt.system.as_deref(), Some("Be brief."));
+    assert_eq!(
+        ollama::GenerateOptions::of(&served.prompt).system,
+        Some("Be brief.")
+    );
+
+    // A reload forgets it, so a model pulled again without one is caught.
+    templates.reload().unwrap();
+    mock.set("/api/show", MockReply::Json(json!({ "template": "" })));
+    let err = templates.apply(&mut request()).await.unwrap_err();
+    assert!(err.reason.contains("no chat template"), "{err}");
+}
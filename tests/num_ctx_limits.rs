@@ -0,0 +1,57 @@
+//! Requests sizing the context window with `num_ctx`, against a mock Ollama
+//! that knows how long some models were trained for and not others.
+
+use std::num::NonZeroUsize;
+
+use mesh_ai_node::{
+    PromptRequest,
+    context::{ContextPolicy, TruncateStrategy},
+    http_client::HttpClientConfig,
+    mock_ollama::{MockOllama, MockReply},
+    ollama,
+};
+use serde_json::json;
+
+fn request(num_ctx: u64) -> PromptRequest {
+    PromptRequest {
+        num_ctx: Some(num_ctx),
+        ..PromptRequest::new("Hi".to_string())
+    }
+}
+
+#[tokio::test]
+async fn windows_are_capped_by_the_model_and_the_operator() {
+    let mock = MockOllama::start().await.unwrap();
+    ollama::init(
+        &mock.url(),
+        false,
+        NonZeroUsize::new(2).unwrap(),
+        &HttpClientConfig::default(),
+    )
+    .unwrap();
+    let open = ContextPolicy::new(TruncateStrategy::Off, 4096);
+    let capped = ContextPolicy::new(TruncateStrategy::Off, 4096).with_max_num_ctx(Some(8192));
+
+    mock.set(
+        "/api/show",
+        MockReply::Json(json!({ "model_info": { "llama.context_length": 2048 } })),
+    );
+    assert_eq!(open.validate("trained", &request(2048)).await, Ok(()));
+    let err = open.validate("trained", &request(4096)).await.unwrap_err();
+    assert!(err.contains("2048 tokens trained was trained for"), "{err}");
+
+    // Without a trained length, only the operator's cap can vouch for it.
+    mock.set("/api/show", MockReply::Json(json!({ "parameters": "" })));
+    let err = open.validate("unknown", &request(1024)).await.unwrap_err();
+    assert!(err.contains("trained length is unknown"), "{err}");
+    assert_eq!(capped.validate("unknown", &request(8192)).await, Ok(()));
+    let err = capped
+        .validate("unknown", &request(65536))
+        .await
+        .unwrap_err();
+    assert!(err.contains("this node's limit of 8192"), "{err}");
+
+    // Leaving it unset is always fine.
+    let unset = PromptRequest::new("Hi".to_string());
+    assert_eq!(open.validate("unknown", &unset).await, Ok(()));
+}
@@ -0,0 +1,55 @@
+//! How the backend copes with an Ollama that misbehaves.
+//!
+//! The backend is process-wide, so the cases share one mock and run in turn.
+
+use std::{num::NonZeroUsize, time::Duration};
+
+use mesh_ai_node::{
+    http_client::HttpClientConfig,
+    mock_ollama::{MockOllama, MockReply},
+    ollama::{self, GenerateOptions},
+};
+
+async fn generate() -> Result<ollama::Generation, ollama::BackendError> {
+    ollama::generate("mock", "1 + 1".to_string(), GenerateOptions::default()).await
+}
+
+#[tokio::test]
+async fn backend_failures() {
+    let mock = MockOllama::start().await.unwrap();
+    ollama::init(
+        &mock.url(),
+        false,
+        NonZeroUsize::new(2).unwrap(),
+        &HttpClientConfig::default(),
+    )
+    .unwrap();
+
+    // The canned answer.
+    let generation = generate().await.unwrap();
+    assert_eq!(generation.text, "2");
+    assert_eq!(generation.prompt_tokens, 5);
+
+    // A server error is an error, not an empty answer.
+    mock.set("/api/generate", MockReply::Status(500));
+    let err = generate().await.unwrap_err();
+    assert!(err.to_string().contains("500"), "{err}");
+
+    // So is a body that isn't JSON.
+    mock.set("/api/generate", MockReply::Malformed);
+    assert!(generate().await.is_err());
+
+    // Giving up on a slow reply frees its slot for the next request.
+    mock.set(
+        "/api/generate",
+        MockReply::Delay(Duration::from_secs(30), Box::new(MockReply::Status(200))),
+    );
+    let slow = tokio::time::timeout(Duration::from_millis(200), generate()).await;
+    assert!(slow.is_err(), "the slow reply should time out");
+    assert_eq!(ollama::usage().in_flight, 0);
+
+    // Embeddings and the model list go through the same client.
+    mock.set("/api/embed", MockReply::Status(503));
+    assert!(ollama::embed("mock", &["a".to_string()]).await.is_err());
+    assert_eq!(ollama::local_models().await.unwrap(), ["mock"]);
+}
@@ -0,0 +1,64 @@
+//! Streamed generations reach the caller chunk by chunk, as Ollama sends
+//! them, rather than once the whole answer is in.
+
+use std::{
+    num::NonZeroUsize,
+    time::{Duration, Instant},
+};
+
+use mesh_ai_node::{
+    http_client::HttpClientConfig,
+    mock_ollama::{MockOllama, MockReply},
+    ollama::{self, GenerateOptions},
+};
+use serde_json::json;
+use tokio::sync::mpsc;
+
+const CHUNKS: usize = 5;
+const INTERVAL: Duration = Duration::from_millis(400);
+
+#[tokio::test]
+async fn the_first_chunk_arrives_before_the_rest_are_sent() {
+    let mock = MockOllama::start().await.unwrap();
+    let mut chunks: Vec<_> = (0..CHUNKS)
+        .map(|i| json!({ "response": format!("{i} "), "done": false }))
+        .collect();
+    chunks.push(json!({ "response": "", "done": true, "eval_count": CHUNKS }));
+    mock.set(
+        "/api/generate",
+        MockReply::Stream {
+            chunks,
+            interval: INTERVAL,
+        },
+    );
+    ollama::init(
+        &mock.url(),
+        false,
+        NonZeroUsize::new(1).unwrap(),
+        &HttpClientConfig::default(),
+    )
+    .unwrap();
+
+    let (tx, mut rx) = mpsc::channel(1);
+    let started = Instant::now();
+    let generation = tokio::spawn(async move {
+        ollama::generate_stream("mock", "count".to_string(), GenerateOptions::default(), tx).await
+    });
+
+    assert_eq!(rx.recv().await.as_deref(), Some("0 "));
+    let first_chunk = started.elapsed();
+    let mut rest = Vec::new();
+    while let Some(chunk) = rx.recv().await {
+        rest.push(chunk);
+    }
+    let total = started.elapsed();
+    let generation = generation.await.unwrap().unwrap();
+
+    assert_eq!(rest, ["1 ", "2 ", "3 ", "4 "]);
+    assert_eq!(generation.text, "0 1 2 3 4 ");
+    assert_eq!(generation.completion_tokens, CHUNKS as u64);
+    // The whole answer takes two seconds to send; the first chunk
+    // must not wait for it.
+    assert!(total >= INTERVAL * CHUNKS as u32, "took {total:?}");
+    assert!(first_chunk < INTERVAL, "first chunk after {first_chunk:?}");
+}
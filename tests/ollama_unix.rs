@@ -0,0 +1,63 @@
+//! The backend reached over a Unix socket, with `--ollama-url unix://PATH`.
+//!
+//! The backend is process-wide, so this runs in a test binary of its own.
+#![cfg(unix)]
+
+use std::{fs, num::NonZeroUsize};
+
+use mesh_ai_node::{
+    http_client::HttpClientConfig,
+    mock_ollama::{MockOllama, MockReply},
+    ollama::{self, GenerateOptions},
+    testing::TempDir,
+};
+
+#[tokio::test]
+async fn talks_to_ollama_over_a_unix_socket() {
+    let dir = TempDir::new("unix");
+    let socket = dir.join("ollama.sock");
+    let mock = MockOllama::start_unix(&socket).await.unwrap();
+    assert!(mock.url().starts_with("unix://"));
+    ollama::init(
+        &mock.url(),
+        false,
+        NonZeroUsize::new(2).unwrap(),
+        &HttpClientConfig::default(),
+    )
+    .unwrap();
+
+    let generation = ollama::generate("mock", "1 + 1".to_string(), GenerateOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(generation.text, "2");
+    assert_eq!(ollama::local_models().await.unwrap(), ["mock"]);
+
+    mock.set("/api/generate", MockReply::Status(500));
+    let err = ollama::generate("mock", "1 + 1".to_string(), GenerateOptions::default())
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("500"), "{err}");
+}
+
+#[test]
+fn refuses_a_path_that_is_not_a_socket() {
+    let dir = TempDir::new("unix-file");
+    let file = dir.join("not-a-socket");
+    fs::write(&file, "").unwrap();
+    let err = ollama::init(
+        &format!("unix://{}", file.display()),
+        false,
+        NonZeroUsize::new(1).unwrap(),
+        &HttpClientConfig::default(),
+    )
+    .unwrap_err();
+    assert!(err.to_string().contains("socket"), "{err}");
+    let err = ollama::init(
+        "unix://relative/ollama.sock",
+        false,
+        NonZeroUsize::new(1).unwrap(),
+        &HttpClientConfig::default(),
+    )
+    .unwrap_err();
+    assert!(err.to_string().contains("absolute"), "{err}");
+}
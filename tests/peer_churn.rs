@@ -0,0 +1,77 @@
+//! Soak test: thousands of peers come and go, each failing, using quota and
+//! identifying, and the per-peer tables stay bounded without losing what
+//! must be kept.
+
+use std::time::{Duration, Instant};
+
+use libp2p::PeerId;
+use mesh_ai_node::{
+    bans::{BanConfig, BanList},
+    pex::{KnownWorker, KnownWorkers},
+    quota::{Account, Limits, Quotas},
+};
+
+const CAPACITY: usize = 64;
+const CHURN: usize = 20_000;
+
+#[test]
+fn tables_stay_bounded_under_churn() {
+    let start = Instant::now();
+    let relay = PeerId::random();
+    let mut bans = BanList::new(BanConfig {
+        threshold: 2,
+        window: Duration::from_secs(60),
+        base_ban: Duration::from_secs(600),
+        max_ban: Duration::from_secs(3600),
+        max_records: CAPACITY,
+    });
+    let limits = Limits {
+        requests: Some(1000),
+        tokens: None,
+    };
+    let mut quotas = Quotas::new(Duration::from_secs(3600), limits, [], CAPACITY);
+    let mut workers = KnownWorkers::new(CAPACITY, Duration::from_secs(180));
+    bans.exempt(relay);
+    quotas.exempt(relay);
+    workers.exempt(relay);
+    workers.insert(relay, KnownWorker::default(), start);
+
+    // A handful of abusers get banned first; their bans must outlast the
+    // churn that follows.
+    let abusers: Vec<PeerId> = (0..8).map(|_| PeerId::random()).collect();
+    for peer in &abusers {
+        bans.record_failure(*peer, start);
+        assert!(bans.record_failure(*peer, start).is_some());
+    }
+    let regular = Account::Peer(PeerId::random());
+    for _ in 0..10 {
+        quotas.admit(&regular).unwrap();
+    }
+
+    for i in 0..CHURN {
+        let now = start + Duration::from_millis(i as u64);
+        let peer = PeerId::random();
+        bans.record_failure(peer, now);
+        let _ = quotas.admit(&Account::Peer(peer));
+        workers.insert(peer, KnownWorker::default(), now);
+        if i % 1000 == 0 {
+            bans.prune(now);
+            quotas.prune();
+            workers.prune(now);
+        }
+        assert!(bans.len() <= CAPACITY);
+        assert!(quotas.len() <= CAPACITY);
+        assert!(workers.len() <= CAPACITY);
+    }
+
+    let end = start + Duration::from_millis(CHURN as u64);
+    for peer in &abusers {
+        assert!(
+            bans.remaining(peer, end).is_some(),
+            "{peer}'s ban was evicted"
+        );
+    }
+    assert!(workers.get(&relay).is_some());
+    let status = quotas.admit(&regular).unwrap();
+    assert_eq!(status.remaining_requests, Some(1000 - 11));
+}
@@ -0,0 +1,65 @@
+//! A worker announces a model that loaded after it started to the peers
+//! that connect from then on.
+
+use std::time::Duration;
+
+use futures::StreamExt;
+use libp2p::{Multiaddr, identify, swarm::SwarmEvent};
+use mesh_ai_node::{
+    node::{self, BehaviourEvent, NodeConfig, TransportKind},
+    testing::{keypair, listen_on_tcp},
+};
+
+fn config(models: &[&str]) -> NodeConfig {
+    NodeConfig {
+        transports: vec![TransportKind::Tcp],
+        announced_models: models.iter().map(ToString::to_string).collect(),
+        ..Default::default()
+    }
+}
+
+/// Connects a fresh client to `addr` and returns the models it's told of.
+async fn models_seen(addr: Multiaddr, seed: u8) -> Vec<String> {
+    let mut client = node::build_swarm(keypair(seed), &config(&[])).unwrap();
+    client.dial(addr).unwrap();
+    tokio::time::timeout(Duration::from_secs(30), async {
+        loop {
+            if let SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Received {
+                info,
+                ..
+            })) = client.select_next_some().await
+            {
+                return node::announced_models(&info.agent_version);
+            }
+        }
+    })
+    .await
+    .expect("the worker identifies itself")
+}
+
+#[tokio::test]
+async fn new_connections_see_a_model_announced_late() {
+    let key = keypair(1);
+    let public = key.public();
+    let mut worker = node::build_swarm(key, &config(&["small"])).unwrap();
+    let addr = listen_on_tcp(&mut worker).await.unwrap();
+    let (reannounce_tx, mut reannounce_rx) = tokio::sync::mpsc::channel::<NodeConfig>(1);
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = worker.select_next_some() => {}
+                Some(config) = reannounce_rx.recv() => {
+                    node::reannounce(&mut worker, public.clone(), &config);
+                }
+            }
+        }
+    });
+
+    assert_eq!(models_seen(addr.clone(), 2).await, ["small"]);
+
+    reannounce_tx
+        .send(config(&["large", "small"]))
+        .await
+        .unwrap();
+    assert_eq!(models_seen(addr, 3).await, ["large", "small"]);
+}
@@ -0,0 +1,165 @@
+//! Retries against an in-process node, whose prompts are answered by a
+//! script in place of the backend.
+
+use std::{
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use clap::Parser;
+use libp2p::{Multiaddr, PeerId};
+use mesh_ai_node::{
+    PromptRequest, PromptResponse, ResponseStatus,
+    client::{Client, ClientConfig},
+    middleware::{Middleware, Next, Outcome, Request},
+    mock_ollama::MockOllama,
+    node::{self, NodeConfig, TransportKind},
+    retry::RetryPolicy,
+    server::{Node, Opt},
+    testing::{keypair, serving_node},
+};
+
+/// How the node answers its `n`th run, counting from zero: after how long,
+/// and with what.
+type Script = fn(usize) -> (Duration, PromptResponse);
+
+/// What the node ran: the key of every run, in order.
+#[derive(Default)]
+struct Seen {
+    keys: Mutex<Vec<String>>,
+    runs: AtomicUsize,
+}
+
+/// Answers every prompt the node runs by `script`, noting its key.
+struct Scripted {
+    script: Script,
+    seen: Arc<Seen>,
+}
+
+#[async_trait]
+impl Middleware for Scripted {
+    async fn handle(&self, request: Request, _next: Next<'_>) -> Outcome {
+        let key = request.prompt.idempotency_key.unwrap_or_default();
+        self.seen.keys.lock().unwrap().push(key);
+        let (delay, response) = (self.script)(self.seen.runs.fetch_add(1, Ordering::SeqCst));
+        tokio::time::sleep(delay).await;
+        Outcome::answered(response)
+    }
+}
+
+async fn setup(script: Script, config: ClientConfig) -> (Client, PeerId, Multiaddr, Arc<Seen>) {
+    let backend = MockOllama::start().await.unwrap();
+    let opt = Opt::parse_from([
+        "mesh-ai-node",
+        "--model",
+        "mock",
+        "--ollama-url",
+        &backend.url(),
+    ]);
+    let seen = Arc::new(Seen::default());
+    let node = Node::new(opt).await.unwrap().with(Scripted {
+        script,
+        seen: seen.clone(),
+    });
+    let (worker_id, addr) = serving_node(node).await;
+    let swarm = node::build_swarm(
+        keypair(2),
+        &NodeConfig {
+            transports: vec![TransportKind::Tcp],
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    (Client::new(swarm, config), worker_id, addr, seen)
+}
+
+fn prompt() -> PromptRequest {
+    PromptRequest::new("hi".to_string())
+}
+
+fn policy(budget: Duration) -> RetryPolicy {
+    RetryPolicy {
+        max_retries: 1_000,
+        budget,
+        initial_backoff: Duration::from_millis(10),
+        max_backoff: Duration::from_millis(20),
+    }
+}
+
+#[tokio::test]
+async fn every_attempt_carries_the_same_key() {
+    let script: Script = |n| {
+        let response = match n {
+            0 | 1 => PromptResponse::busy(),
+            _ => PromptResponse::ok("done".to_string()),
+        };
+        (Duration::ZERO, response)
+    };
+    let (client, worker, addr, seen) = setup(script, ClientConfig::default()).await;
+    let response = client
+        .send_prompt_with_retry(
+            worker,
+            vec![addr],
+            prompt(),
+            &policy(Duration::from_secs(30)),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status, ResponseStatus::Ok);
+
+    let keys = seen.keys.lock().unwrap().clone();
+    assert_eq!(keys.len(), 3);
+    assert!(!keys[0].is_empty());
+    assert!(keys.iter().all(|key| *key == keys[0]), "{keys:?}");
+}
+
+#[tokio::test]
+async fn gives_up_once_the_budget_is_spent() {
+    let script: Script = |_| (Duration::ZERO, PromptResponse::busy());
+    let (client, worker, addr, seen) = setup(script, ClientConfig::default()).await;
+    let budget = Duration::from_millis(500);
+    let started = Instant::now();
+    let response = client
+        .send_prompt_with_retry(worker, vec![addr], prompt(), &policy(budget))
+        .await
+        .unwrap();
+    assert_eq!(response.status, ResponseStatus::Busy);
+    assert!(started.elapsed() < budget * 2, "{:?}", started.elapsed());
+    let runs = seen.runs.load(Ordering::SeqCst);
+    assert!((2..1_000).contains(&runs), "{runs} runs");
+}
+
+#[tokio::test]
+async fn a_late_answer_is_shared_with_the_retry_instead_of_run_twice() {
+    // Slower than the client waits for a response.
+    let script: Script = |_| {
+        (
+            Duration::from_millis(800),
+            PromptResponse::ok("done".to_string()),
+        )
+    };
+    let config = ClientConfig {
+        response_timeout: Duration::from_millis(300),
+        ..Default::default()
+    };
+    let (client, worker, addr, seen) = setup(script, config).await;
+    let started = Instant::now();
+    let response = client
+        .send_prompt_with_retry(
+            worker,
+            vec![addr],
+            prompt(),
+            &policy(Duration::from_secs(30)),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status, ResponseStatus::Ok);
+    assert_eq!(response.response, "done");
+    // The answer came after the first attempt timed out, so a retry got it.
+    assert!(started.elapsed() >= Duration::from_millis(800));
+    assert_eq!(seen.runs.load(Ordering::SeqCst), 1);
+}
@@ -0,0 +1,40 @@
+//! Nodes with secp256k1 identities, as imported from the wider DePIN stack,
+//! exchanging a prompt on the memory transport.
+
+use std::time::Duration;
+
+use libp2p::identity;
+use mesh_ai_node::{
+    PromptRequest, ResponseStatus,
+    client::{Client, ClientConfig},
+    identity::KeyType,
+    node::NodeConfig,
+    testing::{echo_worker, listening_node_with},
+};
+
+#[tokio::test]
+async fn secp256k1_nodes_exchange_a_prompt() {
+    let worker_key = KeyType::Secp256k1.generate();
+    assert_eq!(worker_key.key_type(), identity::KeyType::Secp256k1);
+    let (worker, worker_id, addr) = listening_node_with(worker_key, NodeConfig::default())
+        .await
+        .unwrap();
+    tokio::spawn(echo_worker(worker));
+
+    let (swarm, _, _) = listening_node_with(KeyType::Secp256k1.generate(), NodeConfig::default())
+        .await
+        .unwrap();
+    let client = Client::new(swarm, ClientConfig::default());
+    client.connect(worker_id, vec![addr]).await.unwrap();
+
+    let request = PromptRequest::new("hi".to_string());
+    let response = tokio::time::timeout(
+        Duration::from_secs(30),
+        client.send_prompt(worker_id, request),
+    )
+    .await
+    .expect("the worker answers")
+    .unwrap();
+    assert_eq!(response.status, ResponseStatus::Ok);
+    assert_eq!(response.response, "echo: hi");
+}